@@ -0,0 +1,333 @@
+use std::{
+    fmt,
+    num::ParseIntError,
+    str::{FromStr, ParseBoolError},
+};
+
+use thiserror::Error;
+use wa_types::jid::{JIDParseError, JID};
+
+use crate::node::{AttrValue, Attrs, Node};
+
+#[derive(Error, Debug)]
+pub enum AttrError {
+    #[error("didn't find required attribute '{key}'")]
+    Missing { key: String },
+    #[error("expected attribute '{key}' to be {expected}")]
+    InvalidType { key: String, expected: &'static str },
+    #[error("failed to parse JID in attribute '{key}': {source}")]
+    InvalidJID { key: String, source: JIDParseError },
+    #[error("failed to parse int in attribute '{key}': {source}")]
+    InvalidInt { key: String, source: ParseIntError },
+    #[error("failed to parse bool in attribute '{key}': {source}")]
+    InvalidBool { key: String, source: ParseBoolError },
+    #[error("invalid unix timestamp in attribute '{key}': {source}")]
+    InvalidTimestamp {
+        key: String,
+        source: time::error::ComponentRange,
+    },
+}
+
+/// [`AttrErrors`] contains all the errors that occurred while reading attributes with an
+/// [`AttrUtility`].
+#[derive(Debug)]
+pub struct AttrErrors(pub Vec<AttrError>);
+
+impl fmt::Display for AttrErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.as_slice() {
+            [] => Ok(()),
+            [error] => write!(f, "{error}"),
+            [first, rest @ ..] => write!(f, "{first} (and {} other errors)", rest.len()),
+        }
+    }
+}
+
+impl std::error::Error for AttrErrors {}
+
+/// [`AttrUtility`] is a helper for reading typed attributes from a [`Node`].
+///
+/// Instead of failing on the first missing or malformed attribute, all errors are collected
+/// and can be checked once at the end with [`AttrUtility::ok`] or [`AttrUtility::into_result`].
+/// Getters return an empty value for attributes that failed to parse.
+pub struct AttrUtility<'a> {
+    pub attrs: &'a Attrs,
+    pub errors: Vec<AttrError>,
+}
+
+impl Node {
+    /// Returns an [`AttrUtility`] for reading the attributes of the node.
+    pub fn attr_getter(&self) -> AttrUtility<'_> {
+        AttrUtility {
+            attrs: &self.attrs,
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl<'a> AttrUtility<'a> {
+    fn get(&mut self, key: &str, require: bool) -> Option<&'a AttrValue> {
+        let value = self.attrs.get(key);
+        if value.is_none() && require {
+            self.errors.push(AttrError::Missing {
+                key: key.to_string(),
+            });
+        }
+        value
+    }
+
+    fn get_string(&mut self, key: &str, require: bool) -> Option<&'a str> {
+        match self.get(key, require)? {
+            AttrValue::String(value) => Some(value),
+            AttrValue::JID(_) => {
+                self.errors.push(AttrError::InvalidType {
+                    key: key.to_string(),
+                    expected: "string",
+                });
+                None
+            }
+        }
+    }
+
+    fn get_parsed<T, E>(
+        &mut self,
+        key: &str,
+        require: bool,
+        map_err: fn(String, E) -> AttrError,
+    ) -> Option<T>
+    where
+        T: FromStr<Err = E>,
+    {
+        let value = self.get_string(key, require)?;
+        match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(err) => {
+                self.errors.push(map_err(key.to_string(), err));
+                None
+            }
+        }
+    }
+
+    fn get_jid(&mut self, key: &str, require: bool) -> Option<JID> {
+        match self.get(key, require)? {
+            AttrValue::JID(jid) => Some(jid.clone()),
+            AttrValue::String(value) => match JID::from_str(value) {
+                Ok(jid) => Some(jid),
+                Err(source) => {
+                    self.errors.push(AttrError::InvalidJID {
+                        key: key.to_string(),
+                        source,
+                    });
+                    None
+                }
+            },
+        }
+    }
+
+    fn get_unix_time(
+        &mut self,
+        key: &str,
+        require: bool,
+        milli: bool,
+    ) -> Option<time::OffsetDateTime> {
+        let value: i64 = self.get_parsed(key, require, |key, source| AttrError::InvalidInt {
+            key,
+            source,
+        })?;
+        let parsed = if milli {
+            time::OffsetDateTime::from_unix_timestamp_nanos(value as i128 * 1_000_000)
+        } else {
+            time::OffsetDateTime::from_unix_timestamp(value)
+        };
+        match parsed {
+            Ok(parsed) => Some(parsed),
+            Err(source) => {
+                self.errors.push(AttrError::InvalidTimestamp {
+                    key: key.to_string(),
+                    source,
+                });
+                None
+            }
+        }
+    }
+
+    /// Returns the string attribute with the given key, or [`Option::None`] if it's not set.
+    pub fn optional_string(&mut self, key: &str) -> Option<&'a str> {
+        self.get_string(key, false)
+    }
+
+    /// Returns the string attribute with the given key. If it's not set, an error is recorded
+    /// and an empty string is returned.
+    pub fn string(&mut self, key: &str) -> &'a str {
+        self.get_string(key, true).unwrap_or_default()
+    }
+
+    /// Returns the JID attribute with the given key, or [`Option::None`] if it's not set.
+    ///
+    /// String values are parsed as JIDs too.
+    pub fn optional_jid(&mut self, key: &str) -> Option<JID> {
+        self.get_jid(key, false)
+    }
+
+    /// Returns the JID attribute with the given key, or an empty JID if it's not set.
+    pub fn optional_jid_or_empty(&mut self, key: &str) -> JID {
+        self.get_jid(key, false).unwrap_or_else(empty_jid)
+    }
+
+    /// Returns the JID attribute with the given key. If it's not set, an error is recorded and
+    /// an empty JID is returned.
+    pub fn jid(&mut self, key: &str) -> JID {
+        self.get_jid(key, true).unwrap_or_else(empty_jid)
+    }
+
+    /// Returns the integer attribute with the given key, or [`Option::None`] if it's not set.
+    pub fn optional_i64(&mut self, key: &str) -> Option<i64> {
+        self.get_parsed(key, false, |key, source| AttrError::InvalidInt {
+            key,
+            source,
+        })
+    }
+
+    /// Returns the integer attribute with the given key. If it's not set, an error is recorded
+    /// and zero is returned.
+    pub fn i64(&mut self, key: &str) -> i64 {
+        self.get_parsed(key, true, |key, source| AttrError::InvalidInt {
+            key,
+            source,
+        })
+        .unwrap_or_default()
+    }
+
+    /// Returns the unsigned integer attribute with the given key, or [`Option::None`] if it's
+    /// not set.
+    pub fn optional_u64(&mut self, key: &str) -> Option<u64> {
+        self.get_parsed(key, false, |key, source| AttrError::InvalidInt {
+            key,
+            source,
+        })
+    }
+
+    /// Returns the unsigned integer attribute with the given key. If it's not set, an error is
+    /// recorded and zero is returned.
+    pub fn u64(&mut self, key: &str) -> u64 {
+        self.get_parsed(key, true, |key, source| AttrError::InvalidInt {
+            key,
+            source,
+        })
+        .unwrap_or_default()
+    }
+
+    /// Returns the boolean attribute with the given key, or [`Option::None`] if it's not set.
+    pub fn optional_bool(&mut self, key: &str) -> Option<bool> {
+        self.get_parsed(key, false, |key, source| AttrError::InvalidBool {
+            key,
+            source,
+        })
+    }
+
+    /// Returns the boolean attribute with the given key. If it's not set, an error is recorded
+    /// and `false` is returned.
+    pub fn bool(&mut self, key: &str) -> bool {
+        self.get_parsed(key, true, |key, source| AttrError::InvalidBool {
+            key,
+            source,
+        })
+        .unwrap_or_default()
+    }
+
+    /// Returns the unix timestamp (in seconds) attribute with the given key, or
+    /// [`Option::None`] if it's not set.
+    pub fn optional_unix_time(&mut self, key: &str) -> Option<time::OffsetDateTime> {
+        self.get_unix_time(key, false, false)
+    }
+
+    /// Returns the unix timestamp (in seconds) attribute with the given key. If it's not set,
+    /// an error is recorded and the unix epoch is returned.
+    pub fn unix_time(&mut self, key: &str) -> time::OffsetDateTime {
+        self.get_unix_time(key, true, false)
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH)
+    }
+
+    /// Returns the unix timestamp (in milliseconds) attribute with the given key, or
+    /// [`Option::None`] if it's not set.
+    pub fn optional_unix_milli(&mut self, key: &str) -> Option<time::OffsetDateTime> {
+        self.get_unix_time(key, false, true)
+    }
+
+    /// Returns the unix timestamp (in milliseconds) attribute with the given key. If it's not
+    /// set, an error is recorded and the unix epoch is returned.
+    pub fn unix_milli(&mut self, key: &str) -> time::OffsetDateTime {
+        self.get_unix_time(key, true, true)
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH)
+    }
+
+    /// Returns true if there were no errors reading attributes so far.
+    pub fn ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Returns all the errors that occurred while reading attributes, if there were any.
+    pub fn into_result(self) -> Result<(), AttrErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AttrErrors(self.errors))
+        }
+    }
+}
+
+fn empty_jid() -> JID {
+    JID::new(String::new(), String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::NodeContent;
+
+    #[test]
+    fn attr_getter_collects_all_errors() {
+        let node = Node::new(
+            "message",
+            Attrs::from([
+                ("id".to_string(), AttrValue::from("3EB0")),
+                ("t".to_string(), AttrValue::from("not a number")),
+                ("from".to_string(), AttrValue::from("1234@s.whatsapp.net")),
+                ("offline".to_string(), AttrValue::from("yes")),
+            ]),
+            NodeContent::None,
+        );
+
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("id"), "3EB0");
+        assert_eq!(
+            ag.jid("from"),
+            JID::new("1234".to_string(), "s.whatsapp.net".to_string())
+        );
+        assert_eq!(ag.unix_time("t"), time::OffsetDateTime::UNIX_EPOCH);
+        assert!(!ag.bool("offline"));
+        assert!(ag.jid("participant").is_empty());
+        assert_eq!(ag.optional_string("notify"), None);
+
+        let errors = ag.into_result().unwrap_err().0;
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(&errors[0], AttrError::InvalidInt { key, .. } if key == "t"));
+        assert!(matches!(&errors[1], AttrError::InvalidBool { key, .. } if key == "offline"));
+        assert!(matches!(&errors[2], AttrError::Missing { key } if key == "participant"));
+    }
+
+    #[test]
+    fn attr_errors_display_the_first_error() {
+        let missing = |key: &str| AttrError::Missing {
+            key: key.to_string(),
+        };
+        assert_eq!(
+            AttrErrors(vec![missing("id")]).to_string(),
+            "didn't find required attribute 'id'"
+        );
+        assert_eq!(
+            AttrErrors(vec![missing("id"), missing("t"), missing("from")]).to_string(),
+            "didn't find required attribute 'id' (and 2 other errors)"
+        );
+    }
+}
@@ -0,0 +1,446 @@
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+use thiserror::Error;
+use wa_types::jid::{INTEROP_SERVER, JID, MESSENGER_SERVER};
+
+use crate::node::{AttrValue, Attrs, Node, NodeContent};
+use crate::token::{self, TokenIndexError};
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("unexpected end of data: needed {needed} bytes at position {position}")]
+    UnexpectedEof { position: usize, needed: usize },
+    #[error("{count} leftover bytes after decoding")]
+    LeftoverBytes { count: usize },
+    #[error("invalid token {token} at position {position}")]
+    InvalidToken { token: u8, position: usize },
+    #[error("invalid list size tag {token} at position {position}")]
+    InvalidListSize { token: u8, position: usize },
+    #[error(transparent)]
+    TokenIndex(#[from] TokenIndexError),
+    #[error("invalid packed value {value} for packed type {token}")]
+    InvalidPackedValue { value: u8, token: u8 },
+    #[error("invalid node")]
+    InvalidNode,
+    #[error("invalid type {kind} for {expected}")]
+    InvalidType {
+        kind: &'static str,
+        expected: &'static str,
+    },
+    #[error("invalid server {server} for {kind} JID")]
+    InvalidJIDServer { server: String, kind: &'static str },
+    #[error("invalid UTF-8 string at position {position}")]
+    InvalidUtf8 { position: usize },
+    #[error("failed to decompress frame: {0}")]
+    Decompress(#[from] std::io::Error),
+    #[error("empty frame")]
+    EmptyFrame,
+}
+
+/// [`Value`] is a single value read from the binary stream, before it's known whether it's
+/// an attribute value, a tag or the content of a node.
+#[allow(clippy::upper_case_acronyms)]
+enum Value {
+    None,
+    String(String),
+    Bytes(Vec<u8>),
+    JID(JID),
+    Nodes(Vec<Node>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::None => "nothing",
+            Value::String(_) => "string",
+            Value::Bytes(_) => "bytes",
+            Value::JID(_) => "JID",
+            Value::Nodes(_) => "list of nodes",
+        }
+    }
+
+    fn into_string(self, expected: &'static str) -> Result<String, DecodeError> {
+        match self {
+            Value::String(string) => Ok(string),
+            other => Err(DecodeError::InvalidType {
+                kind: other.kind(),
+                expected,
+            }),
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Decoder { data, index: 0 }
+    }
+
+    fn check_eos(&self, length: usize) -> Result<(), DecodeError> {
+        if self.index + length > self.data.len() {
+            Err(DecodeError::UnexpectedEof {
+                position: self.index,
+                needed: length,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, DecodeError> {
+        self.check_eos(1)?;
+        let byte = self.data[self.index];
+        self.index += 1;
+        Ok(byte)
+    }
+
+    fn read_raw(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        self.check_eos(length)?;
+        let raw = &self.data[self.index..self.index + length];
+        self.index += length;
+        Ok(raw)
+    }
+
+    fn read_int_n(&mut self, n: usize) -> Result<usize, DecodeError> {
+        let raw = self.read_raw(n)?;
+        Ok(raw
+            .iter()
+            .fold(0usize, |value, byte| (value << 8) | *byte as usize))
+    }
+
+    fn read_int20(&mut self) -> Result<usize, DecodeError> {
+        let raw = self.read_raw(3)?;
+        Ok((((raw[0] & 0x0f) as usize) << 16) | ((raw[1] as usize) << 8) | raw[2] as usize)
+    }
+
+    fn read_packed8(&mut self, tag: u8) -> Result<String, DecodeError> {
+        let start_byte = self.read_byte()?;
+        let length = (start_byte & 0x7f) as usize;
+
+        let mut value = String::with_capacity(length * 2);
+        for _ in 0..length {
+            let byte = self.read_byte()?;
+            value.push(unpack_byte(tag, (byte & 0xf0) >> 4)?);
+            value.push(unpack_byte(tag, byte & 0x0f)?);
+        }
+
+        if start_byte >> 7 != 0 {
+            value.pop();
+        }
+
+        Ok(value)
+    }
+
+    fn read_list_size(&mut self, tag: u8) -> Result<usize, DecodeError> {
+        match tag {
+            token::LIST_EMPTY => Ok(0),
+            token::LIST8 => self.read_int_n(1),
+            token::LIST16 => self.read_int_n(2),
+            _ => Err(DecodeError::InvalidListSize {
+                token: tag,
+                position: self.index,
+            }),
+        }
+    }
+
+    fn read_list(&mut self, tag: u8) -> Result<Vec<Node>, DecodeError> {
+        let size = self.read_list_size(tag)?;
+        let mut nodes = Vec::with_capacity(size);
+        for _ in 0..size {
+            nodes.push(self.read_node()?);
+        }
+        Ok(nodes)
+    }
+
+    fn read_bytes_or_string(
+        &mut self,
+        length: usize,
+        as_string: bool,
+    ) -> Result<Value, DecodeError> {
+        let position = self.index;
+        let raw = self.read_raw(length)?;
+        if as_string {
+            let string =
+                std::str::from_utf8(raw).map_err(|_| DecodeError::InvalidUtf8 { position })?;
+            Ok(Value::String(string.to_string()))
+        } else {
+            Ok(Value::Bytes(raw.to_vec()))
+        }
+    }
+
+    fn read_string(&mut self, expected: &'static str) -> Result<String, DecodeError> {
+        self.read(true)?.into_string(expected)
+    }
+
+    fn read_optional_string(&mut self, expected: &'static str) -> Result<String, DecodeError> {
+        match self.read(true)? {
+            Value::None => Ok(String::new()),
+            value => value.into_string(expected),
+        }
+    }
+
+    fn read_jid_pair(&mut self) -> Result<JID, DecodeError> {
+        let user = self.read_optional_string("JID user")?;
+        let server = self.read_string("JID server")?;
+        Ok(JID::new(user, server))
+    }
+
+    fn read_ad_jid(&mut self) -> Result<JID, DecodeError> {
+        let agent = self.read_byte()?;
+        let device = self.read_byte()?;
+        let user = self.read_string("AD JID user")?;
+        Ok(JID::new_ad_jid(user, agent, device))
+    }
+
+    fn read_interop_jid(&mut self) -> Result<JID, DecodeError> {
+        let user = self.read_string("interop JID user")?;
+        let device = self.read_int_n(2)? as u16;
+        let integrator = self.read_int_n(2)? as u16;
+        let server = self.read_string("interop JID server")?;
+        if server != INTEROP_SERVER {
+            return Err(DecodeError::InvalidJIDServer {
+                server,
+                kind: "interop",
+            });
+        }
+        Ok(JID {
+            user,
+            device,
+            integrator,
+            server,
+            raw_agent: 0,
+        })
+    }
+
+    fn read_fb_jid(&mut self) -> Result<JID, DecodeError> {
+        let user = self.read_string("FB JID user")?;
+        let device = self.read_int_n(2)? as u16;
+        let server = self.read_string("FB JID server")?;
+        if server != MESSENGER_SERVER {
+            return Err(DecodeError::InvalidJIDServer { server, kind: "FB" });
+        }
+        Ok(JID {
+            user,
+            device,
+            server,
+            integrator: 0,
+            raw_agent: 0,
+        })
+    }
+
+    fn read(&mut self, as_string: bool) -> Result<Value, DecodeError> {
+        let tag = self.read_byte()?;
+
+        match tag {
+            token::LIST_EMPTY => Ok(Value::None),
+            token::LIST8 | token::LIST16 => Ok(Value::Nodes(self.read_list(tag)?)),
+            token::BINARY8 => {
+                let size = self.read_int_n(1)?;
+                self.read_bytes_or_string(size, as_string)
+            }
+            token::BINARY20 => {
+                let size = self.read_int20()?;
+                self.read_bytes_or_string(size, as_string)
+            }
+            token::BINARY32 => {
+                let size = self.read_int_n(4)?;
+                self.read_bytes_or_string(size, as_string)
+            }
+            token::DICTIONARY0..=token::DICTIONARY3 => {
+                let index = self.read_byte()?;
+                let token = token::get_double_token(tag - token::DICTIONARY0, index)?;
+                Ok(Value::String(token.to_string()))
+            }
+            token::FB_JID => Ok(Value::JID(self.read_fb_jid()?)),
+            token::INTEROP_JID => Ok(Value::JID(self.read_interop_jid()?)),
+            token::JID_PAIR => Ok(Value::JID(self.read_jid_pair()?)),
+            token::AD_JID => Ok(Value::JID(self.read_ad_jid()?)),
+            token::NIBBLE8 | token::HEX8 => Ok(Value::String(self.read_packed8(tag)?)),
+            _ if (tag as usize) < token::SINGLE_BYTE_TOKENS.len() => {
+                Ok(Value::String(token::get_single_token(tag)?.to_string()))
+            }
+            _ => Err(DecodeError::InvalidToken {
+                token: tag,
+                position: self.index - 1,
+            }),
+        }
+    }
+
+    fn read_attributes(&mut self, count: usize) -> Result<Attrs, DecodeError> {
+        let mut attrs = Attrs::with_capacity(count);
+        for _ in 0..count {
+            let key = self.read_string("attribute key")?;
+            let value = match self.read(true)? {
+                Value::String(string) => AttrValue::String(string),
+                Value::JID(jid) => AttrValue::JID(jid),
+                other => {
+                    return Err(DecodeError::InvalidType {
+                        kind: other.kind(),
+                        expected: "attribute value",
+                    })
+                }
+            };
+            attrs.insert(key, value);
+        }
+        Ok(attrs)
+    }
+
+    fn read_node(&mut self) -> Result<Node, DecodeError> {
+        let size = self.read_byte()?;
+        let list_size = self.read_list_size(size)?;
+
+        let tag = match self.read(true)? {
+            Value::None => String::new(),
+            value => value.into_string("node tag")?,
+        };
+        if list_size == 0 || tag.is_empty() {
+            return Err(DecodeError::InvalidNode);
+        }
+
+        let attrs = self.read_attributes((list_size - 1) >> 1)?;
+        if list_size % 2 == 1 {
+            return Ok(Node::new(tag, attrs, NodeContent::None));
+        }
+
+        let content = match self.read(false)? {
+            Value::None => NodeContent::None,
+            Value::String(string) => NodeContent::String(string),
+            Value::Bytes(bytes) => NodeContent::Bytes(bytes),
+            Value::JID(jid) => NodeContent::JID(jid),
+            Value::Nodes(nodes) => NodeContent::Nodes(nodes),
+        };
+
+        Ok(Node::new(tag, attrs, content))
+    }
+}
+
+fn unpack_byte(tag: u8, value: u8) -> Result<char, DecodeError> {
+    match tag {
+        token::NIBBLE8 => unpack_nibble(value),
+        _ => unpack_hex(value),
+    }
+    .ok_or(DecodeError::InvalidPackedValue { value, token: tag })
+}
+
+fn unpack_nibble(value: u8) -> Option<char> {
+    match value {
+        0..=9 => Some((b'0' + value) as char),
+        10 => Some('-'),
+        11 => Some('.'),
+        15 => Some('\x00'),
+        _ => None,
+    }
+}
+
+fn unpack_hex(value: u8) -> Option<char> {
+    match value {
+        0..=9 => Some((b'0' + value) as char),
+        10..=15 => Some((b'A' + value - 10) as char),
+        _ => None,
+    }
+}
+
+/// [`unmarshal`] decodes a WhatsApp binary XML message into a [`Node`].
+///
+/// The data must already be unpacked with [`unpack`]. All of the data must be consumed by the
+/// node, otherwise [`DecodeError::LeftoverBytes`] is returned.
+pub fn unmarshal(data: &[u8]) -> Result<Node, DecodeError> {
+    let mut decoder = Decoder::new(data);
+    let node = decoder.read_node()?;
+    if decoder.index != data.len() {
+        return Err(DecodeError::LeftoverBytes {
+            count: data.len() - decoder.index,
+        });
+    }
+    Ok(node)
+}
+
+/// [`unpack`] strips the leading flags byte of a decrypted frame and decompresses the rest of
+/// the data if the flags say it's compressed.
+pub fn unpack(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let (flags, data) = data.split_first().ok_or(DecodeError::EmptyFrame)?;
+    if flags & 2 != 0 {
+        let mut decompressed = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmarshal_simple_node() {
+        // <iq id="1" type="get" xmlns="urn:xmpp:ping"/>
+        let data = [token::LIST8, 7, 25, 8, 85, 4, 41, 22, 43];
+        let node = unmarshal(&data).unwrap();
+        assert_eq!(node.tag, "iq");
+        assert_eq!(node.attrs.get("id"), Some(&AttrValue::from("1")));
+        assert_eq!(node.attrs.get("type"), Some(&AttrValue::from("get")));
+        assert_eq!(
+            node.attrs.get("xmlns"),
+            Some(&AttrValue::from("urn:xmpp:ping"))
+        );
+        assert_eq!(node.content, NodeContent::None);
+    }
+
+    #[test]
+    fn unmarshal_jids_and_packed_strings() {
+        // <message from="123-456@g.us" participant="1234.0:5@s.whatsapp.net" t="1700000000"/>
+        let mut data = vec![token::LIST8, 7, 19];
+        data.extend([
+            6,
+            token::JID_PAIR,
+            token::NIBBLE8,
+            0x84,
+            0x12,
+            0x3a,
+            0x45,
+            0x6f,
+            28,
+        ]);
+        data.extend([5, token::AD_JID, 0, 5, token::NIBBLE8, 0x02, 0x12, 0x34]);
+        data.extend([26, token::NIBBLE8, 0x05, 0x17, 0x00, 0x00, 0x00, 0x00]);
+
+        let node = unmarshal(&data).unwrap();
+        assert_eq!(node.tag, "message");
+        assert_eq!(
+            node.attrs.get("from"),
+            Some(&AttrValue::JID(JID::new(
+                "123-456".to_string(),
+                "g.us".to_string()
+            )))
+        );
+        assert_eq!(
+            node.attrs.get("participant"),
+            Some(&AttrValue::JID(JID::new_ad_jid("1234".to_string(), 0, 5)))
+        );
+        assert_eq!(node.attrs.get("t"), Some(&AttrValue::from("1700000000")));
+    }
+
+    #[test]
+    fn unmarshal_leftover_bytes() {
+        let data = [token::LIST8, 1, 25, 0];
+        assert!(matches!(
+            unmarshal(&data),
+            Err(DecodeError::LeftoverBytes { count: 1 })
+        ));
+    }
+
+    #[test]
+    fn unmarshal_truncated() {
+        let data = [token::LIST8, 2, 25, token::BINARY8, 10, 1];
+        assert!(matches!(
+            unmarshal(&data),
+            Err(DecodeError::UnexpectedEof { .. })
+        ));
+    }
+}
@@ -0,0 +1,384 @@
+use thiserror::Error;
+use wa_types::jid::{
+    DEFAULT_USER_SERVER, HIDDEN_USER_SERVER, HOSTED_SERVER, INTEROP_SERVER, JID, MESSENGER_SERVER,
+};
+
+use crate::node::{AttrValue, Attrs, Node, NodeContent};
+use crate::token;
+
+#[derive(Error, Debug)]
+pub enum EncodeError {
+    #[error("list too large: got {size} items, limit: {limit}")]
+    ListTooLarge { size: usize, limit: usize },
+    #[error("binary data too large: got {size} bytes, limit: {limit}")]
+    BinaryTooLarge { size: usize, limit: usize },
+    #[error("invalid node: tag must not be empty")]
+    EmptyTag,
+}
+
+struct Encoder {
+    data: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        // The first byte is the flags byte of the frame. It's always zero because we never
+        // compress outgoing data.
+        Encoder { data: vec![0] }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        self.data.push(byte);
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn push_int_n(&mut self, value: usize, n: usize) {
+        for i in (0..n).rev() {
+            self.push_byte((value >> (i * 8)) as u8);
+        }
+    }
+
+    fn push_int20(&mut self, value: usize) {
+        self.push_bytes(&[
+            ((value >> 16) & 0x0f) as u8,
+            ((value >> 8) & 0xff) as u8,
+            (value & 0xff) as u8,
+        ]);
+    }
+
+    fn write_byte_length(&mut self, length: usize) -> Result<(), EncodeError> {
+        if length < 1 << 8 {
+            self.push_byte(token::BINARY8);
+            self.push_int_n(length, 1);
+        } else if length < 1 << 20 {
+            self.push_byte(token::BINARY20);
+            self.push_int20(length);
+        } else if length < i32::MAX as usize {
+            self.push_byte(token::BINARY32);
+            self.push_int_n(length, 4);
+        } else {
+            return Err(EncodeError::BinaryTooLarge {
+                size: length,
+                limit: i32::MAX as usize,
+            });
+        }
+        Ok(())
+    }
+
+    fn write_list_start(&mut self, size: usize) -> Result<(), EncodeError> {
+        if size == 0 {
+            self.push_byte(token::LIST_EMPTY);
+        } else if size < 1 << 8 {
+            self.push_byte(token::LIST8);
+            self.push_int_n(size, 1);
+        } else if size < 1 << 16 {
+            self.push_byte(token::LIST16);
+            self.push_int_n(size, 2);
+        } else {
+            return Err(EncodeError::ListTooLarge {
+                size,
+                limit: (1 << 16) - 1,
+            });
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        self.write_byte_length(bytes.len())?;
+        self.push_bytes(bytes);
+        Ok(())
+    }
+
+    fn write_packed_bytes(&mut self, value: &str, data_type: u8) {
+        self.push_byte(data_type);
+
+        let mut rounded_length = value.len().div_ceil(2) as u8;
+        if value.len() % 2 == 1 {
+            rounded_length |= 0x80;
+        }
+        self.push_byte(rounded_length);
+
+        let pack: fn(u8) -> u8 = if data_type == token::NIBBLE8 {
+            pack_nibble
+        } else {
+            pack_hex
+        };
+
+        for pair in value.as_bytes().chunks(2) {
+            let high = pack(pair[0]);
+            let low = pair.get(1).copied().map(pack).unwrap_or(0x0f);
+            self.push_byte((high << 4) | low);
+        }
+    }
+
+    fn write_string(&mut self, value: &str) -> Result<(), EncodeError> {
+        let index_map = token::token_indices_map();
+
+        if let Some(index) = index_map.index_of_single_token(value) {
+            self.push_byte(index);
+        } else if let Some((dict_index, index)) = index_map.index_of_double_token(value) {
+            self.push_byte(token::DICTIONARY0 + dict_index);
+            self.push_byte(index);
+        } else if validate_nibble(value) {
+            self.write_packed_bytes(value, token::NIBBLE8);
+        } else if validate_hex(value) {
+            self.write_packed_bytes(value, token::HEX8);
+        } else {
+            self.write_bytes(value.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_jid(&mut self, jid: &JID) -> Result<(), EncodeError> {
+        let server = jid.server.as_str();
+        if ((server == DEFAULT_USER_SERVER || server == HIDDEN_USER_SERVER) && jid.device > 0)
+            || server == HOSTED_SERVER
+        {
+            self.push_byte(token::AD_JID);
+            self.push_byte(jid.actual_agent());
+            self.push_byte(jid.device as u8);
+            self.write_string(&jid.user)?;
+        } else if server == MESSENGER_SERVER {
+            self.push_byte(token::FB_JID);
+            self.write_string(&jid.user)?;
+            self.push_int_n(jid.device as usize, 2);
+            self.write_string(server)?;
+        } else if server == INTEROP_SERVER {
+            self.push_byte(token::INTEROP_JID);
+            self.write_string(&jid.user)?;
+            self.push_int_n(jid.device as usize, 2);
+            self.push_int_n(jid.integrator as usize, 2);
+            self.write_string(server)?;
+        } else {
+            self.push_byte(token::JID_PAIR);
+            if jid.user.is_empty() {
+                self.push_byte(token::LIST_EMPTY);
+            } else {
+                self.write_string(&jid.user)?;
+            }
+            self.write_string(server)?;
+        }
+        Ok(())
+    }
+
+    fn write_attributes(&mut self, attrs: &Attrs) -> Result<(), EncodeError> {
+        for (key, value) in attrs {
+            match value {
+                AttrValue::String(value) if value.is_empty() => continue,
+                AttrValue::String(value) => {
+                    self.write_string(key)?;
+                    self.write_string(value)?;
+                }
+                AttrValue::JID(jid) => {
+                    self.write_string(key)?;
+                    self.write_jid(jid)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_content(&mut self, content: &NodeContent) -> Result<(), EncodeError> {
+        match content {
+            NodeContent::None => self.push_byte(token::LIST_EMPTY),
+            NodeContent::Nodes(nodes) => {
+                self.write_list_start(nodes.len())?;
+                for node in nodes {
+                    self.write_node(node)?;
+                }
+            }
+            NodeContent::Bytes(bytes) => self.write_bytes(bytes)?,
+            NodeContent::String(string) => self.write_string(string)?,
+            NodeContent::JID(jid) => self.write_jid(jid)?,
+        }
+        Ok(())
+    }
+
+    fn write_node(&mut self, node: &Node) -> Result<(), EncodeError> {
+        if node.tag.is_empty() {
+            return Err(EncodeError::EmptyTag);
+        }
+
+        let has_content = node.content != NodeContent::None;
+        let attr_count = node
+            .attrs
+            .values()
+            .filter(|value| !matches!(value, AttrValue::String(value) if value.is_empty()))
+            .count();
+
+        self.write_list_start(2 * attr_count + 1 + has_content as usize)?;
+        self.write_string(&node.tag)?;
+        self.write_attributes(&node.attrs)?;
+        if has_content {
+            self.write_content(&node.content)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the string would be encoded as a dictionary token or a packed string
+/// rather than as raw bytes.
+pub(crate) fn is_token_or_packable(value: &str) -> bool {
+    let index_map = token::token_indices_map();
+    index_map.index_of_single_token(value).is_some()
+        || index_map.index_of_double_token(value).is_some()
+        || validate_nibble(value)
+        || validate_hex(value)
+}
+
+fn validate_nibble(value: &str) -> bool {
+    value.len() <= token::PACKED_MAX as usize
+        && value
+            .bytes()
+            .all(|char| char.is_ascii_digit() || char == b'-' || char == b'.')
+}
+
+fn validate_hex(value: &str) -> bool {
+    value.len() <= token::PACKED_MAX as usize
+        && value
+            .bytes()
+            .all(|char| char.is_ascii_digit() || (b'A'..=b'F').contains(&char))
+}
+
+fn pack_nibble(value: u8) -> u8 {
+    match value {
+        b'-' => 10,
+        b'.' => 11,
+        _ => value - b'0',
+    }
+}
+
+fn pack_hex(value: u8) -> u8 {
+    match value {
+        b'A'..=b'F' => 10 + value - b'A',
+        _ => value - b'0',
+    }
+}
+
+/// [`marshal`] encodes a [`Node`] into the WhatsApp binary XML format.
+///
+/// The returned data starts with the (always zero) flags byte, so it can be sent to the
+/// socket as is. Attributes with empty string values are skipped.
+pub fn marshal(node: &Node) -> Result<Vec<u8>, EncodeError> {
+    let mut encoder = Encoder::new();
+    encoder.write_node(node)?;
+    Ok(encoder.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::decoder::{unmarshal, unpack};
+
+    fn arb_string() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::sample::select(token::SINGLE_BYTE_TOKENS[1..].to_vec()).prop_map(String::from),
+            prop::sample::select(token::DOUBLE_BYTE_TOKENS.concat()).prop_map(String::from),
+            "[0-9.-]{1,40}",
+            "[0-9A-F]{1,40}",
+            "[0-9a-f]{1,40}",
+            "\\PC{1,300}",
+        ]
+    }
+
+    fn arb_token_or_packed() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::sample::select(token::SINGLE_BYTE_TOKENS[1..].to_vec()).prop_map(String::from),
+            prop::sample::select(token::DOUBLE_BYTE_TOKENS.concat()).prop_map(String::from),
+            "[0-9.-]{0,127}",
+            "[0-9A-F]{1,127}",
+        ]
+    }
+
+    fn arb_jid() -> impl Strategy<Value = JID> {
+        prop_oneof![
+            (
+                "[0-9]{0,15}",
+                prop::sample::select(vec![
+                    "s.whatsapp.net",
+                    "g.us",
+                    "broadcast",
+                    "lid",
+                    "newsletter"
+                ])
+            )
+                .prop_map(|(user, server)| JID::new(user, server.to_string())),
+            ("[0-9]{1,15}", 0u8..255, 1u8..=255)
+                .prop_map(|(user, agent, device)| JID::new_ad_jid(user, agent, device)),
+            ("[0-9]{1,15}", any::<u16>()).prop_map(|(user, device)| JID {
+                device,
+                ..JID::new(user, MESSENGER_SERVER.to_string())
+            }),
+            ("[0-9]{1,15}", any::<u16>(), any::<u16>()).prop_map(|(user, device, integrator)| {
+                JID {
+                    device,
+                    integrator,
+                    ..JID::new(user, INTEROP_SERVER.to_string())
+                }
+            }),
+        ]
+    }
+
+    fn arb_attrs() -> impl Strategy<Value = Attrs> {
+        prop::collection::hash_map(
+            arb_string(),
+            prop_oneof![
+                arb_string().prop_map(AttrValue::String),
+                arb_jid().prop_map(AttrValue::JID),
+            ],
+            0..6,
+        )
+    }
+
+    fn arb_node() -> impl Strategy<Value = Node> {
+        let leaf = (
+            arb_string(),
+            arb_attrs(),
+            prop_oneof![
+                Just(NodeContent::None),
+                prop::collection::vec(any::<u8>(), 0..2048).prop_map(NodeContent::Bytes),
+                arb_token_or_packed().prop_map(NodeContent::String),
+                arb_jid().prop_map(NodeContent::JID),
+            ],
+        )
+            .prop_map(|(tag, attrs, content)| Node::new(tag, attrs, content));
+
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            (
+                arb_string(),
+                arb_attrs(),
+                prop::collection::vec(inner, 1..8),
+            )
+                .prop_map(|(tag, attrs, children)| {
+                    Node::new(tag, attrs, NodeContent::Nodes(children))
+                })
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn marshal_unmarshal_round_trip(node in arb_node()) {
+            let encoded = marshal(&node).unwrap();
+            let decoded = unmarshal(&unpack(&encoded).unwrap()).unwrap();
+            prop_assert_eq!(decoded, node);
+        }
+    }
+
+    #[test]
+    fn marshal_skips_empty_attributes() {
+        let node = Node::new(
+            "iq",
+            Attrs::from([
+                ("id".to_string(), AttrValue::from("1")),
+                ("to".to_string(), AttrValue::from("")),
+            ]),
+            NodeContent::None,
+        );
+        assert_eq!(marshal(&node).unwrap(), vec![0, token::LIST8, 3, 25, 8, 85]);
+    }
+}
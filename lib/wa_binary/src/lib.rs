@@ -1 +1,6 @@
+pub mod attrs;
+pub mod decoder;
+pub mod encoder;
+pub mod node;
 pub mod token;
+pub mod xml;
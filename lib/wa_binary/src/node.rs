@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use wa_types::jid::JID;
+
+/// [`AttrValue`] is the value of a single attribute of a [`Node`].
+///
+/// On the wire, attribute values are either strings (raw, tokenized or packed) or JIDs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AttrValue {
+    String(String),
+    JID(JID),
+}
+
+impl From<String> for AttrValue {
+    fn from(value: String) -> Self {
+        AttrValue::String(value)
+    }
+}
+
+impl From<&str> for AttrValue {
+    fn from(value: &str) -> Self {
+        AttrValue::String(value.to_string())
+    }
+}
+
+impl From<JID> for AttrValue {
+    fn from(value: JID) -> Self {
+        AttrValue::JID(value)
+    }
+}
+
+/// [`Attrs`] is the type for the attributes of a [`Node`].
+pub type Attrs = HashMap<String, AttrValue>;
+
+/// [`NodeContent`] is the content of a [`Node`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum NodeContent {
+    #[default]
+    None,
+    Nodes(Vec<Node>),
+    Bytes(Vec<u8>),
+    String(String),
+    JID(JID),
+}
+
+/// [`Node`] represents an XML element in the WhatsApp binary XML format.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Node {
+    /// The tag of the element.
+    pub tag: String,
+    /// The attributes of the element.
+    pub attrs: Attrs,
+    /// The content inside the element. Can be nothing, a list of child nodes, a byte array,
+    /// a string or a JID.
+    pub content: NodeContent,
+}
+
+impl Node {
+    /// Creates a new [`Node`] with the given tag, attributes and content.
+    pub fn new(tag: impl Into<String>, attrs: Attrs, content: NodeContent) -> Self {
+        Node {
+            tag: tag.into(),
+            attrs,
+            content,
+        }
+    }
+
+    /// Returns the content of the node as a slice of nodes. If the content is not a list of
+    /// nodes, this returns an empty slice.
+    pub fn get_children(&self) -> &[Node] {
+        match &self.content {
+            NodeContent::Nodes(nodes) => nodes,
+            _ => &[],
+        }
+    }
+
+    /// Returns all the direct children of the node that have the given tag.
+    pub fn get_children_by_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.get_children()
+            .iter()
+            .filter(move |child| child.tag == tag)
+    }
+
+    /// Finds the first child with the given tag, then the first child of that node with the
+    /// next tag and so on. Returns [`Option::None`] if any of the tags is not found.
+    pub fn get_optional_child_by_tag(&self, tags: &[&str]) -> Option<&Node> {
+        let mut node = self;
+        for tag in tags {
+            node = node.get_children().iter().find(|child| child.tag == *tag)?;
+        }
+        Some(node)
+    }
+
+    /// Returns the byte content of the node, if there is any.
+    pub fn content_bytes(&self) -> Option<&[u8]> {
+        match &self.content {
+            NodeContent::Bytes(bytes) => Some(bytes),
+            NodeContent::String(string) => Some(string.as_bytes()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use std::{fmt, str::FromStr};
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use thiserror::Error;
+use wa_types::jid::JID;
+
+use crate::encoder;
+use crate::node::{AttrValue, Attrs, Node, NodeContent};
+
+/// [`MAX_BYTES_TO_PRINT_AS_HEX`] is the maximum number of non-printable bytes that are printed
+/// as hex when formatting a node. Longer content is replaced with a short summary.
+pub const MAX_BYTES_TO_PRINT_AS_HEX: usize = 128;
+
+const INDENT_XML: &str = "  ";
+const HEX_LINE_LENGTH: usize = 80;
+/// Marks content that is printed as hex, so that [`parse_xml`] reads it back as bytes.
+const HEX_MARKER: &str = "<!-- hex -->";
+
+#[derive(Error, Debug)]
+pub enum XMLParseError {
+    #[error(transparent)]
+    Xml(#[from] quick_xml::Error),
+    #[error(transparent)]
+    Attribute(#[from] quick_xml::events::attributes::AttrError),
+    #[error("no root element found")]
+    NoRootElement,
+    #[error("unexpected content after the root element")]
+    TrailingContent,
+    #[error("unclosed element <{0}>")]
+    UnclosedElement(String),
+    #[error("element <{0}> has both text and child elements")]
+    MixedContent(String),
+    #[error("element <{0}> has invalid hex content")]
+    InvalidHex(String),
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn printable(data: &[u8]) -> Option<&str> {
+    let string = std::str::from_utf8(data).ok()?;
+    string
+        .chars()
+        .all(|char| !char.is_control() || char == '\n' || char == '\t')
+        .then_some(string)
+}
+
+/// Returns the field numbers of the top-level fields in the given data if it looks like a
+/// protobuf message, or [`Option::None`] if it doesn't.
+fn protobuf_fields(mut data: &[u8]) -> Option<Vec<u64>> {
+    fn read_varint(data: &mut &[u8]) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = data.split_first()?;
+            *data = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn skip(data: &mut &[u8], length: usize) -> Option<()> {
+        *data = data.get(length..)?;
+        Some(())
+    }
+
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = read_varint(&mut data)?;
+        let field = key >> 3;
+        if field == 0 {
+            return None;
+        }
+        match key & 0x07 {
+            0 => {
+                read_varint(&mut data)?;
+            }
+            1 => skip(&mut data, 8)?,
+            2 => {
+                let length = read_varint(&mut data)?;
+                skip(&mut data, length.try_into().ok()?)?;
+            }
+            5 => skip(&mut data, 4)?,
+            _ => return None,
+        }
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    (!fields.is_empty()).then_some(fields)
+}
+
+impl Node {
+    fn attribute_string(&self) -> String {
+        let mut keys = self.attrs.keys().collect::<Vec<_>>();
+        keys.sort();
+
+        keys.into_iter()
+            .map(|key| {
+                let value = match &self.attrs[key] {
+                    AttrValue::String(string) => escape(string),
+                    AttrValue::JID(jid) => escape(&jid.to_string()),
+                };
+                format!(" {key}=\"{value}\"")
+            })
+            .collect()
+    }
+
+    fn content_lines(&self) -> Vec<String> {
+        let mut lines = match &self.content {
+            NodeContent::None => Vec::new(),
+            NodeContent::Nodes(nodes) => nodes
+                .iter()
+                .flat_map(|node| {
+                    node.xml_string()
+                        .lines()
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            NodeContent::Bytes(bytes) => {
+                if let Some(string) = printable(bytes) {
+                    string.lines().map(escape).collect()
+                } else if bytes.len() > MAX_BYTES_TO_PRINT_AS_HEX {
+                    match protobuf_fields(bytes) {
+                        Some(fields) => {
+                            let fields = fields
+                                .iter()
+                                .map(u64::to_string)
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            vec![format!(
+                                "<!-- {} bytes, protobuf fields: {fields} -->",
+                                bytes.len()
+                            )]
+                        }
+                        None => vec![format!("<!-- {} bytes -->", bytes.len())],
+                    }
+                } else {
+                    let hex = bytes
+                        .iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect::<String>();
+                    let mut lines = hex
+                        .as_bytes()
+                        .chunks(HEX_LINE_LENGTH)
+                        .map(|line| String::from_utf8_lossy(line).into_owned())
+                        .collect::<Vec<_>>();
+                    lines[0].insert_str(0, HEX_MARKER);
+                    lines
+                }
+            }
+            NodeContent::String(string) => string.lines().map(escape).collect(),
+            NodeContent::JID(jid) => vec![escape(&jid.to_string())],
+        };
+
+        if lines.len() > 1 {
+            for line in &mut lines {
+                line.insert_str(0, INDENT_XML);
+            }
+        }
+
+        lines
+    }
+
+    /// Returns the node formatted as indented XML. Binary content is printed as hex after a
+    /// `<!-- hex -->` marker, or as a short summary if it's longer than
+    /// [`MAX_BYTES_TO_PRINT_AS_HEX`].
+    pub fn xml_string(&self) -> String {
+        let content = self.content_lines();
+        if content.is_empty() {
+            return format!("<{}{}/>", self.tag, self.attribute_string());
+        }
+
+        let newline = if content.len() == 1 { "" } else { "\n" };
+        format!(
+            "<{tag}{attrs}>{newline}{content}{newline}</{tag}>",
+            tag = self.tag,
+            attrs = self.attribute_string(),
+            content = content.join(newline),
+        )
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.xml_string())
+    }
+}
+
+fn parse_attrs(start: &BytesStart) -> Result<Attrs, XMLParseError> {
+    let mut attrs = Attrs::new();
+    for attr in start.attributes() {
+        let attr = attr?;
+        let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+        let value = attr.unescape_value()?.into_owned();
+        let value = match value.contains('@').then(|| JID::from_str(&value)) {
+            Some(Ok(jid)) => AttrValue::JID(jid),
+            _ => AttrValue::String(value),
+        };
+        attrs.insert(key, value);
+    }
+    Ok(attrs)
+}
+
+/// An element that has been opened but not closed yet while parsing.
+struct PartialNode {
+    node: Node,
+    children: Vec<Node>,
+    text: String,
+    /// Whether the text is hex after a [`HEX_MARKER`].
+    hex: bool,
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .chars()
+        .filter(|char| !char.is_whitespace())
+        .map(|char| char.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<Vec<_>>>()?;
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
+
+impl PartialNode {
+    fn finish(mut self) -> Result<Node, XMLParseError> {
+        let text = self.text.trim();
+        self.node.content = match (self.children.is_empty(), text.is_empty()) {
+            (true, true) => NodeContent::None,
+            (false, true) => NodeContent::Nodes(self.children),
+            (true, false) if self.hex => match decode_hex(text) {
+                Some(bytes) => NodeContent::Bytes(bytes),
+                None => return Err(XMLParseError::InvalidHex(self.node.tag)),
+            },
+            (true, false) if encoder::is_token_or_packable(text) => {
+                NodeContent::String(text.to_string())
+            }
+            (true, false) => NodeContent::Bytes(text.as_bytes().to_vec()),
+            (false, false) => return Err(XMLParseError::MixedContent(self.node.tag)),
+        };
+        Ok(self.node)
+    }
+}
+
+/// [`parse_xml`] parses an XML snippet into a [`Node`].
+///
+/// Attribute values containing `@` that are valid JIDs are parsed as JIDs. Text content that
+/// would be sent as a dictionary token or a packed string becomes [`NodeContent::String`],
+/// any other text becomes [`NodeContent::Bytes`], which matches what [`crate::decoder`]
+/// produces for the same node. Text after a `<!-- hex -->` marker is decoded as hex, as
+/// printed by [`Node::xml_string`].
+pub fn parse_xml(xml: &str) -> Result<Node, XMLParseError> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut stack: Vec<PartialNode> = Vec::new();
+    let mut root = None;
+
+    loop {
+        let event = reader.read_event()?;
+        let finished = match event {
+            Event::Start(start) => {
+                stack.push(PartialNode {
+                    node: Node::new(
+                        String::from_utf8_lossy(start.name().as_ref()),
+                        parse_attrs(&start)?,
+                        NodeContent::None,
+                    ),
+                    children: Vec::new(),
+                    text: String::new(),
+                    hex: false,
+                });
+                None
+            }
+            Event::Empty(start) => Some(Node::new(
+                String::from_utf8_lossy(start.name().as_ref()),
+                parse_attrs(&start)?,
+                NodeContent::None,
+            )),
+            Event::End(_) => match stack.pop() {
+                Some(partial) => Some(partial.finish()?),
+                None => return Err(XMLParseError::TrailingContent),
+            },
+            Event::Text(text) => {
+                match stack.last_mut() {
+                    Some(partial) => partial.text.push_str(&text.unescape()?),
+                    None => return Err(XMLParseError::TrailingContent),
+                }
+                None
+            }
+            Event::CData(data) => {
+                match stack.last_mut() {
+                    Some(partial) => partial.text.push_str(&String::from_utf8_lossy(&data)),
+                    None => return Err(XMLParseError::TrailingContent),
+                }
+                None
+            }
+            Event::Comment(comment) => {
+                if let Some(partial) = stack.last_mut() {
+                    partial.hex |= comment.unescape()?.trim() == "hex";
+                }
+                None
+            }
+            Event::Eof => break,
+            _ => None,
+        };
+
+        if let Some(node) = finished {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(node),
+                None if root.is_none() => root = Some(node),
+                None => return Err(XMLParseError::TrailingContent),
+            }
+        }
+    }
+
+    if let Some(partial) = stack.pop() {
+        return Err(XMLParseError::UnclosedElement(partial.node.tag));
+    }
+    root.ok_or(XMLParseError::NoRootElement)
+}
+
+impl FromStr for Node {
+    type Err = XMLParseError;
+
+    fn from_str(xml: &str) -> Result<Self, Self::Err> {
+        parse_xml(xml)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::{unmarshal, unpack};
+    use crate::encoder::marshal;
+
+    const IQ_XML: &str = r#"<iq from="s.whatsapp.net" id="1337" type="result">
+  <usync>
+    <list>
+      <user jid="1234:5@s.whatsapp.net">
+        <devices>
+          <device-list>
+            <device id="0"/>
+            <device id="5" key-index="2"/>
+          </device-list>
+        </devices>
+        <status>Hey there &amp; welcome</status>
+      </user>
+    </list>
+  </usync>
+</iq>"#;
+
+    #[test]
+    fn xml_string_round_trip() {
+        let node = parse_xml(IQ_XML).unwrap();
+        assert_eq!(node.to_string(), IQ_XML);
+        assert_eq!(node.attrs["from"], AttrValue::from("s.whatsapp.net"));
+        assert_eq!(
+            node.get_optional_child_by_tag(&["usync", "list", "user"])
+                .unwrap()
+                .attrs["jid"],
+            AttrValue::JID(JID::new_ad_jid("1234".to_string(), 0, 5))
+        );
+    }
+
+    #[test]
+    fn parsed_xml_matches_decoded_node() {
+        let node = parse_xml(IQ_XML).unwrap();
+        let decoded = unmarshal(&unpack(&marshal(&node).unwrap()).unwrap()).unwrap();
+        assert_eq!(decoded, node);
+    }
+
+    #[test]
+    fn xml_string_summarises_binary() {
+        let short = Node::new("enc", Attrs::new(), NodeContent::Bytes(vec![0x00, 0xff]));
+        assert_eq!(short.to_string(), "<enc><!-- hex -->00ff</enc>");
+
+        let mut protobuf = vec![0x0a, 0xc8, 0x01];
+        protobuf.extend([0u8; 200]);
+        protobuf.extend([0x10, 0x01]);
+        let long = Node::new("enc", Attrs::new(), NodeContent::Bytes(protobuf));
+        assert_eq!(
+            long.to_string(),
+            "<enc><!-- 205 bytes, protobuf fields: 1, 2 --></enc>"
+        );
+    }
+
+    #[test]
+    fn binary_content_round_trip() {
+        let node = Node::new(
+            "message",
+            Attrs::new(),
+            NodeContent::Nodes(vec![
+                Node::new(
+                    "enc",
+                    Attrs::new(),
+                    NodeContent::Bytes(vec![0x00, 0xff, 0x0a]),
+                ),
+                Node::new("key", Attrs::new(), NodeContent::Bytes((0..100).collect())),
+            ]),
+        );
+        let xml = node.to_string();
+        assert!(xml.contains("    <!-- hex -->000102"));
+        assert_eq!(parse_xml(&xml).unwrap(), node);
+
+        assert!(matches!(
+            parse_xml("<enc><!-- hex -->0g</enc>"),
+            Err(XMLParseError::InvalidHex(tag)) if tag == "enc"
+        ));
+    }
+}
@@ -0,0 +1,1282 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use prost::Message as _;
+use rand::{rngs::OsRng, Rng};
+use thiserror::Error;
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use wa_binary::{
+    decoder::{unmarshal, unpack},
+    encoder::marshal,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_proto::items::wa_web_protobufs_e2e::Message;
+use wa_socket::{
+    frame_socket::FrameSocket,
+    noise_handshake::{do_handshake, CertRoot, HandshakeError, WA_CERT_ROOT},
+    noise_socket::NoiseSocket,
+    transport::{connect_websocket, Transport},
+    SocketError, URL,
+};
+use wa_store::{signal::SignalStore, Device, Store, StoreError};
+use wa_types::{
+    events::{
+        self, ConnectFailure, ConnectFailureReason, Event, IdentityChange, LoggedOut, StreamError,
+        TempBanReason, TemporaryBan, UndecryptableMessage, QR,
+    },
+    group::GroupInfo,
+    jid::JID,
+    presence::ReceiptType,
+};
+
+use crate::{
+    client_payload::{ClientPayloadBuilder, PayloadError},
+    dispatcher::EventDispatcher,
+    group::{get_group_info, get_joined_groups, GroupError},
+    keepalive::{keepalive_loop, KeepAliveConfig, KeepAliveEnd},
+    pair::{handle_pair_device, handle_pair_success},
+    pair_code::{PairClientType, PairCodeError, PhoneLinking},
+    prekeys::{parse_pre_key_count, pre_key_count_query, PreKeyConfig, PreKeyError, PreKeyUpload},
+    receipt::{mark_delivered, parse_receipt},
+    receive::decrypt_message,
+    reconnect::{reconnect_with_backoff, Backoff},
+    request::{InfoQuery, IqType, RequestError, RequestSender},
+    retry::{handle_retry_receipt, retry_receipt, RecentMessages, RetryCounts},
+    send::{GroupCache, SendError, SendResponse},
+};
+
+/// [`REQUEST_TIMEOUT`] is how long requests wait for a response by default.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// [`INCOMING_QUEUE_SIZE`] is how many received stanzas can wait for the worker. When the
+/// queue is full, the socket stops reading until the worker catches up, so a slow event
+/// handler slows down the connection instead of buffering without limit. Responses to
+/// requests the worker waits for can't be read while the queue is full either, so such
+/// requests time out; the size matches the handler queue of whatsmeow, which makes that
+/// unlikely.
+pub const INCOMING_QUEUE_SIZE: usize = 2048;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("already connected to WhatsApp")]
+    AlreadyConnected,
+    #[error("not logged in")]
+    NotLoggedIn,
+    #[error("failed to open websocket: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Socket(#[from] SocketError),
+    #[error("noise handshake failed: {0}")]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+    #[error(transparent)]
+    Payload(#[from] PayloadError),
+    #[error(transparent)]
+    PairCode(#[from] PairCodeError),
+}
+
+/// [`ClientConfig`] contains the settings of a [`Client`]. The defaults connect to the real
+/// WhatsApp servers.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub url: String,
+    pub cert_root: CertRoot,
+    pub payload: ClientPayloadBuilder,
+    pub request_timeout: Duration,
+    /// Whether to reconnect automatically when the connection drops, unless the server
+    /// closed it because the device was logged out or connected elsewhere.
+    pub auto_reconnect: bool,
+    pub backoff: Backoff,
+    pub keepalive: KeepAliveConfig,
+    pub pre_keys: PreKeyConfig,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            url: URL.to_string(),
+            cert_root: WA_CERT_ROOT,
+            payload: ClientPayloadBuilder::default(),
+            request_timeout: REQUEST_TIMEOUT,
+            auto_reconnect: true,
+            backoff: Backoff::default(),
+            keepalive: KeepAliveConfig::default(),
+            pre_keys: PreKeyConfig::default(),
+        }
+    }
+}
+
+struct Connection {
+    /// Frames to send. The socket is closed when this is dropped.
+    outgoing: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    socket_task: JoinHandle<()>,
+    worker: JoinHandle<()>,
+    keepalive: JoinHandle<()>,
+}
+
+/// [`Client`] is a connection to WhatsApp as a linked device. It owns the socket, the store
+/// and the [`EventDispatcher`] that incoming stanzas are reported to.
+///
+/// The socket is read and written by a background task, which hands the responses to
+/// requests to their callers and every other stanza to a worker that handles them in order.
+/// Handling stanzas uses libsignal, whose futures aren't [`Send`], so the worker runs on a
+/// blocking thread of the runtime instead of as a task.
+pub struct Client {
+    config: ClientConfig,
+    store: Arc<dyn Store>,
+    device: RwLock<Device>,
+    dispatcher: EventDispatcher,
+
+    connect_lock: tokio::sync::Mutex<()>,
+    connection: Mutex<Option<Connection>>,
+    pending_requests: Mutex<HashMap<String, oneshot::Sender<Node>>>,
+    id_prefix: String,
+    id_counter: AtomicU64,
+    logged_in: AtomicBool,
+    /// Set while the connection should be kept up, i.e. between [`Client::connect`] and
+    /// [`Client::disconnect`] unless the server ended the session for good.
+    keep_connected: AtomicBool,
+    reconnect_attempts: AtomicU32,
+    ping_latency: Mutex<Option<Duration>>,
+    /// The pairing started by [`Client::pair_phone`], until the phone was linked.
+    phone_linking: Mutex<Option<PhoneLinking>>,
+
+    recent_messages: RecentMessages,
+    retry_counts: RetryCounts,
+    group_cache: GroupCache,
+}
+
+/// Builds the `<ack>` for a stanza that doesn't get a receipt.
+fn ack_node(node: &Node) -> Node {
+    let mut attrs = Attrs::from([("class".to_string(), AttrValue::from(node.tag.as_str()))]);
+    for (key, ack_key) in [
+        ("id", "id"),
+        ("from", "to"),
+        ("participant", "participant"),
+        ("recipient", "recipient"),
+    ] {
+        if let Some(value) = node.attrs.get(key) {
+            attrs.insert(ack_key.to_string(), value.clone());
+        }
+    }
+    if node.tag != "message" {
+        if let Some(value) = node.attrs.get("type") {
+            attrs.insert("type".to_string(), value.clone());
+        }
+    }
+    Node::new("ack", attrs, NodeContent::None)
+}
+
+/// Builds an empty `<iq>` result for a request from the server.
+fn iq_result_node(node: &Node) -> Node {
+    let mut attrs = Attrs::from([("type".to_string(), AttrValue::from("result"))]);
+    for (key, result_key) in [("id", "id"), ("from", "to")] {
+        if let Some(value) = node.attrs.get(key) {
+            attrs.insert(result_key.to_string(), value.clone());
+        }
+    }
+    Node::new("iq", attrs, NodeContent::None)
+}
+
+impl Client {
+    /// Creates a client with the device in the store, or a new unpaired device if there
+    /// isn't one yet.
+    pub async fn new(
+        store: Arc<dyn Store>,
+        config: ClientConfig,
+    ) -> Result<Arc<Self>, ClientError> {
+        let device = match store.get_device().await? {
+            Some(device) => device,
+            None => Device::generate()?,
+        };
+        let [first, second] = OsRng.gen::<[u8; 2]>();
+        Ok(Arc::new(Client {
+            config,
+            store,
+            device: RwLock::new(device),
+            dispatcher: EventDispatcher::new(),
+            connect_lock: tokio::sync::Mutex::new(()),
+            connection: Mutex::new(None),
+            pending_requests: Mutex::new(HashMap::new()),
+            id_prefix: format!("{first}.{second}-"),
+            id_counter: AtomicU64::new(1),
+            logged_in: AtomicBool::new(false),
+            keep_connected: AtomicBool::new(false),
+            reconnect_attempts: AtomicU32::new(0),
+            ping_latency: Mutex::new(None),
+            phone_linking: Mutex::new(None),
+            recent_messages: RecentMessages::default(),
+            retry_counts: RetryCounts::default(),
+            group_cache: GroupCache::default(),
+        }))
+    }
+
+    pub fn dispatcher(&self) -> &EventDispatcher {
+        &self.dispatcher
+    }
+
+    pub fn store(&self) -> &Arc<dyn Store> {
+        &self.store
+    }
+
+    /// Returns a copy of the device, which changes when pairing or logging out.
+    pub fn device(&self) -> Device {
+        self.device.read().unwrap().clone()
+    }
+
+    /// Returns true if the socket is open.
+    pub fn is_connected(&self) -> bool {
+        self.connection
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|connection| {
+                connection.outgoing.is_some() && !connection.socket_task.is_finished()
+            })
+    }
+
+    /// Returns true if the server accepted the login of the current connection.
+    pub fn is_logged_in(&self) -> bool {
+        self.logged_in.load(Ordering::SeqCst)
+    }
+
+    /// Returns the round trip time of the last keepalive ping that was answered.
+    pub fn ping_latency(&self) -> Option<Duration> {
+        *self.ping_latency.lock().unwrap()
+    }
+
+    /// Connects to the server and logs in, or registers a new device if it isn't paired yet.
+    /// This returns once the handshake is done; [`Event::Connected`] or [`Event::QR`] is
+    /// dispatched when the server accepts the login.
+    pub async fn connect(self: &Arc<Self>) -> Result<(), ClientError> {
+        let _guard = self.connect_lock.lock().await;
+        if self.is_connected() {
+            return Err(ClientError::AlreadyConnected);
+        }
+        self.keep_connected.store(true, Ordering::SeqCst);
+        self.connect_locked().await
+    }
+
+    async fn connect_locked(self: &Arc<Self>) -> Result<(), ClientError> {
+        // Wait for the previous connection to finish cleaning up.
+        self.disconnect_locked().await;
+
+        let payload = self.config.payload.device_payload(&self.device())?;
+        let mut frame_socket = FrameSocket::new();
+        frame_socket.connect(connect_websocket(&self.config.url).await?)?;
+        let socket = do_handshake(
+            frame_socket,
+            &self.device().noise_key,
+            &payload.encode_to_vec(),
+            &self.config.cert_root,
+        )
+        .await?;
+
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+        let (incoming, mut incoming_receiver) = mpsc::channel(INCOMING_QUEUE_SIZE);
+        let socket_task =
+            tokio::spawn(self.clone().run_socket(socket, outgoing_receiver, incoming));
+        let client = self.clone();
+        let handle = Handle::current();
+        let worker = tokio::task::spawn_blocking(move || {
+            handle.block_on(async move {
+                while let Some(node) = incoming_receiver.recv().await {
+                    client.handle_node(&node).await;
+                }
+                client.logged_in.store(false, Ordering::SeqCst);
+                client.dispatcher.dispatch(Event::Disconnected).await;
+                if client.config.auto_reconnect && client.keep_connected.load(Ordering::SeqCst) {
+                    tokio::spawn(client.reconnect());
+                }
+            })
+        });
+        let client = self.clone();
+        let keepalive = tokio::spawn(async move {
+            let end = keepalive_loop(
+                &*client,
+                &client.config.keepalive,
+                &client.dispatcher,
+                &client.ping_latency,
+            )
+            .await;
+            // The socket is probably half-open, so close it and let the client reconnect.
+            if end == KeepAliveEnd::TooManyFailures {
+                client.close_socket(true);
+            }
+        });
+        *self.connection.lock().unwrap() = Some(Connection {
+            outgoing: Some(outgoing),
+            socket_task,
+            worker,
+            keepalive,
+        });
+        Ok(())
+    }
+
+    /// Reconnects with the backoff from the config, until it succeeds or the client is
+    /// disconnected manually. The future is boxed because it's spawned by the connection it
+    /// creates, which would make its type recursive.
+    fn reconnect(self: Arc<Self>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async move {
+            let should_reconnect = || self.keep_connected.load(Ordering::SeqCst);
+            reconnect_with_backoff(
+                &self.config.backoff,
+                &self.reconnect_attempts,
+                &self.dispatcher,
+                should_reconnect,
+                || async {
+                    let _guard = self.connect_lock.lock().await;
+                    // The client may have been disconnected or connected manually while
+                    // waiting.
+                    if !should_reconnect() || self.is_connected() {
+                        return Ok(());
+                    }
+                    self.connect_locked().await
+                },
+            )
+            .await;
+        })
+    }
+
+    /// Closes the connection and waits until [`Event::Disconnected`] has been dispatched.
+    pub async fn disconnect(&self) {
+        let _guard = self.connect_lock.lock().await;
+        self.keep_connected.store(false, Ordering::SeqCst);
+        self.disconnect_locked().await;
+    }
+
+    async fn disconnect_locked(&self) {
+        let Some(connection) = self.connection.lock().unwrap().take() else {
+            return;
+        };
+        drop(connection.outgoing);
+        connection.keepalive.abort();
+        let _ = connection.socket_task.await;
+        let _ = connection.worker.await;
+    }
+
+    /// Closes the socket without waiting, which is used by the worker when the server ends
+    /// the session. The client reconnects afterwards if `reconnect` is true.
+    fn close_socket(&self, reconnect: bool) {
+        if !reconnect {
+            self.keep_connected.store(false, Ordering::SeqCst);
+        }
+        if let Some(connection) = self.connection.lock().unwrap().as_mut() {
+            connection.outgoing = None;
+        }
+    }
+
+    /// Unlinks the device from the phone, disconnects and deletes the device from the store.
+    /// The client gets a new unpaired device, so connecting again will show QR codes.
+    pub async fn logout(&self) -> Result<(), ClientError> {
+        let own_id = self.device().id.ok_or(ClientError::NotLoggedIn)?;
+        self.send_iq(InfoQuery::new(
+            "md",
+            IqType::Set,
+            NodeContent::Nodes(vec![Node::new(
+                "remove-companion-device",
+                Attrs::from([
+                    ("jid".to_string(), AttrValue::JID(own_id)),
+                    ("reason".to_string(), AttrValue::from("user_initiated")),
+                ]),
+                NodeContent::None,
+            )]),
+        ))
+        .await?;
+        self.disconnect().await;
+        self.reset_device().await
+    }
+
+    async fn reset_device(&self) -> Result<(), ClientError> {
+        self.store.delete_device().await?;
+        *self.device.write().unwrap() = Device::generate()?;
+        Ok(())
+    }
+
+    /// Requests a pairing code for the phone number, as an alternative to scanning a QR code.
+    /// The returned code, formatted as `XXXX-XXXX`, has to be entered on the phone under
+    /// "Link with phone number instead". The client must be connected and not logged in.
+    pub async fn pair_phone(
+        &self,
+        phone: &str,
+        show_push_notification: bool,
+        client_type: PairClientType,
+        client_display_name: &str,
+    ) -> Result<String, ClientError> {
+        let (mut linking, request) = PhoneLinking::start(
+            phone,
+            &self.device(),
+            show_push_notification,
+            client_type,
+            client_display_name,
+        )?;
+        let response = self
+            .send_iq(InfoQuery::new(
+                "md",
+                IqType::Set,
+                NodeContent::Nodes(vec![request]),
+            ))
+            .await?;
+        let code = linking.handle_hello_response(&response)?;
+        *self.phone_linking.lock().unwrap() = Some(linking);
+        Ok(code)
+    }
+
+    /// Sends a message to a user or group, see [`crate::send::send_message`].
+    ///
+    /// The Signal stores can't be sent between threads, so like incoming stanzas, the message
+    /// is encrypted and sent on a blocking thread. The returned future is [`Send`] and can be
+    /// spawned like any other.
+    pub async fn send_message(
+        self: &Arc<Self>,
+        to: JID,
+        message: Message,
+    ) -> Result<SendResponse, SendError> {
+        let client = self.clone();
+        let handle = Handle::current();
+        let task = tokio::task::spawn_blocking(move || {
+            handle.block_on(client.send_message_on_current_thread(to, message))
+        });
+        match task.await {
+            Ok(result) => result,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+
+    async fn send_message_on_current_thread(
+        &self,
+        to: JID,
+        message: Message,
+    ) -> Result<SendResponse, SendError> {
+        let device = self.device();
+        let mut signal_store = SignalStore::new(self.store.clone(), &device);
+        crate::send::send_message(
+            self,
+            &device,
+            &mut signal_store,
+            &self.recent_messages,
+            &self.group_cache,
+            to,
+            message,
+        )
+        .await
+    }
+
+    /// Fetches the metadata and participants of a group.
+    pub async fn get_group_info(&self, jid: &JID) -> Result<GroupInfo, GroupError> {
+        get_group_info(self, jid).await
+    }
+
+    /// Fetches the metadata and participants of all the groups the user participates in.
+    pub async fn get_joined_groups(&self) -> Result<Vec<GroupInfo>, GroupError> {
+        get_joined_groups(self).await
+    }
+
+    async fn run_socket<T: Transport>(
+        self: Arc<Self>,
+        mut socket: NoiseSocket<T>,
+        mut outgoing: mpsc::UnboundedReceiver<Vec<u8>>,
+        incoming: mpsc::Sender<Node>,
+    ) {
+        loop {
+            tokio::select! {
+                data = outgoing.recv() => match data {
+                    Some(data) => {
+                        if socket.send_frame(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                frame = socket.receive_frame() => {
+                    let Ok(frame) = frame else {
+                        break;
+                    };
+                    let Ok(node) = unpack(&frame).and_then(|data| unmarshal(&data)) else {
+                        continue;
+                    };
+                    if let Some(node) = self.resolve_response(node) {
+                        if incoming.send(node).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        socket.close().await;
+        // Dropping the senders makes the pending requests fail with
+        // RequestError::Disconnected.
+        self.pending_requests.lock().unwrap().clear();
+    }
+
+    /// Hands the node to the request waiting for it, or returns it if it isn't a response.
+    /// Requests are answered with an `<iq>` result or error, except for stanzas like
+    /// messages, which the server acknowledges with an `<ack>` that has the same ID.
+    fn resolve_response(&self, node: Node) -> Option<Node> {
+        let is_response = match node.tag.as_str() {
+            "iq" => matches!(
+                node.attrs.get("type"),
+                Some(AttrValue::String(iq_type)) if iq_type == "result" || iq_type == "error"
+            ),
+            "ack" => true,
+            _ => false,
+        };
+        let Some(AttrValue::String(id)) = node.attrs.get("id").filter(|_| is_response) else {
+            return Some(node);
+        };
+        match self.pending_requests.lock().unwrap().remove(id) {
+            Some(sender) => {
+                let _ = sender.send(node);
+                None
+            }
+            None => Some(node),
+        }
+    }
+
+    async fn handle_node(self: &Arc<Self>, node: &Node) {
+        match node.tag.as_str() {
+            "success" => self.handle_success().await,
+            "failure" => self.handle_failure(node).await,
+            "stream:error" => self.handle_stream_error(node).await,
+            "iq" => self.handle_iq(node).await,
+            "message" => self.handle_message(node).await,
+            "receipt" => self.handle_receipt(node).await,
+            "notification" => {
+                let _ = self.send_node(ack_node(node)).await;
+                if node.attr_getter().optional_string("type") == Some("link_code_companion_reg") {
+                    self.handle_link_code_notification(node).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn handle_success(self: &Arc<Self>) {
+        self.logged_in.store(true, Ordering::SeqCst);
+        self.reconnect_attempts.store(0, Ordering::SeqCst);
+        // This waits for responses, so it runs separately from the worker.
+        let client = self.clone();
+        tokio::spawn(async move {
+            let _ = client.upload_pre_keys_if_needed().await;
+        });
+        self.dispatcher.dispatch(Event::Connected).await;
+    }
+
+    /// Asks the server how many pre-keys it has left for this device, and uploads more if
+    /// there are fewer than [`PreKeyConfig::threshold`].
+    async fn upload_pre_keys_if_needed(&self) -> Result<(), PreKeyError> {
+        let response = self.send_iq(pre_key_count_query()).await?;
+        if !self
+            .config
+            .pre_keys
+            .needs_upload(parse_pre_key_count(&response)?)
+        {
+            return Ok(());
+        }
+        let upload =
+            PreKeyUpload::prepare(&self.device(), &*self.store, &self.config.pre_keys).await?;
+        self.send_iq(upload.query.clone()).await?;
+        upload.mark_uploaded(&*self.store).await?;
+        Ok(())
+    }
+
+    /// Handles the notification the phone sends once the code from [`Client::pair_phone`]
+    /// was entered. The adv secret derived from it is saved before answering, because the
+    /// `pair-success` that follows is authenticated with it.
+    async fn handle_link_code_notification(&self, node: &Node) {
+        let mut device = self.device();
+        let finish = match self.phone_linking.lock().unwrap().as_ref() {
+            Some(linking) => linking.handle_notification(node, &mut device),
+            None => return,
+        };
+        let result = async {
+            let finish = finish?;
+            self.store.put_device(&device).await?;
+            *self.device.write().unwrap() = device;
+            self.send_iq(InfoQuery::new(
+                "md",
+                IqType::Set,
+                NodeContent::Nodes(vec![finish]),
+            ))
+            .await?;
+            Ok::<_, ClientError>(())
+        }
+        .await;
+        if let Err(err) = result {
+            self.dispatcher
+                .dispatch(Event::PairError(events::PairError {
+                    id: None,
+                    error: err.to_string(),
+                }))
+                .await;
+        }
+    }
+
+    async fn handle_logged_out(&self, on_connect: bool, reason: ConnectFailureReason) {
+        self.logged_in.store(false, Ordering::SeqCst);
+        self.close_socket(false);
+        let _ = self.reset_device().await;
+        self.dispatcher
+            .dispatch(Event::LoggedOut(LoggedOut { on_connect, reason }))
+            .await;
+    }
+
+    async fn handle_failure(&self, node: &Node) {
+        let mut ag = node.attr_getter();
+        let reason =
+            ConnectFailureReason::from_str(ag.optional_string("reason").unwrap_or_default())
+                .expect("unknown reasons use the default variant");
+        let message = ag
+            .optional_string("message")
+            .unwrap_or_default()
+            .to_string();
+        if reason.is_logged_out() {
+            return self.handle_logged_out(true, reason).await;
+        }
+        let reconnect = reason.should_reconnect();
+        let event = match reason {
+            ConnectFailureReason::TempBanned => Event::TemporaryBan(TemporaryBan {
+                code: TempBanReason::from_str(ag.optional_string("code").unwrap_or_default())
+                    .expect("unknown codes use the default variant"),
+                expire: time::Duration::seconds(ag.optional_i64("expire").unwrap_or_default()),
+            }),
+            ConnectFailureReason::ClientOutdated => Event::ClientOutdated,
+            reason => Event::ConnectFailure(ConnectFailure { reason, message }),
+        };
+        self.close_socket(reconnect);
+        self.dispatcher.dispatch(event).await;
+    }
+
+    async fn handle_stream_error(&self, node: &Node) {
+        let code = node
+            .attr_getter()
+            .optional_string("code")
+            .unwrap_or_default()
+            .to_string();
+        let conflict = node
+            .get_optional_child_by_tag(&["conflict"])
+            .and_then(|conflict| conflict.attr_getter().optional_string("type"))
+            .unwrap_or_default();
+        let event = match (code.as_str(), conflict) {
+            ("401", "device_removed") => {
+                return self
+                    .handle_logged_out(false, ConnectFailureReason::LoggedOut)
+                    .await
+            }
+            (_, "replaced") => {
+                self.close_socket(false);
+                Event::StreamReplaced
+            }
+            // The server asks the client to reconnect, e.g. after pairing.
+            ("515" | "503", _) => return self.close_socket(true),
+            _ => {
+                self.close_socket(true);
+                Event::StreamError(StreamError { code })
+            }
+        };
+        self.dispatcher.dispatch(event).await;
+    }
+
+    async fn handle_iq(&self, node: &Node) {
+        let mut ag = node.attr_getter();
+        let iq_type = ag.optional_string("type").unwrap_or_default();
+        let xmlns = ag.optional_string("xmlns").unwrap_or_default();
+        if iq_type == "get" && xmlns == "urn:xmpp:ping" {
+            let _ = self.send_node(iq_result_node(node)).await;
+        } else if node.get_optional_child_by_tag(&["pair-device"]).is_some() {
+            let (ack, codes) = handle_pair_device(node, &self.device());
+            let _ = self.send_node(ack).await;
+            self.dispatcher.dispatch(Event::QR(QR { codes })).await;
+        } else if node.get_optional_child_by_tag(&["pair-success"]).is_some() {
+            let mut device = self.device();
+            let response = handle_pair_success(node, &mut device, &*self.store).await;
+            *self.device.write().unwrap() = device;
+            let _ = self.send_node(response.reply).await;
+            let linked_with_code = self.phone_linking.lock().unwrap().take().is_some();
+            let event = match response.result {
+                Ok(success) => Event::PairSuccess(success),
+                Err(error) => Event::PairError(events::PairError {
+                    id: node
+                        .get_optional_child_by_tag(&["pair-success", "device"])
+                        .and_then(|device| device.attr_getter().optional_jid("jid")),
+                    error: if linked_with_code {
+                        PairCodeError::from(error).to_string()
+                    } else {
+                        error.to_string()
+                    },
+                }),
+            };
+            self.dispatcher.dispatch(event).await;
+        }
+    }
+
+    async fn handle_message(&self, node: &Node) {
+        let device = self.device();
+        let mut signal_store = SignalStore::new(self.store.clone(), &device);
+        let Ok(decrypted) = decrypt_message(node, &device, &mut signal_store).await else {
+            let _ = self.send_node(ack_node(node)).await;
+            return;
+        };
+        if decrypted.identity_changed {
+            self.dispatcher
+                .dispatch(Event::IdentityChange(IdentityChange {
+                    jid: decrypted.info.source.sender.clone(),
+                    timestamp: time::OffsetDateTime::now_utc(),
+                    implicit: true,
+                }))
+                .await;
+        }
+        let is_unavailable = node.get_optional_child_by_tag(&["unavailable"]).is_some();
+        if !decrypted.errors.is_empty() || is_unavailable {
+            if let Ok(Some(receipt)) =
+                retry_receipt(node, &decrypted, &device, &*self.store, &self.retry_counts).await
+            {
+                let _ = self.send_node(receipt).await;
+            }
+            self.dispatcher
+                .dispatch(Event::UndecryptableMessage(Box::new(
+                    UndecryptableMessage {
+                        info: decrypted.info.clone(),
+                        is_unavailable,
+                    },
+                )))
+                .await;
+        } else {
+            let _ = mark_delivered(self, &decrypted.info).await;
+        }
+        for message in decrypted.messages {
+            self.dispatcher
+                .dispatch(Event::Message(Box::new(message)))
+                .await;
+        }
+    }
+
+    async fn handle_receipt(&self, node: &Node) {
+        let device = self.device();
+        match parse_receipt(node, &device) {
+            Ok(receipt) if matches!(receipt.r#type, ReceiptType::Retry) => {
+                let mut signal_store = SignalStore::new(self.store.clone(), &device);
+                let _ = handle_retry_receipt(
+                    self,
+                    &device,
+                    &mut signal_store,
+                    &self.recent_messages,
+                    node,
+                )
+                .await;
+            }
+            Ok(receipt) => {
+                self.dispatcher.dispatch(Event::Receipt(receipt)).await;
+            }
+            Err(_) => {}
+        }
+        let _ = self.send_node(ack_node(node)).await;
+    }
+}
+
+/// [`PendingRequest`] removes a request from [`Client::pending_requests`] when
+/// [`Client::send_request`] returns or its future is dropped, e.g. by a caller that has a
+/// shorter timeout of its own.
+struct PendingRequest<'a> {
+    requests: &'a Mutex<HashMap<String, oneshot::Sender<Node>>>,
+    id: String,
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        self.requests.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl RequestSender for Client {
+    fn generate_request_id(&self) -> String {
+        format!(
+            "{}{}",
+            self.id_prefix,
+            self.id_counter.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+
+    async fn send_request(&self, node: Node) -> Result<Node, RequestError> {
+        let id = node
+            .attr_getter()
+            .optional_string("id")
+            .unwrap_or_default()
+            .to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending_requests
+            .lock()
+            .unwrap()
+            .insert(id.clone(), sender);
+        let _pending = PendingRequest {
+            requests: &self.pending_requests,
+            id,
+        };
+        self.send_node(node).await?;
+        match tokio::time::timeout(self.config.request_timeout, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(RequestError::Disconnected),
+            Err(_) => Err(RequestError::Timeout),
+        }
+    }
+
+    async fn send_node(&self, node: Node) -> Result<(), RequestError> {
+        let data = marshal(&node)?;
+        let outgoing = self
+            .connection
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|connection| connection.outgoing.clone())
+            .ok_or(RequestError::NotConnected)?;
+        outgoing.send(data).map_err(|_| RequestError::NotConnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use wa_mock_server::{stanza, MockConnection, MockServer};
+    use wa_store::{memory::MemoryStore, DeviceStore, PreKeyStore};
+    use wa_types::jid::DEFAULT_USER_SERVER;
+
+    use super::*;
+    use crate::{
+        dispatcher::{EventFilter, EventStream},
+        pair_code::tests::{phone_adv_secret, phone_notification},
+        prekeys::WANTED_PRE_KEY_COUNT,
+        receive::{decrypt_message, tests::TestPeer},
+        request::bytes_node,
+        send::tests::{delivered, fake_server, logged_in_peer, text},
+        test_util::node,
+    };
+
+    struct TestConnection {
+        client: Arc<Client>,
+        connection: MockConnection,
+        events: EventStream,
+        server: MockServer,
+    }
+
+    async fn connect(store: Arc<dyn Store>, request_timeout: Duration) -> TestConnection {
+        let mut server = MockServer::start().await.unwrap();
+        let config = ClientConfig {
+            url: server.url(),
+            cert_root: server.cert_root().clone(),
+            request_timeout,
+            backoff: Backoff {
+                initial: Duration::from_millis(10),
+                jitter: 0.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let client = Client::new(store, config).await.unwrap();
+        let events = client.dispatcher().subscribe(EventFilter::all(), 16);
+        client.connect().await.unwrap();
+        TestConnection {
+            client,
+            connection: server.accept().await.unwrap(),
+            events,
+            server,
+        }
+    }
+
+    fn pre_key_count(query: &Node, count: u32) -> Node {
+        stanza::iq_result(
+            query,
+            NodeContent::Nodes(vec![node(
+                "count",
+                &[("value", &count.to_string())],
+                NodeContent::None,
+            )]),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connects_and_matches_responses_to_requests() {
+        let TestConnection {
+            client,
+            mut connection,
+            mut events,
+            ..
+        } = connect(Arc::new(MemoryStore::new()), REQUEST_TIMEOUT).await;
+
+        connection
+            .send_node(&node("success", &[], NodeContent::None))
+            .await
+            .unwrap();
+        assert!(matches!(events.next().await, Some(Event::Connected)));
+        assert!(client.is_connected());
+        assert!(client.is_logged_in());
+        // The server still has enough pre-keys, so none are uploaded.
+        let count_query = connection.expect_node("iq").await.unwrap();
+        connection
+            .send_node(&pre_key_count(&count_query, 50))
+            .await
+            .unwrap();
+
+        let requests = ["first", "second"].map(|namespace| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .send_iq(InfoQuery::new(namespace, IqType::Get, NodeContent::None))
+                    .await
+            })
+        });
+        let mut received = vec![
+            connection.expect_node("iq").await.unwrap(),
+            connection.expect_node("iq").await.unwrap(),
+        ];
+        // Answer in reverse order, with the namespace of each request as the error text.
+        received.reverse();
+        for request in &received {
+            let namespace = request.attr_getter().string("xmlns").to_string();
+            connection
+                .send_node(&stanza::iq_error(request, 404, &namespace))
+                .await
+                .unwrap();
+        }
+        for (request, namespace) in requests.into_iter().zip(["first", "second"]) {
+            assert!(matches!(
+                request.await.unwrap(),
+                Err(RequestError::Iq { code: 404, text }) if text == namespace
+            ));
+        }
+
+        let mut ping = node(
+            "iq",
+            &[
+                ("id", "ping-1"),
+                ("type", "get"),
+                ("xmlns", "urn:xmpp:ping"),
+            ],
+            NodeContent::None,
+        );
+        ping.attrs.insert(
+            "from".to_string(),
+            AttrValue::JID(JID::new(String::new(), DEFAULT_USER_SERVER.to_string())),
+        );
+        connection.send_node(&ping).await.unwrap();
+        let pong = connection.expect_node("iq").await.unwrap();
+        let mut ag = pong.attr_getter();
+        assert_eq!(ag.string("id"), "ping-1");
+        assert_eq!(ag.string("type"), "result");
+
+        client.disconnect().await;
+        assert!(matches!(events.next().await, Some(Event::Disconnected)));
+        assert!(!client.is_connected());
+        assert!(!client.is_logged_in());
+        assert!(matches!(
+            client
+                .send_node(node("presence", &[], NodeContent::None))
+                .await,
+            Err(RequestError::NotConnected)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uploads_pre_keys_after_login() {
+        let store = Arc::new(MemoryStore::new());
+        let TestConnection { mut connection, .. } = connect(store.clone(), REQUEST_TIMEOUT).await;
+
+        connection
+            .send_node(&node("success", &[], NodeContent::None))
+            .await
+            .unwrap();
+        let count_query = connection.expect_node("iq").await.unwrap();
+        let mut ag = count_query.attr_getter();
+        assert_eq!(ag.string("xmlns"), "encrypt");
+        assert_eq!(ag.string("type"), "get");
+        connection
+            .send_node(&pre_key_count(&count_query, 2))
+            .await
+            .unwrap();
+
+        let upload = connection.expect_node("iq").await.unwrap();
+        assert_eq!(upload.attr_getter().string("type"), "set");
+        let keys = upload
+            .get_optional_child_by_tag(&["list"])
+            .unwrap()
+            .get_children_by_tag("key")
+            .count();
+        assert_eq!(keys as u32, WANTED_PRE_KEY_COUNT);
+        assert_eq!(store.uploaded_pre_key_count().await.unwrap(), 0);
+        connection
+            .send_node(&stanza::iq_result(&upload, NodeContent::None))
+            .await
+            .unwrap();
+
+        // The keys are marked as uploaded once the response arrives.
+        for _ in 0..100 {
+            if store.uploaded_pre_key_count().await.unwrap() == WANTED_PRE_KEY_COUNT {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("pre-keys weren't marked as uploaded");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pairs_with_a_phone_number() {
+        let store = Arc::new(MemoryStore::new());
+        let TestConnection {
+            client,
+            mut connection,
+            ..
+        } = connect(store.clone(), REQUEST_TIMEOUT).await;
+
+        let pairing = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .pair_phone("+1 555 123 4567", true, PairClientType::Chrome, "Chrome")
+                    .await
+            })
+        };
+        let hello = connection.expect_node("iq").await.unwrap();
+        let registration = hello
+            .get_optional_child_by_tag(&["link_code_companion_reg"])
+            .unwrap()
+            .clone();
+        assert_eq!(
+            registration.attr_getter().string("stage"),
+            "companion_hello"
+        );
+        connection
+            .send_node(&stanza::iq_result(
+                &hello,
+                NodeContent::Nodes(vec![Node::new(
+                    "link_code_companion_reg",
+                    Attrs::new(),
+                    NodeContent::Nodes(vec![bytes_node("link_code_pairing_ref", b"ref")]),
+                )]),
+            ))
+            .await
+            .unwrap();
+        let code = pairing.await.unwrap().unwrap();
+
+        let (primary_hello, ephemeral, identity) = phone_notification(&code, b"ref");
+        let mut notification = node(
+            "notification",
+            &[
+                ("id", "notification-1"),
+                ("type", "link_code_companion_reg"),
+            ],
+            NodeContent::None,
+        );
+        notification.content = NodeContent::Nodes(vec![primary_hello]);
+        connection.send_node(&notification).await.unwrap();
+        let ack = connection.expect_node("ack").await.unwrap();
+        assert_eq!(ack.attr_getter().string("id"), "notification-1");
+
+        let finish = connection.expect_node("iq").await.unwrap();
+        let adv_secret = phone_adv_secret(
+            &code,
+            &registration,
+            finish
+                .get_optional_child_by_tag(&["link_code_companion_reg"])
+                .unwrap(),
+            &ephemeral,
+            &identity,
+        )
+        .unwrap();
+        // The adv secret is saved before companion_finish is sent.
+        assert_eq!(client.device().adv_secret_key, adv_secret);
+        assert_eq!(
+            store.get_device().await.unwrap().unwrap().adv_secret_key,
+            adv_secret
+        );
+        connection
+            .send_node(&stanza::iq_result(&finish, NodeContent::None))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sends_messages_and_waits_for_the_ack() {
+        let alice = logged_in_peer("1111", 0);
+        let mut bob = TestPeer::new("2222", 0);
+        alice.store.put_device(&alice.device).await.unwrap();
+        let server = Arc::new(fake_server(&[&alice, &bob]).await);
+        let TestConnection {
+            client,
+            mut connection,
+            ..
+        } = connect(alice.store.clone(), Duration::from_secs(5)).await;
+
+        let bob_user = bob.jid().to_non_ad();
+        let responder = server.clone();
+        let serving = tokio::spawn(async move {
+            connection
+                .serve(|node| {
+                    responder.sent.lock().unwrap().push(node.clone());
+                    vec![responder.answer(node)]
+                })
+                .await
+        });
+        // Sending works from spawned tasks, which need the future to be Send.
+        let sending = {
+            let client = client.clone();
+            tokio::spawn(async move { client.send_message(bob_user, text("hello")).await })
+        };
+        let response = sending.await.unwrap().unwrap();
+        assert_eq!(response.timestamp.unix_timestamp(), 1700000000);
+
+        let sent = server.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].attrs["id"], AttrValue::from(response.id.0.as_str()));
+        let node = delivered(
+            &sent[0],
+            &bob.jid(),
+            &[("from", AttrValue::JID(alice.jid()))],
+        );
+        let decrypted = decrypt_message(&node, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        assert_eq!(decrypted.messages[0].message.conversation(), "hello");
+
+        client.disconnect().await;
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn times_out_requests_and_handles_logout_on_connect() {
+        let TestConnection {
+            client,
+            mut connection,
+            mut events,
+            ..
+        } = connect(Arc::new(MemoryStore::new()), Duration::from_millis(100)).await;
+
+        let result = client
+            .send_iq(InfoQuery::new("w", IqType::Get, NodeContent::None))
+            .await;
+        assert!(matches!(result, Err(RequestError::Timeout)));
+        connection.expect_node("iq").await.unwrap();
+        // Requests whose future is dropped before the response are forgotten as well.
+        let dropped = tokio::time::timeout(
+            Duration::from_millis(10),
+            client.send_iq(InfoQuery::new("w", IqType::Get, NodeContent::None)),
+        )
+        .await;
+        assert!(dropped.is_err());
+        connection.expect_node("iq").await.unwrap();
+        assert!(client.pending_requests.lock().unwrap().is_empty());
+
+        connection
+            .send_node(&node("failure", &[("reason", "401")], NodeContent::None))
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(Event::LoggedOut(LoggedOut {
+                on_connect: true,
+                reason: ConnectFailureReason::LoggedOut
+            }))
+        ));
+        assert!(matches!(events.next().await, Some(Event::Disconnected)));
+        assert!(!client.is_connected());
+        assert!(client.store().get_device().await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnects_until_the_stream_is_replaced() {
+        let TestConnection {
+            client,
+            mut connection,
+            mut events,
+            mut server,
+        } = connect(Arc::new(MemoryStore::new()), REQUEST_TIMEOUT).await;
+
+        connection.close().await;
+        assert!(matches!(events.next().await, Some(Event::Disconnected)));
+        let Some(Event::Reconnecting(reconnecting)) = events.next().await else {
+            panic!("expected a reconnecting event");
+        };
+        assert_eq!(reconnecting.attempt, 1);
+        assert_eq!(reconnecting.delay, Duration::from_millis(10));
+
+        let mut connection = server.accept().await.unwrap();
+        connection
+            .send_node(&node("success", &[], NodeContent::None))
+            .await
+            .unwrap();
+        assert!(matches!(events.next().await, Some(Event::Connected)));
+        assert!(client.is_connected());
+
+        let mut stream_error = node("stream:error", &[], NodeContent::None);
+        stream_error.content = NodeContent::Nodes(vec![node(
+            "conflict",
+            &[("type", "replaced")],
+            NodeContent::None,
+        )]);
+        connection.send_node(&stream_error).await.unwrap();
+        assert!(matches!(events.next().await, Some(Event::StreamReplaced)));
+        assert!(matches!(events.next().await, Some(Event::Disconnected)));
+        let next = tokio::time::timeout(Duration::from_millis(100), events.next()).await;
+        assert!(next.is_err());
+        assert!(!client.is_connected());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnects_after_temporary_failures() {
+        let TestConnection {
+            client,
+            mut connection,
+            mut events,
+            mut server,
+        } = connect(Arc::new(MemoryStore::new()), REQUEST_TIMEOUT).await;
+
+        connection
+            .send_node(&node("failure", &[("reason", "503")], NodeContent::None))
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(Event::ConnectFailure(ConnectFailure {
+                reason: ConnectFailureReason::ServiceUnavailable,
+                ..
+            }))
+        ));
+        assert!(matches!(events.next().await, Some(Event::Disconnected)));
+        assert!(matches!(events.next().await, Some(Event::Reconnecting(_))));
+        let mut connection = server.accept().await.unwrap();
+        connection
+            .send_node(&node("success", &[], NodeContent::None))
+            .await
+            .unwrap();
+        assert!(matches!(events.next().await, Some(Event::Connected)));
+        assert!(client.is_connected());
+
+        // Other failures won't go away by connecting again.
+        connection
+            .send_node(&node("failure", &[("reason", "405")], NodeContent::None))
+            .await
+            .unwrap();
+        assert!(matches!(events.next().await, Some(Event::ClientOutdated)));
+        assert!(matches!(events.next().await, Some(Event::Disconnected)));
+        let next = tokio::time::timeout(Duration::from_millis(100), events.next()).await;
+        assert!(next.is_err());
+        assert!(!client.is_connected());
+    }
+}
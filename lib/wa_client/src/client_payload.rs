@@ -0,0 +1,320 @@
+use libsignal_protocol::PublicKey;
+use md5::{Digest, Md5};
+use prost::Message;
+use thiserror::Error;
+use wa_proto::{
+    items::{
+        wa_web_protobufs_companion_reg::{
+            device_props::{self, HistorySyncConfig, PlatformType},
+            DeviceProps,
+        },
+        wa_web_protobufs_wa6::{
+            client_payload::{
+                user_agent::{self, Platform, ReleaseChannel},
+                web_info::WebSubPlatform,
+                ConnectReason, ConnectType, DevicePairingRegistrationData, UserAgent, WebInfo,
+            },
+            ClientPayload,
+        },
+    },
+    PROTO_VERSION,
+};
+use wa_store::Device;
+use wa_types::jid::{DEFAULT_USER_SERVER, JID};
+
+/// [`DJB_KEY_TYPE`] is the key type byte of curve25519 keys in the signal protocol.
+pub(crate) const DJB_KEY_TYPE: u8 = 5;
+
+#[derive(Error, Debug)]
+pub enum PayloadError {
+    #[error("can't log in as {0}, expected a phone number JID")]
+    InvalidJID(JID),
+}
+
+/// [`RegistrationData`] contains the keys of a new device that are sent to the server when
+/// registering it.
+#[derive(Clone, Debug)]
+pub struct RegistrationData {
+    pub registration_id: u32,
+    pub identity_key: PublicKey,
+    pub signed_pre_key_id: u32,
+    pub signed_pre_key: PublicKey,
+    pub signed_pre_key_signature: Vec<u8>,
+}
+
+impl From<&Device> for RegistrationData {
+    fn from(device: &Device) -> Self {
+        RegistrationData {
+            registration_id: device.registration_id,
+            identity_key: device.identity_key.public_key,
+            signed_pre_key_id: device.signed_pre_key.key_id,
+            signed_pre_key: device.signed_pre_key.key_pair.public_key,
+            signed_pre_key_signature: device.signed_pre_key.signature.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// [`ClientPayloadBuilder`] builds the `ClientPayload` that is sent at the end of the noise
+/// handshake, either to register a new device or to log in with an existing one.
+///
+/// The OS name and platform type are shown to the user in the linked devices list of their
+/// phone.
+#[derive(Clone, Debug)]
+pub struct ClientPayloadBuilder {
+    version: [u32; 3],
+    os_name: String,
+    platform_type: PlatformType,
+    user_agent_platform: Platform,
+    os_version: String,
+    manufacturer: String,
+    device: String,
+    locale_language: String,
+    locale_country: String,
+    push_name: Option<String>,
+}
+
+impl Default for ClientPayloadBuilder {
+    fn default() -> Self {
+        ClientPayloadBuilder {
+            version: PROTO_VERSION,
+            os_name: "wa-rs".to_string(),
+            platform_type: PlatformType::Unknown,
+            user_agent_platform: Platform::Web,
+            os_version: "0.1.0".to_string(),
+            manufacturer: String::new(),
+            device: "Desktop".to_string(),
+            locale_language: "en".to_string(),
+            locale_country: "US".to_string(),
+            push_name: None,
+        }
+    }
+}
+
+impl ClientPayloadBuilder {
+    pub fn new() -> Self {
+        ClientPayloadBuilder::default()
+    }
+
+    /// Sets the WhatsApp web version to claim. Defaults to [`PROTO_VERSION`].
+    pub fn version(mut self, version: [u32; 3]) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets the name shown in the linked devices list.
+    pub fn os_name(mut self, os_name: impl Into<String>) -> Self {
+        self.os_name = os_name.into();
+        self
+    }
+
+    /// Sets the platform type, which decides the icon shown in the linked devices list.
+    pub fn platform_type(mut self, platform_type: PlatformType) -> Self {
+        self.platform_type = platform_type;
+        self
+    }
+
+    /// Sets the platform of the user agent. Defaults to [`Platform::Web`].
+    pub fn user_agent_platform(mut self, platform: Platform) -> Self {
+        self.user_agent_platform = platform;
+        self
+    }
+
+    /// Sets the OS version of the user agent.
+    pub fn os_version(mut self, os_version: impl Into<String>) -> Self {
+        self.os_version = os_version.into();
+        self
+    }
+
+    /// Sets the manufacturer of the user agent.
+    pub fn manufacturer(mut self, manufacturer: impl Into<String>) -> Self {
+        self.manufacturer = manufacturer.into();
+        self
+    }
+
+    /// Sets the device (or browser) name of the user agent. Defaults to `Desktop`.
+    pub fn device(mut self, device: impl Into<String>) -> Self {
+        self.device = device.into();
+        self
+    }
+
+    /// Sets the ISO 639-1 language and ISO 3166-1 alpha-2 country of the user agent.
+    pub fn locale(mut self, language: impl Into<String>, country: impl Into<String>) -> Self {
+        self.locale_language = language.into();
+        self.locale_country = country.into();
+        self
+    }
+
+    /// Sets the push name to send when logging in.
+    pub fn push_name(mut self, push_name: impl Into<String>) -> Self {
+        self.push_name = Some(push_name.into());
+        self
+    }
+
+    /// Returns the MD5 hash of the version string, which is sent as the build hash when
+    /// registering.
+    pub fn version_hash(&self) -> [u8; 16] {
+        let [primary, secondary, tertiary] = self.version;
+        Md5::digest(format!("{primary}.{secondary}.{tertiary}")).into()
+    }
+
+    /// Returns the device props that are sent when registering and in pairing requests.
+    pub fn device_props(&self) -> DeviceProps {
+        let mut props = DeviceProps {
+            os: Some(self.os_name.clone()),
+            version: Some(device_props::AppVersion {
+                primary: Some(0),
+                secondary: Some(1),
+                tertiary: Some(0),
+                ..Default::default()
+            }),
+            require_full_sync: Some(false),
+            history_sync_config: Some(HistorySyncConfig {
+                full_sync_days_limit: Some(3650),
+                full_sync_size_mb_limit: Some(102400),
+                storage_quota_mb: Some(102400),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        props.set_platform_type(self.platform_type);
+        props
+    }
+
+    fn base_payload(&self) -> ClientPayload {
+        let [primary, secondary, tertiary] = self.version;
+        let mut user_agent = UserAgent {
+            app_version: Some(user_agent::AppVersion {
+                primary: Some(primary),
+                secondary: Some(secondary),
+                tertiary: Some(tertiary),
+                ..Default::default()
+            }),
+            mcc: Some("000".to_string()),
+            mnc: Some("000".to_string()),
+            os_version: Some(self.os_version.clone()),
+            manufacturer: Some(self.manufacturer.clone()),
+            device: Some(self.device.clone()),
+            os_build_number: Some(self.os_version.clone()),
+            locale_language_iso6391: Some(self.locale_language.clone()),
+            locale_country_iso31661_alpha2: Some(self.locale_country.clone()),
+            ..Default::default()
+        };
+        user_agent.set_platform(self.user_agent_platform);
+        user_agent.set_release_channel(ReleaseChannel::Release);
+
+        let mut web_info = WebInfo::default();
+        web_info.set_web_sub_platform(WebSubPlatform::WebBrowser);
+
+        let mut payload = ClientPayload {
+            user_agent: Some(user_agent),
+            web_info: Some(web_info),
+            ..Default::default()
+        };
+        payload.set_connect_type(ConnectType::WifiUnknown);
+        payload.set_connect_reason(ConnectReason::UserActivated);
+        payload
+    }
+
+    /// Returns the payload for registering a new device, which is used before pairing.
+    pub fn registration_payload(&self, registration: &RegistrationData) -> ClientPayload {
+        let mut payload = self.base_payload();
+        payload.device_pairing_data = Some(DevicePairingRegistrationData {
+            e_regid: Some(registration.registration_id.to_be_bytes().to_vec()),
+            e_keytype: Some(vec![DJB_KEY_TYPE]),
+            e_ident: Some(registration.identity_key.public_key_bytes().to_vec()),
+            e_skey_id: Some(registration.signed_pre_key_id.to_be_bytes()[1..].to_vec()),
+            e_skey_val: Some(registration.signed_pre_key.public_key_bytes().to_vec()),
+            e_skey_sig: Some(registration.signed_pre_key_signature.clone()),
+            build_hash: Some(self.version_hash().to_vec()),
+            device_props: Some(self.device_props().encode_to_vec()),
+        });
+        payload.passive = Some(false);
+        payload.pull = Some(false);
+        payload
+    }
+
+    /// Returns the payload for logging in as an existing device, which must have a phone
+    /// number JID.
+    pub fn login_payload(&self, jid: &JID) -> Result<ClientPayload, PayloadError> {
+        let username = match jid.server.as_str() {
+            DEFAULT_USER_SERVER => jid.user.parse::<u64>().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| PayloadError::InvalidJID(jid.clone()))?;
+
+        let mut payload = self.base_payload();
+        payload.username = Some(username);
+        payload.device = Some(jid.device as u32);
+        payload.push_name.clone_from(&self.push_name);
+        payload.passive = Some(true);
+        payload.pull = Some(true);
+        Ok(payload)
+    }
+
+    /// Returns the login payload if the device has been paired, or the registration payload
+    /// if it hasn't.
+    pub fn device_payload(&self, device: &Device) -> Result<ClientPayload, PayloadError> {
+        match &device.id {
+            Some(jid) => self.login_payload(jid),
+            None => Ok(self.registration_payload(&RegistrationData::from(device))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_and_registration_payloads() {
+        let builder = ClientPayloadBuilder::new()
+            .os_name("Support Desk")
+            .platform_type(PlatformType::Chrome);
+
+        let jid = JID::new_ad_jid("1234".to_string(), 0, 7);
+        let login = builder.login_payload(&jid).unwrap();
+        assert_eq!(login.username, Some(1234));
+        assert_eq!(login.device, Some(7));
+        assert_eq!(login.passive, Some(true));
+        assert!(login.device_pairing_data.is_none());
+        let app_version = login.user_agent.unwrap().app_version.unwrap();
+        assert_eq!(
+            [
+                app_version.primary(),
+                app_version.secondary(),
+                app_version.tertiary()
+            ],
+            PROTO_VERSION
+        );
+
+        let key = PublicKey::from_djb_public_key_bytes(&[9; 32]).unwrap();
+        let registration = builder.registration_payload(&RegistrationData {
+            registration_id: 0x01020304,
+            identity_key: key,
+            signed_pre_key_id: 1,
+            signed_pre_key: key,
+            signed_pre_key_signature: vec![0; 64],
+        });
+        assert_eq!(registration.username, None);
+        let pairing_data = registration.device_pairing_data.unwrap();
+        assert_eq!(pairing_data.e_regid(), [1, 2, 3, 4]);
+        assert_eq!(pairing_data.e_skey_id(), [0, 0, 1]);
+        let props = DeviceProps::decode(pairing_data.device_props()).unwrap();
+        assert_eq!(props.os(), "Support Desk");
+        assert_eq!(props.platform_type(), PlatformType::Chrome);
+    }
+
+    #[test]
+    fn login_payload_rejects_non_phone_jids() {
+        let builder = ClientPayloadBuilder::new();
+        for jid in [
+            JID::new_ad_jid("1234".to_string(), 1, 7),
+            JID::new("not-a-number".to_string(), DEFAULT_USER_SERVER.to_string()),
+        ] {
+            assert!(matches!(
+                builder.login_payload(&jid),
+                Err(PayloadError::InvalidJID(invalid)) if invalid == jid
+            ));
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::HashSet,
+    panic::{catch_unwind, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use tokio::sync::mpsc;
+use wa_types::events::{self, Event, EventKind};
+
+type Handler = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// [`EventFilter`] selects which kinds of events a handler or stream receives. An empty
+/// filter receives every event.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter(HashSet<EventKind>);
+
+impl EventFilter {
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn kinds(kinds: &[EventKind]) -> Self {
+        Self(kinds.iter().copied().collect())
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        self.0.is_empty() || self.0.contains(&event.kind())
+    }
+}
+
+#[derive(Default)]
+struct Handlers {
+    next_id: u64,
+    handlers: Vec<(u64, EventFilter, Handler)>,
+    streams: Vec<(EventFilter, mpsc::Sender<Event>)>,
+}
+
+/// [`EventDispatcher`] delivers events to the handlers and streams registered on it. Handlers
+/// are called in the order they were added, and a handler that panics doesn't stop the others
+/// from being called.
+#[derive(Clone, Default)]
+pub struct EventDispatcher {
+    inner: Arc<Mutex<Handlers>>,
+}
+
+/// [`HandlerHandle`] identifies a registered handler so that it can be removed again.
+#[derive(Debug)]
+pub struct HandlerHandle {
+    id: u64,
+    dispatcher: Weak<Mutex<Handlers>>,
+}
+
+impl HandlerHandle {
+    /// Removes the handler. Returns false if it was already removed or the dispatcher is gone.
+    pub fn remove(self) -> bool {
+        let Some(inner) = self.dispatcher.upgrade() else {
+            return false;
+        };
+        let mut inner = inner.lock().unwrap();
+        let len = inner.handlers.len();
+        inner.handlers.retain(|(id, _, _)| *id != self.id);
+        inner.handlers.len() != len
+    }
+}
+
+impl EventDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for the events matching the filter.
+    pub fn add_handler<F>(&self, filter: EventFilter, handler: F) -> HandlerHandle
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.handlers.push((id, filter, Arc::new(handler)));
+        HandlerHandle {
+            id,
+            dispatcher: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Registers a handler that is only called for incoming messages.
+    pub fn on_message<F>(&self, handler: F) -> HandlerHandle
+    where
+        F: Fn(&events::Message) + Send + Sync + 'static,
+    {
+        self.add_handler(EventFilter::kinds(&[EventKind::Message]), move |event| {
+            if let Event::Message(message) = event {
+                handler(message)
+            }
+        })
+    }
+
+    /// Registers a handler that is only called for receipts.
+    pub fn on_receipt<F>(&self, handler: F) -> HandlerHandle
+    where
+        F: Fn(&events::Receipt) + Send + Sync + 'static,
+    {
+        self.add_handler(EventFilter::kinds(&[EventKind::Receipt]), move |event| {
+            if let Event::Receipt(receipt) = event {
+                handler(receipt)
+            }
+        })
+    }
+
+    /// Returns a stream of the events matching the filter. At most `capacity` events are
+    /// buffered, after which [`EventDispatcher::dispatch`] waits for the stream to be read.
+    /// The stream is unregistered when it's dropped.
+    pub fn subscribe(&self, filter: EventFilter, capacity: usize) -> EventStream {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.inner.lock().unwrap().streams.push((filter, sender));
+        EventStream { receiver }
+    }
+
+    /// Returns the number of handlers that panicked. Events are only buffered for streams
+    /// after all handlers have been called.
+    pub async fn dispatch(&self, event: Event) -> usize {
+        let (handlers, streams) = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.streams.retain(|(_, sender)| !sender.is_closed());
+            let handlers: Vec<Handler> = inner
+                .handlers
+                .iter()
+                .filter(|(_, filter, _)| filter.matches(&event))
+                .map(|(_, _, handler)| handler.clone())
+                .collect();
+            let streams: Vec<mpsc::Sender<Event>> = inner
+                .streams
+                .iter()
+                .filter(|(filter, _)| filter.matches(&event))
+                .map(|(_, sender)| sender.clone())
+                .collect();
+            (handlers, streams)
+        };
+
+        let panicked = handlers
+            .iter()
+            .filter(|handler| catch_unwind(AssertUnwindSafe(|| handler(&event))).is_err())
+            .count();
+        for sender in streams {
+            // A send only fails if the stream was dropped, which is cleaned up on the next
+            // dispatch.
+            let _ = sender.send(event.clone()).await;
+        }
+        panicked
+    }
+}
+
+/// [`EventStream`] is a [`Stream`] of events returned by [`EventDispatcher::subscribe`].
+pub struct EventStream {
+    receiver: mpsc::Receiver<Event>,
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures_util::StreamExt;
+    use wa_types::events::QR;
+
+    use super::*;
+
+    fn qr() -> Event {
+        Event::QR(QR {
+            codes: vec!["code".into()],
+        })
+    }
+
+    #[tokio::test]
+    async fn calls_matching_handlers_and_removes_them() {
+        let dispatcher = EventDispatcher::new();
+        let all = Arc::new(AtomicUsize::new(0));
+        let connected = Arc::new(AtomicUsize::new(0));
+
+        let counter = all.clone();
+        let all_handle = dispatcher.add_handler(EventFilter::all(), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let counter = connected.clone();
+        dispatcher.add_handler(EventFilter::kinds(&[EventKind::Connected]), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        dispatcher.dispatch(Event::Connected).await;
+        dispatcher.dispatch(qr()).await;
+        assert_eq!(all.load(Ordering::SeqCst), 2);
+        assert_eq!(connected.load(Ordering::SeqCst), 1);
+
+        assert!(all_handle.remove());
+        dispatcher.dispatch(Event::Connected).await;
+        assert_eq!(all.load(Ordering::SeqCst), 2);
+        assert_eq!(connected.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn survives_panicking_handlers() {
+        let dispatcher = EventDispatcher::new();
+        let called = Arc::new(AtomicUsize::new(0));
+
+        dispatcher.add_handler(EventFilter::all(), |_| panic!("handler failed"));
+        let counter = called.clone();
+        dispatcher.add_handler(EventFilter::all(), move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(dispatcher.dispatch(Event::Connected).await, 1);
+        assert_eq!(called.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn streams_filtered_events_with_backpressure() {
+        let dispatcher = EventDispatcher::new();
+        let mut stream = dispatcher.subscribe(EventFilter::kinds(&[EventKind::Connected]), 1);
+
+        dispatcher.dispatch(qr()).await;
+        dispatcher.dispatch(Event::Connected).await;
+        // The buffer is full, so the next dispatch waits until the stream is read.
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            dispatcher.dispatch(Event::Connected),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        assert!(matches!(stream.next().await, Some(Event::Connected)));
+        dispatcher.dispatch(Event::Disconnected).await;
+
+        drop(stream);
+        dispatcher.dispatch(Event::Connected).await;
+        dispatcher.dispatch(Event::Connected).await;
+        assert!(dispatcher.inner.lock().unwrap().streams.is_empty());
+    }
+}
@@ -0,0 +1,449 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+use wa_binary::{
+    attrs::AttrErrors,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_types::{
+    group::{
+        GroupAnnounce, GroupEphemeral, GroupIncognito, GroupInfo, GroupIsDefaultSub,
+        GroupLinkedParent, GroupLocked, GroupMemberAddMode, GroupName, GroupParent,
+        GroupParticipant, GroupPartipantAddRequest, GroupTopic,
+    },
+    jid::{GROUP_SERVER, HIDDEN_USER_SERVER, JID},
+};
+
+use crate::request::{InfoQuery, IqType, RequestError, RequestSender};
+
+#[derive(Error, Debug)]
+pub enum GroupError {
+    #[error("the group doesn't exist")]
+    NotFound,
+    #[error("not a participant of the group")]
+    NotInGroup,
+    #[error("missing <{0}> in group response")]
+    MissingNode(&'static str),
+    #[error("invalid attributes in group {jid}: {source}")]
+    Attrs { jid: String, source: AttrErrors },
+    #[error(transparent)]
+    Request(#[from] RequestError),
+}
+
+fn empty_jid() -> JID {
+    JID::new(String::new(), String::new())
+}
+
+fn node_text(node: &Node) -> String {
+    match &node.content {
+        NodeContent::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        NodeContent::String(string) => string.clone(),
+        _ => String::new(),
+    }
+}
+
+fn parse_participant(node: &Node) -> Result<GroupParticipant, AttrErrors> {
+    let mut ag = node.attr_getter();
+    let participant_type = ag.optional_string("type").unwrap_or_default();
+    let jid = ag.jid("jid");
+    let mut lid = ag.optional_jid_or_empty("lid");
+    // Participants of groups that hide phone numbers only have a LID.
+    if jid.server == HIDDEN_USER_SERVER && lid.is_empty() {
+        lid = jid.clone();
+    }
+    let display_name = ag.optional_string("display_name").map(str::to_string);
+    let error = ag
+        .optional_i64("error")
+        .filter(|code| *code != 0)
+        .map(|code| code as i32);
+    ag.into_result()?;
+
+    let mut add_request = None;
+    if error.is_some() {
+        if let Some(request) = node.get_optional_child_by_tag(&["add_request"]) {
+            let mut ag = request.attr_getter();
+            add_request = Some(GroupPartipantAddRequest {
+                code: ag.string("code").to_string(),
+                expiration: ag.unix_time("expiration"),
+            });
+            ag.into_result()?;
+        }
+    }
+
+    Ok(GroupParticipant {
+        jid,
+        lid,
+        is_admin: participant_type == "admin" || participant_type == "superadmin",
+        is_super_admin: participant_type == "superadmin",
+        display_name,
+        error,
+        add_request,
+    })
+}
+
+/// [`parse_group_node`] parses a `<group>` node, as returned by [`get_group_info`] and
+/// [`get_joined_groups`].
+pub fn parse_group_node(node: &Node) -> Result<GroupInfo, GroupError> {
+    let mut ag = node.attr_getter();
+    let jid = JID::new(ag.string("id").to_string(), GROUP_SERVER.to_string());
+    let attrs_error = |source| GroupError::Attrs {
+        jid: jid.to_string(),
+        source,
+    };
+
+    let mut group = GroupInfo {
+        jid: jid.clone(),
+        owner_jid: ag.optional_jid_or_empty("creator"),
+        name: GroupName {
+            name: ag
+                .optional_string("subject")
+                .unwrap_or_default()
+                .to_string(),
+            name_set_at: ag
+                .optional_unix_time("s_t")
+                .unwrap_or(time::OffsetDateTime::UNIX_EPOCH),
+            name_set_by: ag.optional_jid_or_empty("s_o"),
+        },
+        topic: GroupTopic {
+            topic: String::new(),
+            topic_id: String::new(),
+            topic_set_at: time::OffsetDateTime::UNIX_EPOCH,
+            topic_set_by: empty_jid(),
+            topic_deleted: false,
+        },
+        locked: GroupLocked { is_locked: false },
+        announce: GroupAnnounce {
+            is_announce: false,
+            announce_version_id: ag.optional_string("a_v_id").unwrap_or_default().to_string(),
+        },
+        ephemeral: GroupEphemeral {
+            is_ephemeral: false,
+            disappearing_timer: 0,
+        },
+        incognito: GroupIncognito {
+            is_incognito: false,
+        },
+        parent: GroupParent {
+            is_parent: false,
+            default_membership_approval_mode: String::new(),
+        },
+        linked_parent: GroupLinkedParent {
+            linked_parent_jid: empty_jid(),
+        },
+        is_default_sub: GroupIsDefaultSub {
+            is_default_sub_group: false,
+        },
+        group_created: ag.unix_time("creation"),
+        participant_version_id: ag.optional_string("p_v_id").unwrap_or_default().to_string(),
+        participants: Vec::new(),
+        member_add_mode: GroupMemberAddMode::UnknownVariant(String::new()),
+    };
+    ag.into_result().map_err(attrs_error)?;
+
+    for child in node.get_children() {
+        let mut ag = child.attr_getter();
+        match child.tag.as_str() {
+            "participant" => group
+                .participants
+                .push(parse_participant(child).map_err(attrs_error)?),
+            "description" => {
+                if let Some(body) = child.get_optional_child_by_tag(&["body"]) {
+                    group.topic = GroupTopic {
+                        topic: node_text(body),
+                        topic_id: ag.string("id").to_string(),
+                        topic_set_at: ag.unix_time("t"),
+                        topic_set_by: ag.optional_jid_or_empty("participant"),
+                        topic_deleted: false,
+                    };
+                }
+            }
+            "announcement" => group.announce.is_announce = true,
+            "locked" => group.locked.is_locked = true,
+            "ephemeral" => {
+                group.ephemeral = GroupEphemeral {
+                    is_ephemeral: true,
+                    disappearing_timer: ag.u64("expiration"),
+                }
+            }
+            "member_add_mode" => {
+                group.member_add_mode = GroupMemberAddMode::from_str(&node_text(child))
+                    .expect("unknown modes use the default variant")
+            }
+            "linked_parent" => group.linked_parent.linked_parent_jid = ag.jid("jid"),
+            "default_sub_group" => group.is_default_sub.is_default_sub_group = true,
+            "parent" => {
+                group.parent = GroupParent {
+                    is_parent: true,
+                    default_membership_approval_mode: ag
+                        .optional_string("default_membership_approval_mode")
+                        .unwrap_or_default()
+                        .to_string(),
+                }
+            }
+            "incognito" => group.incognito.is_incognito = true,
+            _ => {}
+        }
+        ag.into_result().map_err(attrs_error)?;
+    }
+    Ok(group)
+}
+
+/// [`group_info_query`] returns the request for the metadata and participants of a group.
+pub fn group_info_query(jid: &JID) -> InfoQuery {
+    InfoQuery {
+        to: jid.clone(),
+        ..InfoQuery::new(
+            "w:g2",
+            IqType::Get,
+            NodeContent::Nodes(vec![Node::new(
+                "query",
+                Attrs::from([("request".to_string(), AttrValue::from("interactive"))]),
+                NodeContent::None,
+            )]),
+        )
+    }
+}
+
+/// [`joined_groups_query`] returns the request for all the groups the user participates in.
+pub fn joined_groups_query() -> InfoQuery {
+    InfoQuery {
+        to: JID::new(String::new(), GROUP_SERVER.to_string()),
+        ..InfoQuery::new(
+            "w:g2",
+            IqType::Get,
+            NodeContent::Nodes(vec![Node::new(
+                "participating",
+                Attrs::new(),
+                NodeContent::Nodes(vec![
+                    Node::new("participants", Attrs::new(), NodeContent::None),
+                    Node::new("description", Attrs::new(), NodeContent::None),
+                ]),
+            )]),
+        )
+    }
+}
+
+/// [`get_group_info`] fetches the metadata and participants of a group.
+pub async fn get_group_info(
+    sender: &dyn RequestSender,
+    jid: &JID,
+) -> Result<GroupInfo, GroupError> {
+    let response = match sender.send_iq(group_info_query(jid)).await {
+        Ok(response) => response,
+        Err(RequestError::Iq { code: 404, .. }) => return Err(GroupError::NotFound),
+        Err(RequestError::Iq { code: 403, .. }) => return Err(GroupError::NotInGroup),
+        Err(err) => return Err(err.into()),
+    };
+    let group = response
+        .get_optional_child_by_tag(&["group"])
+        .ok_or(GroupError::MissingNode("group"))?;
+    parse_group_node(group)
+}
+
+/// [`get_joined_groups`] fetches the metadata and participants of all the groups the user
+/// participates in.
+pub async fn get_joined_groups(sender: &dyn RequestSender) -> Result<Vec<GroupInfo>, GroupError> {
+    let response = sender.send_iq(joined_groups_query()).await?;
+    let groups = response
+        .get_optional_child_by_tag(&["groups"])
+        .ok_or(GroupError::MissingNode("groups"))?;
+    groups
+        .get_children_by_tag("group")
+        .map(parse_group_node)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use wa_mock_server::stanza;
+
+    use super::*;
+    use crate::test_util::{node, FakeServer};
+
+    #[test]
+    fn parses_group_nodes() {
+        let group = node(
+            "group",
+            &[
+                ("id", "120363000000000001"),
+                ("creator", "1234@s.whatsapp.net"),
+                ("subject", "Support"),
+                ("s_t", "1700000000"),
+                ("s_o", "1234@s.whatsapp.net"),
+                ("creation", "1690000000"),
+                ("p_v_id", "pv1"),
+            ],
+            NodeContent::Nodes(vec![
+                node(
+                    "participant",
+                    &[
+                        ("jid", "1234@s.whatsapp.net"),
+                        ("lid", "5678@lid"),
+                        ("type", "superadmin"),
+                    ],
+                    NodeContent::None,
+                ),
+                node(
+                    "participant",
+                    &[("jid", "9999@lid"), ("type", "admin")],
+                    NodeContent::None,
+                ),
+                node(
+                    "participant",
+                    &[("jid", "4321@s.whatsapp.net"), ("error", "403")],
+                    NodeContent::Nodes(vec![node(
+                        "add_request",
+                        &[("code", "invite"), ("expiration", "1700600000")],
+                        NodeContent::None,
+                    )]),
+                ),
+                node(
+                    "description",
+                    &[("id", "topic1"), ("t", "1700000100")],
+                    NodeContent::Nodes(vec![node(
+                        "body",
+                        &[],
+                        NodeContent::Bytes(b"Questions go here".to_vec()),
+                    )]),
+                ),
+                node("announcement", &[], NodeContent::None),
+                node("ephemeral", &[("expiration", "86400")], NodeContent::None),
+                node(
+                    "member_add_mode",
+                    &[],
+                    NodeContent::Bytes(b"admin_add".to_vec()),
+                ),
+                node(
+                    "linked_parent",
+                    &[("jid", "120363000000000002@g.us")],
+                    NodeContent::None,
+                ),
+                node("default_sub_group", &[], NodeContent::None),
+            ]),
+        );
+
+        let info = parse_group_node(&group).unwrap();
+        assert_eq!(info.jid.to_string(), "120363000000000001@g.us");
+        assert_eq!(info.owner_jid.user, "1234");
+        assert_eq!(info.name.name, "Support");
+        assert_eq!(info.name.name_set_at.unix_timestamp(), 1700000000);
+        assert_eq!(info.group_created.unix_timestamp(), 1690000000);
+        assert_eq!(info.participant_version_id, "pv1");
+        assert_eq!(info.topic.topic, "Questions go here");
+        assert_eq!(info.topic.topic_id, "topic1");
+        assert!(info.announce.is_announce);
+        assert!(!info.locked.is_locked);
+        assert_eq!(info.ephemeral.disappearing_timer, 86400);
+        assert!(matches!(info.member_add_mode, GroupMemberAddMode::Admin));
+        assert_eq!(
+            info.linked_parent.linked_parent_jid.to_string(),
+            "120363000000000002@g.us"
+        );
+        assert!(info.is_default_sub.is_default_sub_group);
+        assert!(!info.parent.is_parent);
+
+        let [owner, hidden, failed] = info.participants.as_slice() else {
+            panic!("expected three participants");
+        };
+        assert!(owner.is_admin && owner.is_super_admin);
+        assert_eq!(owner.lid.to_string(), "5678@lid");
+        assert!(hidden.is_admin && !hidden.is_super_admin);
+        assert_eq!(hidden.lid, hidden.jid);
+        assert_eq!(failed.error, Some(403));
+        assert_eq!(failed.add_request.as_ref().unwrap().code, "invite");
+
+        // Groups without a name are sent without a subject.
+        let mut unnamed = group.clone();
+        unnamed.attrs.remove("subject");
+        unnamed.attrs.remove("s_t");
+        let info = parse_group_node(&unnamed).unwrap();
+        assert_eq!(info.name.name, "");
+        assert_eq!(info.name.name_set_at, time::OffsetDateTime::UNIX_EPOCH);
+
+        let mut invalid = group.clone();
+        invalid.attrs.remove("creation");
+        assert!(matches!(
+            parse_group_node(&invalid),
+            Err(GroupError::Attrs { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetches_joined_groups() {
+        let server = FakeServer::answering(|request| {
+            assert!(request
+                .get_optional_child_by_tag(&["participating", "participants"])
+                .is_some());
+            stanza::iq_result(
+                request,
+                NodeContent::Nodes(vec![node(
+                    "groups",
+                    &[],
+                    NodeContent::Nodes(vec![
+                        node(
+                            "group",
+                            &[
+                                ("id", "120363000000000001"),
+                                ("subject", "Support"),
+                                ("s_t", "1700000000"),
+                                ("creation", "1690000000"),
+                            ],
+                            NodeContent::Nodes(vec![node(
+                                "participant",
+                                &[("jid", "1234@s.whatsapp.net")],
+                                NodeContent::None,
+                            )]),
+                        ),
+                        node(
+                            "group",
+                            &[("id", "120363000000000002"), ("creation", "1690000000")],
+                            NodeContent::None,
+                        ),
+                    ]),
+                )]),
+            )
+        });
+        let groups = get_joined_groups(&server).await.unwrap();
+        assert_eq!(
+            server.sent.lock().unwrap()[0].attrs["xmlns"],
+            AttrValue::from("w:g2")
+        );
+        let [support, unnamed] = groups.as_slice() else {
+            panic!("expected two groups");
+        };
+        assert_eq!(support.jid.to_string(), "120363000000000001@g.us");
+        assert_eq!(support.name.name, "Support");
+        assert_eq!(support.participants.len(), 1);
+        assert_eq!(unnamed.jid.to_string(), "120363000000000002@g.us");
+        assert_eq!(unnamed.name.name, "");
+        assert!(unnamed.participants.is_empty());
+
+        let server = FakeServer::answering(|request| stanza::iq_result(request, NodeContent::None));
+        assert!(matches!(
+            get_joined_groups(&server).await,
+            Err(GroupError::MissingNode("groups"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn maps_group_info_errors() {
+        let jid = JID::new("120363000000000001".to_string(), GROUP_SERVER.to_string());
+        let server =
+            FakeServer::answering(|request| stanza::iq_error(request, 404, "item-not-found"));
+        assert!(matches!(
+            get_group_info(&server, &jid).await,
+            Err(GroupError::NotFound)
+        ));
+        let server = FakeServer::answering(|request| stanza::iq_error(request, 403, "forbidden"));
+        assert!(matches!(
+            get_group_info(&server, &jid).await,
+            Err(GroupError::NotInGroup)
+        ));
+        let server =
+            FakeServer::answering(|request| stanza::iq_error(request, 500, "internal-error"));
+        assert!(matches!(
+            get_group_info(&server, &jid).await,
+            Err(GroupError::Request(RequestError::Iq { code: 500, .. }))
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+use std::{sync::Mutex, time::Duration};
+
+use rand::{rngs::OsRng, Rng};
+use time::OffsetDateTime;
+use tokio::time::Instant;
+use wa_binary::node::{Attrs, Node, NodeContent};
+use wa_types::events::{Event, KeepAliveTimeout};
+
+use crate::{
+    dispatcher::EventDispatcher,
+    request::{InfoQuery, IqType, RequestError, RequestSender},
+};
+
+/// [`KeepAliveConfig`] controls how often the client pings the server to detect connections
+/// that died without being closed.
+#[derive(Clone, Debug)]
+pub struct KeepAliveConfig {
+    /// Pings are sent after a random interval between the min and max.
+    pub interval_min: Duration,
+    pub interval_max: Duration,
+    /// How long to wait for the response to a ping before counting it as failed.
+    pub response_timeout: Duration,
+    /// The number of consecutive failed pings after which the client reconnects.
+    pub max_failures: u32,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        KeepAliveConfig {
+            interval_min: Duration::from_secs(20),
+            interval_max: Duration::from_secs(30),
+            response_timeout: Duration::from_secs(10),
+            max_failures: 6,
+        }
+    }
+}
+
+/// [`KeepAliveEnd`] is why [`keepalive_loop`] returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KeepAliveEnd {
+    Disconnected,
+    TooManyFailures,
+}
+
+/// [`ping_query`] returns the ping the server answers with an empty result.
+pub fn ping_query() -> InfoQuery {
+    InfoQuery::new(
+        "w:p",
+        IqType::Get,
+        NodeContent::Nodes(vec![Node::new("ping", Attrs::new(), NodeContent::None)]),
+    )
+}
+
+/// Pings the server until the connection is closed or [`KeepAliveConfig::max_failures`]
+/// pings in a row have failed, in which case the caller should reconnect. The round trip time
+/// of the last successful ping is stored in `latency`.
+pub(crate) async fn keepalive_loop(
+    sender: &dyn RequestSender,
+    config: &KeepAliveConfig,
+    dispatcher: &EventDispatcher,
+    latency: &Mutex<Option<Duration>>,
+) -> KeepAliveEnd {
+    let mut failures = 0;
+    let mut last_success = None;
+    loop {
+        let interval =
+            OsRng.gen_range(config.interval_min..=config.interval_max.max(config.interval_min));
+        tokio::time::sleep(interval).await;
+
+        let start = Instant::now();
+        match tokio::time::timeout(config.response_timeout, sender.send_iq(ping_query())).await {
+            Ok(Ok(_)) => {
+                *latency.lock().unwrap() = Some(start.elapsed());
+                last_success = Some(OffsetDateTime::now_utc());
+                if failures > 0 {
+                    failures = 0;
+                    dispatcher.dispatch(Event::KeepAliveRestored).await;
+                }
+            }
+            Ok(Err(RequestError::NotConnected | RequestError::Disconnected)) => {
+                return KeepAliveEnd::Disconnected;
+            }
+            Ok(Err(_)) | Err(_) => {
+                failures += 1;
+                dispatcher
+                    .dispatch(Event::KeepAliveTimeout(KeepAliveTimeout {
+                        error_count: failures,
+                        last_success,
+                    }))
+                    .await;
+                if failures >= config.max_failures {
+                    return KeepAliveEnd::TooManyFailures;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use async_trait::async_trait;
+    use futures_util::StreamExt;
+    use wa_types::events::EventKind;
+
+    use super::*;
+    use crate::{
+        dispatcher::EventFilter,
+        test_util::{FakeServer, Respond},
+    };
+
+    /// [`Ping`] is how the fake server answers a ping.
+    enum Ping {
+        Answer(Duration),
+        Ignore,
+        Error,
+        Close,
+    }
+
+    /// [`Pings`] answers each ping with the next [`Ping`].
+    struct Pings(Mutex<VecDeque<Ping>>);
+
+    #[async_trait]
+    impl Respond for Pings {
+        async fn respond(&self, request: &Node) -> Result<Node, RequestError> {
+            assert_eq!(request.attr_getter().string("xmlns"), "w:p");
+            assert!(request.get_optional_child_by_tag(&["ping"]).is_some());
+            let ping = self.0.lock().unwrap().pop_front();
+            match ping {
+                Some(Ping::Answer(delay)) => {
+                    tokio::time::sleep(delay).await;
+                    Ok(request.clone())
+                }
+                Some(Ping::Ignore) => std::future::pending().await,
+                Some(Ping::Error) => Err(RequestError::Iq {
+                    code: 500,
+                    text: "internal-server-error".to_string(),
+                }),
+                Some(Ping::Close) | None => Err(RequestError::Disconnected),
+            }
+        }
+    }
+
+    fn config() -> KeepAliveConfig {
+        KeepAliveConfig {
+            max_failures: 3,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_timeouts_and_restores() {
+        let server = FakeServer::new(Pings(Mutex::new(VecDeque::from([
+            Ping::Answer(Duration::from_millis(300)),
+            Ping::Ignore,
+            Ping::Error,
+            Ping::Answer(Duration::from_millis(50)),
+            Ping::Close,
+        ]))));
+        let dispatcher = EventDispatcher::new();
+        let mut events = dispatcher.subscribe(
+            EventFilter::kinds(&[EventKind::KeepAliveTimeout, EventKind::KeepAliveRestored]),
+            8,
+        );
+        let latency = Mutex::new(None);
+
+        let end = keepalive_loop(&server, &config(), &dispatcher, &latency).await;
+        assert_eq!(end, KeepAliveEnd::Disconnected);
+        assert_eq!(*latency.lock().unwrap(), Some(Duration::from_millis(50)));
+
+        for error_count in [1, 2] {
+            let Some(Event::KeepAliveTimeout(timeout)) = events.next().await else {
+                panic!("expected a keepalive timeout");
+            };
+            assert_eq!(timeout.error_count, error_count);
+            assert!(timeout.last_success.is_some());
+        }
+        assert!(matches!(
+            events.next().await,
+            Some(Event::KeepAliveRestored)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_consecutive_failures() {
+        let server = FakeServer::new(Pings(Mutex::new(VecDeque::from([
+            Ping::Ignore,
+            Ping::Ignore,
+            Ping::Ignore,
+        ]))));
+        let dispatcher = EventDispatcher::new();
+        let latency = Mutex::new(None);
+
+        let start = Instant::now();
+        let end = keepalive_loop(&server, &config(), &dispatcher, &latency).await;
+        assert_eq!(end, KeepAliveEnd::TooManyFailures);
+        assert!(start.elapsed() >= Duration::from_secs(3 * (20 + 10)));
+        assert_eq!(*latency.lock().unwrap(), None);
+    }
+}
@@ -0,0 +1,19 @@
+pub mod client;
+pub mod client_payload;
+pub mod dispatcher;
+pub mod group;
+pub mod keepalive;
+pub mod pair;
+pub mod pair_code;
+pub mod prekeys;
+pub mod receipt;
+pub mod receive;
+pub mod reconnect;
+pub mod request;
+pub mod retry;
+pub mod send;
+pub mod sender_key;
+pub mod usync;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,539 @@
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use libsignal_protocol::{KeyPair, PublicKey, SignalProtocolError};
+use prost::Message;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use wa_binary::{
+    attrs::AttrErrors,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_proto::items::wa_adv::{
+    AdvDeviceIdentity, AdvEncryptionType, AdvSignedDeviceIdentity, AdvSignedDeviceIdentityHmac,
+};
+use wa_store::{Device, DeviceStore, StoreError};
+use wa_types::events::PairSuccess;
+
+use crate::request::server_jid;
+
+const ADV_ACCOUNT_SIGNATURE_PREFIX: [u8; 2] = [6, 0];
+const ADV_DEVICE_SIGNATURE_PREFIX: [u8; 2] = [6, 1];
+const ADV_HOSTED_ACCOUNT_SIGNATURE_PREFIX: [u8; 2] = [6, 5];
+
+/// [`QR_FIRST_CODE_TIMEOUT`] is how long the first QR code of a `pair-device` request is valid.
+pub const QR_FIRST_CODE_TIMEOUT: Duration = Duration::from_secs(60);
+/// [`QR_CODE_TIMEOUT`] is how long each of the following QR codes is valid.
+pub const QR_CODE_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Error, Debug)]
+pub enum PairError {
+    #[error("missing <{0}> in pair success")]
+    MissingNode(&'static str),
+    #[error("invalid attributes in pair success: {0}")]
+    Attrs(#[from] AttrErrors),
+    #[error("failed to parse {what}: {source}")]
+    Proto {
+        what: &'static str,
+        source: prost::DecodeError,
+    },
+    #[error("invalid device identity HMAC in pair success")]
+    InvalidDeviceIdentityHmac,
+    #[error("invalid account signature in pair success")]
+    InvalidAccountSignature,
+    #[error("failed to sign device identity: {0}")]
+    Signal(#[from] SignalProtocolError),
+    #[error("failed to save device: {0}")]
+    Store(#[from] StoreError),
+}
+
+impl PairError {
+    /// Returns the error code and text to send to the server when pairing fails.
+    fn error_code(&self) -> (u16, &'static str) {
+        match self {
+            PairError::InvalidDeviceIdentityHmac | PairError::InvalidAccountSignature => {
+                (401, "not-authorized")
+            }
+            _ => (500, "internal-error"),
+        }
+    }
+}
+
+/// [`make_qr_data`] returns the contents of the QR code for the given ref, which the phone
+/// scans to link the device.
+pub fn make_qr_data(device: &Device, reference: &str) -> String {
+    [
+        reference.to_string(),
+        STANDARD.encode(device.noise_key.public_key.public_key_bytes()),
+        STANDARD.encode(device.identity_key.public_key.public_key_bytes()),
+        STANDARD.encode(device.adv_secret_key),
+    ]
+    .join(",")
+}
+
+/// [`handle_pair_device`] handles the `pair-device` iq the server sends to unpaired devices.
+/// Returns the acknowledgement to send back and the QR codes for all the refs in the request.
+pub fn handle_pair_device(node: &Node, device: &Device) -> (Node, Vec<String>) {
+    let mut ack_attrs = Attrs::from([("type".to_string(), AttrValue::from("result"))]);
+    for key in ["id", "from"] {
+        if let Some(value) = node.attrs.get(key) {
+            let key = if key == "from" { "to" } else { key };
+            ack_attrs.insert(key.to_string(), value.clone());
+        }
+    }
+    let ack = Node::new("iq", ack_attrs, NodeContent::None);
+
+    let codes = node
+        .get_optional_child_by_tag(&["pair-device"])
+        .into_iter()
+        .flat_map(|pair_device| pair_device.get_children_by_tag("ref"))
+        .filter_map(|reference| match &reference.content {
+            NodeContent::Bytes(bytes) => String::from_utf8(bytes.clone()).ok(),
+            NodeContent::String(string) => Some(string.clone()),
+            _ => None,
+        })
+        .map(|reference| make_qr_data(device, &reference))
+        .collect();
+
+    (ack, codes)
+}
+
+/// [`QrEvent`] is emitted by [`rotate_qr_codes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QrEvent {
+    /// A new QR code should be shown. It's valid until the timeout passes.
+    Code { code: String, timeout: Duration },
+    /// All QR codes have expired without the phone scanning any of them.
+    Timeout,
+}
+
+/// [`rotate_qr_codes`] sends the given QR codes to the channel one by one, waiting for each
+/// of them to expire before sending the next one, followed by [`QrEvent::Timeout`].
+///
+/// This stops early if the receiver is dropped, which is how callers should cancel it when
+/// pairing succeeds.
+pub async fn rotate_qr_codes(codes: Vec<String>, sender: mpsc::Sender<QrEvent>) {
+    for (index, code) in codes.into_iter().enumerate() {
+        let timeout = if index == 0 {
+            QR_FIRST_CODE_TIMEOUT
+        } else {
+            QR_CODE_TIMEOUT
+        };
+        if sender.send(QrEvent::Code { code, timeout }).await.is_err() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => {}
+            _ = sender.closed() => return,
+        }
+    }
+    let _ = sender.send(QrEvent::Timeout).await;
+}
+
+/// [`PairResponse`] is the result of handling a `pair-success` iq.
+pub struct PairResponse {
+    /// The iq to send back to the server, which is an error if pairing failed.
+    pub reply: Node,
+    pub result: Result<PairSuccess, PairError>,
+}
+
+fn verify_account_signature(
+    device_identity: &AdvSignedDeviceIdentity,
+    identity_key: &KeyPair,
+    is_hosted: bool,
+) -> bool {
+    let (Some(account_signature_key), Some(account_signature)) = (
+        &device_identity.account_signature_key,
+        &device_identity.account_signature,
+    ) else {
+        return false;
+    };
+    if account_signature_key.len() != 32 || account_signature.len() != 64 {
+        return false;
+    }
+    let Ok(signature_key) = PublicKey::from_djb_public_key_bytes(account_signature_key) else {
+        return false;
+    };
+
+    let prefix = if is_hosted {
+        ADV_HOSTED_ACCOUNT_SIGNATURE_PREFIX
+    } else {
+        ADV_ACCOUNT_SIGNATURE_PREFIX
+    };
+    let message = [
+        &prefix[..],
+        device_identity.details(),
+        identity_key.public_key.public_key_bytes(),
+    ]
+    .concat();
+    signature_key.verify_signature(&message, account_signature)
+}
+
+fn generate_device_signature(
+    device_identity: &AdvSignedDeviceIdentity,
+    identity_key: &KeyPair,
+) -> Result<Vec<u8>, SignalProtocolError> {
+    let message = [
+        &ADV_DEVICE_SIGNATURE_PREFIX[..],
+        device_identity.details(),
+        identity_key.public_key.public_key_bytes(),
+        device_identity.account_signature_key(),
+    ]
+    .concat();
+    Ok(identity_key
+        .private_key
+        .calculate_signature(&message, &mut OsRng)?
+        .to_vec())
+}
+
+struct VerifiedPair {
+    success: PairSuccess,
+    account: AdvSignedDeviceIdentity,
+    self_signed_identity: Vec<u8>,
+    key_index: u32,
+}
+
+fn verify_pair_success(node: &Node, device: &Device) -> Result<VerifiedPair, PairError> {
+    let pair_success = node
+        .get_optional_child_by_tag(&["pair-success"])
+        .ok_or(PairError::MissingNode("pair-success"))?;
+    let device_identity_bytes = pair_success
+        .get_optional_child_by_tag(&["device-identity"])
+        .and_then(Node::content_bytes)
+        .ok_or(PairError::MissingNode("device-identity"))?;
+    let device_node = pair_success
+        .get_optional_child_by_tag(&["device"])
+        .ok_or(PairError::MissingNode("device"))?;
+
+    let mut ag = device_node.attr_getter();
+    let id = ag.jid("jid");
+    let lid = ag.optional_jid("lid");
+    ag.into_result()?;
+    let business_name = pair_success
+        .get_optional_child_by_tag(&["biz"])
+        .and_then(|biz| biz.attr_getter().optional_string("name"))
+        .unwrap_or_default()
+        .to_string();
+    let platform = pair_success
+        .get_optional_child_by_tag(&["platform"])
+        .and_then(|platform| platform.attr_getter().optional_string("name"))
+        .unwrap_or_default()
+        .to_string();
+
+    let container =
+        AdvSignedDeviceIdentityHmac::decode(device_identity_bytes).map_err(|source| {
+            PairError::Proto {
+                what: "device identity container",
+                source,
+            }
+        })?;
+    let is_hosted = container.account_type() == AdvEncryptionType::Hosted;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&device.adv_secret_key)
+        .expect("HMAC can take keys of any size");
+    if is_hosted {
+        mac.update(&ADV_HOSTED_ACCOUNT_SIGNATURE_PREFIX);
+    }
+    mac.update(container.details());
+    mac.verify_slice(container.hmac())
+        .map_err(|_| PairError::InvalidDeviceIdentityHmac)?;
+
+    let mut device_identity =
+        AdvSignedDeviceIdentity::decode(container.details()).map_err(|source| {
+            PairError::Proto {
+                what: "signed device identity",
+                source,
+            }
+        })?;
+    let device_identity_details =
+        AdvDeviceIdentity::decode(device_identity.details()).map_err(|source| {
+            PairError::Proto {
+                what: "device identity details",
+                source,
+            }
+        })?;
+
+    if !verify_account_signature(&device_identity, &device.identity_key, is_hosted) {
+        return Err(PairError::InvalidAccountSignature);
+    }
+    device_identity.device_signature = Some(generate_device_signature(
+        &device_identity,
+        &device.identity_key,
+    )?);
+
+    let account = device_identity.clone();
+    device_identity.account_signature_key = None;
+
+    Ok(VerifiedPair {
+        success: PairSuccess {
+            id,
+            lid,
+            business_name,
+            platform,
+        },
+        account,
+        self_signed_identity: device_identity.encode_to_vec(),
+        key_index: device_identity_details.key_index(),
+    })
+}
+
+fn pair_error_node(request_id: &AttrValue, error: &PairError) -> Node {
+    let (code, text) = error.error_code();
+    Node::new(
+        "iq",
+        Attrs::from([
+            ("to".to_string(), AttrValue::JID(server_jid())),
+            ("type".to_string(), AttrValue::from("error")),
+            ("id".to_string(), request_id.clone()),
+        ]),
+        NodeContent::Nodes(vec![Node::new(
+            "error",
+            Attrs::from([
+                ("code".to_string(), AttrValue::from(code.to_string())),
+                ("text".to_string(), AttrValue::from(text)),
+            ]),
+            NodeContent::None,
+        )]),
+    )
+}
+
+/// [`handle_pair_success`] handles the `pair-success` iq the server sends after the phone
+/// scanned a QR code (or entered a pairing code).
+///
+/// The device identity is verified against the adv secret and identity key of the device, and
+/// if it's valid, the device is updated with its new JID and saved to the store, so that the
+/// next connection logs in instead of registering again.
+pub async fn handle_pair_success(
+    node: &Node,
+    device: &mut Device,
+    store: &dyn DeviceStore,
+) -> PairResponse {
+    let request_id = node
+        .attrs
+        .get("id")
+        .cloned()
+        .unwrap_or_else(|| AttrValue::from(""));
+
+    let verified = match verify_pair_success(node, device) {
+        Ok(verified) => verified,
+        Err(error) => {
+            return PairResponse {
+                reply: pair_error_node(&request_id, &error),
+                result: Err(error),
+            }
+        }
+    };
+
+    let mut paired = device.clone();
+    paired.id = Some(verified.success.id.clone());
+    paired.lid.clone_from(&verified.success.lid);
+    paired.account = Some(verified.account);
+    paired
+        .business_name
+        .clone_from(&verified.success.business_name);
+    paired.platform.clone_from(&verified.success.platform);
+    if let Err(error) = store.put_device(&paired).await {
+        let error = PairError::from(error);
+        return PairResponse {
+            reply: pair_error_node(&request_id, &error),
+            result: Err(error),
+        };
+    }
+    *device = paired;
+
+    PairResponse {
+        reply: Node::new(
+            "iq",
+            Attrs::from([
+                ("to".to_string(), AttrValue::JID(server_jid())),
+                ("type".to_string(), AttrValue::from("result")),
+                ("id".to_string(), request_id),
+            ]),
+            NodeContent::Nodes(vec![Node::new(
+                "pair-device-sign",
+                Attrs::new(),
+                NodeContent::Nodes(vec![Node::new(
+                    "device-identity",
+                    Attrs::from([(
+                        "key-index".to_string(),
+                        AttrValue::from(verified.key_index.to_string()),
+                    )]),
+                    NodeContent::Bytes(verified.self_signed_identity),
+                )]),
+            )]),
+        ),
+        result: Ok(verified.success),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use wa_store::memory::MemoryStore;
+    use wa_types::jid::JID;
+
+    use super::*;
+
+    /// Builds the `pair-success` iq the way the phone and server would for the given device.
+    pub(crate) fn pair_success_node(device: &Device, adv_secret_key: &[u8]) -> Node {
+        let account_key = KeyPair::generate(&mut OsRng);
+        let details = AdvDeviceIdentity {
+            raw_id: Some(1),
+            key_index: Some(3),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let message = [
+            &ADV_ACCOUNT_SIGNATURE_PREFIX[..],
+            &details,
+            device.identity_key.public_key.public_key_bytes(),
+        ]
+        .concat();
+        let signed = AdvSignedDeviceIdentity {
+            details: Some(details),
+            account_signature_key: Some(account_key.public_key.public_key_bytes().to_vec()),
+            account_signature: Some(
+                account_key
+                    .calculate_signature(&message, &mut OsRng)
+                    .unwrap()
+                    .to_vec(),
+            ),
+            device_signature: None,
+        }
+        .encode_to_vec();
+        let mut mac = Hmac::<Sha256>::new_from_slice(adv_secret_key).unwrap();
+        mac.update(&signed);
+        let container = AdvSignedDeviceIdentityHmac {
+            details: Some(signed),
+            hmac: Some(mac.finalize().into_bytes().to_vec()),
+            account_type: None,
+        };
+
+        Node::new(
+            "iq",
+            Attrs::from([
+                ("id".to_string(), AttrValue::from("pair-1")),
+                ("type".to_string(), AttrValue::from("set")),
+            ]),
+            NodeContent::Nodes(vec![Node::new(
+                "pair-success",
+                Attrs::new(),
+                NodeContent::Nodes(vec![
+                    Node::new(
+                        "device-identity",
+                        Attrs::new(),
+                        NodeContent::Bytes(container.encode_to_vec()),
+                    ),
+                    Node::new(
+                        "device",
+                        Attrs::from([(
+                            "jid".to_string(),
+                            AttrValue::JID(JID::new_ad_jid("1234".to_string(), 0, 7)),
+                        )]),
+                        NodeContent::None,
+                    ),
+                    Node::new(
+                        "platform",
+                        Attrs::from([("name".to_string(), AttrValue::from("android"))]),
+                        NodeContent::None,
+                    ),
+                ]),
+            )]),
+        )
+    }
+
+    #[test]
+    fn pair_device_generates_qr_codes() {
+        let device = Device::generate().unwrap();
+        let node = Node::new(
+            "iq",
+            Attrs::from([
+                ("id".to_string(), AttrValue::from("1")),
+                ("from".to_string(), AttrValue::JID(server_jid())),
+            ]),
+            NodeContent::Nodes(vec![Node::new(
+                "pair-device",
+                Attrs::new(),
+                NodeContent::Nodes(vec![
+                    Node::new("ref", Attrs::new(), NodeContent::Bytes(b"ref1".to_vec())),
+                    Node::new("ref", Attrs::new(), NodeContent::Bytes(b"ref2".to_vec())),
+                ]),
+            )]),
+        );
+
+        let (ack, codes) = handle_pair_device(&node, &device);
+        assert_eq!(ack.attrs["to"], AttrValue::JID(server_jid()));
+        assert_eq!(codes.len(), 2);
+        let parts = codes[1].split(',').collect::<Vec<_>>();
+        assert_eq!(parts[0], "ref2");
+        assert_eq!(
+            STANDARD.decode(parts[3]).unwrap(),
+            device.adv_secret_key.to_vec()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn qr_codes_rotate_until_timeout() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        tokio::spawn(rotate_qr_codes(
+            vec!["a".to_string(), "b".to_string()],
+            sender,
+        ));
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(
+            receiver.recv().await,
+            Some(QrEvent::Code {
+                code: "a".to_string(),
+                timeout: QR_FIRST_CODE_TIMEOUT
+            })
+        );
+        assert!(matches!(receiver.recv().await, Some(QrEvent::Code { code, .. }) if code == "b"));
+        assert_eq!(start.elapsed(), QR_FIRST_CODE_TIMEOUT);
+        assert_eq!(receiver.recv().await, Some(QrEvent::Timeout));
+        assert_eq!(start.elapsed(), QR_FIRST_CODE_TIMEOUT + QR_CODE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn pair_success_saves_device() {
+        let store = MemoryStore::new();
+        let mut device = Device::generate().unwrap();
+        let node = pair_success_node(&device, &device.adv_secret_key);
+
+        let response = handle_pair_success(&node, &mut device, &store).await;
+        let success = response.result.unwrap();
+        assert_eq!(success.id, JID::new_ad_jid("1234".to_string(), 0, 7));
+        assert_eq!(success.platform, "android");
+        assert_eq!(response.reply.attrs["type"], AttrValue::from("result"));
+        let identity = response
+            .reply
+            .get_optional_child_by_tag(&["pair-device-sign", "device-identity"])
+            .unwrap();
+        assert_eq!(identity.attrs["key-index"], AttrValue::from("3"));
+        let signed = AdvSignedDeviceIdentity::decode(identity.content_bytes().unwrap()).unwrap();
+        assert!(signed.account_signature_key.is_none());
+        assert_eq!(signed.device_signature().len(), 64);
+
+        let stored = store.get_device().await.unwrap().unwrap();
+        assert_eq!(stored.id, Some(success.id));
+        assert!(device.is_paired());
+    }
+
+    #[tokio::test]
+    async fn pair_success_rejects_wrong_hmac() {
+        let store = MemoryStore::new();
+        let mut device = Device::generate().unwrap();
+        let node = pair_success_node(&device, &[0; 32]);
+
+        let response = handle_pair_success(&node, &mut device, &store).await;
+        assert!(matches!(
+            response.result,
+            Err(PairError::InvalidDeviceIdentityHmac)
+        ));
+        assert_eq!(response.reply.attrs["type"], AttrValue::from("error"));
+        assert!(store.get_device().await.unwrap().is_none());
+        assert!(!device.is_paired());
+    }
+}
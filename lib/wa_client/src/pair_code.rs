@@ -0,0 +1,557 @@
+use std::time::{Duration, Instant};
+
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes256,
+};
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use hkdf::Hkdf;
+use libsignal_protocol::{KeyPair, PublicKey, SignalProtocolError};
+use rand::{rngs::OsRng, Rng};
+use sha2::Sha256;
+use thiserror::Error;
+use wa_binary::node::{AttrValue, Attrs, Node, NodeContent};
+use wa_store::Device;
+use wa_types::jid::{DEFAULT_USER_SERVER, JID};
+
+use crate::{pair::PairError, request::bytes_node};
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+const LINKING_BASE32_ALPHABET: &[u8; 32] = b"123456789ABCDEFGHJKLMNPQRSTVWXYZ";
+const LINK_CODE_PBKDF2_ROUNDS: u32 = 2 << 16;
+const WRAPPED_KEY_LENGTH: usize = 80;
+
+/// [`PAIR_CODE_TIMEOUT`] is how long a pairing code can be used after it was requested.
+pub const PAIR_CODE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// [`PairClientType`] is the type of client shown on the phone when entering the pairing code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PairClientType {
+    Unknown = 0,
+    Chrome,
+    Edge,
+    Firefox,
+    IE,
+    Opera,
+    Safari,
+    Electron,
+    UWP,
+    OtherWebClient,
+}
+
+#[derive(Error, Debug)]
+pub enum PairCodeError {
+    #[error("phone number too short")]
+    PhoneNumberTooShort,
+    #[error("international phone number required (must not start with 0)")]
+    PhoneNumberNotInternational,
+    #[error("missing <{0}> in pairing code response")]
+    MissingNode(&'static str),
+    #[error("pairing code notification is for a different pairing request")]
+    RefMismatch,
+    #[error("pairing code has expired")]
+    Expired,
+    #[error("wrong pairing code entered on the phone")]
+    WrongCode,
+    #[error("invalid wrapped primary ephemeral key")]
+    InvalidWrappedKey,
+    #[error("failed to encrypt key bundle")]
+    EncryptFailed,
+    #[error(transparent)]
+    Signal(#[from] SignalProtocolError),
+    #[error(transparent)]
+    Pair(PairError),
+}
+
+impl From<PairError> for PairCodeError {
+    /// With pairing codes, the adv secret used to authenticate the pair success is derived
+    /// from the code, so an invalid HMAC means the code that was entered on the phone was
+    /// wrong.
+    fn from(err: PairError) -> Self {
+        match err {
+            PairError::InvalidDeviceIdentityHmac => PairCodeError::WrongCode,
+            err => PairCodeError::Pair(err),
+        }
+    }
+}
+
+fn encode_linking_code(data: &[u8; 5]) -> String {
+    let bits = data
+        .iter()
+        .fold(0u64, |bits, &byte| bits << 8 | byte as u64);
+    (0..8)
+        .rev()
+        .map(|index| LINKING_BASE32_ALPHABET[(bits >> (index * 5)) as usize & 0x1f] as char)
+        .collect()
+}
+
+fn link_code_key(linking_code: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        linking_code.as_bytes(),
+        salt,
+        LINK_CODE_PBKDF2_ROUNDS,
+        &mut key,
+    );
+    key
+}
+
+/// Encrypts the given public key with the pairing code, returning `salt || iv || ciphertext`.
+fn wrap_ephemeral_key(linking_code: &str, public_key: &[u8]) -> Vec<u8> {
+    let salt: [u8; 32] = OsRng.gen();
+    let iv: [u8; 16] = OsRng.gen();
+    let mut encrypted = public_key.to_vec();
+    Aes256Ctr::new(&link_code_key(linking_code, &salt).into(), &iv.into())
+        .apply_keystream(&mut encrypted);
+    [&salt[..], &iv, &encrypted].concat()
+}
+
+/// Decrypts a public key that was wrapped with [`wrap_ephemeral_key`].
+fn unwrap_ephemeral_key(linking_code: &str, wrapped: &[u8]) -> Result<[u8; 32], PairCodeError> {
+    if wrapped.len() != WRAPPED_KEY_LENGTH {
+        return Err(PairCodeError::InvalidWrappedKey);
+    }
+    let (salt, rest) = wrapped.split_at(32);
+    let (iv, encrypted) = rest.split_at(16);
+    let mut decrypted = [0u8; 32];
+    decrypted.copy_from_slice(encrypted);
+    Aes256Ctr::new(&link_code_key(linking_code, salt).into(), iv.into())
+        .apply_keystream(&mut decrypted);
+    Ok(decrypted)
+}
+
+fn hkdf_sha256(secret: &[u8], salt: Option<&[u8]>, info: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(salt, secret)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256 output");
+    key
+}
+
+fn child_bytes<'a>(node: &'a Node, tag: &'static str) -> Result<&'a [u8], PairCodeError> {
+    node.get_optional_child_by_tag(&[tag])
+        .and_then(|child| match &child.content {
+            NodeContent::Bytes(bytes) => Some(bytes.as_slice()),
+            NodeContent::String(string) => Some(string.as_bytes()),
+            _ => None,
+        })
+        .ok_or(PairCodeError::MissingNode(tag))
+}
+
+/// [`PhoneLinking`] is a pending pairing with a phone number and pairing code, as an
+/// alternative to scanning a QR code.
+///
+/// The flow is:
+/// 1. [`PhoneLinking::start`] builds the `companion_hello` request, which must be sent in a
+///    `md` set iq to the server.
+/// 2. [`PhoneLinking::handle_hello_response`] returns the code to show to the user, who
+///    enters it on their phone.
+/// 3. The phone then sends a `link_code_companion_reg` notification, which is handled by
+///    [`PhoneLinking::handle_notification`]. The returned `companion_finish` request must be
+///    sent in another `md` set iq.
+/// 4. The server sends `pair-success`, which is handled by [`crate::pair::handle_pair_success`]
+///    like with QR pairing. Errors from that can be converted into [`PairCodeError`] to find
+///    out if the wrong code was entered.
+///
+/// The keys are exchanged as raw node content in the `salt || iv || ciphertext` layout that
+/// the server and phone expect, not as the `CompanionEphemeralIdentity` and
+/// `EncryptedPairingRequest` messages from `WAWebProtobufsCompanionReg.proto`. Those messages
+/// belong to a different linking flow and aren't accepted in `link_code_companion_reg`.
+pub struct PhoneLinking {
+    jid: JID,
+    key_pair: KeyPair,
+    linking_code: String,
+    pairing_ref: Option<Vec<u8>>,
+    started_at: Instant,
+}
+
+impl PhoneLinking {
+    /// Starts pairing with the given phone number (in international format, non-digits are
+    /// ignored). Returns the pending pairing and the `link_code_companion_reg` node to send.
+    pub fn start(
+        phone: &str,
+        device: &Device,
+        show_push_notification: bool,
+        client_type: PairClientType,
+        client_display_name: &str,
+    ) -> Result<(Self, Node), PairCodeError> {
+        let phone = phone
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect::<String>();
+        if phone.len() <= 6 {
+            return Err(PairCodeError::PhoneNumberTooShort);
+        }
+        if phone.starts_with('0') {
+            return Err(PairCodeError::PhoneNumberNotInternational);
+        }
+        let jid = JID::new(phone, DEFAULT_USER_SERVER.to_string());
+
+        let key_pair = KeyPair::generate(&mut OsRng);
+        let linking_code = encode_linking_code(&OsRng.gen());
+        let wrapped_ephemeral_key =
+            wrap_ephemeral_key(&linking_code, key_pair.public_key.public_key_bytes());
+
+        let request = Node::new(
+            "link_code_companion_reg",
+            Attrs::from([
+                ("jid".to_string(), AttrValue::JID(jid.clone())),
+                ("stage".to_string(), AttrValue::from("companion_hello")),
+                (
+                    "should_show_push_notification".to_string(),
+                    AttrValue::from(show_push_notification.to_string()),
+                ),
+            ]),
+            NodeContent::Nodes(vec![
+                bytes_node(
+                    "link_code_pairing_wrapped_companion_ephemeral_pub",
+                    wrapped_ephemeral_key,
+                ),
+                bytes_node(
+                    "companion_server_auth_key_pub",
+                    device.noise_key.public_key.public_key_bytes(),
+                ),
+                bytes_node(
+                    "companion_platform_id",
+                    (client_type as u8).to_string().into_bytes(),
+                ),
+                bytes_node("companion_platform_display", client_display_name.as_bytes()),
+                bytes_node("link_code_pairing_nonce", [0]),
+            ]),
+        );
+
+        Ok((
+            PhoneLinking {
+                jid,
+                key_pair,
+                linking_code,
+                pairing_ref: None,
+                started_at: Instant::now(),
+            },
+            request,
+        ))
+    }
+
+    fn check_expired(&self) -> Result<(), PairCodeError> {
+        if self.started_at.elapsed() > PAIR_CODE_TIMEOUT {
+            return Err(PairCodeError::Expired);
+        }
+        Ok(())
+    }
+
+    /// Handles the response to the `companion_hello` request and returns the pairing code to
+    /// show to the user, formatted as `XXXX-XXXX`.
+    pub fn handle_hello_response(&mut self, response: &Node) -> Result<String, PairCodeError> {
+        let registration = response
+            .get_optional_child_by_tag(&["link_code_companion_reg"])
+            .ok_or(PairCodeError::MissingNode("link_code_companion_reg"))?;
+        self.pairing_ref = Some(child_bytes(registration, "link_code_pairing_ref")?.to_vec());
+        Ok(format!(
+            "{}-{}",
+            &self.linking_code[..4],
+            &self.linking_code[4..]
+        ))
+    }
+
+    /// Handles the `link_code_companion_reg` notification the phone sends after the code was
+    /// entered. This derives the new adv secret of the device and returns the
+    /// `companion_finish` node to send to the server.
+    pub fn handle_notification(
+        &self,
+        node: &Node,
+        device: &mut Device,
+    ) -> Result<Node, PairCodeError> {
+        self.check_expired()?;
+        let registration = if node.tag == "link_code_companion_reg" {
+            node
+        } else {
+            node.get_optional_child_by_tag(&["link_code_companion_reg"])
+                .ok_or(PairCodeError::MissingNode("link_code_companion_reg"))?
+        };
+
+        let pairing_ref = child_bytes(registration, "link_code_pairing_ref")?;
+        if self.pairing_ref.as_deref() != Some(pairing_ref) {
+            return Err(PairCodeError::RefMismatch);
+        }
+        let wrapped_primary_ephemeral = child_bytes(
+            registration,
+            "link_code_pairing_wrapped_primary_ephemeral_pub",
+        )?;
+        let primary_identity = child_bytes(registration, "primary_identity_pub")?;
+
+        let primary_ephemeral = PublicKey::from_djb_public_key_bytes(&unwrap_ephemeral_key(
+            &self.linking_code,
+            wrapped_primary_ephemeral,
+        )?)?;
+        let ephemeral_shared_secret = self.key_pair.calculate_agreement(&primary_ephemeral)?;
+
+        let adv_secret_random: [u8; 32] = OsRng.gen();
+        let key_bundle_salt: [u8; 32] = OsRng.gen();
+        let key_bundle_nonce: [u8; 12] = OsRng.gen();
+
+        let key_bundle_key = hkdf_sha256(
+            &ephemeral_shared_secret,
+            Some(&key_bundle_salt),
+            b"link_code_pairing_key_bundle_encryption_key",
+        );
+        let plaintext_key_bundle = [
+            device.identity_key.public_key.public_key_bytes(),
+            primary_identity,
+            &adv_secret_random,
+        ]
+        .concat();
+        let encrypted_key_bundle = Aes256Gcm::new(&key_bundle_key.into())
+            .encrypt(
+                Nonce::from_slice(&key_bundle_nonce),
+                plaintext_key_bundle.as_slice(),
+            )
+            .map_err(|_| PairCodeError::EncryptFailed)?;
+        let wrapped_key_bundle = [
+            &key_bundle_salt[..],
+            &key_bundle_nonce,
+            &encrypted_key_bundle,
+        ]
+        .concat();
+
+        let primary_identity = PublicKey::from_djb_public_key_bytes(primary_identity)?;
+        let identity_shared_secret = device.identity_key.calculate_agreement(&primary_identity)?;
+        let adv_secret_input = [
+            &ephemeral_shared_secret[..],
+            &identity_shared_secret,
+            &adv_secret_random,
+        ]
+        .concat();
+        device.adv_secret_key = hkdf_sha256(&adv_secret_input, None, b"adv_secret");
+
+        Ok(Node::new(
+            "link_code_companion_reg",
+            Attrs::from([
+                ("jid".to_string(), AttrValue::JID(self.jid.clone())),
+                ("stage".to_string(), AttrValue::from("companion_finish")),
+            ]),
+            NodeContent::Nodes(vec![
+                bytes_node("link_code_pairing_wrapped_key_bundle", wrapped_key_bundle),
+                bytes_node(
+                    "companion_identity_public",
+                    device.identity_key.public_key.public_key_bytes(),
+                ),
+                bytes_node("link_code_pairing_ref", pairing_ref),
+            ]),
+        ))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use wa_store::memory::MemoryStore;
+
+    use crate::pair::{handle_pair_success, tests::pair_success_node};
+
+    /// The phone side of the pairing code flow, returning the notification to send to the
+    /// companion along with the phone's keys.
+    pub(crate) fn phone_notification(code: &str, pairing_ref: &[u8]) -> (Node, KeyPair, KeyPair) {
+        let ephemeral = KeyPair::generate(&mut OsRng);
+        let identity = KeyPair::generate(&mut OsRng);
+        let code = code.replace('-', "");
+        let node = Node::new(
+            "link_code_companion_reg",
+            Attrs::from([("stage".to_string(), AttrValue::from("primary_hello"))]),
+            NodeContent::Nodes(vec![
+                bytes_node("link_code_pairing_ref", pairing_ref),
+                bytes_node(
+                    "link_code_pairing_wrapped_primary_ephemeral_pub",
+                    wrap_ephemeral_key(&code, ephemeral.public_key.public_key_bytes()),
+                ),
+                bytes_node(
+                    "primary_identity_pub",
+                    identity.public_key.public_key_bytes(),
+                ),
+            ]),
+        );
+        (node, ephemeral, identity)
+    }
+
+    /// Derives the adv secret the way the phone does after receiving `companion_finish`, or
+    /// returns `None` if the phone can't decrypt the key bundle.
+    pub(crate) fn phone_adv_secret(
+        code: &str,
+        hello: &Node,
+        finish: &Node,
+        ephemeral: &KeyPair,
+        identity: &KeyPair,
+    ) -> Option<[u8; 32]> {
+        let code = code.replace('-', "");
+        let companion_ephemeral = unwrap_ephemeral_key(
+            &code,
+            child_bytes(hello, "link_code_pairing_wrapped_companion_ephemeral_pub").unwrap(),
+        )
+        .unwrap();
+        let ephemeral_shared_secret = ephemeral
+            .calculate_agreement(
+                &PublicKey::from_djb_public_key_bytes(&companion_ephemeral).unwrap(),
+            )
+            .unwrap();
+
+        let wrapped = child_bytes(finish, "link_code_pairing_wrapped_key_bundle").unwrap();
+        let key = hkdf_sha256(
+            &ephemeral_shared_secret,
+            Some(&wrapped[..32]),
+            b"link_code_pairing_key_bundle_encryption_key",
+        );
+        let bundle = Aes256Gcm::new(&key.into())
+            .decrypt(Nonce::from_slice(&wrapped[32..44]), &wrapped[44..])
+            .ok()?;
+        let companion_identity = PublicKey::from_djb_public_key_bytes(&bundle[..32]).unwrap();
+        assert_eq!(&bundle[32..64], identity.public_key.public_key_bytes());
+
+        let identity_shared_secret = identity.calculate_agreement(&companion_identity).unwrap();
+        let input = [
+            &ephemeral_shared_secret[..],
+            &identity_shared_secret,
+            &bundle[64..],
+        ]
+        .concat();
+        Some(hkdf_sha256(&input, None, b"adv_secret"))
+    }
+
+    fn hello_response(pairing_ref: &[u8]) -> Node {
+        Node::new(
+            "iq",
+            Attrs::new(),
+            NodeContent::Nodes(vec![Node::new(
+                "link_code_companion_reg",
+                Attrs::new(),
+                NodeContent::Nodes(vec![bytes_node("link_code_pairing_ref", pairing_ref)]),
+            )]),
+        )
+    }
+
+    #[test]
+    fn linking_code_encoding() {
+        assert_eq!(encode_linking_code(&[0; 5]), "11111111");
+        assert_eq!(encode_linking_code(&[0xff; 5]), "ZZZZZZZZ");
+        assert!(matches!(
+            PhoneLinking::start(
+                "+0 123 4567",
+                &Device::generate().unwrap(),
+                true,
+                PairClientType::Chrome,
+                "Chrome (Linux)"
+            ),
+            Err(PairCodeError::PhoneNumberNotInternational)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pairing_code_flow() {
+        let store = MemoryStore::new();
+        let mut device = Device::generate().unwrap();
+        let (mut linking, hello) = PhoneLinking::start(
+            "+1 (555) 123-4567",
+            &device,
+            true,
+            PairClientType::Chrome,
+            "Chrome (Linux)",
+        )
+        .unwrap();
+        assert_eq!(
+            hello.attrs["jid"],
+            AttrValue::JID(JID::new(
+                "15551234567".to_string(),
+                DEFAULT_USER_SERVER.to_string()
+            ))
+        );
+
+        let code = linking
+            .handle_hello_response(&hello_response(b"ref"))
+            .unwrap();
+        assert_eq!(code.len(), 9);
+
+        let (notification, ephemeral, identity) = phone_notification(&code, b"ref");
+        let finish = linking
+            .handle_notification(&notification, &mut device)
+            .unwrap();
+        let adv_secret = phone_adv_secret(&code, &hello, &finish, &ephemeral, &identity).unwrap();
+        assert_eq!(adv_secret, device.adv_secret_key);
+
+        let success = handle_pair_success(
+            &pair_success_node(&device, &adv_secret),
+            &mut device,
+            &store,
+        )
+        .await;
+        assert!(success.result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn wrong_code_and_expiry() {
+        let store = MemoryStore::new();
+        let mut device = Device::generate().unwrap();
+        let (mut linking, hello) = PhoneLinking::start(
+            "15551234567",
+            &device,
+            false,
+            PairClientType::Firefox,
+            "Bot",
+        )
+        .unwrap();
+        let code = linking
+            .handle_hello_response(&hello_response(b"ref"))
+            .unwrap();
+        let wrong_code = if code.starts_with('1') {
+            code.replacen('1', "2", 1)
+        } else {
+            format!("1{}", &code[1..])
+        };
+
+        assert!(matches!(
+            linking.handle_notification(&phone_notification(&code, b"other").0, &mut device),
+            Err(PairCodeError::RefMismatch)
+        ));
+
+        // The companion can't tell that the phone used the wrong code until pairing fails.
+        let (notification, ephemeral, identity) = phone_notification(&wrong_code, b"ref");
+        let finish = linking
+            .handle_notification(&notification, &mut device)
+            .unwrap();
+        assert_eq!(
+            phone_adv_secret(&wrong_code, &hello, &finish, &ephemeral, &identity),
+            None
+        );
+        // Without the key bundle, the phone's adv secret can only be derived from the
+        // agreements, which differ from the companion's.
+        let phone_ephemeral = unwrap_ephemeral_key(
+            &wrong_code.replace('-', ""),
+            child_bytes(&hello, "link_code_pairing_wrapped_companion_ephemeral_pub").unwrap(),
+        )
+        .unwrap();
+        let phone_secret = hkdf_sha256(
+            &ephemeral
+                .calculate_agreement(
+                    &PublicKey::from_djb_public_key_bytes(&phone_ephemeral).unwrap(),
+                )
+                .unwrap(),
+            None,
+            b"adv_secret",
+        );
+        let response = handle_pair_success(
+            &pair_success_node(&device, &phone_secret),
+            &mut device,
+            &store,
+        )
+        .await;
+        assert!(matches!(
+            response.result.map_err(PairCodeError::from),
+            Err(PairCodeError::WrongCode)
+        ));
+
+        linking.started_at = Instant::now() - PAIR_CODE_TIMEOUT - Duration::from_secs(1);
+        assert!(matches!(
+            linking.handle_notification(&notification, &mut device),
+            Err(PairCodeError::Expired)
+        ));
+    }
+}
@@ -0,0 +1,401 @@
+use libsignal_protocol::{DeviceId, IdentityKey, PreKeyBundle, PublicKey, SignalProtocolError};
+use thiserror::Error;
+use wa_binary::{
+    attrs::AttrErrors,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_store::{Device, PreKey, PreKeyStore, StoreError};
+use wa_types::jid::JID;
+
+use crate::{
+    client_payload::DJB_KEY_TYPE,
+    request::{bytes_node, InfoQuery, IqType, RequestError, RequestSender},
+};
+
+/// [`WANTED_PRE_KEY_COUNT`] is the default number of pre-keys to upload in one batch.
+pub const WANTED_PRE_KEY_COUNT: u32 = 50;
+/// [`MIN_PRE_KEY_COUNT`] is the default server-side count below which more pre-keys are
+/// uploaded.
+pub const MIN_PRE_KEY_COUNT: u32 = 5;
+
+#[derive(Error, Debug)]
+pub enum PreKeyError {
+    #[error("missing <{0}> in pre-key response")]
+    MissingNode(&'static str),
+    #[error("invalid attributes in pre-key response: {0}")]
+    Attrs(#[from] AttrErrors),
+    #[error("invalid {0} in pre-key bundle")]
+    InvalidBundle(&'static str),
+    #[error("server returned error {code} for pre-key bundle: {text}")]
+    Server { code: u16, text: String },
+    #[error("failed to get pre-keys: {0}")]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Signal(#[from] SignalProtocolError),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+}
+
+/// [`PreKeyConfig`] decides when and how many pre-keys are uploaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PreKeyConfig {
+    /// More pre-keys are uploaded when the server has fewer than this many left.
+    pub threshold: u32,
+    /// How many pre-keys to upload at once.
+    pub batch_size: u32,
+}
+
+impl Default for PreKeyConfig {
+    fn default() -> Self {
+        PreKeyConfig {
+            threshold: MIN_PRE_KEY_COUNT,
+            batch_size: WANTED_PRE_KEY_COUNT,
+        }
+    }
+}
+
+impl PreKeyConfig {
+    /// Returns true if the given server count is low enough to upload more pre-keys.
+    pub fn needs_upload(&self, server_count: u32) -> bool {
+        server_count < self.threshold
+    }
+}
+
+/// Encodes a pre-key ID as the 3 big-endian bytes the server expects.
+fn key_id_bytes(key_id: u32) -> Vec<u8> {
+    key_id.to_be_bytes()[1..].to_vec()
+}
+
+pub(crate) fn pre_key_node(tag: &str, pre_key: &PreKey) -> Node {
+    let mut content = vec![
+        bytes_node("id", key_id_bytes(pre_key.key_id)),
+        bytes_node("value", pre_key.key_pair.public_key.public_key_bytes()),
+    ];
+    if let Some(signature) = &pre_key.signature {
+        content.push(bytes_node("signature", signature.as_slice()));
+    }
+    Node::new(tag, Attrs::new(), NodeContent::Nodes(content))
+}
+
+/// [`pre_key_count_query`] builds the request for the number of pre-keys the server still has
+/// for this device, which should be sent after connecting.
+pub fn pre_key_count_query() -> InfoQuery {
+    InfoQuery::new(
+        "encrypt",
+        IqType::Get,
+        NodeContent::Nodes(vec![Node::new("count", Attrs::new(), NodeContent::None)]),
+    )
+}
+
+/// [`parse_pre_key_count`] returns the count from the response to [`pre_key_count_query`].
+pub fn parse_pre_key_count(response: &Node) -> Result<u32, PreKeyError> {
+    let count = response
+        .get_optional_child_by_tag(&["count"])
+        .ok_or(PreKeyError::MissingNode("count"))?;
+    let mut ag = count.attr_getter();
+    let value = ag.u64("value");
+    ag.into_result()?;
+    Ok(value as u32)
+}
+
+/// [`PreKeyUpload`] is a batch of pre-keys to upload. Once the server has acknowledged
+/// [`PreKeyUpload::query`], [`PreKeyUpload::mark_uploaded`] must be called so the keys aren't
+/// uploaded again.
+#[derive(Clone, Debug)]
+pub struct PreKeyUpload {
+    pub query: InfoQuery,
+    last_key_id: Option<u32>,
+}
+
+impl PreKeyUpload {
+    /// Gets or generates a batch of pre-keys from the store and builds the upload request,
+    /// which also contains the identity and signed pre-key of the device.
+    pub async fn prepare(
+        device: &Device,
+        store: &dyn PreKeyStore,
+        config: &PreKeyConfig,
+    ) -> Result<Self, PreKeyError> {
+        let pre_keys = store.get_or_gen_pre_keys(config.batch_size).await?;
+        let last_key_id = pre_keys.iter().map(|pre_key| pre_key.key_id).max();
+        let query = InfoQuery::new(
+            "encrypt",
+            IqType::Set,
+            NodeContent::Nodes(vec![
+                bytes_node("registration", device.registration_id.to_be_bytes()),
+                bytes_node("type", [DJB_KEY_TYPE]),
+                bytes_node(
+                    "identity",
+                    device.identity_key.public_key.public_key_bytes(),
+                ),
+                Node::new(
+                    "list",
+                    Attrs::new(),
+                    NodeContent::Nodes(
+                        pre_keys
+                            .iter()
+                            .map(|pre_key| pre_key_node("key", pre_key))
+                            .collect(),
+                    ),
+                ),
+                pre_key_node("skey", &device.signed_pre_key),
+            ]),
+        );
+        Ok(PreKeyUpload { query, last_key_id })
+    }
+
+    /// Marks the uploaded pre-keys in the store.
+    pub async fn mark_uploaded(&self, store: &dyn PreKeyStore) -> Result<(), StoreError> {
+        match self.last_key_id {
+            Some(last_key_id) => store.mark_pre_keys_as_uploaded(last_key_id).await,
+            None => Ok(()),
+        }
+    }
+}
+
+fn child_bytes<'a>(node: &'a Node, tags: &[&'static str]) -> Result<&'a [u8], PreKeyError> {
+    node.get_optional_child_by_tag(tags)
+        .and_then(Node::content_bytes)
+        .ok_or(PreKeyError::MissingNode(tags[tags.len() - 1]))
+}
+
+/// Decodes the 3 big-endian bytes of a pre-key ID.
+fn parse_key_id(bytes: &[u8]) -> Result<u32, PreKeyError> {
+    if bytes.len() != 3 {
+        return Err(PreKeyError::InvalidBundle("key ID"));
+    }
+    Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+}
+
+fn parse_public_key(bytes: &[u8], what: &'static str) -> Result<PublicKey, PreKeyError> {
+    PublicKey::from_djb_public_key_bytes(bytes).map_err(|_| PreKeyError::InvalidBundle(what))
+}
+
+/// Parses a pre-key bundle of the given device. The registration ID is a child of `parent`,
+/// and the keys are children of `keys`, which is the same node in bundle responses but not in
+/// retry receipts.
+pub(crate) fn parse_pre_key_bundle(
+    parent: &Node,
+    keys: &Node,
+    jid: &JID,
+) -> Result<PreKeyBundle, PreKeyError> {
+    let registration_id: [u8; 4] = child_bytes(parent, &["registration"])?
+        .try_into()
+        .map_err(|_| PreKeyError::InvalidBundle("registration ID"))?;
+    let identity = parse_public_key(child_bytes(keys, &["identity"])?, "identity key")?;
+    let pre_key = match keys.get_optional_child_by_tag(&["key"]) {
+        Some(key) => Some((
+            parse_key_id(child_bytes(key, &["id"])?)?.into(),
+            parse_public_key(child_bytes(key, &["value"])?, "pre-key")?,
+        )),
+        None => None,
+    };
+    let signed_pre_key = keys
+        .get_optional_child_by_tag(&["skey"])
+        .ok_or(PreKeyError::MissingNode("skey"))?;
+    Ok(PreKeyBundle::new(
+        u32::from_be_bytes(registration_id),
+        DeviceId::from(jid.device as u32),
+        pre_key,
+        parse_key_id(child_bytes(signed_pre_key, &["id"])?)?.into(),
+        parse_public_key(child_bytes(signed_pre_key, &["value"])?, "signed pre-key")?,
+        child_bytes(signed_pre_key, &["signature"])?.to_vec(),
+        IdentityKey::new(identity),
+    )?)
+}
+
+/// [`PreKeyBundles`] are the bundles of each requested device, or the reason there isn't one.
+pub type PreKeyBundles = Vec<(JID, Result<PreKeyBundle, PreKeyError>)>;
+
+/// [`pre_key_bundle_query`] builds the request for the pre-key bundles of the given devices,
+/// which are needed to start signal sessions with them.
+pub fn pre_key_bundle_query(devices: &[JID]) -> InfoQuery {
+    InfoQuery::new(
+        "encrypt",
+        IqType::Get,
+        NodeContent::Nodes(vec![Node::new(
+            "key",
+            Attrs::new(),
+            NodeContent::Nodes(
+                devices
+                    .iter()
+                    .map(|device| {
+                        Node::new(
+                            "user",
+                            Attrs::from([
+                                ("jid".to_string(), AttrValue::JID(device.clone())),
+                                ("reason".to_string(), AttrValue::from("identity")),
+                            ]),
+                            NodeContent::None,
+                        )
+                    })
+                    .collect(),
+            ),
+        )]),
+    )
+}
+
+/// [`parse_pre_key_bundles`] returns the bundle of each device in the response to
+/// [`pre_key_bundle_query`]. Devices the server couldn't return a bundle for have an error
+/// instead.
+pub fn parse_pre_key_bundles(response: &Node) -> Result<PreKeyBundles, PreKeyError> {
+    let list = response
+        .get_optional_child_by_tag(&["list"])
+        .ok_or(PreKeyError::MissingNode("list"))?;
+    list.get_children_by_tag("user")
+        .map(|user| {
+            let mut ag = user.attr_getter();
+            let jid = ag.jid("jid");
+            ag.into_result()?;
+            let bundle = match user.get_optional_child_by_tag(&["error"]) {
+                Some(error) => {
+                    let mut ag = error.attr_getter();
+                    Err(PreKeyError::Server {
+                        code: ag.optional_u64("code").unwrap_or_default() as u16,
+                        text: ag.optional_string("text").unwrap_or_default().to_string(),
+                    })
+                }
+                None => parse_pre_key_bundle(user, user, &jid),
+            };
+            Ok((jid, bundle))
+        })
+        .collect()
+}
+
+/// [`fetch_pre_key_bundles`] fetches the pre-key bundles of the given devices.
+pub async fn fetch_pre_key_bundles(
+    sender: &dyn RequestSender,
+    devices: &[JID],
+) -> Result<PreKeyBundles, PreKeyError> {
+    parse_pre_key_bundles(&sender.send_iq(pre_key_bundle_query(devices)).await?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use wa_store::memory::MemoryStore;
+
+    use super::*;
+
+    /// Builds the `<user>` node the server returns in a pre-key bundle response.
+    pub(crate) fn bundle_node(jid: &JID, device: &Device, pre_key: &PreKey) -> Node {
+        Node::new(
+            "user",
+            Attrs::from([("jid".to_string(), AttrValue::JID(jid.clone()))]),
+            NodeContent::Nodes(vec![
+                bytes_node("registration", device.registration_id.to_be_bytes()),
+                bytes_node("type", [DJB_KEY_TYPE]),
+                bytes_node(
+                    "identity",
+                    device.identity_key.public_key.public_key_bytes(),
+                ),
+                pre_key_node("key", pre_key),
+                pre_key_node("skey", &device.signed_pre_key),
+            ]),
+        )
+    }
+
+    #[tokio::test]
+    async fn parses_bundles() {
+        let device = Device::generate().unwrap();
+        let pre_key = MemoryStore::new().gen_one_pre_key().await.unwrap();
+        let jid = JID::new_ad_jid("1234".to_string(), 0, 2);
+        let failed = JID::new_ad_jid("1234".to_string(), 0, 3);
+        let response = Node::new(
+            "iq",
+            Attrs::new(),
+            NodeContent::Nodes(vec![Node::new(
+                "list",
+                Attrs::new(),
+                NodeContent::Nodes(vec![
+                    bundle_node(&jid, &device, &pre_key),
+                    Node::new(
+                        "user",
+                        Attrs::from([("jid".to_string(), AttrValue::JID(failed.clone()))]),
+                        NodeContent::Nodes(vec![Node::new(
+                            "error",
+                            Attrs::from([
+                                ("code".to_string(), AttrValue::from("404")),
+                                ("text".to_string(), AttrValue::from("item-not-found")),
+                            ]),
+                            NodeContent::None,
+                        )]),
+                    ),
+                ]),
+            )]),
+        );
+
+        let bundles = parse_pre_key_bundles(&response).unwrap();
+        let bundle = bundles[0].1.as_ref().unwrap();
+        assert_eq!(bundles[0].0, jid);
+        assert_eq!(bundle.registration_id().unwrap(), device.registration_id);
+        assert_eq!(u32::from(bundle.device_id().unwrap()), 2);
+        assert_eq!(
+            bundle.identity_key().unwrap().public_key(),
+            &device.identity_key.public_key
+        );
+        assert_eq!(bundles[1].0, failed);
+        assert!(matches!(
+            bundles[1].1,
+            Err(PreKeyError::Server { code: 404, .. })
+        ));
+    }
+
+    #[test]
+    fn parses_count() {
+        let response = Node::new(
+            "iq",
+            Attrs::new(),
+            NodeContent::Nodes(vec![Node::new(
+                "count",
+                Attrs::from([("value".to_string(), AttrValue::from("3"))]),
+                NodeContent::None,
+            )]),
+        );
+        let count = parse_pre_key_count(&response).unwrap();
+        assert_eq!(count, 3);
+        assert!(PreKeyConfig::default().needs_upload(count));
+        assert!(!PreKeyConfig::default().needs_upload(MIN_PRE_KEY_COUNT));
+    }
+
+    #[tokio::test]
+    async fn upload_batch_is_marked_uploaded() {
+        let store = MemoryStore::new();
+        let device = Device::generate().unwrap();
+        let config = PreKeyConfig {
+            threshold: 5,
+            batch_size: 3,
+        };
+
+        let upload = PreKeyUpload::prepare(&device, &store, &config)
+            .await
+            .unwrap();
+        let node = upload.query.clone().into_node("1");
+        assert_eq!(node.attrs["xmlns"], AttrValue::from("encrypt"));
+        let keys = node
+            .get_optional_child_by_tag(&["list"])
+            .unwrap()
+            .get_children_by_tag("key")
+            .map(|key| {
+                key.get_optional_child_by_tag(&["id"])
+                    .unwrap()
+                    .content_bytes()
+                    .unwrap()
+                    .to_vec()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [[0, 0, 1], [0, 0, 2], [0, 0, 3]]);
+        let skey = node.get_optional_child_by_tag(&["skey"]).unwrap();
+        assert_eq!(
+            skey.get_optional_child_by_tag(&["signature"])
+                .unwrap()
+                .content_bytes(),
+            device.signed_pre_key.signature.as_deref()
+        );
+
+        upload.mark_uploaded(&store).await.unwrap();
+        assert_eq!(store.uploaded_pre_key_count().await.unwrap(), 3);
+        let next = PreKeyUpload::prepare(&device, &store, &config)
+            .await
+            .unwrap();
+        assert_eq!(next.last_key_id, Some(6));
+    }
+}
@@ -0,0 +1,454 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+use time::OffsetDateTime;
+use wa_binary::{
+    attrs::AttrErrors,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_store::Device;
+use wa_types::{
+    events::Receipt,
+    jid::{DEFAULT_USER_SERVER, HIDDEN_USER_SERVER, MESSENGER_SERVER, NEWSLETTER_SERVER},
+    message::{MessageID, MessageInfo, MessageSource},
+    presence::ReceiptType,
+    user::PrivacySettingReadReceipts,
+};
+
+use crate::{
+    receive::{parse_message_source, ReceiveError},
+    request::{RequestError, RequestSender},
+};
+
+#[derive(Error, Debug)]
+pub enum ReceiptError {
+    #[error("no message IDs to send a receipt for")]
+    NoMessages,
+    #[error("invalid attributes in receipt: {0}")]
+    Attrs(#[from] AttrErrors),
+    #[error("failed to parse receipt source: {0}")]
+    Source(#[from] ReceiveError),
+    #[error("missing participant in group receipt")]
+    MissingParticipant,
+    #[error(transparent)]
+    Request(#[from] RequestError),
+}
+
+/// [`parse_receipt`] parses an incoming `<receipt>` stanza.
+///
+/// Receipts for several messages have the first ID in the `id` attribute and the rest in a
+/// `<list>` of `<item>`s, like the ones built by [`receipt_node`]. Group receipts without a
+/// `participant` are accepted with an empty [`MessageSource::sender`].
+pub fn parse_receipt(node: &Node, device: &Device) -> Result<Receipt, ReceiptError> {
+    let source = parse_message_source(node, device, false)?;
+    let mut ag = node.attr_getter();
+    let timestamp = ag.unix_time("t");
+    let receipt_type = ag.optional_string("type").unwrap_or_default();
+    let mut message_ids = vec![MessageID(ag.string("id").to_string())];
+    ag.into_result()?;
+    if let Some(list) = node.get_optional_child_by_tag(&["list"]) {
+        for item in list.get_children_by_tag("item") {
+            let mut ag = item.attr_getter();
+            message_ids.push(MessageID(ag.string("id").to_string()));
+            ag.into_result()?;
+        }
+    }
+    Ok(Receipt {
+        source,
+        message_ids,
+        timestamp,
+        r#type: ReceiptType::from_str(receipt_type).expect("unknown types use the default variant"),
+    })
+}
+
+/// [`receipt_node`] builds a receipt of the given type for messages in the same chat.
+///
+/// The first ID goes in the `id` attribute and the rest in a `<list>` of `<item>`s, so any
+/// number of messages can be marked at once. In groups and broadcast lists, the receipt is
+/// addressed to the sender of the messages as the `participant`, or to
+/// [`MessageSource::broadcast_list_owner`] if it's set. If neither is set, there's no one to
+/// address the receipt to and [`ReceiptError::MissingParticipant`] is returned.
+pub fn receipt_node(
+    ids: &[MessageID],
+    source: &MessageSource,
+    receipt_type: &ReceiptType,
+    timestamp: Option<OffsetDateTime>,
+) -> Result<Node, ReceiptError> {
+    let (first, rest) = ids.split_first().ok_or(ReceiptError::NoMessages)?;
+    let mut attrs = Attrs::from([
+        ("id".to_string(), AttrValue::from(first.0.as_str())),
+        ("to".to_string(), AttrValue::JID(source.chat.clone())),
+    ]);
+    let receipt_type = receipt_type.to_string();
+    if !receipt_type.is_empty() {
+        attrs.insert("type".to_string(), AttrValue::from(receipt_type));
+    }
+    if let Some(timestamp) = timestamp {
+        attrs.insert(
+            "t".to_string(),
+            AttrValue::from(timestamp.unix_timestamp().to_string()),
+        );
+    }
+    if source.chat.server != DEFAULT_USER_SERVER
+        && source.chat.server != MESSENGER_SERVER
+        && source.chat.server != HIDDEN_USER_SERVER
+    {
+        let participant = if source.broadcast_list_owner.is_empty() {
+            &source.sender
+        } else {
+            &source.broadcast_list_owner
+        };
+        if !participant.is_empty() {
+            attrs.insert(
+                "participant".to_string(),
+                AttrValue::JID(participant.clone().to_non_ad()),
+            );
+        } else if source.is_group {
+            return Err(ReceiptError::MissingParticipant);
+        }
+    }
+    let content = if rest.is_empty() {
+        NodeContent::None
+    } else {
+        NodeContent::Nodes(vec![Node::new(
+            "list",
+            Attrs::new(),
+            NodeContent::Nodes(
+                rest.iter()
+                    .map(|id| {
+                        Node::new(
+                            "item",
+                            Attrs::from([("id".to_string(), AttrValue::from(id.0.as_str()))]),
+                            NodeContent::None,
+                        )
+                    })
+                    .collect(),
+            ),
+        )])
+    };
+    Ok(Node::new("receipt", attrs, content))
+}
+
+/// Returns the `-self` variant of read and played receipts if others shouldn't be told.
+fn private_receipt_type(
+    receipt_type: ReceiptType,
+    source: &MessageSource,
+    read_receipts: &PrivacySettingReadReceipts,
+) -> ReceiptType {
+    if source.chat.server != NEWSLETTER_SERVER && *read_receipts != PrivacySettingReadReceipts::None
+    {
+        return receipt_type;
+    }
+    match receipt_type {
+        ReceiptType::Read => ReceiptType::ReadSelf,
+        ReceiptType::Played => ReceiptType::PlayedSelf,
+        receipt_type => receipt_type,
+    }
+}
+
+/// [`mark_read`] marks messages in the same chat as read.
+///
+/// `read_receipts` is the user's [`wa_types::user::PrivacySettings::read_receipts`]. If it's
+/// [`PrivacySettingReadReceipts::None`], a `read-self` receipt is sent instead, which only
+/// syncs the read status to our other devices.
+pub async fn mark_read(
+    sender: &dyn RequestSender,
+    ids: &[MessageID],
+    timestamp: OffsetDateTime,
+    source: &MessageSource,
+    read_receipts: &PrivacySettingReadReceipts,
+) -> Result<(), ReceiptError> {
+    let receipt_type = private_receipt_type(ReceiptType::Read, source, read_receipts);
+    let node = receipt_node(ids, source, &receipt_type, Some(timestamp))?;
+    Ok(sender.send_node(node).await?)
+}
+
+/// [`mark_played`] marks view-once media messages in the same chat as opened. Like
+/// [`mark_read`], it sends a `played-self` receipt if read receipts are disabled.
+pub async fn mark_played(
+    sender: &dyn RequestSender,
+    ids: &[MessageID],
+    timestamp: OffsetDateTime,
+    source: &MessageSource,
+    read_receipts: &PrivacySettingReadReceipts,
+) -> Result<(), ReceiptError> {
+    let receipt_type = private_receipt_type(ReceiptType::Played, source, read_receipts);
+    let node = receipt_node(ids, source, &receipt_type, Some(timestamp))?;
+    Ok(sender.send_node(node).await?)
+}
+
+/// [`delivery_receipt`] builds the receipt that tells the server a message was delivered to
+/// this device, which should be sent for every incoming message.
+///
+/// Messages sent by our own other devices get a [`ReceiptType::Sender`] receipt, which is
+/// addressed to the sending device with the chat as the `recipient`.
+pub fn delivery_receipt(info: &MessageInfo) -> Node {
+    let source = &info.source;
+    let receipt_type = if source.is_from_me {
+        ReceiptType::Sender
+    } else {
+        ReceiptType::Delivered
+    };
+    let mut node = receipt_node(std::slice::from_ref(&info.id), source, &receipt_type, None)
+        .expect("one message ID is always given");
+    if source.is_from_me && !source.is_group {
+        node.attrs
+            .insert("to".to_string(), AttrValue::JID(source.sender.clone()));
+        node.attrs
+            .insert("recipient".to_string(), AttrValue::JID(source.chat.clone()));
+    } else if source.is_group {
+        // The exact device that sent the message must get the delivery receipt.
+        node.attrs.insert(
+            "participant".to_string(),
+            AttrValue::JID(source.sender.clone()),
+        );
+    }
+    node
+}
+
+/// [`mark_delivered`] sends the delivery receipt of an incoming message.
+pub async fn mark_delivered(
+    sender: &dyn RequestSender,
+    info: &MessageInfo,
+) -> Result<(), ReceiptError> {
+    Ok(sender.send_node(delivery_receipt(info)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use wa_types::{
+        jid::{BROADCAST_SERVER, GROUP_SERVER, JID},
+        message::{EditAttribute, MessageServerID},
+    };
+
+    use super::*;
+
+    fn source(chat: JID, sender: JID, is_from_me: bool) -> MessageSource {
+        MessageSource {
+            is_group: chat.server == GROUP_SERVER || chat.server == BROADCAST_SERVER,
+            chat,
+            sender,
+            is_from_me,
+            broadcast_list_owner: JID::new(String::new(), String::new()),
+        }
+    }
+
+    fn info(source: MessageSource) -> MessageInfo {
+        MessageInfo {
+            source,
+            id: MessageID("ABCD".to_string()),
+            server_id: MessageServerID(String::new()),
+            r#type: "text".to_string(),
+            push_name: String::new(),
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            category: String::new(),
+            multicast: false,
+            media_type: String::new(),
+            edit: EditAttribute::Empty,
+            verified_name: None,
+            device_sent_meta: None,
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<MessageID> {
+        ids.iter().map(|id| MessageID(id.to_string())).collect()
+    }
+
+    #[test]
+    fn batches_ids_into_list() {
+        let bob = JID::new("2222".to_string(), DEFAULT_USER_SERVER.to_string());
+        let node = receipt_node(
+            &ids(&["A", "B", "C"]),
+            &source(bob.clone(), bob.clone(), false),
+            &ReceiptType::Read,
+            Some(OffsetDateTime::from_unix_timestamp(1700000000).unwrap()),
+        )
+        .unwrap();
+        assert_eq!(node.attrs["id"], AttrValue::from("A"));
+        assert_eq!(node.attrs["type"], AttrValue::from("read"));
+        assert_eq!(node.attrs["t"], AttrValue::from("1700000000"));
+        assert!(!node.attrs.contains_key("participant"));
+        let items = node
+            .get_optional_child_by_tag(&["list"])
+            .unwrap()
+            .get_children_by_tag("item")
+            .map(|item| item.attrs["id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(items, [AttrValue::from("B"), AttrValue::from("C")]);
+        assert!(matches!(
+            receipt_node(
+                &[],
+                &source(bob.clone(), bob, false),
+                &ReceiptType::Read,
+                None
+            ),
+            Err(ReceiptError::NoMessages)
+        ));
+    }
+
+    #[test]
+    fn addresses_group_and_broadcast_senders() {
+        let group = JID::new("1234-5678".to_string(), GROUP_SERVER.to_string());
+        let bob = JID::new_ad_jid("2222".to_string(), 0, 3);
+        let node = receipt_node(
+            &ids(&["A"]),
+            &source(group, bob.clone(), false),
+            &ReceiptType::Read,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            node.attrs["participant"],
+            AttrValue::JID(bob.clone().to_non_ad())
+        );
+
+        // Chats with a LID user are one-to-one chats too.
+        let bob_lid = JID::new_ad_jid("123456789".to_string(), 1, 3);
+        let node = receipt_node(
+            &ids(&["A"]),
+            &source(bob_lid.clone().to_non_ad(), bob_lid, false),
+            &ReceiptType::Read,
+            None,
+        )
+        .unwrap();
+        assert!(!node.attrs.contains_key("participant"));
+
+        let list = JID::new("1700000000".to_string(), BROADCAST_SERVER.to_string());
+        let mut source = source(list, JID::new_ad_jid("1111".to_string(), 0, 0), true);
+        source.broadcast_list_owner = bob.clone();
+        let node = receipt_node(&ids(&["A"]), &source, &ReceiptType::Read, None).unwrap();
+        assert_eq!(node.attrs["participant"], AttrValue::JID(bob.to_non_ad()));
+
+        source.broadcast_list_owner = JID::new(String::new(), String::new());
+        source.sender = JID::new(String::new(), String::new());
+        assert!(matches!(
+            receipt_node(&ids(&["A"]), &source, &ReceiptType::Read, None),
+            Err(ReceiptError::MissingParticipant)
+        ));
+    }
+
+    #[test]
+    fn disabled_read_receipts_only_sync_to_own_devices() {
+        let bob = JID::new("2222".to_string(), DEFAULT_USER_SERVER.to_string());
+        let source = source(bob.clone(), bob, false);
+        assert!(matches!(
+            private_receipt_type(
+                ReceiptType::Read,
+                &source,
+                &PrivacySettingReadReceipts::None
+            ),
+            ReceiptType::ReadSelf
+        ));
+        assert!(matches!(
+            private_receipt_type(
+                ReceiptType::Played,
+                &source,
+                &PrivacySettingReadReceipts::None
+            ),
+            ReceiptType::PlayedSelf
+        ));
+        assert!(matches!(
+            private_receipt_type(ReceiptType::Read, &source, &PrivacySettingReadReceipts::All),
+            ReceiptType::Read
+        ));
+    }
+
+    fn incoming(attrs: &[(&str, AttrValue)], content: NodeContent) -> Node {
+        let mut all_attrs = Attrs::from([
+            ("id".to_string(), AttrValue::from("A")),
+            ("t".to_string(), AttrValue::from("1700000000")),
+        ]);
+        for (key, value) in attrs {
+            all_attrs.insert(key.to_string(), value.clone());
+        }
+        Node::new("receipt", all_attrs, content)
+    }
+
+    #[test]
+    fn parses_batched_group_receipts() {
+        let mut device = Device::generate().unwrap();
+        device.id = Some(JID::new_ad_jid("1111".to_string(), 0, 0));
+        let group = JID::new("1234-5678".to_string(), GROUP_SERVER.to_string());
+        let bob = JID::new_ad_jid("2222".to_string(), 0, 3);
+        let built = receipt_node(
+            &ids(&["A", "B"]),
+            &source(group.clone(), bob.clone(), false),
+            &ReceiptType::Read,
+            None,
+        )
+        .unwrap();
+        let node = incoming(
+            &[
+                ("from", AttrValue::JID(group.clone())),
+                ("participant", AttrValue::JID(bob.clone())),
+                ("type", AttrValue::from("read")),
+            ],
+            built.content,
+        );
+        let receipt = parse_receipt(&node, &device).unwrap();
+        assert!(receipt.source.is_group);
+        assert_eq!(receipt.source.chat, group);
+        assert_eq!(receipt.source.sender, bob);
+        assert!(!receipt.source.is_from_me);
+        assert!(matches!(receipt.r#type, ReceiptType::Read));
+        assert_eq!(receipt.timestamp.unix_timestamp(), 1700000000);
+        let ids = receipt
+            .message_ids
+            .iter()
+            .map(|id| id.0.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["A", "B"]);
+
+        let node = incoming(&[("from", AttrValue::JID(group))], NodeContent::None);
+        let receipt = parse_receipt(&node, &device).unwrap();
+        assert!(receipt.source.is_group);
+        assert!(receipt.source.sender.is_empty());
+        assert!(!receipt.source.is_from_me);
+    }
+
+    #[test]
+    fn parses_receipt_types() {
+        let mut device = Device::generate().unwrap();
+        device.id = Some(JID::new_ad_jid("1111".to_string(), 0, 0));
+        let bob = JID::new_ad_jid("2222".to_string(), 0, 3);
+        let own_device = JID::new_ad_jid("1111".to_string(), 0, 2);
+        let parse = |from: &JID, receipt_type: Option<&str>| {
+            let mut attrs = vec![("from", AttrValue::JID(from.clone()))];
+            attrs.extend(receipt_type.map(|t| ("type", AttrValue::from(t))));
+            parse_receipt(&incoming(&attrs, NodeContent::None), &device).unwrap()
+        };
+
+        let delivered = parse(&bob, None);
+        assert!(matches!(delivered.r#type, ReceiptType::Delivered));
+        assert_eq!(delivered.source.chat, bob.clone().to_non_ad());
+        assert!(matches!(
+            parse(&own_device, Some("peer_msg")).r#type,
+            ReceiptType::PeerMsg
+        ));
+        let history_sync = parse(&own_device, Some("hist_sync"));
+        assert!(matches!(history_sync.r#type, ReceiptType::HistorySync));
+        assert!(history_sync.source.is_from_me);
+        assert!(matches!(
+            parse(&bob, Some("inactive")).r#type,
+            ReceiptType::Inactive
+        ));
+        assert!(matches!(
+            parse(&bob, Some("something")).r#type,
+            ReceiptType::UnknownVariant(ref t) if t == "something"
+        ));
+    }
+
+    #[test]
+    fn delivery_receipt_from_own_device() {
+        let bob = JID::new("2222".to_string(), DEFAULT_USER_SERVER.to_string());
+        let own_device = JID::new_ad_jid("1111".to_string(), 0, 2);
+        let node = delivery_receipt(&info(source(bob.clone(), own_device.clone(), true)));
+        assert_eq!(node.attrs["type"], AttrValue::from("sender"));
+        assert_eq!(node.attrs["to"], AttrValue::JID(own_device));
+        assert_eq!(node.attrs["recipient"], AttrValue::JID(bob.clone()));
+
+        let node = delivery_receipt(&info(source(bob.clone(), bob.clone(), false)));
+        assert!(!node.attrs.contains_key("type"));
+        assert_eq!(node.attrs["to"], AttrValue::JID(bob));
+    }
+}
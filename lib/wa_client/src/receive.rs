@@ -0,0 +1,644 @@
+use libsignal_protocol::{
+    message_decrypt_prekey, message_decrypt_signal, PreKeySignalMessage, ProtocolAddress,
+    SignalMessage, SignalProtocolError,
+};
+use prost::Message as _;
+use rand::rngs::OsRng;
+use thiserror::Error;
+use wa_binary::{attrs::AttrErrors, node::Node};
+use wa_proto::items::{
+    wa_web_protobufs_e2e::Message,
+    wa_web_protobufs_vname_cert::{verified_name_certificate::Details, VerifiedNameCertificate},
+};
+use wa_store::{signal::SignalStore, Device};
+use wa_types::{
+    events,
+    jid::{BROADCAST_SERVER, GROUP_SERVER, JID},
+    message::{
+        DeviceSentMeta, EditAttribute, MessageID, MessageInfo, MessageServerID, MessageSource,
+    },
+    user::VerifiedName,
+};
+
+use crate::sender_key::{self, SenderKeyError};
+
+#[derive(Error, Debug)]
+pub enum ReceiveError {
+    #[error("invalid attributes in message: {0}")]
+    Attrs(#[from] AttrErrors),
+    #[error("failed to parse verified name: {0}")]
+    VerifiedName(prost::DecodeError),
+    #[error("can't receive messages before logging in")]
+    NotLoggedIn,
+}
+
+#[derive(Error, Debug)]
+pub enum DecryptError {
+    #[error("unsupported encryption type {0}")]
+    UnsupportedType(String),
+    #[error("received {0} in a non-group chat")]
+    UnexpectedGroupMessage(&'static str),
+    #[error("failed to decrypt {enc_type}: {source}")]
+    Signal {
+        enc_type: &'static str,
+        source: SignalProtocolError,
+    },
+    #[error("failed to decrypt {enc_type}: {source}")]
+    SenderKey {
+        enc_type: &'static str,
+        source: SenderKeyError,
+    },
+    #[error("invalid padding in decrypted message")]
+    InvalidPadding,
+    #[error("failed to unmarshal decrypted message: {0}")]
+    Proto(#[from] prost::DecodeError),
+}
+
+fn is_own_user(user: &JID, device: &Device) -> bool {
+    [&device.id, &device.lid]
+        .into_iter()
+        .flatten()
+        .any(|own| own.user == user.user && own.server == user.server)
+}
+
+/// Parses who sent a message stanza and where. Receipts are parsed the same way, but the
+/// participant of a group receipt may be missing, in which case the sender is empty.
+pub(crate) fn parse_message_source(
+    node: &Node,
+    device: &Device,
+    require_participant: bool,
+) -> Result<MessageSource, ReceiveError> {
+    let mut ag = node.attr_getter();
+    let from = ag.jid("from");
+    let source = if from.server == GROUP_SERVER || from.server == BROADCAST_SERVER {
+        let sender = if require_participant {
+            ag.jid("participant")
+        } else {
+            ag.optional_jid_or_empty("participant")
+        };
+        let broadcast_list_owner = if from.server == BROADCAST_SERVER {
+            ag.optional_jid_or_empty("recipient")
+        } else {
+            JID::new(String::new(), String::new())
+        };
+        MessageSource {
+            is_from_me: is_own_user(&sender, device),
+            chat: from,
+            sender,
+            is_group: true,
+            broadcast_list_owner,
+        }
+    } else if is_own_user(&from, device) {
+        let chat = match ag.optional_jid("recipient") {
+            Some(recipient) => recipient,
+            None => from.clone().to_non_ad(),
+        };
+        MessageSource {
+            chat,
+            sender: from,
+            is_from_me: true,
+            is_group: false,
+            broadcast_list_owner: JID::new(String::new(), String::new()),
+        }
+    } else {
+        MessageSource {
+            chat: from.clone().to_non_ad(),
+            sender: from,
+            is_from_me: false,
+            is_group: false,
+            broadcast_list_owner: JID::new(String::new(), String::new()),
+        }
+    };
+    ag.into_result()?;
+    Ok(source)
+}
+
+fn parse_verified_name(node: &Node) -> Result<Option<VerifiedName>, prost::DecodeError> {
+    let Some(bytes) = node.content_bytes() else {
+        return Ok(None);
+    };
+    let certificate = VerifiedNameCertificate::decode(bytes)?;
+    let details = Details::decode(certificate.details())?;
+    Ok(Some(VerifiedName {
+        certificate,
+        details,
+    }))
+}
+
+/// [`parse_message_info`] parses the metadata of an incoming `<message>` stanza.
+pub fn parse_message_info(node: &Node, device: &Device) -> Result<MessageInfo, ReceiveError> {
+    if device.id.is_none() {
+        return Err(ReceiveError::NotLoggedIn);
+    }
+    let source = parse_message_source(node, device, true)?;
+    let mut ag = node.attr_getter();
+    let id = ag.string("id").to_string();
+    let server_id = ag.optional_string("server_id").unwrap_or_default();
+    let timestamp = ag.unix_time("t");
+    let push_name = ag.optional_string("notify").unwrap_or_default();
+    let category = ag.optional_string("category").unwrap_or_default();
+    let message_type = ag.optional_string("type").unwrap_or_default();
+    let edit = ag
+        .optional_string("edit")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(EditAttribute::Empty);
+    ag.into_result()?;
+
+    let mut info = MessageInfo {
+        source,
+        id: MessageID(id),
+        server_id: MessageServerID(server_id.to_string()),
+        r#type: message_type.to_string(),
+        push_name: push_name.to_string(),
+        timestamp,
+        category: category.to_string(),
+        multicast: false,
+        media_type: String::new(),
+        edit,
+        verified_name: None,
+        device_sent_meta: None,
+    };
+    for child in node.get_children() {
+        match child.tag.as_str() {
+            "multicast" => info.multicast = true,
+            "verified_name" => {
+                info.verified_name =
+                    parse_verified_name(child).map_err(ReceiveError::VerifiedName)?;
+            }
+            _ => {
+                if let Some(media_type) = child.attr_getter().optional_string("mediatype") {
+                    info.media_type = media_type.to_string();
+                }
+            }
+        }
+    }
+    Ok(info)
+}
+
+/// [`unpad_message`] removes the random padding that is added to messages before encrypting
+/// them. The value of the last byte is the number of padding bytes.
+pub(crate) fn unpad_message(plaintext: &[u8]) -> Result<&[u8], DecryptError> {
+    match plaintext.last() {
+        Some(&padding) if padding > 0 && padding as usize <= plaintext.len() => {
+            Ok(&plaintext[..plaintext.len() - padding as usize])
+        }
+        _ => Err(DecryptError::InvalidPadding),
+    }
+}
+
+/// [`DecryptedStanza`] is the result of decrypting all the `<enc>` children of a message
+/// stanza.
+#[derive(Debug)]
+pub struct DecryptedStanza {
+    pub info: MessageInfo,
+    pub messages: Vec<events::Message>,
+    /// Errors of the children that failed to decrypt. If there are any, a retry receipt
+    /// should be sent with [`crate::retry::retry_receipt`].
+    pub errors: Vec<DecryptError>,
+    /// True if the sender's identity key changed, e.g. because they reinstalled WhatsApp.
+    /// The old identity and session were replaced, and [`events::IdentityChange`] should be
+    /// dispatched.
+    pub identity_changed: bool,
+}
+
+async fn decrypt_prekey_message(
+    message: &PreKeySignalMessage,
+    address: &ProtocolAddress,
+    signal_store: &mut SignalStore,
+) -> Result<Vec<u8>, SignalProtocolError> {
+    message_decrypt_prekey(
+        message,
+        address,
+        &mut signal_store.clone(),
+        &mut signal_store.clone(),
+        &mut signal_store.clone(),
+        &signal_store.clone(),
+        &mut signal_store.clone(),
+        &mut OsRng,
+    )
+    .await
+}
+
+/// Decrypts an `<enc>` child. If the sender's identity changed, the old identity is
+/// forgotten and `identity_changed` is set.
+async fn decrypt_enc(
+    enc: &Node,
+    info: &MessageInfo,
+    signal_store: &mut SignalStore,
+    identity_changed: &mut bool,
+) -> Result<Vec<u8>, DecryptError> {
+    let mut ag = enc.attr_getter();
+    let enc_type = ag.string("type").to_string();
+    let version = ag.optional_string("v").unwrap_or("2");
+    let ciphertext = enc.content_bytes().unwrap_or_default();
+    let address = info.source.sender.signal_address();
+
+    let signal_error = |enc_type| move |source| DecryptError::Signal { enc_type, source };
+    let plaintext = match enc_type.as_str() {
+        "pkmsg" => {
+            let message =
+                PreKeySignalMessage::try_from(ciphertext).map_err(signal_error("pkmsg"))?;
+            match decrypt_prekey_message(&message, &address, signal_store).await {
+                // The sender reinstalled WhatsApp, so the new identity replaces the old one.
+                Err(SignalProtocolError::UntrustedIdentity(_)) => {
+                    signal_store.clear_identity(&address).await.map_err(|err| {
+                        DecryptError::Signal {
+                            enc_type: "pkmsg",
+                            source: SignalProtocolError::InvalidState(
+                                "clear_identity",
+                                err.to_string(),
+                            ),
+                        }
+                    })?;
+                    *identity_changed = true;
+                    decrypt_prekey_message(&message, &address, signal_store).await
+                }
+                result => result,
+            }
+            .map_err(signal_error("pkmsg"))?
+        }
+        "msg" => {
+            let message = SignalMessage::try_from(ciphertext).map_err(signal_error("msg"))?;
+            message_decrypt_signal(
+                &message,
+                &address,
+                &mut signal_store.clone(),
+                &mut signal_store.clone(),
+                &mut OsRng,
+            )
+            .await
+            .map_err(signal_error("msg"))?
+        }
+        "skmsg" => {
+            if !info.source.is_group {
+                return Err(DecryptError::UnexpectedGroupMessage("skmsg"));
+            }
+            sender_key::group_decrypt(signal_store, &info.source.chat, &address, ciphertext)
+                .await
+                .map_err(|source| DecryptError::SenderKey {
+                    enc_type: "skmsg",
+                    source,
+                })?
+        }
+        _ => return Err(DecryptError::UnsupportedType(enc_type)),
+    };
+    if version == "3" {
+        Ok(plaintext)
+    } else {
+        Ok(unpad_message(&plaintext)?.to_vec())
+    }
+}
+
+/// Handles the parts of a decrypted message that are meant for the client rather than the
+/// user. Returns the message to dispatch, or [`Option::None`] if nothing is left.
+async fn handle_decrypted(
+    mut message: Message,
+    info: &mut MessageInfo,
+    signal_store: &mut SignalStore,
+) -> Result<Option<Message>, DecryptError> {
+    if let Some(device_sent) = message.device_sent_message.take() {
+        info.device_sent_meta = Some(DeviceSentMeta {
+            destination_jid: device_sent.destination_jid().to_string(),
+            phash: device_sent.phash().to_string(),
+        });
+        message = device_sent.message.map(|inner| *inner).unwrap_or_default();
+    }
+    if let Some(distribution) = message.sender_key_distribution_message.take() {
+        if info.source.is_group {
+            sender_key::process_sender_key_distribution_message(
+                signal_store,
+                &info.source.chat,
+                &info.source.sender.signal_address(),
+                distribution.axolotl_sender_key_distribution_message(),
+            )
+            .await
+            .map_err(|source| DecryptError::SenderKey {
+                enc_type: "skdm",
+                source,
+            })?;
+        }
+    }
+    Ok((message != Message::default()).then_some(message))
+}
+
+/// [`decrypt_message`] parses and decrypts an incoming `<message>` stanza.
+///
+/// Each `<enc>` child is decrypted with the signal session (`pkmsg` and `msg`) or the group
+/// sender key (`skmsg`) of the sender. Sender key distribution messages are processed before
+/// later children, so a `pkmsg` carrying the sender key can be followed by the `skmsg` it
+/// decrypts. Children that fail to decrypt are collected in [`DecryptedStanza::errors`].
+pub async fn decrypt_message(
+    node: &Node,
+    device: &Device,
+    signal_store: &mut SignalStore,
+) -> Result<DecryptedStanza, ReceiveError> {
+    let info = parse_message_info(node, device)?;
+    let mut messages = Vec::new();
+    let mut errors = Vec::new();
+    let mut identity_changed = false;
+    for enc in node.get_children_by_tag("enc") {
+        let result = async {
+            let plaintext = decrypt_enc(enc, &info, signal_store, &mut identity_changed).await?;
+            let mut info = info.clone();
+            let message = handle_decrypted(
+                Message::decode(plaintext.as_slice())?,
+                &mut info,
+                signal_store,
+            )
+            .await?;
+            Ok(message.map(|message| events::Message { info, message }))
+        }
+        .await;
+        match result {
+            Ok(Some(message)) => messages.push(message),
+            Ok(None) => {}
+            Err(err) => errors.push(err),
+        }
+    }
+    Ok(DecryptedStanza {
+        info,
+        messages,
+        errors,
+        identity_changed,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{sync::Arc, time::SystemTime};
+
+    use libsignal_protocol::{
+        message_encrypt, process_prekey_bundle, CiphertextMessage, DeviceId, IdentityKey,
+        PreKeyBundle,
+    };
+    use wa_binary::node::{AttrValue, Attrs, NodeContent};
+    use wa_proto::items::wa_web_protobufs_e2e;
+    use wa_store::{memory::MemoryStore, IdentityStore, PreKeyStore, SessionStore};
+
+    use super::*;
+    use crate::send::{enc_node, tests::text};
+
+    /// A device with its own store, acting as one side of a conversation.
+    pub(crate) struct TestPeer {
+        pub device: Device,
+        pub store: Arc<MemoryStore>,
+        pub signal: SignalStore,
+    }
+
+    impl TestPeer {
+        pub(crate) fn new(user: &str, device_id: u8) -> Self {
+            let mut device = Device::generate().unwrap();
+            device.id = Some(JID::new_ad_jid(user.to_string(), 0, device_id));
+            let store = Arc::new(MemoryStore::new());
+            let signal = SignalStore::new(store.clone(), &device);
+            TestPeer {
+                device,
+                store,
+                signal,
+            }
+        }
+
+        pub(crate) fn jid(&self) -> JID {
+            self.device.id.clone().unwrap()
+        }
+
+        /// Returns the pre-key bundle the server would hand out for this device.
+        pub(crate) async fn bundle(&self) -> PreKeyBundle {
+            let pre_key = self.store.gen_one_pre_key().await.unwrap();
+            PreKeyBundle::new(
+                self.device.registration_id,
+                DeviceId::from(self.jid().device as u32),
+                Some((pre_key.key_id.into(), pre_key.key_pair.public_key)),
+                self.device.signed_pre_key.key_id.into(),
+                self.device.signed_pre_key.key_pair.public_key,
+                self.device.signed_pre_key.signature.clone().unwrap(),
+                IdentityKey::new(self.device.identity_key.public_key),
+            )
+            .unwrap()
+        }
+
+        /// Encrypts a message to the given peer, establishing a session first if needed.
+        pub(crate) async fn encrypt_for(&mut self, to: &TestPeer, plaintext: &[u8]) -> Node {
+            let address = to.jid().signal_address();
+            if self
+                .store
+                .get_session(&address.to_string())
+                .await
+                .unwrap()
+                .is_none()
+            {
+                process_prekey_bundle(
+                    &address,
+                    &mut self.signal.clone(),
+                    &mut self.signal,
+                    &to.bundle().await,
+                    SystemTime::now(),
+                    &mut OsRng,
+                )
+                .await
+                .unwrap();
+            }
+            let encrypted = message_encrypt(
+                plaintext,
+                &address,
+                &mut self.signal.clone(),
+                &mut self.signal,
+                SystemTime::now(),
+            )
+            .await
+            .unwrap();
+            let enc_type = match encrypted {
+                CiphertextMessage::PreKeySignalMessage(_) => "pkmsg",
+                _ => "msg",
+            };
+            enc_node(enc_type, encrypted.serialize())
+        }
+    }
+
+    pub(crate) fn padded(message: &Message) -> Vec<u8> {
+        let mut plaintext = message.encode_to_vec();
+        plaintext.extend_from_slice(&[3; 3]);
+        plaintext
+    }
+
+    fn message_node(attrs: &[(&str, AttrValue)], content: Vec<Node>) -> Node {
+        let mut all_attrs = Attrs::from([
+            ("id".to_string(), AttrValue::from("ABCD")),
+            ("t".to_string(), AttrValue::from("1700000000")),
+            ("type".to_string(), AttrValue::from("text")),
+            ("notify".to_string(), AttrValue::from("Bob")),
+        ]);
+        for (key, value) in attrs {
+            all_attrs.insert(key.to_string(), value.clone());
+        }
+        Node::new("message", all_attrs, NodeContent::Nodes(content))
+    }
+
+    #[test]
+    fn unpads_messages() {
+        assert_eq!(unpad_message(&[1, 2, 2, 2]).unwrap(), [1, 2]);
+        assert!(matches!(
+            unpad_message(&[1, 0]),
+            Err(DecryptError::InvalidPadding)
+        ));
+        assert!(matches!(
+            unpad_message(&[5]),
+            Err(DecryptError::InvalidPadding)
+        ));
+    }
+
+    #[tokio::test]
+    async fn decrypts_direct_messages() {
+        let mut alice = TestPeer::new("1111", 0);
+        let mut bob = TestPeer::new("2222", 3);
+
+        let first = bob.encrypt_for(&alice, &padded(&text("hello"))).await;
+        assert_eq!(first.attrs["type"], AttrValue::from("pkmsg"));
+        let node = message_node(&[("from", AttrValue::JID(bob.jid()))], vec![first]);
+        let decrypted = decrypt_message(&node, &alice.device, &mut alice.signal)
+            .await
+            .unwrap();
+        assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+        assert_eq!(decrypted.info.source.chat, bob.jid().to_non_ad());
+        assert_eq!(decrypted.info.push_name, "Bob");
+        assert_eq!(decrypted.messages[0].message.conversation(), "hello");
+
+        // Alice's reply acknowledges the session, so Bob's next message is a normal msg.
+        let reply = alice.encrypt_for(&bob, &padded(&text("hi"))).await;
+        let node = message_node(&[("from", AttrValue::JID(alice.jid()))], vec![reply]);
+        decrypt_message(&node, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        let second = bob.encrypt_for(&alice, &padded(&text("again"))).await;
+        assert_eq!(second.attrs["type"], AttrValue::from("msg"));
+        let node = message_node(&[("from", AttrValue::JID(bob.jid()))], vec![second]);
+        let decrypted = decrypt_message(&node, &alice.device, &mut alice.signal)
+            .await
+            .unwrap();
+        assert_eq!(decrypted.messages[0].message.conversation(), "again");
+    }
+
+    #[tokio::test]
+    async fn decrypts_group_messages_with_distributed_sender_key() {
+        let mut alice = TestPeer::new("1111", 0);
+        let mut bob = TestPeer::new("2222", 0);
+        let group = JID::new("1234-5678".to_string(), GROUP_SERVER.to_string());
+        let bob_address = bob.jid().signal_address();
+
+        let skdm =
+            sender_key::create_sender_key_distribution_message(&bob.signal, &group, &bob_address)
+                .await
+                .unwrap();
+        let distribution = Message {
+            sender_key_distribution_message: Some(
+                wa_web_protobufs_e2e::SenderKeyDistributionMessage {
+                    group_id: Some(group.to_string()),
+                    axolotl_sender_key_distribution_message: Some(skdm),
+                },
+            ),
+            ..Default::default()
+        };
+        let pkmsg = bob.encrypt_for(&alice, &padded(&distribution)).await;
+        let skmsg = sender_key::group_encrypt(
+            &bob.signal,
+            &group,
+            &bob_address,
+            &padded(&text("hello group")),
+        )
+        .await
+        .unwrap();
+
+        let node = message_node(
+            &[
+                ("from", AttrValue::JID(group.clone())),
+                ("participant", AttrValue::JID(bob.jid())),
+            ],
+            vec![pkmsg, enc_node("skmsg", &skmsg)],
+        );
+        let decrypted = decrypt_message(&node, &alice.device, &mut alice.signal)
+            .await
+            .unwrap();
+        assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+        assert!(decrypted.info.source.is_group);
+        assert_eq!(decrypted.info.source.sender, bob.jid());
+        assert_eq!(decrypted.messages.len(), 1);
+        assert_eq!(decrypted.messages[0].message.conversation(), "hello group");
+    }
+
+    #[tokio::test]
+    async fn replaces_changed_identities() {
+        let mut alice = TestPeer::new("1111", 0);
+        let mut bob = TestPeer::new("2222", 0);
+
+        let first = bob.encrypt_for(&alice, &padded(&text("hello"))).await;
+        let node = message_node(&[("from", AttrValue::JID(bob.jid()))], vec![first]);
+        let decrypted = decrypt_message(&node, &alice.device, &mut alice.signal)
+            .await
+            .unwrap();
+        assert!(!decrypted.identity_changed);
+
+        // Bob reinstalls WhatsApp, which gives them a new identity key.
+        let mut bob = TestPeer::new("2222", 0);
+        let second = bob.encrypt_for(&alice, &padded(&text("new phone"))).await;
+        let node = message_node(&[("from", AttrValue::JID(bob.jid()))], vec![second]);
+        let decrypted = decrypt_message(&node, &alice.device, &mut alice.signal)
+            .await
+            .unwrap();
+        assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+        assert!(decrypted.identity_changed);
+        assert_eq!(decrypted.messages[0].message.conversation(), "new phone");
+        assert_eq!(
+            alice
+                .store
+                .get_identity(&bob.jid().signal_address().to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+            bob.device.identity_key.public_key.public_key_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn collects_decryption_errors() {
+        let mut alice = TestPeer::new("1111", 0);
+        let bob = TestPeer::new("2222", 0);
+
+        let node = message_node(
+            &[("from", AttrValue::JID(bob.jid()))],
+            vec![enc_node("msg", &[0x33, 1, 2, 3, 4, 5, 6, 7, 8, 9])],
+        );
+        let decrypted = decrypt_message(&node, &alice.device, &mut alice.signal)
+            .await
+            .unwrap();
+        assert!(decrypted.messages.is_empty());
+        assert!(matches!(
+            decrypted.errors[..],
+            [DecryptError::Signal {
+                enc_type: "msg",
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn parses_own_message_source() {
+        let alice = TestPeer::new("1111", 0);
+        let other_device = JID::new_ad_jid("1111".to_string(), 0, 4);
+        let recipient = JID::new("2222".to_string(), "s.whatsapp.net".to_string());
+        let node = message_node(
+            &[
+                ("from", AttrValue::JID(other_device.clone())),
+                ("recipient", AttrValue::JID(recipient.clone())),
+                ("edit", AttrValue::from("1")),
+            ],
+            vec![],
+        );
+        let info = parse_message_info(&node, &alice.device).unwrap();
+        assert!(info.source.is_from_me);
+        assert_eq!(info.source.chat, recipient);
+        assert_eq!(info.source.sender, other_device);
+        assert!(matches!(info.edit, EditAttribute::MessageEdit));
+        assert_eq!(info.timestamp.unix_timestamp(), 1700000000);
+    }
+}
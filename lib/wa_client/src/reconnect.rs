@@ -0,0 +1,168 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use rand::{rngs::OsRng, Rng};
+use wa_types::events::{Event, Reconnecting};
+
+use crate::dispatcher::EventDispatcher;
+
+/// [`Backoff`] is how long the client waits before each automatic reconnection attempt. The
+/// delay starts at [`Backoff::initial`] and is multiplied for every failed attempt, up to
+/// [`Backoff::max`].
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+    /// The fraction of each delay that is random, between 0 and 1, so that clients that lost
+    /// their connections at the same time don't all reconnect at the same time.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(120),
+            multiplier: 2,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay before the given attempt, counting from 1.
+    pub fn delay(&self, attempt: u32, rng: &mut impl Rng) -> Duration {
+        let exponent = attempt.saturating_sub(1);
+        let delay = self
+            .initial
+            .saturating_mul(self.multiplier.saturating_pow(exponent))
+            .min(self.max);
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * rng.gen::<f64>())
+    }
+}
+
+/// Calls `connect` until it succeeds, waiting for the backoff delay before each attempt and
+/// dispatching [`Event::Reconnecting`]. Gives up as soon as `should_reconnect` returns false.
+/// Returns true if the client reconnected.
+pub(crate) async fn reconnect_with_backoff<F, Fut, E>(
+    backoff: &Backoff,
+    attempts: &AtomicU32,
+    dispatcher: &EventDispatcher,
+    should_reconnect: impl Fn() -> bool,
+    mut connect: F,
+) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    while should_reconnect() {
+        let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+        let delay = backoff.delay(attempt, &mut OsRng);
+        dispatcher
+            .dispatch(Event::Reconnecting(Reconnecting { attempt, delay }))
+            .await;
+        tokio::time::sleep(delay).await;
+        if !should_reconnect() {
+            break;
+        }
+        if connect().await.is_ok() {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use futures_util::StreamExt;
+    use tokio::time::Instant;
+    use wa_types::events::EventKind;
+
+    use super::*;
+    use crate::dispatcher::EventFilter;
+
+    fn backoff(jitter: f64) -> Backoff {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+            multiplier: 2,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_cap() {
+        let delays = (1..=5)
+            .map(|attempt| backoff(0.0).delay(attempt, &mut OsRng).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        assert_eq!(
+            backoff(0.0).delay(u32::MAX, &mut OsRng),
+            Duration::from_secs(5)
+        );
+
+        for _ in 0..100 {
+            let delay = backoff(0.5).delay(3, &mut OsRng);
+            assert!(delay > Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_connected() {
+        let dispatcher = EventDispatcher::new();
+        let mut events = dispatcher.subscribe(EventFilter::kinds(&[EventKind::Reconnecting]), 8);
+        let attempts = AtomicU32::new(0);
+        let mut failures = 3;
+
+        let start = Instant::now();
+        let connected = reconnect_with_backoff(
+            &backoff(0.0),
+            &attempts,
+            &dispatcher,
+            || true,
+            || {
+                let result = if failures > 0 { Err(()) } else { Ok(()) };
+                failures -= 1;
+                async move { result }
+            },
+        )
+        .await;
+        assert!(connected);
+        assert_eq!(start.elapsed(), Duration::from_secs(1 + 2 + 4 + 5));
+
+        for (attempt, delay) in [(1, 1), (2, 2), (3, 4), (4, 5)] {
+            let Some(Event::Reconnecting(event)) = events.next().await else {
+                panic!("expected a reconnecting event");
+            };
+            assert_eq!(event.attempt, attempt);
+            assert_eq!(event.delay, Duration::from_secs(delay));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_when_reconnecting_is_disabled() {
+        let dispatcher = EventDispatcher::new();
+        let attempts = AtomicU32::new(0);
+        let enabled = AtomicBool::new(true);
+
+        let connected = reconnect_with_backoff(
+            &backoff(0.0),
+            &attempts,
+            &dispatcher,
+            || enabled.load(Ordering::SeqCst),
+            || {
+                enabled.store(false, Ordering::SeqCst);
+                async { Err::<(), ()>(()) }
+            },
+        )
+        .await;
+        assert!(!connected);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,130 @@
+use async_trait::async_trait;
+use thiserror::Error;
+use wa_binary::{
+    encoder::EncodeError,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_types::jid::{DEFAULT_USER_SERVER, JID};
+
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error("not connected to WhatsApp")]
+    NotConnected,
+    #[error("connection closed before a response was received")]
+    Disconnected,
+    #[error("timed out waiting for a response")]
+    Timeout,
+    #[error("server returned error {code}: {text}")]
+    Iq { code: u16, text: String },
+    #[error("failed to encode node: {0}")]
+    Encode(#[from] EncodeError),
+}
+
+/// [`IqType`] is the type of an `<iq>` request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IqType {
+    Get,
+    Set,
+}
+
+impl IqType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IqType::Get => "get",
+            IqType::Set => "set",
+        }
+    }
+}
+
+/// [`server_jid`] returns the JID of the WhatsApp server, which most requests are sent to.
+pub fn server_jid() -> JID {
+    JID::new(String::new(), DEFAULT_USER_SERVER.to_string())
+}
+
+pub(crate) fn bytes_node(tag: &str, content: impl Into<Vec<u8>>) -> Node {
+    Node::new(tag, Attrs::new(), NodeContent::Bytes(content.into()))
+}
+
+/// [`InfoQuery`] is an `<iq>` request that hasn't been assigned an ID yet.
+#[derive(Clone, Debug)]
+pub struct InfoQuery {
+    pub namespace: &'static str,
+    pub iq_type: IqType,
+    pub to: JID,
+    pub content: NodeContent,
+}
+
+impl InfoQuery {
+    /// Creates a request to the WhatsApp server.
+    pub fn new(namespace: &'static str, iq_type: IqType, content: NodeContent) -> Self {
+        InfoQuery {
+            namespace,
+            iq_type,
+            to: server_jid(),
+            content,
+        }
+    }
+
+    /// Builds the `<iq>` node with the given request ID.
+    pub fn into_node(self, id: impl Into<String>) -> Node {
+        Node::new(
+            "iq",
+            Attrs::from([
+                ("id".to_string(), AttrValue::from(id.into())),
+                ("xmlns".to_string(), AttrValue::from(self.namespace)),
+                ("type".to_string(), AttrValue::from(self.iq_type.as_str())),
+                ("to".to_string(), AttrValue::JID(self.to)),
+            ]),
+            self.content,
+        )
+    }
+}
+
+/// Returns the error of an `<iq>` response of type `error`.
+fn iq_error(response: &Node) -> Option<RequestError> {
+    if response.attrs.get("type") != Some(&AttrValue::from("error")) {
+        return None;
+    }
+    let mut ag = response
+        .get_optional_child_by_tag(&["error"])
+        .map(Node::attr_getter);
+    Some(RequestError::Iq {
+        code: ag
+            .as_mut()
+            .and_then(|ag| ag.optional_u64("code"))
+            .unwrap_or_default() as u16,
+        text: ag
+            .as_mut()
+            .and_then(|ag| ag.optional_string("text"))
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+/// [`RequestSender`] sends requests to the server and waits for the responses. It's
+/// implemented by the client, and lets the features built on top of it be tested without a
+/// connection.
+#[async_trait]
+pub trait RequestSender: Send + Sync {
+    /// Returns a new unique ID for a request.
+    fn generate_request_id(&self) -> String;
+
+    /// Sends the node, which must have an `id` attribute, and waits for the response with
+    /// the same ID.
+    async fn send_request(&self, node: Node) -> Result<Node, RequestError>;
+
+    /// Sends the node without waiting for a response.
+    async fn send_node(&self, node: Node) -> Result<(), RequestError>;
+
+    /// Sends the `<iq>` request and waits for the response, turning error responses into
+    /// [`RequestError::Iq`].
+    async fn send_iq(&self, query: InfoQuery) -> Result<Node, RequestError> {
+        let response = self
+            .send_request(query.into_node(self.generate_request_id()))
+            .await?;
+        match iq_error(&response) {
+            Some(err) => Err(err),
+            None => Ok(response),
+        }
+    }
+}
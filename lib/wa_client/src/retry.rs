@@ -0,0 +1,643 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use libsignal_protocol::{message_encrypt, CiphertextMessage, SessionStore, SignalProtocolError};
+use prost::Message as _;
+use thiserror::Error;
+use wa_binary::{
+    attrs::AttrErrors,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_proto::items::wa_web_protobufs_e2e::Message;
+use wa_store::{signal::SignalStore, Device, PreKeyStore, StoreError};
+use wa_types::{
+    jid::{GROUP_SERVER, JID},
+    message::MessageID,
+};
+
+use crate::{
+    client_payload::DJB_KEY_TYPE,
+    prekeys::{fetch_pre_key_bundles, parse_pre_key_bundle, pre_key_node, PreKeyError},
+    receive::DecryptedStanza,
+    request::{bytes_node, RequestError, RequestSender},
+    send::{
+        device_sent_message, enc_node, message_type, pad_message, process_bundle,
+        sender_key_distribution_message,
+    },
+    sender_key::SenderKeyError,
+};
+
+/// [`MAX_RETRY_COUNT`] is the number of retry receipts after which a message is given up on,
+/// in both directions.
+pub const MAX_RETRY_COUNT: u32 = 5;
+/// [`RECENT_MESSAGES_SIZE`] is the default number of sent messages kept for resending.
+pub const RECENT_MESSAGES_SIZE: usize = 256;
+
+#[derive(Error, Debug)]
+pub enum RetryError {
+    #[error("can't handle retries before logging in")]
+    NotLoggedIn,
+    #[error("missing <{0}> in retry receipt")]
+    MissingNode(&'static str),
+    #[error("invalid attributes in retry receipt: {0}")]
+    Attrs(#[from] AttrErrors),
+    #[error("message {0} was retried too many times")]
+    TooManyRetries(String),
+    #[error("message {0} isn't in the recently sent messages")]
+    MessageNotFound(String),
+    #[error("failed to rebuild session: {0}")]
+    PreKey(#[from] PreKeyError),
+    #[error("failed to generate pre-key: {0}")]
+    Store(#[from] StoreError),
+    #[error("failed to encrypt message: {0}")]
+    Signal(#[from] SignalProtocolError),
+    #[error("failed to create sender key: {0}")]
+    SenderKey(#[from] SenderKeyError),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+}
+
+/// A map that forgets the oldest entries once it's full.
+struct BoundedMap<K, V> {
+    capacity: usize,
+    order: VecDeque<K>,
+    entries: HashMap<K, V>,
+}
+
+impl<K: Clone + Eq + Hash, V> BoundedMap<K, V> {
+    fn new(capacity: usize) -> Self {
+        BoundedMap {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            entries: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn entry(&mut self, key: K) -> &mut V
+    where
+        V: Default,
+    {
+        if !self.entries.contains_key(&key) {
+            if self.order.len() >= self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+            self.order.push_back(key.clone());
+        }
+        self.entries.entry(key).or_default()
+    }
+}
+
+/// [`RecentMessages`] keeps the last sent messages, so that they can be sent again when a
+/// recipient sends a retry receipt.
+pub struct RecentMessages(Mutex<BoundedMap<(JID, String), Message>>);
+
+impl RecentMessages {
+    /// Creates a cache that keeps the given number of messages.
+    pub fn new(capacity: usize) -> Self {
+        RecentMessages(Mutex::new(BoundedMap::new(capacity)))
+    }
+
+    /// Remembers a message that was sent to the given chat.
+    pub fn add(&self, chat: &JID, id: &MessageID, message: Message) {
+        let key = (chat.clone().to_non_ad(), id.0.clone());
+        *self.0.lock().unwrap().entry(key) = message;
+    }
+
+    /// Returns the message with the given ID that was sent to the given chat.
+    pub fn get(&self, chat: &JID, id: &str) -> Option<Message> {
+        let key = (chat.clone().to_non_ad(), id.to_string());
+        self.0.lock().unwrap().entries.get(&key).cloned()
+    }
+}
+
+impl Default for RecentMessages {
+    fn default() -> Self {
+        RecentMessages::new(RECENT_MESSAGES_SIZE)
+    }
+}
+
+/// [`RetryCounts`] counts the retry receipts sent for each incoming message.
+pub struct RetryCounts(Mutex<BoundedMap<String, u32>>);
+
+impl RetryCounts {
+    /// Creates a counter that remembers the given number of messages.
+    pub fn new(capacity: usize) -> Self {
+        RetryCounts(Mutex::new(BoundedMap::new(capacity)))
+    }
+
+    /// Increments and returns the retry count of the message, or returns [`Option::None`] if
+    /// it has reached [`MAX_RETRY_COUNT`].
+    pub fn increment(&self, id: &MessageID) -> Option<u32> {
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(id.0.clone());
+        if *count >= MAX_RETRY_COUNT {
+            return None;
+        }
+        *count += 1;
+        Some(*count)
+    }
+}
+
+impl Default for RetryCounts {
+    fn default() -> Self {
+        RetryCounts::new(RECENT_MESSAGES_SIZE)
+    }
+}
+
+/// [`retry_receipt`] builds the receipt that asks the sender of a message to send it again
+/// because decrypting it failed. Returns [`Option::None`] if everything was decrypted or the
+/// message has been retried too many times.
+///
+/// The receipt contains our registration ID. From the second retry on it also contains our
+/// identity and a fresh pre-key, so the sender can start a new session if ours is broken.
+pub async fn retry_receipt(
+    node: &Node,
+    decrypted: &DecryptedStanza,
+    device: &Device,
+    store: &dyn PreKeyStore,
+    retry_counts: &RetryCounts,
+) -> Result<Option<Node>, RetryError> {
+    if decrypted.errors.is_empty() {
+        return Ok(None);
+    }
+    let Some(retry_count) = retry_counts.increment(&decrypted.info.id) else {
+        return Ok(None);
+    };
+    let info = &decrypted.info;
+    let mut attrs = Attrs::from([
+        ("id".to_string(), AttrValue::from(info.id.0.as_str())),
+        ("type".to_string(), AttrValue::from("retry")),
+        ("to".to_string(), node.attrs["from"].clone()),
+    ]);
+    for key in ["recipient", "participant"] {
+        if let Some(value) = node.attrs.get(key) {
+            attrs.insert(key.to_string(), value.clone());
+        }
+    }
+    let mut content = vec![
+        Node::new(
+            "retry",
+            Attrs::from([
+                (
+                    "count".to_string(),
+                    AttrValue::from(retry_count.to_string()),
+                ),
+                ("id".to_string(), AttrValue::from(info.id.0.as_str())),
+                (
+                    "t".to_string(),
+                    AttrValue::from(info.timestamp.unix_timestamp().to_string()),
+                ),
+                ("v".to_string(), AttrValue::from("1")),
+            ]),
+            NodeContent::None,
+        ),
+        bytes_node("registration", device.registration_id.to_be_bytes()),
+    ];
+    // The first retry only asks for the message again. If that doesn't help either, the
+    // sender gets our keys to start a new session.
+    if retry_count > 1 {
+        let account = device.account.as_ref().ok_or(RetryError::NotLoggedIn)?;
+        let pre_key = store.gen_one_pre_key().await?;
+        content.push(Node::new(
+            "keys",
+            Attrs::new(),
+            NodeContent::Nodes(vec![
+                bytes_node("type", [DJB_KEY_TYPE]),
+                bytes_node(
+                    "identity",
+                    device.identity_key.public_key.public_key_bytes(),
+                ),
+                pre_key_node("key", &pre_key),
+                pre_key_node("skey", &device.signed_pre_key),
+                bytes_node("device-identity", account.encode_to_vec()),
+            ]),
+        ));
+    }
+    Ok(Some(Node::new(
+        "receipt",
+        attrs,
+        NodeContent::Nodes(content),
+    )))
+}
+
+/// [`handle_retry_receipt`] sends a message again to the device that failed to decrypt it.
+///
+/// The message is looked up in `recent_messages`, and is only sent to the device that asked
+/// for it. If the receipt contains keys, the session with the device is rebuilt from them;
+/// otherwise it's rebuilt from a fetched pre-key bundle from the second retry on, or if
+/// there's no session at all. Group messages are sent in the pairwise session along with our
+/// sender key, since the device presumably doesn't have it.
+pub async fn handle_retry_receipt(
+    sender: &dyn RequestSender,
+    device: &Device,
+    signal_store: &mut SignalStore,
+    recent_messages: &RecentMessages,
+    node: &Node,
+) -> Result<(), RetryError> {
+    let own_id = device.id.clone().ok_or(RetryError::NotLoggedIn)?;
+    let mut ag = node.attr_getter();
+    let from = ag.jid("from");
+    let participant = ag.optional_jid("participant");
+    let recipient = ag.optional_jid("recipient");
+    ag.into_result()?;
+    let retry = node
+        .get_optional_child_by_tag(&["retry"])
+        .ok_or(RetryError::MissingNode("retry"))?;
+    let mut ag = retry.attr_getter();
+    let id = ag.string("id").to_string();
+    let timestamp = ag.string("t").to_string();
+    let retry_count = ag.optional_u64("count").unwrap_or(1) as u32;
+    ag.into_result()?;
+    if retry_count > MAX_RETRY_COUNT {
+        return Err(RetryError::TooManyRetries(id));
+    }
+
+    let is_group = from.server == GROUP_SERVER;
+    let requester = participant.unwrap_or_else(|| from.clone());
+    let chat = match (is_group, &recipient) {
+        (false, Some(recipient)) => recipient.clone(),
+        _ => from.clone(),
+    };
+    let original = recent_messages
+        .get(&chat, &id)
+        .ok_or_else(|| RetryError::MessageNotFound(id.clone()))?;
+    let message = if is_group {
+        Message {
+            sender_key_distribution_message: Some(
+                sender_key_distribution_message(signal_store, &own_id, &chat).await?,
+            ),
+            ..original.clone()
+        }
+    } else if requester.user == own_id.user {
+        device_sent_message(&chat, original.clone())
+    } else {
+        original.clone()
+    };
+
+    let address = requester.signal_address();
+    let bundle = match node.get_optional_child_by_tag(&["keys"]) {
+        Some(keys) => Some(parse_pre_key_bundle(node, keys, &requester)?),
+        None if retry_count >= 2 || signal_store.load_session(&address).await?.is_none() => {
+            let (_, bundle) = fetch_pre_key_bundles(sender, std::slice::from_ref(&requester))
+                .await?
+                .into_iter()
+                .next()
+                .ok_or(PreKeyError::MissingNode("user"))?;
+            Some(bundle?)
+        }
+        None => None,
+    };
+    if let Some(bundle) = bundle {
+        process_bundle(signal_store, &address, &bundle).await?;
+    }
+    let encrypted = message_encrypt(
+        &pad_message(message.encode_to_vec()),
+        &address,
+        &mut signal_store.clone(),
+        &mut signal_store.clone(),
+        SystemTime::now(),
+    )
+    .await?;
+    let (enc_type, include_identity) = match encrypted {
+        CiphertextMessage::PreKeySignalMessage(_) => ("pkmsg", true),
+        _ => ("msg", false),
+    };
+    let mut enc = enc_node(enc_type, encrypted.serialize());
+    enc.attrs.insert(
+        "count".to_string(),
+        AttrValue::from(retry_count.to_string()),
+    );
+
+    let mut attrs = Attrs::from([
+        ("id".to_string(), AttrValue::from(id)),
+        ("type".to_string(), AttrValue::from(message_type(&original))),
+        ("to".to_string(), AttrValue::JID(from)),
+        ("t".to_string(), AttrValue::from(timestamp)),
+    ]);
+    for key in ["participant", "recipient"] {
+        if let Some(value) = node.attrs.get(key) {
+            attrs.insert(key.to_string(), value.clone());
+        }
+    }
+    let mut content = vec![enc];
+    if include_identity {
+        let account = device.account.as_ref().ok_or(RetryError::NotLoggedIn)?;
+        content.push(bytes_node("device-identity", account.encode_to_vec()));
+    }
+    sender
+        .send_node(Node::new("message", attrs, NodeContent::Nodes(content)))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wa_types::jid::DEFAULT_USER_SERVER;
+
+    use super::*;
+    use crate::{
+        receive::{decrypt_message, tests::TestPeer},
+        send::{
+            send_message,
+            tests::{delivered, fake_server, logged_in_peer, text},
+            GroupCache,
+        },
+    };
+
+    /// Turns a receipt we built into the one the server delivers to the message sender.
+    fn deliver_receipt(receipt: &Node, from: &JID, to: &JID) -> Node {
+        let mut receipt = receipt.clone();
+        receipt.attrs.remove("to");
+        receipt
+            .attrs
+            .insert("from".to_string(), AttrValue::JID(from.clone()));
+        receipt
+            .attrs
+            .insert("to".to_string(), AttrValue::JID(to.clone()));
+        receipt
+    }
+
+    #[test]
+    fn recent_messages_are_bounded() {
+        let recent = RecentMessages::new(2);
+        let chat = JID::new("2222".to_string(), DEFAULT_USER_SERVER.to_string());
+        for id in ["A", "B", "C"] {
+            recent.add(&chat, &MessageID(id.to_string()), text(id));
+        }
+        assert!(recent.get(&chat, "A").is_none());
+        assert_eq!(recent.get(&chat, "C").unwrap().conversation(), "C");
+        let device = JID::new_ad_jid("2222".to_string(), 0, 3);
+        assert_eq!(recent.get(&device, "B").unwrap().conversation(), "B");
+    }
+
+    #[test]
+    fn retry_counts_stop_at_max() {
+        let counts = RetryCounts::default();
+        let id = MessageID("ABCD".to_string());
+        for expected in 1..=MAX_RETRY_COUNT {
+            assert_eq!(counts.increment(&id), Some(expected));
+        }
+        assert_eq!(counts.increment(&id), None);
+    }
+
+    #[tokio::test]
+    async fn resends_message_to_device_that_failed_to_decrypt() {
+        let mut alice = logged_in_peer("1111", 0);
+        let mut bob = logged_in_peer("2222", 0);
+        let bob_laptop = TestPeer::new("2222", 5);
+        let server = fake_server(&[&alice, &bob, &bob_laptop]).await;
+        let recent = RecentMessages::default();
+        let bob_user = JID::new("2222".to_string(), DEFAULT_USER_SERVER.to_string());
+        send_message(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &recent,
+            &GroupCache::default(),
+            bob_user,
+            text("hello"),
+        )
+        .await
+        .unwrap();
+
+        // Bob's copy got corrupted on the way, so Bob asks for it again.
+        let mut node = delivered(
+            &server.sent_messages()[0],
+            &bob.jid(),
+            &[("from", AttrValue::JID(alice.jid()))],
+        );
+        if let NodeContent::Nodes(children) = &mut node.content {
+            children[0].content = NodeContent::Bytes(vec![0x33, 1, 2, 3]);
+        }
+        let decrypted = decrypt_message(&node, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        let retry_counts = RetryCounts::default();
+        let receipt = retry_receipt(
+            &node,
+            &decrypted,
+            &bob.device,
+            bob.store.as_ref(),
+            &retry_counts,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(receipt.attrs["to"], AttrValue::JID(alice.jid()));
+        let retry = receipt.get_optional_child_by_tag(&["retry"]).unwrap();
+        assert_eq!(retry.attrs["count"], AttrValue::from("1"));
+        assert!(receipt.get_optional_child_by_tag(&["keys"]).is_none());
+
+        // The second retry includes keys, and the session is rebuilt from them.
+        let receipt = retry_receipt(
+            &node,
+            &decrypted,
+            &bob.device,
+            bob.store.as_ref(),
+            &retry_counts,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let retry = receipt.get_optional_child_by_tag(&["retry"]).unwrap();
+        assert_eq!(retry.attrs["count"], AttrValue::from("2"));
+        assert!(receipt
+            .get_optional_child_by_tag(&["keys", "key"])
+            .is_some());
+
+        let receipt = deliver_receipt(&receipt, &bob.jid(), &alice.jid());
+        handle_retry_receipt(&server, &alice.device, &mut alice.signal, &recent, &receipt)
+            .await
+            .unwrap();
+        let resent = server.sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(resent.attrs["to"], AttrValue::JID(bob.jid()));
+        let enc = resent.get_optional_child_by_tag(&["enc"]).unwrap();
+        assert_eq!(enc.attrs["type"], AttrValue::from("pkmsg"));
+        assert_eq!(enc.attrs["count"], AttrValue::from("2"));
+
+        let mut resent = resent.clone();
+        resent.attrs.remove("to");
+        resent
+            .attrs
+            .insert("from".to_string(), AttrValue::JID(alice.jid()));
+        let decrypted = decrypt_message(&resent, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+        assert_eq!(decrypted.messages[0].message.conversation(), "hello");
+    }
+
+    #[tokio::test]
+    async fn resends_message_to_device_that_reinstalled() {
+        let mut alice = logged_in_peer("1111", 0);
+        let old_bob = logged_in_peer("2222", 0);
+        let server = fake_server(&[&alice, &old_bob]).await;
+        let recent = RecentMessages::default();
+        send_message(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &recent,
+            &GroupCache::default(),
+            old_bob.jid().to_non_ad(),
+            text("hello"),
+        )
+        .await
+        .unwrap();
+
+        // Bob reinstalled before the message arrived, so it was encrypted for his old identity.
+        let mut bob = logged_in_peer("2222", 0);
+        let node = delivered(
+            &server.sent_messages()[0],
+            &bob.jid(),
+            &[("from", AttrValue::JID(alice.jid()))],
+        );
+        let decrypted = decrypt_message(&node, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        assert!(!decrypted.errors.is_empty());
+        let retry_counts = RetryCounts::default();
+        let mut receipt = None;
+        for _ in 0..2 {
+            receipt = retry_receipt(
+                &node,
+                &decrypted,
+                &bob.device,
+                bob.store.as_ref(),
+                &retry_counts,
+            )
+            .await
+            .unwrap();
+        }
+        let receipt = deliver_receipt(&receipt.unwrap(), &bob.jid(), &alice.jid());
+        handle_retry_receipt(&server, &alice.device, &mut alice.signal, &recent, &receipt)
+            .await
+            .unwrap();
+
+        let mut resent = server.sent.lock().unwrap().last().cloned().unwrap();
+        resent.attrs.remove("to");
+        resent
+            .attrs
+            .insert("from".to_string(), AttrValue::JID(alice.jid()));
+        let decrypted = decrypt_message(&resent, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+        assert_eq!(decrypted.messages[0].message.conversation(), "hello");
+    }
+
+    #[tokio::test]
+    async fn resends_group_message_with_sender_key() {
+        let mut alice = logged_in_peer("1111", 0);
+        let mut bob = TestPeer::new("2222", 0);
+        let server = fake_server(&[&alice, &bob]).await;
+        let recent = RecentMessages::default();
+        let group = JID::new("1234-5678".to_string(), GROUP_SERVER.to_string());
+        *server.participants.lock().unwrap() = vec![alice.jid(), bob.jid().to_non_ad()];
+        send_message(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &recent,
+            &GroupCache::default(),
+            group.clone(),
+            text("hello group"),
+        )
+        .await
+        .unwrap();
+        let id = server.sent_messages()[0].attrs["id"].clone();
+
+        let receipt = Node::new(
+            "receipt",
+            Attrs::from([
+                ("from".to_string(), AttrValue::JID(group.clone())),
+                ("participant".to_string(), AttrValue::JID(bob.jid())),
+                ("id".to_string(), id.clone()),
+                ("type".to_string(), AttrValue::from("retry")),
+            ]),
+            NodeContent::Nodes(vec![Node::new(
+                "retry",
+                Attrs::from([
+                    ("id".to_string(), id),
+                    ("t".to_string(), AttrValue::from("1700000000")),
+                    ("count".to_string(), AttrValue::from("1")),
+                ]),
+                NodeContent::None,
+            )]),
+        );
+        handle_retry_receipt(&server, &alice.device, &mut alice.signal, &recent, &receipt)
+            .await
+            .unwrap();
+
+        let mut resent = server.sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(resent.attrs["to"], AttrValue::JID(group.clone()));
+        assert_eq!(resent.attrs["participant"], AttrValue::JID(bob.jid()));
+        resent
+            .attrs
+            .insert("from".to_string(), AttrValue::JID(group));
+        resent
+            .attrs
+            .insert("participant".to_string(), AttrValue::JID(alice.jid()));
+        let decrypted = decrypt_message(&resent, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+        assert_eq!(decrypted.messages[0].message.conversation(), "hello group");
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_and_exhausted_retries() {
+        let mut alice = logged_in_peer("1111", 0);
+        let bob = TestPeer::new("2222", 0);
+        let server = fake_server(&[&alice, &bob]).await;
+        let receipt = |count: &str| {
+            Node::new(
+                "receipt",
+                Attrs::from([("from".to_string(), AttrValue::JID(bob.jid()))]),
+                NodeContent::Nodes(vec![Node::new(
+                    "retry",
+                    Attrs::from([
+                        ("id".to_string(), AttrValue::from("ABCD")),
+                        ("t".to_string(), AttrValue::from("1700000000")),
+                        ("count".to_string(), AttrValue::from(count)),
+                    ]),
+                    NodeContent::None,
+                )]),
+            )
+        };
+        let recent = RecentMessages::default();
+        let result = handle_retry_receipt(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &recent,
+            &receipt("1"),
+        )
+        .await;
+        assert!(matches!(result, Err(RetryError::MessageNotFound(_))));
+        let result = handle_retry_receipt(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &recent,
+            &receipt("5"),
+        )
+        .await;
+        assert!(matches!(result, Err(RetryError::MessageNotFound(_))));
+        let result = handle_retry_receipt(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &recent,
+            &receipt("6"),
+        )
+        .await;
+        assert!(matches!(result, Err(RetryError::TooManyRetries(_))));
+    }
+}
@@ -0,0 +1,1071 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use libsignal_protocol::{
+    message_encrypt, process_prekey_bundle, CiphertextMessage, PreKeyBundle, ProtocolAddress,
+    SessionStore, SignalProtocolError,
+};
+use prost::Message as _;
+use rand::{rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use time::OffsetDateTime;
+use wa_binary::{
+    attrs::AttrErrors,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_proto::items::wa_web_protobufs_e2e::{
+    DeviceSentMessage, Message, SenderKeyDistributionMessage,
+};
+use wa_store::{signal::SignalStore, Device};
+use wa_types::{
+    jid::{GROUP_SERVER, JID, LEGACY_USER_SERVER},
+    message::MessageID,
+};
+
+use crate::{
+    group::{get_group_info, GroupError},
+    prekeys::{fetch_pre_key_bundles, PreKeyError},
+    request::{bytes_node, RequestError, RequestSender},
+    retry::RecentMessages,
+    sender_key::{self, SenderKeyError},
+    usync::{get_user_devices, UsyncError},
+};
+
+#[derive(Error, Debug)]
+pub enum SendError {
+    #[error("can't send messages before logging in")]
+    NotLoggedIn,
+    #[error("failed to get devices: {0}")]
+    Usync(#[from] UsyncError),
+    #[error("failed to get pre-keys: {0}")]
+    PreKey(#[from] PreKeyError),
+    #[error("failed to encrypt message: {0}")]
+    Signal(#[from] SignalProtocolError),
+    #[error("failed to encrypt group message: {0}")]
+    SenderKey(#[from] SenderKeyError),
+    #[error("failed to get group participants: {0}")]
+    Group(#[from] GroupError),
+    #[error("invalid attributes in server response: {0}")]
+    Attrs(#[from] AttrErrors),
+    #[error("server returned error {0} for message")]
+    Server(u64),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+}
+
+/// [`SendResponse`] contains the details the server returned about a sent message.
+#[derive(Clone, Debug)]
+pub struct SendResponse {
+    /// The time the server received the message.
+    pub timestamp: OffsetDateTime,
+    /// The ID of the message.
+    pub id: MessageID,
+}
+
+/// [`generate_message_id`] generates a new random ID for an outgoing message, in the same
+/// format as the official web client.
+pub fn generate_message_id(own_id: Option<&JID>) -> MessageID {
+    let mut hasher = Sha256::new();
+    hasher.update(OffsetDateTime::now_utc().unix_timestamp().to_be_bytes());
+    if let Some(own_id) = own_id {
+        hasher.update(own_id.user.as_bytes());
+        hasher.update(format!("@{LEGACY_USER_SERVER}").as_bytes());
+    }
+    hasher.update(OsRng.gen::<[u8; 16]>());
+    let id = hasher.finalize()[..9]
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<String>();
+    MessageID(format!("3EB0{id}"))
+}
+
+/// [`pad_message`] appends 1-15 bytes of random padding to an encoded message before
+/// encrypting it. The reverse of [`crate::receive::unpad_message`].
+pub(crate) fn pad_message(mut plaintext: Vec<u8>) -> Vec<u8> {
+    let padding = match OsRng.gen_range(0..16u8) {
+        0 => 15,
+        padding => padding,
+    };
+    plaintext.resize(plaintext.len() + padding as usize, padding);
+    plaintext
+}
+
+/// [`participant_list_hash`] hashes the devices a group message is sent to. The server
+/// compares it with its own list to detect outdated participant lists.
+pub fn participant_list_hash(devices: &[JID]) -> String {
+    let mut devices = devices.iter().map(JID::ad_string).collect::<Vec<_>>();
+    devices.sort();
+    let hash = Sha256::digest(devices.concat());
+    format!("2:{}", STANDARD_NO_PAD.encode(&hash[..6]))
+}
+
+pub(crate) fn message_type(message: &Message) -> &'static str {
+    if message.reaction_message.is_some() {
+        "reaction"
+    } else if message.image_message.is_some()
+        || message.video_message.is_some()
+        || message.audio_message.is_some()
+        || message.document_message.is_some()
+        || message.sticker_message.is_some()
+    {
+        "media"
+    } else {
+        "text"
+    }
+}
+
+/// Wraps a message sent to `to` for our own other devices, so they know who it was sent to.
+pub(crate) fn device_sent_message(to: &JID, message: Message) -> Message {
+    Message {
+        message_context_info: message.message_context_info.clone(),
+        device_sent_message: Some(Box::new(DeviceSentMessage {
+            destination_jid: Some(to.to_string()),
+            message: Some(Box::new(message)),
+            phash: None,
+        })),
+        ..Default::default()
+    }
+}
+
+/// Creates our sender key in the group if needed, and returns the message that distributes
+/// it to other participants.
+pub(crate) async fn sender_key_distribution_message(
+    signal_store: &SignalStore,
+    own_id: &JID,
+    group: &JID,
+) -> Result<SenderKeyDistributionMessage, SenderKeyError> {
+    let distribution = sender_key::create_sender_key_distribution_message(
+        signal_store,
+        group,
+        &own_id.signal_address(),
+    )
+    .await?;
+    Ok(SenderKeyDistributionMessage {
+        group_id: Some(group.to_string()),
+        axolotl_sender_key_distribution_message: Some(distribution),
+    })
+}
+
+/// The devices in a group, and which of them have received our sender key.
+#[derive(Default)]
+struct CachedGroup {
+    devices: Option<Vec<JID>>,
+    has_sender_key: HashSet<String>,
+}
+
+/// [`GroupCache`] remembers the devices of the participants of groups that messages were
+/// sent to, and which of them already have our sender key, so that the sender key is only
+/// distributed to new devices.
+///
+/// The cache is only kept in memory. After a restart the sender key is distributed to all
+/// devices again, which recipients handle like any other copy of the key.
+#[derive(Default)]
+pub struct GroupCache(Mutex<HashMap<JID, CachedGroup>>);
+
+impl GroupCache {
+    fn devices(&self, group: &JID) -> Option<Vec<JID>> {
+        let groups = self.0.lock().unwrap();
+        groups.get(group)?.devices.clone()
+    }
+
+    fn set_devices(&self, group: &JID, devices: Vec<JID>) {
+        let mut groups = self.0.lock().unwrap();
+        groups.entry(group.clone()).or_default().devices = Some(devices);
+    }
+
+    /// Forgets the device list of the group, so that it's fetched again for the next message.
+    /// The devices that have our sender key are still remembered.
+    pub fn invalidate_devices(&self, group: &JID) {
+        if let Some(cached) = self.0.lock().unwrap().get_mut(group) {
+            cached.devices = None;
+        }
+    }
+
+    fn has_sender_key(&self, group: &JID, device: &JID) -> bool {
+        let groups = self.0.lock().unwrap();
+        groups
+            .get(group)
+            .is_some_and(|cached| cached.has_sender_key.contains(&device.ad_string()))
+    }
+
+    fn add_sender_key_devices(&self, group: &JID, devices: &[JID]) {
+        let mut groups = self.0.lock().unwrap();
+        let cached = groups.entry(group.clone()).or_default();
+        cached
+            .has_sender_key
+            .extend(devices.iter().map(JID::ad_string));
+    }
+}
+
+fn is_own_device(jid: &JID, own_id: &JID) -> bool {
+    jid.user == own_id.user && jid.server == own_id.server && jid.device == own_id.device
+}
+
+/// Fetches the participants of a group, and then all of their devices.
+async fn get_group_devices(sender: &dyn RequestSender, group: &JID) -> Result<Vec<JID>, SendError> {
+    let participants = get_group_info(sender, group)
+        .await?
+        .participants
+        .into_iter()
+        .map(|participant| participant.jid)
+        .collect::<Vec<_>>();
+    Ok(get_user_devices(sender, &participants).await?)
+}
+
+/// Starts a session with the device from its pre-key bundle. If the bundle has a different
+/// identity than the one we trusted, the contact reinstalled WhatsApp, so the old identity
+/// and session are replaced like when receiving a message from the new install.
+pub(crate) async fn process_bundle(
+    signal_store: &SignalStore,
+    address: &ProtocolAddress,
+    bundle: &PreKeyBundle,
+) -> Result<(), SignalProtocolError> {
+    let process = || async {
+        process_prekey_bundle(
+            address,
+            &mut signal_store.clone(),
+            &mut signal_store.clone(),
+            bundle,
+            SystemTime::now(),
+            &mut OsRng,
+        )
+        .await
+    };
+    match process().await {
+        Err(SignalProtocolError::UntrustedIdentity(_)) => {
+            signal_store.clear_identity(address).await.map_err(|err| {
+                SignalProtocolError::InvalidState("clear_identity", err.to_string())
+            })?;
+            process().await
+        }
+        result => result,
+    }
+}
+
+/// The `<to>` nodes of a message, see [`encrypt_for_devices`].
+struct EncryptedParticipants {
+    nodes: Vec<Node>,
+    /// The devices that got a `<to>` node.
+    devices: Vec<JID>,
+    /// Whether any of the nodes contains a `pkmsg`, in which case the message must include
+    /// our device identity.
+    include_identity: bool,
+}
+
+/// Encrypts the plaintext for each device with its signal session, fetching pre-key bundles
+/// to start sessions with the devices that don't have one yet. Devices of our own user get
+/// `own_plaintext` instead.
+///
+/// Devices that the server has no bundle for are skipped, like the official clients do.
+async fn encrypt_for_devices(
+    sender: &dyn RequestSender,
+    signal_store: &mut SignalStore,
+    own_id: &JID,
+    devices: &[JID],
+    plaintext: &[u8],
+    own_plaintext: &[u8],
+) -> Result<EncryptedParticipants, SendError> {
+    let mut missing_sessions = Vec::new();
+    for device in devices {
+        if signal_store
+            .load_session(&device.signal_address())
+            .await?
+            .is_none()
+        {
+            missing_sessions.push(device.clone());
+        }
+    }
+    let mut skipped = HashSet::new();
+    if !missing_sessions.is_empty() {
+        for (jid, bundle) in fetch_pre_key_bundles(sender, &missing_sessions).await? {
+            match bundle {
+                Ok(bundle) => process_bundle(signal_store, &jid.signal_address(), &bundle).await?,
+                Err(_) => {
+                    skipped.insert(jid.ad_string());
+                }
+            }
+        }
+    }
+
+    let mut participants = EncryptedParticipants {
+        nodes: Vec::new(),
+        devices: Vec::new(),
+        include_identity: false,
+    };
+    for device in devices {
+        if skipped.contains(&device.ad_string()) {
+            continue;
+        }
+        let plaintext = if device.user == own_id.user {
+            own_plaintext
+        } else {
+            plaintext
+        };
+        let encrypted = match message_encrypt(
+            plaintext,
+            &device.signal_address(),
+            &mut signal_store.clone(),
+            &mut signal_store.clone(),
+            SystemTime::now(),
+        )
+        .await
+        {
+            Ok(encrypted) => encrypted,
+            // The bundle response didn't include this device, so there's no session.
+            Err(SignalProtocolError::SessionNotFound(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let enc_type = match encrypted {
+            CiphertextMessage::PreKeySignalMessage(_) => {
+                participants.include_identity = true;
+                "pkmsg"
+            }
+            _ => "msg",
+        };
+        participants.nodes.push(Node::new(
+            "to",
+            Attrs::from([("jid".to_string(), AttrValue::JID(device.clone()))]),
+            NodeContent::Nodes(vec![enc_node(enc_type, encrypted.serialize())]),
+        ));
+        participants.devices.push(device.clone());
+    }
+    Ok(participants)
+}
+
+pub(crate) fn enc_node(enc_type: &str, ciphertext: &[u8]) -> Node {
+    Node::new(
+        "enc",
+        Attrs::from([
+            ("v".to_string(), AttrValue::from("2")),
+            ("type".to_string(), AttrValue::from(enc_type)),
+        ]),
+        NodeContent::Bytes(ciphertext.to_vec()),
+    )
+}
+
+/// Builds the `<message>` stanza from the encrypted parts.
+fn message_node(
+    id: &MessageID,
+    to: &JID,
+    message_type: &str,
+    participants: Vec<Node>,
+    include_identity: bool,
+    device: &Device,
+    extra: Option<Node>,
+) -> Result<Node, SendError> {
+    let mut content = Vec::new();
+    if !participants.is_empty() {
+        content.push(Node::new(
+            "participants",
+            Attrs::new(),
+            NodeContent::Nodes(participants),
+        ));
+    }
+    if include_identity {
+        let account = device.account.as_ref().ok_or(SendError::NotLoggedIn)?;
+        content.push(bytes_node("device-identity", account.encode_to_vec()));
+    }
+    content.extend(extra);
+    Ok(Node::new(
+        "message",
+        Attrs::from([
+            ("id".to_string(), AttrValue::from(id.0.as_str())),
+            ("type".to_string(), AttrValue::from(message_type)),
+            ("to".to_string(), AttrValue::JID(to.clone())),
+        ]),
+        NodeContent::Nodes(content),
+    ))
+}
+
+/// The parts of the server's acknowledgement of a sent message.
+struct Ack {
+    timestamp: OffsetDateTime,
+    phash: Option<String>,
+}
+
+async fn send_and_parse_ack(sender: &dyn RequestSender, node: Node) -> Result<Ack, SendError> {
+    let response = sender.send_request(node).await?;
+    let mut ag = response.attr_getter();
+    let error = ag.optional_u64("error");
+    let timestamp = ag.unix_time("t");
+    let phash = ag.optional_string("phash").map(str::to_string);
+    ag.into_result()?;
+    match error {
+        Some(code) => Err(SendError::Server(code)),
+        None => Ok(Ack { timestamp, phash }),
+    }
+}
+
+async fn send_direct_message(
+    sender: &dyn RequestSender,
+    device: &Device,
+    signal_store: &mut SignalStore,
+    own_id: &JID,
+    id: &MessageID,
+    to: JID,
+    message: Message,
+) -> Result<OffsetDateTime, SendError> {
+    let devices = get_user_devices(sender, &[to.clone(), own_id.clone()])
+        .await?
+        .into_iter()
+        .filter(|jid| !is_own_device(jid, own_id))
+        .collect::<Vec<_>>();
+    let device_sent = device_sent_message(&to, message.clone());
+    let participants = encrypt_for_devices(
+        sender,
+        signal_store,
+        own_id,
+        &devices,
+        &pad_message(message.encode_to_vec()),
+        &pad_message(device_sent.encode_to_vec()),
+    )
+    .await?;
+    let node = message_node(
+        id,
+        &to,
+        message_type(&message),
+        participants.nodes,
+        participants.include_identity,
+        device,
+        None,
+    )?;
+    Ok(send_and_parse_ack(sender, node).await?.timestamp)
+}
+
+async fn send_group_message(
+    sender: &dyn RequestSender,
+    device: &Device,
+    signal_store: &mut SignalStore,
+    group_cache: &GroupCache,
+    id: &MessageID,
+    group: JID,
+    message: Message,
+) -> Result<OffsetDateTime, SendError> {
+    let own_id = device.id.as_ref().ok_or(SendError::NotLoggedIn)?;
+    let devices = match group_cache.devices(&group) {
+        Some(devices) => devices,
+        None => {
+            let devices = get_group_devices(sender, &group).await?;
+            group_cache.set_devices(&group, devices.clone());
+            devices
+        }
+    };
+    let distribution = pad_message(
+        Message {
+            sender_key_distribution_message: Some(
+                sender_key_distribution_message(signal_store, own_id, &group).await?,
+            ),
+            ..Default::default()
+        }
+        .encode_to_vec(),
+    );
+    let ciphertext = sender_key::group_encrypt(
+        signal_store,
+        &group,
+        &own_id.signal_address(),
+        &pad_message(message.encode_to_vec()),
+    )
+    .await?;
+
+    let new_devices = devices
+        .iter()
+        .filter(|jid| !is_own_device(jid, own_id) && !group_cache.has_sender_key(&group, jid))
+        .cloned()
+        .collect::<Vec<_>>();
+    let participants = encrypt_for_devices(
+        sender,
+        signal_store,
+        own_id,
+        &new_devices,
+        &distribution,
+        &distribution,
+    )
+    .await?;
+    let mut node = message_node(
+        id,
+        &group,
+        message_type(&message),
+        participants.nodes,
+        participants.include_identity,
+        device,
+        Some(enc_node("skmsg", &ciphertext)),
+    )?;
+    let phash = participant_list_hash(&devices);
+    node.attrs
+        .insert("phash".to_string(), AttrValue::from(phash.as_str()));
+    let ack = send_and_parse_ack(sender, node).await?;
+    group_cache.add_sender_key_devices(&group, &participants.devices);
+    // The server doesn't reject messages sent with an outdated participant list, it only
+    // returns the hash of the current one. The devices that were missing will ask for the
+    // message with a retry receipt, and the next message is sent to the fresh list.
+    if ack.phash.is_some_and(|server_phash| server_phash != phash) {
+        group_cache.invalidate_devices(&group);
+    }
+    Ok(ack.timestamp)
+}
+
+/// [`send_message`] encrypts and sends a message to a user or a group.
+///
+/// The message is encrypted separately for every device of the recipient and of our own
+/// user, starting signal sessions with pre-key bundles where needed. Our other devices get
+/// it wrapped in a [`DeviceSentMessage`] so they know who it was sent to. Group messages are
+/// encrypted once with our sender key, which is distributed in the same stanza to the
+/// devices of the participants that don't have it yet according to `group_cache`.
+///
+/// The message is kept in `recent_messages` so it can be sent again if a recipient fails to
+/// decrypt it.
+pub async fn send_message(
+    sender: &dyn RequestSender,
+    device: &Device,
+    signal_store: &mut SignalStore,
+    recent_messages: &RecentMessages,
+    group_cache: &GroupCache,
+    to: JID,
+    message: Message,
+) -> Result<SendResponse, SendError> {
+    let own_id = device.id.clone().ok_or(SendError::NotLoggedIn)?;
+    let id = generate_message_id(Some(&own_id));
+    recent_messages.add(&to, &id, message.clone());
+    let timestamp = if to.server == GROUP_SERVER {
+        send_group_message(sender, device, signal_store, group_cache, &id, to, message).await?
+    } else {
+        send_direct_message(sender, device, signal_store, &own_id, &id, to, message).await?
+    };
+    Ok(SendResponse { timestamp, id })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use async_trait::async_trait;
+    use wa_store::{IdentityStore, PreKeyStore};
+    use wa_types::jid::DEFAULT_USER_SERVER;
+
+    use super::*;
+    use crate::{
+        prekeys::tests::bundle_node,
+        receive::{decrypt_message, tests::TestPeer, unpad_message},
+        test_util::{FakeServer, Respond},
+    };
+
+    /// [`FakeDevices`] answers requests like the server would for a fixed set of devices.
+    pub(crate) struct FakeDevices {
+        devices: Vec<JID>,
+        /// The pre-key bundles by device, devices without one get a 404 error.
+        pub bundles: Mutex<HashMap<String, Node>>,
+        pub participants: Mutex<Vec<JID>>,
+        /// Participants that join the group after the first time it's queried.
+        pub joining: Mutex<Vec<JID>>,
+    }
+
+    /// Creates a server for the devices of the given peers.
+    pub(crate) async fn fake_server(peers: &[&TestPeer]) -> FakeServer<FakeDevices> {
+        let mut bundles = HashMap::new();
+        for peer in peers {
+            let pre_key = peer.store.gen_one_pre_key().await.unwrap();
+            bundles.insert(
+                peer.jid().ad_string(),
+                bundle_node(&peer.jid(), &peer.device, &pre_key),
+            );
+        }
+        FakeServer::new(FakeDevices {
+            devices: peers.iter().map(|peer| peer.jid()).collect(),
+            bundles: Mutex::new(bundles),
+            participants: Mutex::new(Vec::new()),
+            joining: Mutex::new(Vec::new()),
+        })
+    }
+
+    impl FakeDevices {
+        fn user_devices(&self, user: &JID) -> Node {
+            let devices = self
+                .devices
+                .iter()
+                .filter(|device| device.user == user.user)
+                .map(|device| {
+                    Node::new(
+                        "device",
+                        Attrs::from([(
+                            "id".to_string(),
+                            AttrValue::from(device.device.to_string()),
+                        )]),
+                        NodeContent::None,
+                    )
+                })
+                .collect();
+            Node::new(
+                "user",
+                Attrs::from([("jid".to_string(), AttrValue::JID(user.clone()))]),
+                NodeContent::Nodes(vec![Node::new(
+                    "devices",
+                    Attrs::new(),
+                    NodeContent::Nodes(vec![Node::new(
+                        "device-list",
+                        Attrs::new(),
+                        NodeContent::Nodes(devices),
+                    )]),
+                )]),
+            )
+        }
+
+        pub(crate) fn answer(&self, node: &Node) -> Node {
+            let children = |node: &Node, tags: &[&str]| {
+                node.get_optional_child_by_tag(tags)
+                    .map(|node| node.get_children().to_vec())
+                    .unwrap_or_default()
+            };
+            let jids = |nodes: Vec<Node>| {
+                nodes
+                    .into_iter()
+                    .map(|node| node.attr_getter().jid("jid"))
+                    .collect::<Vec<_>>()
+            };
+            let wrap = |tag: &str, content: Vec<Node>| {
+                Node::new(tag, Attrs::new(), NodeContent::Nodes(content))
+            };
+            let content = match (node.tag.as_str(), node.attrs.get("xmlns")) {
+                ("message", _) => {
+                    let mut devices = Vec::new();
+                    for participant in self.participants.lock().unwrap().iter() {
+                        devices.extend(
+                            self.devices
+                                .iter()
+                                .filter(|device| device.user == participant.user)
+                                .cloned(),
+                        );
+                    }
+                    let mut attrs = Attrs::from([
+                        ("id".to_string(), node.attrs["id"].clone()),
+                        ("t".to_string(), AttrValue::from("1700000000")),
+                    ]);
+                    if node.attrs.contains_key("phash") {
+                        attrs.insert(
+                            "phash".to_string(),
+                            AttrValue::from(participant_list_hash(&devices)),
+                        );
+                    }
+                    return Node::new("ack", attrs, NodeContent::None);
+                }
+                (_, Some(xmlns)) if *xmlns == AttrValue::from("usync") => {
+                    let users = jids(children(node, &["usync", "list"]));
+                    vec![wrap(
+                        "usync",
+                        vec![wrap(
+                            "list",
+                            users.iter().map(|user| self.user_devices(user)).collect(),
+                        )],
+                    )]
+                }
+                (_, Some(xmlns)) if *xmlns == AttrValue::from("encrypt") => {
+                    let devices = jids(children(node, &["key"]));
+                    let bundles = self.bundles.lock().unwrap();
+                    let bundle = |device: &JID| match bundles.get(&device.ad_string()) {
+                        Some(bundle) => bundle.clone(),
+                        None => Node::new(
+                            "user",
+                            Attrs::from([("jid".to_string(), AttrValue::JID(device.clone()))]),
+                            NodeContent::Nodes(vec![Node::new(
+                                "error",
+                                Attrs::from([
+                                    ("code".to_string(), AttrValue::from("404")),
+                                    ("text".to_string(), AttrValue::from("item-not-found")),
+                                ]),
+                                NodeContent::None,
+                            )]),
+                        ),
+                    };
+                    vec![wrap("list", devices.iter().map(bundle).collect())]
+                }
+                (_, Some(xmlns)) if *xmlns == AttrValue::from("w:g2") => {
+                    let mut participants = self.participants.lock().unwrap();
+                    let group = participants
+                        .iter()
+                        .map(|participant| {
+                            Node::new(
+                                "participant",
+                                Attrs::from([(
+                                    "jid".to_string(),
+                                    AttrValue::JID(participant.clone()),
+                                )]),
+                                NodeContent::None,
+                            )
+                        })
+                        .collect();
+                    participants.append(&mut *self.joining.lock().unwrap());
+                    let to = node.attr_getter().jid("to");
+                    vec![Node::new(
+                        "group",
+                        Attrs::from([
+                            ("id".to_string(), AttrValue::from(to.user)),
+                            ("subject".to_string(), AttrValue::from("Group")),
+                            ("s_t".to_string(), AttrValue::from("1700000000")),
+                            ("creation".to_string(), AttrValue::from("1700000000")),
+                        ]),
+                        NodeContent::Nodes(group),
+                    )]
+                }
+                _ => panic!("unexpected request {node:?}"),
+            };
+            Node::new(
+                "iq",
+                Attrs::from([
+                    ("id".to_string(), node.attrs["id"].clone()),
+                    ("type".to_string(), AttrValue::from("result")),
+                ]),
+                NodeContent::Nodes(content),
+            )
+        }
+    }
+
+    #[async_trait]
+    impl Respond for FakeDevices {
+        async fn respond(&self, request: &Node) -> Result<Node, RequestError> {
+            Ok(self.answer(request))
+        }
+    }
+
+    pub(crate) fn text(text: &str) -> Message {
+        Message {
+            conversation: Some(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn logged_in_peer(user: &str, device_id: u8) -> TestPeer {
+        let mut peer = TestPeer::new(user, device_id);
+        peer.device.account = Some(Default::default());
+        peer
+    }
+
+    /// Builds the stanza the server delivers to the given device.
+    pub(crate) fn delivered(sent: &Node, to: &JID, attrs: &[(&str, AttrValue)]) -> Node {
+        let mut content = sent
+            .get_optional_child_by_tag(&["participants"])
+            .into_iter()
+            .flat_map(|participants| participants.get_children_by_tag("to"))
+            .filter(|node| node.attrs["jid"] == AttrValue::JID(to.clone()))
+            .flat_map(|node| node.get_children().to_vec())
+            .collect::<Vec<_>>();
+        content.extend(sent.get_children_by_tag("enc").cloned());
+        let mut all_attrs = Attrs::from([
+            ("id".to_string(), sent.attrs["id"].clone()),
+            ("t".to_string(), AttrValue::from("1700000000")),
+            ("type".to_string(), sent.attrs["type"].clone()),
+        ]);
+        for (key, value) in attrs {
+            all_attrs.insert(key.to_string(), value.clone());
+        }
+        Node::new("message", all_attrs, NodeContent::Nodes(content))
+    }
+
+    #[test]
+    fn pads_messages() {
+        for _ in 0..32 {
+            let padded = pad_message(vec![1, 2, 3]);
+            assert!((4..=18).contains(&padded.len()));
+            assert_eq!(unpad_message(&padded).unwrap(), [1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn generates_message_ids() {
+        let own_id = JID::new_ad_jid("1111".to_string(), 0, 0);
+        let id = generate_message_id(Some(&own_id)).0;
+        assert_eq!(id.len(), 22);
+        assert!(id.starts_with("3EB0"));
+        assert!(id
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase()));
+        assert_ne!(id, generate_message_id(Some(&own_id)).0);
+    }
+
+    #[tokio::test]
+    async fn sends_direct_messages_to_all_devices() {
+        let mut alice = logged_in_peer("1111", 0);
+        let mut alice_phone = TestPeer::new("1111", 2);
+        let mut bob = TestPeer::new("2222", 0);
+        let mut bob_laptop = TestPeer::new("2222", 5);
+        let server = fake_server(&[&alice, &alice_phone, &bob, &bob_laptop]).await;
+        let bob_user = JID::new("2222".to_string(), DEFAULT_USER_SERVER.to_string());
+
+        let response = send_message(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &RecentMessages::default(),
+            &GroupCache::default(),
+            bob_user.clone(),
+            text("hello"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.timestamp.unix_timestamp(), 1700000000);
+        assert!(response.id.0.starts_with("3EB0"));
+
+        let sent = server.sent_messages();
+        let message = &sent[0];
+        assert_eq!(message.attrs["to"], AttrValue::JID(bob_user.clone()));
+        assert_eq!(message.attrs["type"], AttrValue::from("text"));
+        assert!(message
+            .get_optional_child_by_tag(&["device-identity"])
+            .is_some());
+        let recipients = message
+            .get_optional_child_by_tag(&["participants"])
+            .unwrap()
+            .get_children()
+            .len();
+        assert_eq!(recipients, 3);
+
+        for peer in [&mut bob, &mut bob_laptop] {
+            let node = delivered(
+                message,
+                &peer.jid(),
+                &[("from", AttrValue::JID(alice.jid()))],
+            );
+            let decrypted = decrypt_message(&node, &peer.device, &mut peer.signal)
+                .await
+                .unwrap();
+            assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+            assert_eq!(decrypted.messages[0].message.conversation(), "hello");
+        }
+
+        let node = delivered(
+            message,
+            &alice_phone.jid(),
+            &[
+                ("from", AttrValue::JID(alice.jid())),
+                ("recipient", AttrValue::JID(bob_user.clone())),
+            ],
+        );
+        let decrypted = decrypt_message(&node, &alice_phone.device, &mut alice_phone.signal)
+            .await
+            .unwrap();
+        let own_message = &decrypted.messages[0];
+        assert_eq!(own_message.message.conversation(), "hello");
+        assert_eq!(
+            own_message
+                .info
+                .device_sent_meta
+                .as_ref()
+                .unwrap()
+                .destination_jid,
+            bob_user.to_string()
+        );
+
+        // The sessions exist now, so the next message doesn't fetch pre-keys again.
+        send_message(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &RecentMessages::default(),
+            &GroupCache::default(),
+            bob_user,
+            text("again"),
+        )
+        .await
+        .unwrap();
+        let requests = server.sent.lock().unwrap();
+        let key_requests = requests
+            .iter()
+            .filter(|node| node.attrs.get("xmlns") == Some(&AttrValue::from("encrypt")))
+            .count();
+        assert_eq!(key_requests, 1);
+    }
+
+    #[tokio::test]
+    async fn sends_group_messages_and_handles_phash_mismatch() {
+        let mut alice = logged_in_peer("1111", 0);
+        let mut bob = TestPeer::new("2222", 0);
+        let mut carol = TestPeer::new("3333", 1);
+        let server = fake_server(&[&alice, &bob, &carol]).await;
+        let group = JID::new("1234-5678".to_string(), GROUP_SERVER.to_string());
+        let user = |peer: &TestPeer| peer.jid().to_non_ad();
+        *server.participants.lock().unwrap() = vec![user(&alice), user(&bob)];
+        *server.joining.lock().unwrap() = vec![user(&carol)];
+        let recent = RecentMessages::default();
+        let group_cache = GroupCache::default();
+
+        for content in ["first", "second", "third"] {
+            send_message(
+                &server,
+                &alice.device,
+                &mut alice.signal,
+                &recent,
+                &group_cache,
+                group.clone(),
+                text(content),
+            )
+            .await
+            .unwrap();
+        }
+
+        // Carol joined after the participant list was fetched, so the first message only went
+        // to Bob. The server's phash didn't match, so the list was fetched again for the
+        // second message, and only Carol got the sender key with it.
+        let sent = server.sent_messages();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(
+            sent[0].attrs["phash"],
+            AttrValue::from(participant_list_hash(&[alice.jid(), bob.jid()]))
+        );
+        assert_eq!(
+            sent[1].attrs["phash"],
+            AttrValue::from(participant_list_hash(&[
+                alice.jid(),
+                bob.jid(),
+                carol.jid()
+            ]))
+        );
+        let recipients = |node: &Node| {
+            node.get_optional_child_by_tag(&["participants"])
+                .map(|participants| {
+                    participants
+                        .get_children()
+                        .iter()
+                        .map(|to| to.attrs["jid"].clone())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        assert_eq!(recipients(&sent[0]), [AttrValue::JID(bob.jid())]);
+        assert_eq!(recipients(&sent[1]), [AttrValue::JID(carol.jid())]);
+        assert_eq!(recipients(&sent[2]), []);
+        let group_queries = server
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|node| node.attrs.get("xmlns") == Some(&AttrValue::from("w:g2")))
+            .count();
+        assert_eq!(group_queries, 2);
+
+        let deliveries = [
+            (&mut bob, &[(0, "first"), (1, "second"), (2, "third")][..]),
+            (&mut carol, &[(1, "second"), (2, "third")][..]),
+        ];
+        for (peer, messages) in deliveries {
+            for &(i, content) in messages {
+                let node = delivered(
+                    &sent[i],
+                    &peer.jid(),
+                    &[
+                        ("from", AttrValue::JID(group.clone())),
+                        ("participant", AttrValue::JID(alice.jid())),
+                    ],
+                );
+                let decrypted = decrypt_message(&node, &peer.device, &mut peer.signal)
+                    .await
+                    .unwrap();
+                assert!(decrypted.errors.is_empty(), "{:?}", decrypted.errors);
+                assert_eq!(decrypted.messages.len(), 1);
+                assert_eq!(decrypted.messages[0].message.conversation(), content);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_to_peers_that_reinstalled() {
+        let mut alice = logged_in_peer("1111", 0);
+        let old_bob = TestPeer::new("2222", 0);
+        let mut bob = TestPeer::new("2222", 0);
+        let server = fake_server(&[&alice, &bob]).await;
+        // Alice trusted Bob's identity from before they reinstalled WhatsApp.
+        alice
+            .store
+            .put_identity(
+                &bob.jid().signal_address().to_string(),
+                old_bob
+                    .device
+                    .identity_key
+                    .public_key
+                    .public_key_bytes()
+                    .try_into()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        send_message(
+            &server,
+            &alice.device,
+            &mut alice.signal,
+            &RecentMessages::default(),
+            &GroupCache::default(),
+            bob.jid().to_non_ad(),
+            text("hello"),
+        )
+        .await
+        .unwrap();
+
+        let sent = server.sent_messages();
+        let node = delivered(
+            &sent[0],
+            &bob.jid(),
+            &[("from", AttrValue::JID(alice.jid()))],
+        );
+        let decrypted = decrypt_message(&node, &bob.device, &mut bob.signal)
+            .await
+            .unwrap();
+        assert_eq!(decrypted.messages[0].message.conversation(), "hello");
+    }
+
+    #[tokio::test]
+    async fn only_marks_devices_that_got_the_sender_key() {
+        let mut alice = logged_in_peer("1111", 0);
+        let bob = TestPeer::new("2222", 0);
+        let carol = TestPeer::new("3333", 0);
+        let server = fake_server(&[&alice, &bob, &carol]).await;
+        let group = JID::new("1234-5678".to_string(), GROUP_SERVER.to_string());
+        *server.participants.lock().unwrap() = vec![
+            alice.jid().to_non_ad(),
+            bob.jid().to_non_ad(),
+            carol.jid().to_non_ad(),
+        ];
+        let carol_bundle = server
+            .bundles
+            .lock()
+            .unwrap()
+            .remove(&carol.jid().ad_string())
+            .unwrap();
+        let recent = RecentMessages::default();
+        let group_cache = GroupCache::default();
+
+        for content in ["first", "second"] {
+            send_message(
+                &server,
+                &alice.device,
+                &mut alice.signal,
+                &recent,
+                &group_cache,
+                group.clone(),
+                text(content),
+            )
+            .await
+            .unwrap();
+            // Carol's bundle is only available for the second message.
+            server
+                .bundles
+                .lock()
+                .unwrap()
+                .insert(carol.jid().ad_string(), carol_bundle.clone());
+        }
+
+        let recipients = server
+            .sent_messages()
+            .iter()
+            .map(|node| {
+                node.get_optional_child_by_tag(&["participants"])
+                    .map(|participants| participants.get_children().len())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(recipients, [1, 1]);
+        assert!(group_cache.has_sender_key(&group, &carol.jid()));
+    }
+}
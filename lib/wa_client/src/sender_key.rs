@@ -0,0 +1,459 @@
+use aes::{
+    cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit},
+    Aes256,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use libsignal_protocol::{KeyPair, PrivateKey, ProtocolAddress, PublicKey, SignalProtocolError};
+use prost::Message as _;
+use rand::{rngs::OsRng, Rng};
+use sha2::Sha256;
+use thiserror::Error;
+use wa_proto::items::wa_signal_groups::{
+    sender_key_state_structure::{SenderChainKey, SenderMessageKey, SenderSigningKey},
+    SenderKeyDistributionMessage, SenderKeyMessage, SenderKeyRecordStructure,
+    SenderKeyStateStructure,
+};
+use wa_store::{signal::SignalStore, StoreError};
+use wa_types::jid::JID;
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+
+/// [`SENDER_KEY_VERSION`] is the version byte that prefixes sender key messages, with the
+/// message version in the high nibble and the lowest supported version in the low one.
+const SENDER_KEY_VERSION: u8 = 0x33;
+const SIGNATURE_LENGTH: usize = 64;
+/// Sender key records keep the states of the last few keys a sender distributed, so that
+/// messages encrypted with an older key can still be decrypted.
+const MAX_STATES: usize = 5;
+/// [`MAX_MESSAGE_KEYS`] limits how many message keys of skipped iterations are kept per
+/// state, and how far ahead of the chain a message may be.
+const MAX_MESSAGE_KEYS: usize = 2000;
+
+#[derive(Error, Debug)]
+pub enum SenderKeyError {
+    #[error("unsupported sender key message version {0}")]
+    InvalidVersion(u8),
+    #[error("sender key message is too short")]
+    TooShort,
+    #[error("failed to unmarshal sender key message: {0}")]
+    Proto(#[from] prost::DecodeError),
+    #[error("missing {0} in sender key message")]
+    MissingField(&'static str),
+    #[error("no sender key state with ID {0}")]
+    NoSenderKeyState(u32),
+    #[error("invalid sender key message signature")]
+    InvalidSignature,
+    #[error("received message with old iteration {0}")]
+    DuplicateMessage(u32),
+    #[error("message iteration {0} is too far into the future")]
+    TooFarIntoFuture(u32),
+    #[error("failed to decrypt sender key message")]
+    InvalidCiphertext,
+    #[error("failed to access sender key: {0}")]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Signal(#[from] SignalProtocolError),
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn chain_message_key(chain_key: &SenderChainKey) -> SenderMessageKey {
+    SenderMessageKey {
+        iteration: chain_key.iteration,
+        seed: Some(hmac_sha256(chain_key.seed(), &[0x01]).to_vec()),
+    }
+}
+
+fn next_chain_key(chain_key: &SenderChainKey) -> SenderChainKey {
+    SenderChainKey {
+        iteration: Some(chain_key.iteration() + 1),
+        seed: Some(hmac_sha256(chain_key.seed(), &[0x02]).to_vec()),
+    }
+}
+
+/// Returns the AES-CBC key and IV derived from the seed of a message key.
+fn message_cipher(message_key: &SenderMessageKey) -> ([u8; 32], [u8; 16]) {
+    let mut derived = [0; 48];
+    Hkdf::<Sha256>::new(None, message_key.seed())
+        .expand(b"WhisperGroup", &mut derived)
+        .expect("48 bytes is a valid HKDF output length");
+    let (iv, key) = derived.split_at(16);
+    (key.try_into().unwrap(), iv.try_into().unwrap())
+}
+
+fn split_version(serialized: &[u8]) -> Result<(u8, &[u8]), SenderKeyError> {
+    let (&version, message) = serialized.split_first().ok_or(SenderKeyError::TooShort)?;
+    if version >> 4 != SENDER_KEY_VERSION >> 4 {
+        return Err(SenderKeyError::InvalidVersion(version >> 4));
+    }
+    Ok((version, message))
+}
+
+async fn load_record(
+    signal_store: &SignalStore,
+    group: &JID,
+    sender: &ProtocolAddress,
+) -> Result<SenderKeyRecordStructure, SenderKeyError> {
+    Ok(signal_store
+        .get_sender_key(group, sender)
+        .await?
+        .map(|record| SenderKeyRecordStructure::decode(record.as_slice()))
+        .transpose()?
+        .unwrap_or_default())
+}
+
+/// Adds a new state to the front of the record, forgetting the oldest ones.
+fn add_state(record: &mut SenderKeyRecordStructure, state: SenderKeyStateStructure) {
+    record.sender_key_states.insert(0, state);
+    record.sender_key_states.truncate(MAX_STATES);
+}
+
+/// [`create_sender_key_distribution_message`] returns the serialized sender key
+/// distribution message (SKDM) of our own sender key in the group, generating the key if we
+/// don't have one yet.
+///
+/// This uses WhatsApp's sender key format rather than the one of libsignal, which replaced
+/// the numeric key ID with a distribution UUID and isn't understood by other clients.
+pub async fn create_sender_key_distribution_message(
+    signal_store: &SignalStore,
+    group: &JID,
+    sender: &ProtocolAddress,
+) -> Result<Vec<u8>, SenderKeyError> {
+    let mut record = load_record(signal_store, group, sender).await?;
+    if record.sender_key_states.is_empty() {
+        let signing_key = KeyPair::generate(&mut OsRng);
+        add_state(
+            &mut record,
+            SenderKeyStateStructure {
+                sender_key_id: Some(OsRng.gen_range(0..=i32::MAX as u32)),
+                sender_chain_key: Some(SenderChainKey {
+                    iteration: Some(0),
+                    seed: Some(OsRng.gen::<[u8; 32]>().to_vec()),
+                }),
+                sender_signing_key: Some(SenderSigningKey {
+                    public: Some(signing_key.public_key.serialize().to_vec()),
+                    private: Some(signing_key.private_key.serialize()),
+                }),
+                sender_message_keys: Vec::new(),
+            },
+        );
+        signal_store
+            .put_sender_key(group, sender, &record.encode_to_vec())
+            .await?;
+    }
+    let state = &record.sender_key_states[0];
+    let chain_key = state.sender_chain_key.clone().unwrap_or_default();
+    let message = SenderKeyDistributionMessage {
+        id: state.sender_key_id,
+        iteration: chain_key.iteration,
+        chain_key: chain_key.seed,
+        signing_key: state
+            .sender_signing_key
+            .as_ref()
+            .and_then(|key| key.public.clone()),
+    };
+    let mut serialized = vec![SENDER_KEY_VERSION];
+    message
+        .encode(&mut serialized)
+        .expect("Vec has enough capacity");
+    Ok(serialized)
+}
+
+/// [`process_sender_key_distribution_message`] stores the sender key that `sender` sent in
+/// a serialized SKDM, so that its messages in the group can be decrypted.
+pub async fn process_sender_key_distribution_message(
+    signal_store: &SignalStore,
+    group: &JID,
+    sender: &ProtocolAddress,
+    serialized: &[u8],
+) -> Result<(), SenderKeyError> {
+    let (_, message) = split_version(serialized)?;
+    let message = SenderKeyDistributionMessage::decode(message)?;
+    let id = message.id.ok_or(SenderKeyError::MissingField("ID"))?;
+    let chain_key = message
+        .chain_key
+        .ok_or(SenderKeyError::MissingField("chain key"))?;
+    let signing_key = PublicKey::deserialize(
+        message
+            .signing_key
+            .as_deref()
+            .ok_or(SenderKeyError::MissingField("signing key"))?,
+    )?;
+
+    let mut record = load_record(signal_store, group, sender).await?;
+    record
+        .sender_key_states
+        .retain(|state| state.sender_key_id != Some(id));
+    add_state(
+        &mut record,
+        SenderKeyStateStructure {
+            sender_key_id: Some(id),
+            sender_chain_key: Some(SenderChainKey {
+                iteration: message.iteration,
+                seed: Some(chain_key),
+            }),
+            sender_signing_key: Some(SenderSigningKey {
+                public: Some(signing_key.serialize().to_vec()),
+                private: None,
+            }),
+            sender_message_keys: Vec::new(),
+        },
+    );
+    signal_store
+        .put_sender_key(group, sender, &record.encode_to_vec())
+        .await?;
+    Ok(())
+}
+
+/// [`group_encrypt`] encrypts a message with our own sender key in the group and returns
+/// the serialized `skmsg`. [`create_sender_key_distribution_message`] must have been called
+/// first.
+pub async fn group_encrypt(
+    signal_store: &SignalStore,
+    group: &JID,
+    sender: &ProtocolAddress,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, SenderKeyError> {
+    let mut record = load_record(signal_store, group, sender).await?;
+    let state = record
+        .sender_key_states
+        .first_mut()
+        .ok_or(SenderKeyError::NoSenderKeyState(0))?;
+    let signing_key = PrivateKey::deserialize(
+        state
+            .sender_signing_key
+            .as_ref()
+            .and_then(|key| key.private.as_deref())
+            .ok_or(SenderKeyError::MissingField("private signing key"))?,
+    )?;
+    let chain_key = state
+        .sender_chain_key
+        .take()
+        .ok_or(SenderKeyError::MissingField("chain key"))?;
+    let (key, iv) = message_cipher(&chain_message_key(&chain_key));
+    let message = SenderKeyMessage {
+        id: state.sender_key_id,
+        iteration: chain_key.iteration,
+        ciphertext: Some(
+            Aes256CbcEnc::new(&key.into(), &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(plaintext),
+        ),
+    };
+    state.sender_chain_key = Some(next_chain_key(&chain_key));
+
+    let mut serialized = vec![SENDER_KEY_VERSION];
+    message
+        .encode(&mut serialized)
+        .expect("Vec has enough capacity");
+    let signature = signing_key.calculate_signature(&serialized, &mut OsRng)?;
+    serialized.extend_from_slice(&signature);
+    signal_store
+        .put_sender_key(group, sender, &record.encode_to_vec())
+        .await?;
+    Ok(serialized)
+}
+
+/// Returns the message key of the iteration, advancing the chain key past it. Keys of
+/// skipped iterations are kept for messages that arrive out of order.
+fn message_key(
+    state: &mut SenderKeyStateStructure,
+    iteration: u32,
+) -> Result<SenderMessageKey, SenderKeyError> {
+    let mut chain_key = state.sender_chain_key.clone().unwrap_or_default();
+    if chain_key.iteration() > iteration {
+        let index = state
+            .sender_message_keys
+            .iter()
+            .position(|key| key.iteration() == iteration)
+            .ok_or(SenderKeyError::DuplicateMessage(iteration))?;
+        return Ok(state.sender_message_keys.remove(index));
+    }
+    if (iteration - chain_key.iteration()) as usize > MAX_MESSAGE_KEYS {
+        return Err(SenderKeyError::TooFarIntoFuture(iteration));
+    }
+    while chain_key.iteration() < iteration {
+        state
+            .sender_message_keys
+            .push(chain_message_key(&chain_key));
+        chain_key = next_chain_key(&chain_key);
+    }
+    let overflow = state
+        .sender_message_keys
+        .len()
+        .saturating_sub(MAX_MESSAGE_KEYS);
+    state.sender_message_keys.drain(..overflow);
+    state.sender_chain_key = Some(next_chain_key(&chain_key));
+    Ok(chain_message_key(&chain_key))
+}
+
+/// [`group_decrypt`] decrypts a serialized `skmsg` that `sender` sent in the group, using
+/// the sender key from an earlier SKDM.
+pub async fn group_decrypt(
+    signal_store: &SignalStore,
+    group: &JID,
+    sender: &ProtocolAddress,
+    serialized: &[u8],
+) -> Result<Vec<u8>, SenderKeyError> {
+    let signed_length = serialized
+        .len()
+        .checked_sub(SIGNATURE_LENGTH)
+        .ok_or(SenderKeyError::TooShort)?;
+    let (signed, signature) = serialized.split_at(signed_length);
+    let (_, message) = split_version(signed)?;
+    let message = SenderKeyMessage::decode(message)?;
+    let id = message.id.ok_or(SenderKeyError::MissingField("ID"))?;
+
+    let mut record = load_record(signal_store, group, sender).await?;
+    let state = record
+        .sender_key_states
+        .iter_mut()
+        .find(|state| state.sender_key_id == Some(id))
+        .ok_or(SenderKeyError::NoSenderKeyState(id))?;
+    let signing_key = PublicKey::deserialize(
+        state
+            .sender_signing_key
+            .as_ref()
+            .and_then(|key| key.public.as_deref())
+            .ok_or(SenderKeyError::MissingField("signing key"))?,
+    )?;
+    if !signing_key.verify_signature(signed, signature) {
+        return Err(SenderKeyError::InvalidSignature);
+    }
+
+    let (key, iv) = message_cipher(&message_key(state, message.iteration())?);
+    let plaintext = Aes256CbcDec::new(&key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(message.ciphertext())
+        .map_err(|_| SenderKeyError::InvalidCiphertext)?;
+    signal_store
+        .put_sender_key(group, sender, &record.encode_to_vec())
+        .await?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use wa_store::{memory::MemoryStore, Device};
+
+    use super::*;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn signal_store() -> SignalStore {
+        SignalStore::new(Arc::new(MemoryStore::new()), &Device::generate().unwrap())
+    }
+
+    /// A fixed SKDM and two `skmsg`s in WhatsApp's wire format. They were built
+    /// independently of this module from the format of libsignal-protocol-go's group
+    /// messages, which whatsmeow uses: key ID 0x1234567, a chain key of 0x42 bytes and an
+    /// XEdDSA signing key. The messages have iterations 2 and 0 and are decrypted in that
+    /// order, so the second one needs the message key kept while skipping ahead.
+    const SKDM: &str =
+        "3308e78a8d0910001a204242424242424242424242424242424242424242424242424242424242424242\
+                        2221054701d08488451f545a409fb58ae3e58581ca40ac3f7f114698cd71deac73ca01";
+    const SKMSG_2: &str = "3308e78a8d0910021a108ebc889ba9b8d97fd28d9760418be583b94daf88a9f2d9c6fea4fcfa\
+                           661caddd6a063e2161151661d930af44fb5b4f10afcc42f6fd7d743513241155167aeac9b8\
+                           d8642a31071f6e7e3ba8a4b9265287";
+    const SKMSG_0: &str = "3308e78a8d0910001a10dd861c61d037a8b1cc6998b66bb1d4620bfb29a099a8930c4fc8abe2\
+                           9ee5adcade093a78de0e36f5a3b66076c17493fa7b99a50d75cc25dd25805c9837aceee893\
+                           aa043cce6b53172d86654a29ae6689";
+
+    #[tokio::test]
+    async fn decrypts_fixed_messages() {
+        let signal_store = signal_store();
+        let group: JID = "123456789-123345@g.us".parse().unwrap();
+        let sender = JID::new_ad_jid("1234".to_string(), 0, 2).signal_address();
+        process_sender_key_distribution_message(&signal_store, &group, &sender, &hex(SKDM))
+            .await
+            .unwrap();
+
+        let (skmsg_2, skmsg_0) = (hex(SKMSG_2), hex(SKMSG_0));
+        let decrypt = |skmsg| group_decrypt(&signal_store, &group, &sender, skmsg);
+        assert_eq!(decrypt(&skmsg_2).await.unwrap(), b"third message");
+        assert_eq!(decrypt(&skmsg_0).await.unwrap(), b"first message");
+        assert!(matches!(
+            decrypt(&skmsg_0).await,
+            Err(SenderKeyError::DuplicateMessage(0))
+        ));
+
+        let mut tampered = skmsg_2.clone();
+        tampered[10] ^= 1;
+        assert!(matches!(
+            decrypt(&tampered).await,
+            Err(SenderKeyError::InvalidSignature)
+        ));
+
+        let other_group: JID = "123456789-999999@g.us".parse().unwrap();
+        assert!(matches!(
+            group_decrypt(&signal_store, &other_group, &sender, &skmsg_2).await,
+            Err(SenderKeyError::NoSenderKeyState(0x1234567))
+        ));
+    }
+
+    #[tokio::test]
+    async fn round_trips_own_sender_key() {
+        let alice = signal_store();
+        let bob = signal_store();
+        let group: JID = "123456789-123345@g.us".parse().unwrap();
+        let sender = JID::new_ad_jid("1111".to_string(), 0, 0).signal_address();
+
+        let skdm = create_sender_key_distribution_message(&alice, &group, &sender)
+            .await
+            .unwrap();
+        assert_eq!(
+            create_sender_key_distribution_message(&alice, &group, &sender)
+                .await
+                .unwrap(),
+            skdm
+        );
+        process_sender_key_distribution_message(&bob, &group, &sender, &skdm)
+            .await
+            .unwrap();
+
+        let first = group_encrypt(&alice, &group, &sender, b"hello")
+            .await
+            .unwrap();
+        let second = group_encrypt(&alice, &group, &sender, b"world")
+            .await
+            .unwrap();
+        assert_eq!(
+            group_decrypt(&bob, &group, &sender, &second).await.unwrap(),
+            b"world"
+        );
+        assert_eq!(
+            group_decrypt(&bob, &group, &sender, &first).await.unwrap(),
+            b"hello"
+        );
+
+        // A later SKDM continues from the current iteration of the chain.
+        let late = signal_store();
+        let skdm = create_sender_key_distribution_message(&alice, &group, &sender)
+            .await
+            .unwrap();
+        process_sender_key_distribution_message(&late, &group, &sender, &skdm)
+            .await
+            .unwrap();
+        assert!(matches!(
+            group_decrypt(&late, &group, &sender, &second).await,
+            Err(SenderKeyError::DuplicateMessage(1))
+        ));
+        let third = group_encrypt(&alice, &group, &sender, b"again")
+            .await
+            .unwrap();
+        assert_eq!(
+            group_decrypt(&late, &group, &sender, &third).await.unwrap(),
+            b"again"
+        );
+    }
+}
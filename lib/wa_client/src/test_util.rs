@@ -0,0 +1,90 @@
+use std::{ops::Deref, sync::Mutex};
+
+use async_trait::async_trait;
+use wa_binary::node::{AttrValue, Node, NodeContent};
+
+use crate::request::{RequestError, RequestSender};
+
+/// [`Respond`] is how a [`FakeServer`] answers requests.
+#[async_trait]
+pub(crate) trait Respond: Send + Sync {
+    async fn respond(&self, request: &Node) -> Result<Node, RequestError>;
+}
+
+#[async_trait]
+impl Respond for fn(&Node) -> Node {
+    async fn respond(&self, request: &Node) -> Result<Node, RequestError> {
+        Ok(self(request))
+    }
+}
+
+/// [`FakeServer`] records every node sent to it and answers requests with its responder,
+/// whose fields are available through [`Deref`].
+pub(crate) struct FakeServer<R> {
+    responder: R,
+    pub sent: Mutex<Vec<Node>>,
+}
+
+impl<R: Respond> FakeServer<R> {
+    pub(crate) fn new(responder: R) -> Self {
+        FakeServer {
+            responder,
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the `<message>` stanzas sent so far.
+    pub(crate) fn sent_messages(&self) -> Vec<Node> {
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|node| node.tag == "message")
+            .cloned()
+            .collect()
+    }
+}
+
+impl FakeServer<fn(&Node) -> Node> {
+    /// Creates a server that answers every request with the node returned by the function.
+    pub(crate) fn answering(respond: fn(&Node) -> Node) -> Self {
+        FakeServer::new(respond)
+    }
+}
+
+impl<R> Deref for FakeServer<R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.responder
+    }
+}
+
+#[async_trait]
+impl<R: Respond> RequestSender for FakeServer<R> {
+    fn generate_request_id(&self) -> String {
+        self.sent.lock().unwrap().len().to_string()
+    }
+
+    async fn send_request(&self, node: Node) -> Result<Node, RequestError> {
+        self.sent.lock().unwrap().push(node.clone());
+        self.responder.respond(&node).await
+    }
+
+    async fn send_node(&self, node: Node) -> Result<(), RequestError> {
+        self.sent.lock().unwrap().push(node);
+        Ok(())
+    }
+}
+
+/// Builds a node with string attributes.
+pub(crate) fn node(tag: &str, attrs: &[(&str, &str)], content: NodeContent) -> Node {
+    Node::new(
+        tag,
+        attrs
+            .iter()
+            .map(|(key, value)| (key.to_string(), AttrValue::from(*value)))
+            .collect(),
+        content,
+    )
+}
@@ -0,0 +1,138 @@
+use thiserror::Error;
+use wa_binary::{
+    attrs::AttrErrors,
+    node::{AttrValue, Attrs, Node, NodeContent},
+};
+use wa_types::jid::JID;
+
+use crate::request::{InfoQuery, IqType, RequestError, RequestSender};
+
+#[derive(Error, Debug)]
+pub enum UsyncError {
+    #[error("missing <{0}> in usync response")]
+    MissingNode(&'static str),
+    #[error("invalid attributes in usync response: {0}")]
+    Attrs(#[from] AttrErrors),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+}
+
+/// [`device_list_query`] builds a usync request for the devices of the given users.
+pub fn device_list_query(sid: &str, users: &[JID]) -> InfoQuery {
+    let user_nodes = users
+        .iter()
+        .map(|user| {
+            Node::new(
+                "user",
+                Attrs::from([("jid".to_string(), AttrValue::JID(user.clone().to_non_ad()))]),
+                NodeContent::None,
+            )
+        })
+        .collect();
+    InfoQuery::new(
+        "usync",
+        IqType::Get,
+        NodeContent::Nodes(vec![Node::new(
+            "usync",
+            Attrs::from([
+                ("sid".to_string(), AttrValue::from(sid)),
+                ("mode".to_string(), AttrValue::from("query")),
+                ("last".to_string(), AttrValue::from("true")),
+                ("index".to_string(), AttrValue::from("0")),
+                ("context".to_string(), AttrValue::from("message")),
+            ]),
+            NodeContent::Nodes(vec![
+                Node::new(
+                    "query",
+                    Attrs::new(),
+                    NodeContent::Nodes(vec![Node::new(
+                        "devices",
+                        Attrs::from([("version".to_string(), AttrValue::from("2"))]),
+                        NodeContent::None,
+                    )]),
+                ),
+                Node::new("list", Attrs::new(), NodeContent::Nodes(user_nodes)),
+            ]),
+        )]),
+    )
+}
+
+/// [`parse_device_list`] returns the device JIDs of all users in a usync response.
+pub fn parse_device_list(response: &Node) -> Result<Vec<JID>, UsyncError> {
+    let list = response
+        .get_optional_child_by_tag(&["usync", "list"])
+        .ok_or(UsyncError::MissingNode("list"))?;
+    let mut devices = Vec::new();
+    for user in list.get_children_by_tag("user") {
+        let mut ag = user.attr_getter();
+        let jid = ag.jid("jid");
+        ag.into_result()?;
+        let Some(device_list) = user.get_optional_child_by_tag(&["devices", "device-list"]) else {
+            continue;
+        };
+        for device in device_list.get_children_by_tag("device") {
+            let mut ag = device.attr_getter();
+            let device_id = ag.u64("id");
+            ag.into_result()?;
+            let mut device_jid = jid.clone();
+            device_jid.device = device_id as u16;
+            devices.push(device_jid);
+        }
+    }
+    Ok(devices)
+}
+
+/// [`get_user_devices`] fetches the devices of the given users.
+pub async fn get_user_devices(
+    sender: &dyn RequestSender,
+    users: &[JID],
+) -> Result<Vec<JID>, UsyncError> {
+    let query = device_list_query(&sender.generate_request_id(), users);
+    parse_device_list(&sender.send_iq(query).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_device_list() {
+        let device = |id: &str| {
+            Node::new(
+                "device",
+                Attrs::from([("id".to_string(), AttrValue::from(id))]),
+                NodeContent::None,
+            )
+        };
+        let user = JID::new("1234".to_string(), "s.whatsapp.net".to_string());
+        let response = Node::new(
+            "iq",
+            Attrs::new(),
+            NodeContent::Nodes(vec![Node::new(
+                "usync",
+                Attrs::new(),
+                NodeContent::Nodes(vec![Node::new(
+                    "list",
+                    Attrs::new(),
+                    NodeContent::Nodes(vec![Node::new(
+                        "user",
+                        Attrs::from([("jid".to_string(), AttrValue::JID(user.clone()))]),
+                        NodeContent::Nodes(vec![Node::new(
+                            "devices",
+                            Attrs::new(),
+                            NodeContent::Nodes(vec![Node::new(
+                                "device-list",
+                                Attrs::new(),
+                                NodeContent::Nodes(vec![device("0"), device("12")]),
+                            )]),
+                        )]),
+                    )]),
+                )]),
+            )]),
+        );
+        let devices = parse_device_list(&response).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].user, "1234");
+        assert_eq!(devices[1].device, 12);
+    }
+}
@@ -0,0 +1,36 @@
+use libsignal_protocol::PublicKey;
+use prost::Message;
+use wa_proto::items::wa_web_protobufs_wa6::ClientPayload;
+use wa_socket::{
+    frame_socket::FrameSocket,
+    noise_handshake::{self, ServerIdentity},
+    noise_socket::NoiseSocket,
+    transport::Transport,
+};
+
+use crate::MockServerError;
+
+/// [`HandshakeResult`] is what the client revealed about itself in the handshake.
+pub struct HandshakeResult {
+    /// The static noise key of the client.
+    pub client_static_key: PublicKey,
+    /// The client payload sent in the client finish message.
+    pub client_payload: ClientPayload,
+}
+
+/// [`accept_handshake`] performs the server side of the noise handshake on the given connected
+/// frame socket, see [`noise_handshake::accept_handshake`].
+pub async fn accept_handshake<T: Transport>(
+    frame_socket: FrameSocket<T>,
+    identity: &ServerIdentity,
+) -> Result<(NoiseSocket<T>, HandshakeResult), MockServerError> {
+    let (socket, client_static_key, payload) =
+        noise_handshake::accept_handshake(frame_socket, identity).await?;
+    Ok((
+        socket,
+        HandshakeResult {
+            client_static_key,
+            client_payload: ClientPayload::decode(payload.as_slice())?,
+        },
+    ))
+}
@@ -0,0 +1,270 @@
+//! A local websocket server that speaks the same frame and noise protocol as the real WhatsApp
+//! servers, for hermetic end-to-end tests.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), wa_mock_server::MockServerError> {
+//! use wa_mock_server::{stanza, MockServer};
+//!
+//! let mut server = MockServer::start().await?;
+//! // ... connect a client to `server.url()`, trusting `server.cert_root()` ...
+//! let mut connection = server.accept().await?;
+//! connection
+//!     .serve(|node| match node.tag.as_str() {
+//!         "iq" => vec![stanza::iq_result(node, Default::default())],
+//!         _ => Vec::new(),
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use libsignal_protocol::{PublicKey, SignalProtocolError};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_tungstenite::WebSocketStream;
+use wa_binary::{
+    decoder::{unmarshal, unpack, DecodeError},
+    encoder::{marshal, EncodeError},
+    node::Node,
+};
+use wa_proto::items::wa_web_protobufs_wa6::ClientPayload;
+use wa_socket::{
+    frame_socket::FrameSocket,
+    noise_handshake::{CertRoot, HandshakeError, ServerIdentity},
+    noise_socket::NoiseSocket,
+    SocketError,
+};
+
+use crate::handshake::accept_handshake;
+
+pub mod handshake;
+pub mod stanza;
+
+#[derive(Error, Debug)]
+pub enum MockServerError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error(transparent)]
+    Socket(#[from] SocketError),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
+    Signal(#[from] SignalProtocolError),
+    #[error("failed to unmarshal protobuf: {0}")]
+    Protobuf(#[from] prost::DecodeError),
+    #[error("failed to decode node: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("failed to encode node: {0}")]
+    Encode(#[from] EncodeError),
+    #[error("expected <{expected}> node, got {got}")]
+    UnexpectedNode { expected: String, got: Box<Node> },
+    #[error("mock server was shut down")]
+    ServerClosed,
+}
+
+/// [`MockServer`] accepts websocket connections on a random local port and performs the noise
+/// handshake with a self-signed certificate chain.
+pub struct MockServer {
+    address: SocketAddr,
+    identity: Arc<ServerIdentity>,
+    connections: mpsc::UnboundedReceiver<Result<MockConnection, MockServerError>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts listening on `127.0.0.1` with a newly generated server identity.
+    pub async fn start() -> Result<Self, MockServerError> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let address = listener.local_addr()?;
+        let identity = Arc::new(ServerIdentity::generate());
+        let (sender, connections) = mpsc::unbounded_channel();
+
+        let accept_identity = identity.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let identity = accept_identity.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let _ = sender.send(MockConnection::accept(stream, &identity).await);
+                });
+            }
+        });
+
+        Ok(MockServer {
+            address,
+            identity,
+            connections,
+            accept_task,
+        })
+    }
+
+    /// Returns the websocket URL that clients should connect to.
+    pub fn url(&self) -> String {
+        format!("ws://{}/ws/chat", self.address)
+    }
+
+    /// Returns the certificate root that clients must trust to connect to this server.
+    pub fn cert_root(&self) -> &CertRoot {
+        &self.identity.cert_root
+    }
+
+    /// Waits for the next client to connect and finish the handshake.
+    pub async fn accept(&mut self) -> Result<MockConnection, MockServerError> {
+        self.connections
+            .recv()
+            .await
+            .ok_or(MockServerError::ServerClosed)?
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// [`MockConnection`] is a single client connection to a [`MockServer`] that has finished the
+/// handshake. Tests script the conversation by receiving the nodes the client sends and
+/// answering with nodes of their own.
+pub struct MockConnection {
+    socket: NoiseSocket<WebSocketStream<TcpStream>>,
+    /// The static noise key of the client.
+    pub client_static_key: PublicKey,
+    /// The client payload sent at the end of the handshake.
+    pub client_payload: ClientPayload,
+}
+
+impl MockConnection {
+    async fn accept(stream: TcpStream, identity: &ServerIdentity) -> Result<Self, MockServerError> {
+        let websocket = tokio_tungstenite::accept_async(stream).await?;
+        let mut frame_socket = FrameSocket::server();
+        frame_socket.connect(websocket)?;
+
+        let (socket, result) = accept_handshake(frame_socket, identity).await?;
+        Ok(MockConnection {
+            socket,
+            client_static_key: result.client_static_key,
+            client_payload: result.client_payload,
+        })
+    }
+
+    /// Sends the given node to the client.
+    pub async fn send_node(&mut self, node: &Node) -> Result<(), MockServerError> {
+        let data = marshal(node)?;
+        self.socket.send_frame(&data).await?;
+        Ok(())
+    }
+
+    /// Waits for the next node from the client.
+    pub async fn receive_node(&mut self) -> Result<Node, MockServerError> {
+        let data = self.socket.receive_frame().await?;
+        Ok(unmarshal(&unpack(&data)?)?)
+    }
+
+    /// Waits for the next node from the client and checks that it has the given tag.
+    pub async fn expect_node(&mut self, tag: &str) -> Result<Node, MockServerError> {
+        let node = self.receive_node().await?;
+        if node.tag != tag {
+            return Err(MockServerError::UnexpectedNode {
+                expected: tag.to_string(),
+                got: Box::new(node),
+            });
+        }
+        Ok(node)
+    }
+
+    /// Answers every node the client sends with the nodes returned by the handler, until the
+    /// client disconnects.
+    pub async fn serve<F>(&mut self, mut handler: F) -> Result<(), MockServerError>
+    where
+        F: FnMut(&Node) -> Vec<Node>,
+    {
+        loop {
+            let node = match self.receive_node().await {
+                Ok(node) => node,
+                Err(MockServerError::Socket(SocketError::SocketClosed)) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            for response in handler(&node) {
+                self.send_node(&response).await?;
+            }
+        }
+    }
+
+    /// Closes the connection.
+    pub async fn close(&mut self) {
+        self.socket.close().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsignal_protocol::KeyPair;
+    use prost::Message;
+    use rand::rngs::OsRng;
+    use wa_binary::node::{AttrValue, Attrs, NodeContent};
+    use wa_socket::{noise_handshake::do_handshake, transport::connect_websocket};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn client_handshake_and_scripted_iq() {
+        let mut server = MockServer::start().await.unwrap();
+
+        let noise_key = KeyPair::generate(&mut OsRng);
+        let payload = ClientPayload {
+            username: Some(1234),
+            ..Default::default()
+        };
+        let mut frame_socket = FrameSocket::new();
+        frame_socket
+            .connect(connect_websocket(&server.url()).await.unwrap())
+            .unwrap();
+        let mut client = do_handshake(
+            frame_socket,
+            &noise_key,
+            &payload.encode_to_vec(),
+            server.cert_root(),
+        )
+        .await
+        .unwrap();
+
+        let mut connection = server.accept().await.unwrap();
+        assert_eq!(connection.client_payload.username, Some(1234));
+        assert_eq!(connection.client_static_key, noise_key.public_key);
+
+        let server_task = tokio::spawn(async move {
+            connection
+                .serve(|node| vec![stanza::iq_result(node, NodeContent::None)])
+                .await
+        });
+
+        let request = Node::new(
+            "iq",
+            Attrs::from([
+                ("id".to_string(), AttrValue::from("1")),
+                ("type".to_string(), AttrValue::from("get")),
+            ]),
+            NodeContent::None,
+        );
+        client
+            .send_frame(&marshal(&request).unwrap())
+            .await
+            .unwrap();
+        let response = unmarshal(&unpack(&client.receive_frame().await.unwrap()).unwrap()).unwrap();
+        let mut ag = response.attr_getter();
+        assert_eq!(ag.string("id"), "1");
+        assert_eq!(ag.string("type"), "result");
+
+        client.close().await;
+        server_task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use wa_binary::node::{AttrValue, Attrs, Node, NodeContent};
+use wa_types::jid::{DEFAULT_USER_SERVER, JID};
+
+fn server_jid() -> JID {
+    JID::new(String::new(), DEFAULT_USER_SERVER.to_string())
+}
+
+fn request_id(request: &Node) -> AttrValue {
+    request
+        .attrs
+        .get("id")
+        .cloned()
+        .unwrap_or_else(|| AttrValue::from(""))
+}
+
+/// [`iq_result`] builds a successful response to the given `<iq>` request.
+pub fn iq_result(request: &Node, content: NodeContent) -> Node {
+    Node::new(
+        "iq",
+        Attrs::from([
+            ("id".to_string(), request_id(request)),
+            ("type".to_string(), AttrValue::from("result")),
+            ("from".to_string(), AttrValue::JID(server_jid())),
+        ]),
+        content,
+    )
+}
+
+/// [`iq_error`] builds an error response to the given `<iq>` request.
+pub fn iq_error(request: &Node, code: u16, text: &str) -> Node {
+    Node::new(
+        "iq",
+        Attrs::from([
+            ("id".to_string(), request_id(request)),
+            ("type".to_string(), AttrValue::from("error")),
+            ("from".to_string(), AttrValue::JID(server_jid())),
+        ]),
+        NodeContent::Nodes(vec![Node::new(
+            "error",
+            Attrs::from([
+                ("code".to_string(), AttrValue::from(code.to_string())),
+                ("text".to_string(), AttrValue::from(text)),
+            ]),
+            NodeContent::None,
+        )]),
+    )
+}
+
+/// [`message`] builds an incoming `<message>` stanza from the given sender.
+pub fn message(id: &str, from: JID, timestamp: i64, content: Vec<Node>) -> Node {
+    Node::new(
+        "message",
+        Attrs::from([
+            ("id".to_string(), AttrValue::from(id)),
+            ("from".to_string(), AttrValue::JID(from)),
+            ("t".to_string(), AttrValue::from(timestamp.to_string())),
+            ("type".to_string(), AttrValue::from("text")),
+        ]),
+        NodeContent::Nodes(content),
+    )
+}
+
+/// [`receipt`] builds an incoming `<receipt>` stanza. An empty receipt type means the message
+/// was delivered.
+pub fn receipt(id: &str, from: JID, receipt_type: &str, timestamp: i64) -> Node {
+    let mut attrs = Attrs::from([
+        ("id".to_string(), AttrValue::from(id)),
+        ("from".to_string(), AttrValue::JID(from)),
+        ("t".to_string(), AttrValue::from(timestamp.to_string())),
+    ]);
+    if !receipt_type.is_empty() {
+        attrs.insert("type".to_string(), AttrValue::from(receipt_type));
+    }
+    Node::new("receipt", attrs, NodeContent::None)
+}
@@ -0,0 +1,282 @@
+use crate::{transport::Transport, SocketError, FRAME_LENGTH_SIZE, FRAME_MAX_SIZE, WA_CONN_HEADER};
+
+/// [`FrameSocket`] sends and receives length-prefixed frames over a [`Transport`].
+///
+/// Every frame is prefixed with its length as a [`FRAME_LENGTH_SIZE`] byte big-endian integer.
+/// The connection header is sent once per connection, in front of the first frame.
+pub struct FrameSocket<T> {
+    transport: Option<T>,
+    header: Vec<u8>,
+    expected_header: Vec<u8>,
+    header_sent: bool,
+    header_received: bool,
+    incoming: Vec<u8>,
+}
+
+impl<T: Transport> Default for FrameSocket<T> {
+    fn default() -> Self {
+        FrameSocket::new()
+    }
+}
+
+impl<T: Transport> FrameSocket<T> {
+    /// Creates a new disconnected frame socket that sends [`WA_CONN_HEADER`] with the first
+    /// frame.
+    pub fn new() -> Self {
+        FrameSocket::with_header(WA_CONN_HEADER.to_vec())
+    }
+
+    /// Creates a new disconnected frame socket that sends the given header with the first
+    /// frame.
+    pub fn with_header(header: Vec<u8>) -> Self {
+        FrameSocket {
+            transport: None,
+            header,
+            expected_header: Vec::new(),
+            header_sent: false,
+            header_received: false,
+            incoming: Vec::new(),
+        }
+    }
+
+    /// Creates a new disconnected frame socket for the server side of a connection, which
+    /// doesn't send a header but expects [`WA_CONN_HEADER`] in front of the first received
+    /// frame.
+    pub fn server() -> Self {
+        FrameSocket {
+            transport: None,
+            header: Vec::new(),
+            expected_header: WA_CONN_HEADER.to_vec(),
+            header_sent: false,
+            header_received: false,
+            incoming: Vec::new(),
+        }
+    }
+
+    /// Returns true if the socket has an open transport.
+    pub fn is_connected(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    /// Starts using the given transport for sending and receiving frames.
+    pub fn connect(&mut self, transport: T) -> Result<(), SocketError> {
+        if self.transport.is_some() {
+            return Err(SocketError::SocketAlreadyOpen);
+        }
+        self.transport = Some(transport);
+        self.header_sent = false;
+        self.header_received = false;
+        self.incoming.clear();
+        Ok(())
+    }
+
+    /// Closes the underlying transport. Closing an already closed socket does nothing.
+    pub async fn close(&mut self) {
+        if let Some(mut transport) = self.transport.take() {
+            // The connection is being thrown away anyway, so errors don't matter here.
+            let _ = transport.close().await;
+        }
+        self.incoming.clear();
+    }
+
+    /// Sends the given data as one frame, prefixed with the connection header if this is the
+    /// first frame.
+    pub async fn send_frame(&mut self, data: &[u8]) -> Result<(), SocketError> {
+        let transport = self.transport.as_mut().ok_or(SocketError::SocketClosed)?;
+
+        let data_length = data.len();
+        if data_length >= FRAME_MAX_SIZE {
+            return Err(SocketError::FrameTooLarge);
+        }
+
+        let header: &[u8] = if self.header_sent { &[] } else { &self.header };
+        let mut whole_frame = Vec::with_capacity(header.len() + FRAME_LENGTH_SIZE + data_length);
+        whole_frame.extend_from_slice(header);
+        whole_frame.extend_from_slice(&(data_length as u32).to_be_bytes()[4 - FRAME_LENGTH_SIZE..]);
+        whole_frame.extend_from_slice(data);
+
+        if let Err(err) = transport.send(&whole_frame).await {
+            self.close().await;
+            return Err(err.into());
+        }
+        self.header_sent = true;
+        Ok(())
+    }
+
+    /// Returns the next complete frame received from the transport.
+    ///
+    /// This is cancel safe: partially received frames are kept in the socket and completed by
+    /// the next call.
+    pub async fn receive_frame(&mut self) -> Result<Vec<u8>, SocketError> {
+        loop {
+            match self.take_frame() {
+                Ok(Some(frame)) => return Ok(frame),
+                Ok(None) => {}
+                // The rest of the stream can't be framed anymore.
+                Err(err) => {
+                    self.close().await;
+                    return Err(err);
+                }
+            }
+
+            let transport = self.transport.as_mut().ok_or(SocketError::SocketClosed)?;
+            match transport.receive().await {
+                Ok(Some(data)) => self.incoming.extend_from_slice(&data),
+                Ok(None) => {
+                    self.close().await;
+                    return Err(SocketError::SocketClosed);
+                }
+                Err(err) => {
+                    self.close().await;
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> Result<Option<Vec<u8>>, SocketError> {
+        if !self.header_received {
+            if self.incoming.len() < self.expected_header.len() {
+                return Ok(None);
+            }
+            if !self.incoming.starts_with(&self.expected_header) {
+                return Err(SocketError::InvalidHeader);
+            }
+            self.incoming.drain(..self.expected_header.len());
+            self.header_received = true;
+        }
+
+        if self.incoming.len() < FRAME_LENGTH_SIZE {
+            return Ok(None);
+        }
+
+        let length = self.incoming[..FRAME_LENGTH_SIZE]
+            .iter()
+            .fold(0usize, |length, &byte| length << 8 | byte as usize);
+        if length >= FRAME_MAX_SIZE {
+            return Err(SocketError::FrameTooLarge);
+        }
+        if self.incoming.len() < FRAME_LENGTH_SIZE + length {
+            return Ok(None);
+        }
+
+        let rest = self.incoming.split_off(FRAME_LENGTH_SIZE + length);
+        let mut frame = std::mem::replace(&mut self.incoming, rest);
+        frame.drain(..FRAME_LENGTH_SIZE);
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::transport::StreamTransport;
+
+    #[tokio::test]
+    async fn frames_round_trip_over_duplex() {
+        let (client, server) = duplex(64);
+        let mut client_socket = FrameSocket::new();
+        client_socket.connect(StreamTransport::new(client)).unwrap();
+        assert!(matches!(
+            client_socket.connect(StreamTransport::new(duplex(1).0)),
+            Err(SocketError::SocketAlreadyOpen)
+        ));
+
+        let mut server_socket = FrameSocket::server();
+        server_socket.connect(StreamTransport::new(server)).unwrap();
+
+        let big = vec![0x42; 1000];
+        let sent = big.clone();
+        let sender = tokio::spawn(async move {
+            client_socket.send_frame(b"hello").await.unwrap();
+            client_socket.send_frame(&sent).await.unwrap();
+            client_socket.send_frame(&[]).await.unwrap();
+            client_socket
+        });
+
+        assert_eq!(server_socket.receive_frame().await.unwrap(), b"hello");
+        assert_eq!(server_socket.receive_frame().await.unwrap(), big);
+        assert_eq!(server_socket.receive_frame().await.unwrap(), b"");
+
+        let mut client_socket = sender.await.unwrap();
+        server_socket.send_frame(b"reply").await.unwrap();
+        assert_eq!(client_socket.receive_frame().await.unwrap(), b"reply");
+
+        client_socket.close().await;
+        assert!(!client_socket.is_connected());
+        assert!(matches!(
+            client_socket.send_frame(b"closed").await,
+            Err(SocketError::SocketClosed)
+        ));
+        assert!(matches!(
+            server_socket.receive_frame().await,
+            Err(SocketError::SocketClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn partial_frames_are_reassembled() {
+        let (client, mut server) = duplex(1024);
+        let mut socket = FrameSocket::new();
+        socket.connect(StreamTransport::new(client)).unwrap();
+
+        server.write_all(&[0, 0, 5, b'h', b'e']).await.unwrap();
+        let receive = tokio::spawn(async move {
+            let first = socket.receive_frame().await.unwrap();
+            let second = socket.receive_frame().await.unwrap();
+            let closed = socket.receive_frame().await;
+            (first, second, closed)
+        });
+        tokio::task::yield_now().await;
+        server.write_all(&[b'l', b'l', b'o', 0, 0]).await.unwrap();
+        server.write_all(&[2, b'h', b'i']).await.unwrap();
+        drop(server);
+
+        let (first, second, closed) = receive.await.unwrap();
+        assert_eq!(first, b"hello");
+        assert_eq!(second, b"hi");
+        assert!(matches!(closed, Err(SocketError::SocketClosed)));
+    }
+
+    #[tokio::test]
+    async fn header_is_sent_with_first_frame_only() {
+        let (client, mut server) = duplex(1024);
+        let mut socket = FrameSocket::new();
+        socket.connect(StreamTransport::new(client)).unwrap();
+
+        socket.send_frame(b"ab").await.unwrap();
+        socket.send_frame(b"c").await.unwrap();
+        assert!(matches!(
+            socket.send_frame(&vec![0; FRAME_MAX_SIZE]).await,
+            Err(SocketError::FrameTooLarge)
+        ));
+        socket.close().await;
+
+        let mut written = Vec::new();
+        server.read_to_end(&mut written).await.unwrap();
+        let mut expected = WA_CONN_HEADER.to_vec();
+        expected.extend([0, 0, 2, b'a', b'b', 0, 0, 1, b'c']);
+        assert_eq!(written, expected);
+    }
+
+    #[tokio::test]
+    async fn invalid_header_closes_the_socket() {
+        let (mut client, server) = duplex(1024);
+        let mut socket = FrameSocket::server();
+        socket.connect(StreamTransport::new(server)).unwrap();
+
+        client.write_all(b"GET / HTTP/1.1").await.unwrap();
+        assert!(matches!(
+            socket.receive_frame().await,
+            Err(SocketError::InvalidHeader)
+        ));
+        assert!(!socket.is_connected());
+
+        // The transport was closed, so the client reads the end of the stream.
+        let mut written = Vec::new();
+        client.read_to_end(&mut written).await.unwrap();
+        assert!(written.is_empty());
+    }
+}
@@ -1,5 +1,12 @@
+use std::{io, sync::Arc};
+
 use thiserror::Error;
 
+pub mod frame_socket;
+pub mod noise_handshake;
+pub mod noise_socket;
+pub mod transport;
+
 /// [`ORIGIN`] is the Origin header for all WhatsApp websocket connections.
 pub const ORIGIN: &str = "https://web.whatsapp.com";
 /// [`URL`] is the websocket URL for the new multidevice protocol.
@@ -15,10 +22,26 @@ pub const FRAME_LENGTH_SIZE: usize = 3;
 
 #[derive(Error, Clone, Debug)]
 pub enum SocketError {
+    #[error("transport error: {0}")]
+    Transport(Arc<io::Error>),
+    #[error("invalid connection header")]
+    InvalidHeader,
     #[error("frame too large")]
     FrameTooLarge,
     #[error("frame socket is closed")]
     SocketClosed,
     #[error("frame socket is already open")]
     SocketAlreadyOpen,
+    #[error("ran out of nonces for the noise socket")]
+    CounterOverflow,
+    #[error("failed to encrypt frame")]
+    EncryptFailed,
+    #[error("failed to decrypt frame: authentication failed")]
+    DecryptFailed,
+}
+
+impl From<io::Error> for SocketError {
+    fn from(err: io::Error) -> Self {
+        SocketError::Transport(Arc::new(err))
+    }
 }
@@ -0,0 +1,643 @@
+use std::time::Duration;
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use hkdf::Hkdf;
+use libsignal_protocol::{KeyPair, PrivateKey, PublicKey, SignalProtocolError};
+use prost::Message;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use wa_proto::items::{
+    wa_cert::{
+        cert_chain::{noise_certificate::Details, NoiseCertificate},
+        CertChain,
+    },
+    wa_web_protobufs_wa6::{
+        handshake_message::{ClientFinish, ClientHello, ServerHello},
+        HandshakeMessage,
+    },
+};
+
+use crate::{
+    frame_socket::FrameSocket, noise_socket::NoiseSocket, transport::Transport, SocketError,
+    NOISE_START_PATTERN,
+};
+
+/// [`HANDSHAKE_RESPONSE_TIMEOUT`] is how long to wait for the server hello before giving up.
+pub const HANDSHAKE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// [`CertRoot`] is the trusted root of the certificate chain the server sends during the
+/// handshake.
+#[derive(Clone, Debug)]
+pub struct CertRoot {
+    /// The public key that signs the intermediate certificate.
+    pub public_key: [u8; 32],
+    /// The serial that the intermediate certificate must have as its issuer serial.
+    pub issuer_serial: u32,
+}
+
+/// [`WA_CERT_ROOT`] is the root of the certificate chain used by the real WhatsApp servers.
+pub const WA_CERT_ROOT: CertRoot = CertRoot {
+    public_key: [
+        0x14, 0x23, 0x75, 0x57, 0x4d, 0x0a, 0x58, 0x71, 0x66, 0xaa, 0xe7, 0x1e, 0xbe, 0x51, 0x64,
+        0x37, 0xc4, 0xa2, 0x8b, 0x73, 0xe3, 0x69, 0x5c, 0x6c, 0xe1, 0xf7, 0xf9, 0x54, 0x5d, 0xa8,
+        0xee, 0x6b,
+    ],
+    issuer_serial: 0,
+};
+
+/// [`ServerIdentity`] is the static key of a server and a root -> intermediate -> leaf
+/// certificate chain for it. It's used to act as the server side of the handshake in tests and
+/// the mock server.
+pub struct ServerIdentity {
+    /// The root that clients must trust to accept the certificate chain.
+    pub cert_root: CertRoot,
+    pub static_key: KeyPair,
+    /// The marshaled `CertChain` protobuf.
+    pub cert_chain: Vec<u8>,
+}
+
+fn sign_certificate(details: Details, signer: &KeyPair) -> NoiseCertificate {
+    let details = details.encode_to_vec();
+    let signature = signer
+        .calculate_signature(&details, &mut OsRng)
+        .expect("signing with a freshly generated key can't fail");
+    NoiseCertificate {
+        details: Some(details),
+        signature: Some(signature.to_vec()),
+    }
+}
+
+impl ServerIdentity {
+    /// Generates a new static key and a certificate chain for it with a new root.
+    pub fn generate() -> Self {
+        let root = KeyPair::generate(&mut OsRng);
+        let intermediate = KeyPair::generate(&mut OsRng);
+        let static_key = KeyPair::generate(&mut OsRng);
+
+        let cert_chain = CertChain {
+            intermediate: Some(sign_certificate(
+                Details {
+                    serial: Some(1),
+                    issuer_serial: Some(0),
+                    key: Some(intermediate.public_key.public_key_bytes().to_vec()),
+                    ..Default::default()
+                },
+                &root,
+            )),
+            leaf: Some(sign_certificate(
+                Details {
+                    serial: Some(2),
+                    issuer_serial: Some(1),
+                    key: Some(static_key.public_key.public_key_bytes().to_vec()),
+                    ..Default::default()
+                },
+                &intermediate,
+            )),
+        };
+
+        ServerIdentity {
+            cert_root: CertRoot {
+                public_key: root
+                    .public_key
+                    .public_key_bytes()
+                    .try_into()
+                    .expect("curve25519 public keys are 32 bytes"),
+                issuer_serial: 0,
+            },
+            static_key,
+            cert_chain: cert_chain.encode_to_vec(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("failed to unmarshal handshake message: {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error(transparent)]
+    Socket(#[from] SocketError),
+    #[error("timed out waiting for handshake response")]
+    Timeout,
+    #[error("unexpected server hello: {0}")]
+    UnexpectedServerHello(&'static str),
+    #[error("unexpected client hello: {0}")]
+    UnexpectedClientHello(&'static str),
+    #[error("unexpected client finish: {0}")]
+    UnexpectedClientFinish(&'static str),
+    #[error("unexpected length of server static key: got {0}, expected 32")]
+    InvalidServerStaticLength(usize),
+    #[error("failed to encrypt handshake message")]
+    EncryptFailed,
+    #[error("failed to decrypt handshake message")]
+    DecryptFailed,
+    #[error("failed to calculate shared secret: {0}")]
+    SharedSecret(#[from] SignalProtocolError),
+    #[error("invalid server certificate: {0}")]
+    InvalidCertificate(#[from] CertificateError),
+}
+
+#[derive(Error, Debug)]
+pub enum CertificateError {
+    #[error("missing parts of noise certificate")]
+    MissingParts,
+    #[error("unexpected length of noise certificate signature")]
+    InvalidSignatureLength,
+    #[error("failed to verify intermediate certificate")]
+    IntermediateVerificationFailed,
+    #[error("failed to verify leaf certificate")]
+    LeafVerificationFailed,
+    #[error("failed to unmarshal noise certificate details: {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("unexpected issuer serial: got {got}, expected {expected}")]
+    UnexpectedIssuerSerial { got: u32, expected: u32 },
+    #[error("unexpected length of intermediate certificate key: {0}")]
+    InvalidIntermediateKeyLength(usize),
+    #[error("leaf certificate key doesn't match server static key")]
+    KeyMismatch,
+}
+
+/// [`NoiseHandshake`] is the symmetric state of a `Noise_XX_25519_AESGCM_SHA256` handshake.
+///
+/// The low-level methods ([`NoiseHandshake::authenticate`], [`NoiseHandshake::encrypt`],
+/// [`NoiseHandshake::mix_shared_secret_into_key`], ...) can be used for either side of the
+/// handshake, while [`NoiseHandshake::client_hello`], [`NoiseHandshake::process_server_hello`]
+/// and [`NoiseHandshake::client_finish`] perform the client side of the exchange using the
+/// `HandshakeMessage` protobuf.
+pub struct NoiseHandshake {
+    hash: [u8; 32],
+    salt: [u8; 32],
+    key: Aes256Gcm,
+    counter: u32,
+}
+
+impl NoiseHandshake {
+    /// Starts a new handshake with [`NOISE_START_PATTERN`] and mixes the connection header
+    /// (e.g. [`crate::WA_CONN_HEADER`]) into the hash.
+    pub fn new(header: &[u8]) -> Self {
+        let pattern = NOISE_START_PATTERN.as_bytes();
+        let hash: [u8; 32] = if pattern.len() == 32 {
+            pattern.try_into().unwrap()
+        } else {
+            Sha256::digest(pattern).into()
+        };
+
+        let mut handshake = NoiseHandshake {
+            hash,
+            salt: hash,
+            key: Aes256Gcm::new(&hash.into()),
+            counter: 0,
+        };
+        handshake.authenticate(header);
+        handshake
+    }
+
+    /// Mixes the given data into the handshake hash.
+    pub fn authenticate(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        hasher.update(data);
+        self.hash = hasher.finalize().into();
+    }
+
+    fn post_increment_counter(&mut self) -> u32 {
+        let counter = self.counter;
+        self.counter += 1;
+        counter
+    }
+
+    /// Encrypts the given data with the current handshake key and mixes the ciphertext into
+    /// the handshake hash.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let iv = generate_iv(self.post_increment_counter());
+        let ciphertext = self
+            .key
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.hash,
+                },
+            )
+            .map_err(|_| HandshakeError::EncryptFailed)?;
+        self.authenticate(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    /// Decrypts the given data with the current handshake key and mixes the ciphertext into
+    /// the handshake hash.
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let iv = generate_iv(self.post_increment_counter());
+        let plaintext = self
+            .key
+            .decrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.hash,
+                },
+            )
+            .map_err(|_| HandshakeError::DecryptFailed)?;
+        self.authenticate(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Calculates the X25519 shared secret of the given keys and mixes it into the handshake
+    /// key.
+    pub fn mix_shared_secret_into_key(
+        &mut self,
+        private_key: &PrivateKey,
+        public_key: &PublicKey,
+    ) -> Result<(), HandshakeError> {
+        let secret = private_key.calculate_agreement(public_key)?;
+        self.mix_into_key(&secret);
+        Ok(())
+    }
+
+    /// Mixes the given data into the handshake key and resets the nonce counter.
+    pub fn mix_into_key(&mut self, data: &[u8]) {
+        self.counter = 0;
+        let (write, read) = extract_and_expand(&self.salt, data);
+        self.salt = write;
+        self.key = Aes256Gcm::new(&read.into());
+    }
+
+    /// Finishes the handshake and returns the (write, read) keys for the transport phase from
+    /// the point of view of the client. The server uses them the other way around.
+    pub fn finish(self) -> ([u8; 32], [u8; 32]) {
+        extract_and_expand(&self.salt, &[])
+    }
+
+    /// Returns the serialized `ClientHello` message to send to the server. This must be the
+    /// first message of the handshake.
+    pub fn client_hello(&mut self, ephemeral: &KeyPair) -> Vec<u8> {
+        let ephemeral_pub = ephemeral.public_key.public_key_bytes();
+        self.authenticate(ephemeral_pub);
+
+        HandshakeMessage {
+            client_hello: Some(ClientHello {
+                ephemeral: Some(ephemeral_pub.to_vec()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    /// Processes the `ServerHello` message sent by the server, verifying its certificate chain
+    /// against the given root. Returns the ephemeral key of the server, which is needed for
+    /// [`NoiseHandshake::client_finish`].
+    pub fn process_server_hello(
+        &mut self,
+        data: &[u8],
+        ephemeral: &KeyPair,
+        cert_root: &CertRoot,
+    ) -> Result<PublicKey, HandshakeError> {
+        let message = HandshakeMessage::decode(data)?;
+        let server_hello = message
+            .server_hello
+            .ok_or(HandshakeError::UnexpectedServerHello(
+                "missing server hello",
+            ))?;
+
+        let server_ephemeral = server_hello
+            .ephemeral
+            .filter(|ephemeral| ephemeral.len() == 32)
+            .ok_or(HandshakeError::UnexpectedServerHello(
+                "missing or invalid ephemeral key",
+            ))?;
+        let static_ciphertext = server_hello
+            .r#static
+            .ok_or(HandshakeError::UnexpectedServerHello("missing static key"))?;
+        let certificate_ciphertext = server_hello
+            .payload
+            .ok_or(HandshakeError::UnexpectedServerHello("missing certificate"))?;
+
+        self.authenticate(&server_ephemeral);
+        let server_ephemeral = PublicKey::from_djb_public_key_bytes(&server_ephemeral)?;
+        self.mix_shared_secret_into_key(&ephemeral.private_key, &server_ephemeral)?;
+
+        let static_decrypted = self.decrypt(&static_ciphertext)?;
+        if static_decrypted.len() != 32 {
+            return Err(HandshakeError::InvalidServerStaticLength(
+                static_decrypted.len(),
+            ));
+        }
+        let server_static = PublicKey::from_djb_public_key_bytes(&static_decrypted)?;
+        self.mix_shared_secret_into_key(&ephemeral.private_key, &server_static)?;
+
+        let certificate_decrypted = self.decrypt(&certificate_ciphertext)?;
+        verify_server_cert(&certificate_decrypted, &static_decrypted, cert_root)?;
+
+        Ok(server_ephemeral)
+    }
+
+    /// Returns the serialized `ClientFinish` message, which contains our static noise key and
+    /// the encrypted client payload.
+    pub fn client_finish(
+        &mut self,
+        noise_key: &KeyPair,
+        server_ephemeral: &PublicKey,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, HandshakeError> {
+        let encrypted_pubkey = self.encrypt(noise_key.public_key.public_key_bytes())?;
+        self.mix_shared_secret_into_key(&noise_key.private_key, server_ephemeral)?;
+        let encrypted_payload = self.encrypt(payload)?;
+
+        Ok(HandshakeMessage {
+            client_finish: Some(ClientFinish {
+                r#static: Some(encrypted_pubkey),
+                payload: Some(encrypted_payload),
+            }),
+            ..Default::default()
+        }
+        .encode_to_vec())
+    }
+
+    /// Processes the `ClientHello` message and returns the serialized `ServerHello`, which
+    /// contains the static key and certificate chain of the server. This is the server side
+    /// of [`NoiseHandshake::client_hello`].
+    pub fn server_hello(
+        &mut self,
+        data: &[u8],
+        ephemeral: &KeyPair,
+        identity: &ServerIdentity,
+    ) -> Result<Vec<u8>, HandshakeError> {
+        let client_ephemeral = HandshakeMessage::decode(data)?
+            .client_hello
+            .and_then(|client_hello| client_hello.ephemeral)
+            .ok_or(HandshakeError::UnexpectedClientHello(
+                "missing ephemeral key",
+            ))?;
+        self.authenticate(&client_ephemeral);
+        let client_ephemeral = PublicKey::from_djb_public_key_bytes(&client_ephemeral)?;
+
+        let ephemeral_pub = ephemeral.public_key.public_key_bytes().to_vec();
+        self.authenticate(&ephemeral_pub);
+        self.mix_shared_secret_into_key(&ephemeral.private_key, &client_ephemeral)?;
+        let encrypted_static = self.encrypt(identity.static_key.public_key.public_key_bytes())?;
+        self.mix_shared_secret_into_key(&identity.static_key.private_key, &client_ephemeral)?;
+        let encrypted_cert = self.encrypt(&identity.cert_chain)?;
+
+        Ok(HandshakeMessage {
+            server_hello: Some(ServerHello {
+                ephemeral: Some(ephemeral_pub),
+                r#static: Some(encrypted_static),
+                payload: Some(encrypted_cert),
+            }),
+            ..Default::default()
+        }
+        .encode_to_vec())
+    }
+
+    /// Processes the `ClientFinish` message and returns the static key of the client and the
+    /// decrypted client payload. This is the server side of [`NoiseHandshake::client_finish`].
+    pub fn process_client_finish(
+        &mut self,
+        data: &[u8],
+        ephemeral: &KeyPair,
+    ) -> Result<(PublicKey, Vec<u8>), HandshakeError> {
+        let client_finish = HandshakeMessage::decode(data)?.client_finish.ok_or(
+            HandshakeError::UnexpectedClientFinish("missing client finish"),
+        )?;
+        let client_static = self.decrypt(client_finish.r#static())?;
+        let client_static = PublicKey::from_djb_public_key_bytes(&client_static)?;
+        self.mix_shared_secret_into_key(&ephemeral.private_key, &client_static)?;
+        let payload = self.decrypt(client_finish.payload())?;
+        Ok((client_static, payload))
+    }
+}
+
+/// [`do_handshake`] performs the client side of the noise handshake over the given connected
+/// frame socket and returns a [`NoiseSocket`] that encrypts all further frames.
+///
+/// The noise key is the long-term static key of the device, and the payload is the marshaled
+/// `ClientPayload` protobuf.
+pub async fn do_handshake<T: Transport>(
+    mut frame_socket: FrameSocket<T>,
+    noise_key: &KeyPair,
+    client_payload: &[u8],
+    cert_root: &CertRoot,
+) -> Result<NoiseSocket<T>, HandshakeError> {
+    let ephemeral = KeyPair::generate(&mut OsRng);
+    let mut handshake = NoiseHandshake::new(&crate::WA_CONN_HEADER);
+
+    frame_socket
+        .send_frame(&handshake.client_hello(&ephemeral))
+        .await?;
+    let server_hello =
+        tokio::time::timeout(HANDSHAKE_RESPONSE_TIMEOUT, frame_socket.receive_frame())
+            .await
+            .map_err(|_| HandshakeError::Timeout)??;
+
+    let server_ephemeral = handshake.process_server_hello(&server_hello, &ephemeral, cert_root)?;
+    let client_finish = handshake.client_finish(noise_key, &server_ephemeral, client_payload)?;
+    frame_socket.send_frame(&client_finish).await?;
+
+    let (write_key, read_key) = handshake.finish();
+    Ok(NoiseSocket::new(frame_socket, write_key, read_key))
+}
+
+/// [`accept_handshake`] performs the server side of the noise handshake over the given
+/// connected frame socket. Returns the [`NoiseSocket`] along with the static noise key and
+/// the payload the client sent.
+pub async fn accept_handshake<T: Transport>(
+    mut frame_socket: FrameSocket<T>,
+    identity: &ServerIdentity,
+) -> Result<(NoiseSocket<T>, PublicKey, Vec<u8>), HandshakeError> {
+    let ephemeral = KeyPair::generate(&mut OsRng);
+    let mut handshake = NoiseHandshake::new(&crate::WA_CONN_HEADER);
+
+    let client_hello = frame_socket.receive_frame().await?;
+    let server_hello = handshake.server_hello(&client_hello, &ephemeral, identity)?;
+    frame_socket.send_frame(&server_hello).await?;
+    let client_finish = frame_socket.receive_frame().await?;
+    let (client_static, payload) = handshake.process_client_finish(&client_finish, &ephemeral)?;
+
+    // The keys are from the point of view of the client, so they're swapped here.
+    let (read_key, write_key) = handshake.finish();
+    Ok((
+        NoiseSocket::new(frame_socket, write_key, read_key),
+        client_static,
+        payload,
+    ))
+}
+
+fn generate_iv(counter: u32) -> [u8; 12] {
+    let mut iv = [0u8; 12];
+    iv[8..].copy_from_slice(&counter.to_be_bytes());
+    iv
+}
+
+fn extract_and_expand(salt: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(salt), data)
+        .expand(&[], &mut okm)
+        .expect("64 bytes is a valid length for HKDF-SHA256 output");
+
+    let mut write = [0u8; 32];
+    let mut read = [0u8; 32];
+    write.copy_from_slice(&okm[..32]);
+    read.copy_from_slice(&okm[32..]);
+    (write, read)
+}
+
+/// [`verify_server_cert`] verifies the certificate chain sent by the server in the handshake:
+/// the intermediate certificate must be signed by the root, the leaf certificate must be signed
+/// by the intermediate and the leaf key must be the static key of the server.
+pub fn verify_server_cert(
+    cert_decrypted: &[u8],
+    static_decrypted: &[u8],
+    cert_root: &CertRoot,
+) -> Result<(), CertificateError> {
+    let cert_chain = CertChain::decode(cert_decrypted)?;
+
+    let (intermediate, leaf) = match (cert_chain.intermediate, cert_chain.leaf) {
+        (Some(intermediate), Some(leaf)) => (intermediate, leaf),
+        _ => return Err(CertificateError::MissingParts),
+    };
+    let (
+        Some(intermediate_details_raw),
+        Some(intermediate_signature),
+        Some(leaf_details_raw),
+        Some(leaf_signature),
+    ) = (
+        intermediate.details,
+        intermediate.signature,
+        leaf.details,
+        leaf.signature,
+    )
+    else {
+        return Err(CertificateError::MissingParts);
+    };
+
+    if intermediate_signature.len() != 64 || leaf_signature.len() != 64 {
+        return Err(CertificateError::InvalidSignatureLength);
+    }
+
+    let root_key = PublicKey::from_djb_public_key_bytes(&cert_root.public_key)
+        .map_err(|_| CertificateError::IntermediateVerificationFailed)?;
+    if !root_key.verify_signature(&intermediate_details_raw, &intermediate_signature) {
+        return Err(CertificateError::IntermediateVerificationFailed);
+    }
+
+    let intermediate_details = Details::decode(intermediate_details_raw.as_slice())?;
+    if intermediate_details.issuer_serial() != cert_root.issuer_serial {
+        return Err(CertificateError::UnexpectedIssuerSerial {
+            got: intermediate_details.issuer_serial(),
+            expected: cert_root.issuer_serial,
+        });
+    }
+
+    let intermediate_key = PublicKey::from_djb_public_key_bytes(intermediate_details.key())
+        .map_err(|_| {
+            CertificateError::InvalidIntermediateKeyLength(intermediate_details.key().len())
+        })?;
+    if !intermediate_key.verify_signature(&leaf_details_raw, &leaf_signature) {
+        return Err(CertificateError::LeafVerificationFailed);
+    }
+
+    let leaf_details = Details::decode(leaf_details_raw.as_slice())?;
+    if leaf_details.issuer_serial() != intermediate_details.serial() {
+        return Err(CertificateError::UnexpectedIssuerSerial {
+            got: leaf_details.issuer_serial(),
+            expected: intermediate_details.serial(),
+        });
+    }
+
+    if leaf_details.key() != static_decrypted {
+        return Err(CertificateError::KeyMismatch);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transport::StreamTransport, WA_CONN_HEADER};
+
+    #[test]
+    fn handshake_with_local_responder() {
+        let identity = ServerIdentity::generate();
+        let server_ephemeral = KeyPair::generate(&mut OsRng);
+        let mut responder = NoiseHandshake::new(&WA_CONN_HEADER);
+
+        let ephemeral = KeyPair::generate(&mut OsRng);
+        let noise_key = KeyPair::generate(&mut OsRng);
+        let mut handshake = NoiseHandshake::new(&WA_CONN_HEADER);
+
+        let client_hello = handshake.client_hello(&ephemeral);
+        let server_hello = responder
+            .server_hello(&client_hello, &server_ephemeral, &identity)
+            .unwrap();
+        let server_ephemeral_pub = handshake
+            .process_server_hello(&server_hello, &ephemeral, &identity.cert_root)
+            .unwrap();
+        let client_finish = handshake
+            .client_finish(&noise_key, &server_ephemeral_pub, b"client payload")
+            .unwrap();
+        let (client_static, payload) = responder
+            .process_client_finish(&client_finish, &server_ephemeral)
+            .unwrap();
+
+        let (client_write, client_read) = handshake.finish();
+        let (server_read, server_write) = responder.finish();
+        assert_eq!(client_static, noise_key.public_key);
+        assert_eq!(payload, b"client payload");
+        assert_eq!(client_write, server_read);
+        assert_eq!(client_read, server_write);
+    }
+
+    #[tokio::test]
+    async fn do_handshake_over_frame_socket() {
+        let identity = ServerIdentity::generate();
+        let cert_root = identity.cert_root.clone();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server_task = tokio::spawn(async move {
+            let mut frame_socket = FrameSocket::server();
+            frame_socket.connect(StreamTransport::new(server)).unwrap();
+            accept_handshake(frame_socket, &identity).await.unwrap()
+        });
+
+        let mut frame_socket = FrameSocket::new();
+        frame_socket.connect(StreamTransport::new(client)).unwrap();
+        let noise_key = KeyPair::generate(&mut OsRng);
+        let mut client_socket = do_handshake(frame_socket, &noise_key, b"payload", &cert_root)
+            .await
+            .unwrap();
+        let (mut server_socket, client_static, payload) = server_task.await.unwrap();
+        assert_eq!(client_static, noise_key.public_key);
+        assert_eq!(payload, b"payload");
+
+        client_socket.send_frame(b"ping").await.unwrap();
+        assert_eq!(server_socket.receive_frame().await.unwrap(), b"ping");
+        server_socket.send_frame(b"pong").await.unwrap();
+        assert_eq!(client_socket.receive_frame().await.unwrap(), b"pong");
+    }
+
+    #[test]
+    fn handshake_rejects_untrusted_certificate() {
+        let identity = ServerIdentity::generate();
+        let mut responder = NoiseHandshake::new(&WA_CONN_HEADER);
+
+        let ephemeral = KeyPair::generate(&mut OsRng);
+        let mut handshake = NoiseHandshake::new(&WA_CONN_HEADER);
+
+        let client_hello = handshake.client_hello(&ephemeral);
+        let server_hello = responder
+            .server_hello(&client_hello, &KeyPair::generate(&mut OsRng), &identity)
+            .unwrap();
+        let result = handshake.process_server_hello(&server_hello, &ephemeral, &WA_CERT_ROOT);
+        assert!(matches!(
+            result,
+            Err(HandshakeError::InvalidCertificate(
+                CertificateError::IntermediateVerificationFailed
+            ))
+        ));
+    }
+}
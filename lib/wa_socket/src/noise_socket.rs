@@ -0,0 +1,145 @@
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+
+use crate::{frame_socket::FrameSocket, transport::Transport, SocketError};
+
+/// [`NoiseSocket`] encrypts and decrypts every frame of a [`FrameSocket`] with the keys that
+/// were negotiated in the noise handshake.
+///
+/// Each direction has its own AES-256-GCM key and nonce counter, which starts at zero and is
+/// incremented for every frame.
+pub struct NoiseSocket<T> {
+    frame_socket: FrameSocket<T>,
+    write_key: Aes256Gcm,
+    read_key: Aes256Gcm,
+    // The counters are 32 bits on the wire; they're stored as u64 so that running out of nonces
+    // can be detected instead of silently reusing one.
+    write_counter: u64,
+    read_counter: u64,
+}
+
+fn generate_iv(counter: u64) -> Result<[u8; 12], SocketError> {
+    let counter = u32::try_from(counter).map_err(|_| SocketError::CounterOverflow)?;
+    let mut iv = [0u8; 12];
+    iv[8..].copy_from_slice(&counter.to_be_bytes());
+    Ok(iv)
+}
+
+impl<T: Transport> NoiseSocket<T> {
+    /// Creates a new noise socket on an already connected frame socket. The client uses the
+    /// keys returned by [`crate::noise_handshake::NoiseHandshake::finish`] as they are, while
+    /// the server swaps them.
+    pub fn new(frame_socket: FrameSocket<T>, write_key: [u8; 32], read_key: [u8; 32]) -> Self {
+        NoiseSocket {
+            frame_socket,
+            write_key: Aes256Gcm::new(&write_key.into()),
+            read_key: Aes256Gcm::new(&read_key.into()),
+            write_counter: 0,
+            read_counter: 0,
+        }
+    }
+
+    /// Returns true if the underlying frame socket is connected.
+    pub fn is_connected(&self) -> bool {
+        self.frame_socket.is_connected()
+    }
+
+    /// Closes the underlying frame socket.
+    pub async fn close(&mut self) {
+        self.frame_socket.close().await;
+    }
+
+    /// Encrypts the given data (usually a marshaled node) and sends it as one frame.
+    pub async fn send_frame(&mut self, plaintext: &[u8]) -> Result<(), SocketError> {
+        let iv = generate_iv(self.write_counter)?;
+        let ciphertext = self
+            .write_key
+            .encrypt(Nonce::from_slice(&iv), plaintext)
+            .map_err(|_| SocketError::EncryptFailed)?;
+        self.write_counter += 1;
+        self.frame_socket.send_frame(&ciphertext).await
+    }
+
+    /// Receives the next frame and decrypts it.
+    ///
+    /// This is cancel safe, as the read counter is only incremented once a whole frame has
+    /// been received.
+    pub async fn receive_frame(&mut self) -> Result<Vec<u8>, SocketError> {
+        let ciphertext = self.frame_socket.receive_frame().await?;
+        let iv = generate_iv(self.read_counter)?;
+        self.read_counter += 1;
+        self.read_key
+            .decrypt(Nonce::from_slice(&iv), ciphertext.as_slice())
+            .map_err(|_| SocketError::DecryptFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::transport::StreamTransport;
+
+    const CLIENT_WRITE_KEY: [u8; 32] = [1; 32];
+    const CLIENT_READ_KEY: [u8; 32] = [2; 32];
+
+    type TestSocket = NoiseSocket<StreamTransport<DuplexStream>>;
+
+    fn socket_pair() -> (TestSocket, TestSocket) {
+        let (client, server) = duplex(1024);
+        let mut client_frame_socket = FrameSocket::new();
+        client_frame_socket
+            .connect(StreamTransport::new(client))
+            .unwrap();
+        let mut server_frame_socket = FrameSocket::server();
+        server_frame_socket
+            .connect(StreamTransport::new(server))
+            .unwrap();
+
+        (
+            NoiseSocket::new(client_frame_socket, CLIENT_WRITE_KEY, CLIENT_READ_KEY),
+            NoiseSocket::new(server_frame_socket, CLIENT_READ_KEY, CLIENT_WRITE_KEY),
+        )
+    }
+
+    #[tokio::test]
+    async fn encrypted_frames_round_trip() {
+        let (mut client, mut server) = socket_pair();
+
+        client.send_frame(b"first").await.unwrap();
+        client.send_frame(b"second").await.unwrap();
+        assert_eq!(server.receive_frame().await.unwrap(), b"first");
+        assert_eq!(server.receive_frame().await.unwrap(), b"second");
+
+        server.send_frame(b"reply").await.unwrap();
+        assert_eq!(client.receive_frame().await.unwrap(), b"reply");
+    }
+
+    #[tokio::test]
+    async fn wrong_key_or_counter_fails_authentication() {
+        let (mut client, mut server) = socket_pair();
+
+        // Skipping a nonce on one side makes the next frame fail to authenticate.
+        client.write_counter += 1;
+        client.send_frame(b"skipped").await.unwrap();
+        assert!(matches!(
+            server.receive_frame().await,
+            Err(SocketError::DecryptFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn counter_overflow_is_an_error() {
+        let (mut client, mut server) = socket_pair();
+
+        client.write_counter = u32::MAX as u64;
+        server.read_counter = u32::MAX as u64;
+        client.send_frame(b"last").await.unwrap();
+        assert_eq!(server.receive_frame().await.unwrap(), b"last");
+
+        assert!(matches!(
+            client.send_frame(b"too many").await,
+            Err(SocketError::CounterOverflow)
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{ORIGIN, URL};
+
+const READ_BUFFER_SIZE: usize = 32 * 1024;
+
+/// [`Transport`] is the byte transport that a [`crate::frame_socket::FrameSocket`] runs on.
+///
+/// Chunks returned by [`Transport::receive`] don't need to line up with frames: the frame
+/// socket reassembles frames that are split across several chunks or share one.
+#[async_trait]
+pub trait Transport: Send {
+    /// Sends the given bytes.
+    async fn send(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Returns the next chunk of received bytes, or [`Option::None`] if the transport was
+    /// closed by the other side.
+    ///
+    /// This must be cancel safe, as it's used in `tokio::select!` loops.
+    async fn receive(&mut self) -> io::Result<Option<Vec<u8>>>;
+
+    /// Closes the transport.
+    async fn close(&mut self) -> io::Result<()>;
+}
+
+#[async_trait]
+impl<S> Transport for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        SinkExt::send(self, Message::Binary(data.to_vec()))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.next().await {
+                Some(Ok(Message::Binary(data))) => return Ok(Some(data)),
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                // Pings are answered automatically, and text messages aren't part of the
+                // protocol.
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(io::Error::other(err)),
+            }
+        }
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        WebSocketStream::close(self, None)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+/// [`StreamTransport`] is a [`Transport`] over any async byte stream, such as a TCP connection
+/// or an in-memory [`tokio::io::duplex`] pipe.
+pub struct StreamTransport<T> {
+    stream: T,
+}
+
+impl<T> StreamTransport<T> {
+    pub fn new(stream: T) -> Self {
+        StreamTransport { stream }
+    }
+}
+
+#[async_trait]
+impl<T> Transport for StreamTransport<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data).await?;
+        self.stream.flush().await
+    }
+
+    async fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        let read = self.stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.truncate(read);
+        Ok(Some(buffer))
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+/// [`connect_websocket`] opens a websocket connection to the given URL (usually [`URL`]) with
+/// the [`ORIGIN`] header WhatsApp expects.
+pub async fn connect_websocket(
+    url: &str,
+) -> io::Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let mut request = url.into_client_request().map_err(io::Error::other)?;
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static(ORIGIN));
+
+    let (stream, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .map_err(io::Error::other)?;
+    Ok(stream)
+}
+
+/// [`connect`] opens a websocket connection to the WhatsApp [`URL`].
+pub async fn connect() -> io::Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    connect_websocket(URL).await
+}
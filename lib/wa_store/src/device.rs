@@ -0,0 +1,83 @@
+use libsignal_protocol::KeyPair;
+use rand::{rngs::OsRng, Rng};
+use wa_proto::items::wa_adv::AdvSignedDeviceIdentity;
+use wa_types::jid::JID;
+
+use crate::StoreError;
+
+/// [`PreKey`] is a signal pre-key, which is signed by the identity key if it's the signed
+/// pre-key of the device.
+#[derive(Clone)]
+pub struct PreKey {
+    pub key_id: u32,
+    pub key_pair: KeyPair,
+    pub signature: Option<Vec<u8>>,
+}
+
+impl PreKey {
+    /// Generates a new unsigned pre-key with the given ID.
+    pub fn generate(key_id: u32) -> Self {
+        PreKey {
+            key_id,
+            key_pair: KeyPair::generate(&mut OsRng),
+            signature: None,
+        }
+    }
+
+    /// Generates a new pre-key with the given ID and signs it with the given identity key.
+    pub fn generate_signed(identity_key: &KeyPair, key_id: u32) -> Result<Self, StoreError> {
+        let mut pre_key = PreKey::generate(key_id);
+        let signature = identity_key
+            .private_key
+            .calculate_signature(&pre_key.key_pair.public_key.serialize(), &mut OsRng)?;
+        pre_key.signature = Some(signature.to_vec());
+        Ok(pre_key)
+    }
+}
+
+/// [`Device`] contains the keys and account details of this client as a linked device.
+///
+/// A device without an [`Device::id`] hasn't been paired yet, so connecting with it will
+/// register a new device instead of logging in.
+#[derive(Clone)]
+pub struct Device {
+    pub noise_key: KeyPair,
+    pub identity_key: KeyPair,
+    pub signed_pre_key: PreKey,
+    pub registration_id: u32,
+    pub adv_secret_key: [u8; 32],
+
+    pub id: Option<JID>,
+    pub lid: Option<JID>,
+    pub account: Option<AdvSignedDeviceIdentity>,
+    pub platform: String,
+    pub business_name: String,
+    pub push_name: String,
+}
+
+impl Device {
+    /// Generates a new unpaired device with random keys.
+    pub fn generate() -> Result<Self, StoreError> {
+        let identity_key = KeyPair::generate(&mut OsRng);
+        let signed_pre_key = PreKey::generate_signed(&identity_key, 1)?;
+
+        Ok(Device {
+            noise_key: KeyPair::generate(&mut OsRng),
+            identity_key,
+            signed_pre_key,
+            registration_id: OsRng.gen(),
+            adv_secret_key: OsRng.gen(),
+            id: None,
+            lid: None,
+            account: None,
+            platform: String::new(),
+            business_name: String::new(),
+            push_name: String::new(),
+        })
+    }
+
+    /// Returns true if the device has been paired with a phone.
+    pub fn is_paired(&self) -> bool {
+        self.id.is_some()
+    }
+}
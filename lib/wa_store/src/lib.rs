@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use libsignal_protocol::SignalProtocolError;
+use thiserror::Error;
+use wa_types::{
+    jid::JID,
+    user::{ContactInfo, LocalChatSettings},
+};
+
+pub mod device;
+pub mod memory;
+pub mod signal;
+pub mod sqlite;
+
+pub use device::{Device, PreKey};
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error(transparent)]
+    Signal(#[from] SignalProtocolError),
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("failed to unmarshal stored protobuf: {0}")]
+    Protobuf(#[from] prost::DecodeError),
+    #[error("invalid {0} in store")]
+    InvalidData(&'static str),
+    #[error("unsupported database version {0}, this version only supports up to {1}")]
+    UnsupportedVersion(u32, u32),
+    #[error(transparent)]
+    Other(#[from] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// [`AppStateSyncKey`] is a key for decrypting app state patches, shared by the primary device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppStateSyncKey {
+    pub data: Vec<u8>,
+    pub fingerprint: Vec<u8>,
+    pub timestamp: i64,
+}
+
+/// [`ContactEntry`] is a contact name from the address book of the phone.
+#[derive(Clone, Debug)]
+pub struct ContactEntry {
+    pub jid: JID,
+    pub first_name: String,
+    pub full_name: String,
+}
+
+/// [`DeviceStore`] persists the [`Device`] so that it can log in again after a restart.
+#[async_trait]
+pub trait DeviceStore: Send + Sync {
+    /// Returns the stored device, or [`Option::None`] if there isn't one yet.
+    async fn get_device(&self) -> Result<Option<Device>, StoreError>;
+
+    /// Saves the device, replacing the previously stored one.
+    async fn put_device(&self, device: &Device) -> Result<(), StoreError>;
+
+    /// Deletes the stored device, e.g. after logging out.
+    async fn delete_device(&self) -> Result<(), StoreError>;
+}
+
+/// [`IdentityStore`] stores the identity keys of other devices, keyed by their signal address.
+#[async_trait]
+pub trait IdentityStore: Send + Sync {
+    async fn put_identity(&self, address: &str, key: [u8; 32]) -> Result<(), StoreError>;
+    async fn get_identity(&self, address: &str) -> Result<Option<[u8; 32]>, StoreError>;
+    /// Deletes the identities of all devices of the given phone number.
+    async fn delete_all_identities(&self, phone: &str) -> Result<(), StoreError>;
+    async fn delete_identity(&self, address: &str) -> Result<(), StoreError>;
+    /// Returns true if the given key is the stored identity of the address, or if there is no
+    /// stored identity yet.
+    async fn is_trusted_identity(&self, address: &str, key: [u8; 32]) -> Result<bool, StoreError>;
+}
+
+/// [`SessionStore`] stores serialized signal sessions, keyed by signal address.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn get_session(&self, address: &str) -> Result<Option<Vec<u8>>, StoreError>;
+    async fn has_session(&self, address: &str) -> Result<bool, StoreError>;
+    async fn put_session(&self, address: &str, session: &[u8]) -> Result<(), StoreError>;
+    /// Deletes the sessions with all devices of the given phone number.
+    async fn delete_all_sessions(&self, phone: &str) -> Result<(), StoreError>;
+    async fn delete_session(&self, address: &str) -> Result<(), StoreError>;
+}
+
+/// [`PreKeyStore`] stores the one-time pre-keys of this device.
+#[async_trait]
+pub trait PreKeyStore: Send + Sync {
+    /// Returns up to `count` pre-keys that haven't been uploaded yet, generating new ones with
+    /// sequential IDs if there aren't enough.
+    async fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKey>, StoreError>;
+    /// Generates a single pre-key and marks it as uploaded right away, for cases where it's
+    /// sent to the other side directly (e.g. in retry receipts).
+    async fn gen_one_pre_key(&self) -> Result<PreKey, StoreError>;
+    async fn get_pre_key(&self, id: u32) -> Result<Option<PreKey>, StoreError>;
+    async fn remove_pre_key(&self, id: u32) -> Result<(), StoreError>;
+    /// Marks all pre-keys up to and including the given ID as uploaded.
+    async fn mark_pre_keys_as_uploaded(&self, up_to_id: u32) -> Result<(), StoreError>;
+    async fn uploaded_pre_key_count(&self) -> Result<u32, StoreError>;
+}
+
+/// [`SenderKeyStore`] stores serialized group sender keys, keyed by group and sender address.
+#[async_trait]
+pub trait SenderKeyStore: Send + Sync {
+    async fn put_sender_key(
+        &self,
+        group: &str,
+        user: &str,
+        session: &[u8],
+    ) -> Result<(), StoreError>;
+    async fn get_sender_key(&self, group: &str, user: &str) -> Result<Option<Vec<u8>>, StoreError>;
+}
+
+/// [`AppStateSyncKeyStore`] stores the keys for decrypting app state patches.
+#[async_trait]
+pub trait AppStateSyncKeyStore: Send + Sync {
+    async fn put_app_state_sync_key(
+        &self,
+        id: &[u8],
+        key: AppStateSyncKey,
+    ) -> Result<(), StoreError>;
+    async fn get_app_state_sync_key(
+        &self,
+        id: &[u8],
+    ) -> Result<Option<AppStateSyncKey>, StoreError>;
+    /// Returns the ID of the key with the newest timestamp.
+    async fn get_latest_app_state_sync_key_id(&self) -> Result<Option<Vec<u8>>, StoreError>;
+}
+
+/// [`ContactStore`] caches the names of other users.
+#[async_trait]
+pub trait ContactStore: Send + Sync {
+    /// Stores the push name of the user. Returns true and the previous name if it changed.
+    async fn put_push_name(
+        &self,
+        user: &JID,
+        push_name: &str,
+    ) -> Result<(bool, String), StoreError>;
+    /// Stores the business name of the user. Returns true and the previous name if it changed.
+    async fn put_business_name(
+        &self,
+        user: &JID,
+        business_name: &str,
+    ) -> Result<(bool, String), StoreError>;
+    async fn put_contact_name(
+        &self,
+        user: &JID,
+        full_name: &str,
+        first_name: &str,
+    ) -> Result<(), StoreError>;
+    async fn put_all_contact_names(&self, contacts: &[ContactEntry]) -> Result<(), StoreError>;
+    async fn get_contact(&self, user: &JID) -> Result<Option<ContactInfo>, StoreError>;
+    async fn get_all_contacts(&self) -> Result<HashMap<JID, ContactInfo>, StoreError>;
+}
+
+/// [`ChatSettingsStore`] caches the local settings of chats.
+#[async_trait]
+pub trait ChatSettingsStore: Send + Sync {
+    async fn put_muted_until(
+        &self,
+        chat: &JID,
+        muted_until: time::OffsetDateTime,
+    ) -> Result<(), StoreError>;
+    async fn put_pinned(&self, chat: &JID, pinned: bool) -> Result<(), StoreError>;
+    async fn put_archived(&self, chat: &JID, archived: bool) -> Result<(), StoreError>;
+    async fn get_chat_settings(&self, chat: &JID) -> Result<Option<LocalChatSettings>, StoreError>;
+}
+
+/// [`Store`] is everything the client persists. It's implemented automatically for any type
+/// that implements all the sub-stores, like [`memory::MemoryStore`] and
+/// [`sqlite::SqliteStore`].
+pub trait Store:
+    DeviceStore
+    + IdentityStore
+    + SessionStore
+    + PreKeyStore
+    + SenderKeyStore
+    + AppStateSyncKeyStore
+    + ContactStore
+    + ChatSettingsStore
+{
+}
+
+impl<T> Store for T where
+    T: DeviceStore
+        + IdentityStore
+        + SessionStore
+        + PreKeyStore
+        + SenderKeyStore
+        + AppStateSyncKeyStore
+        + ContactStore
+        + ChatSettingsStore
+{
+}
+
+fn empty_contact() -> ContactInfo {
+    ContactInfo {
+        first_name: String::new(),
+        full_name: String::new(),
+        push_name: String::new(),
+        business_name: String::new(),
+    }
+}
+
+fn default_chat_settings() -> LocalChatSettings {
+    LocalChatSettings {
+        muted_until: time::OffsetDateTime::UNIX_EPOCH,
+        pinned: false,
+        archived: false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Exercises every sub-store, so that all backends can share the same test.
+    pub(crate) async fn exercise_store(store: &dyn Store) {
+        assert!(store.get_device().await.unwrap().is_none());
+        let mut device = Device::generate().unwrap();
+        device.id = Some(JID::new_ad_jid("1234".to_string(), 0, 5));
+        device.push_name = "Bot".to_string();
+        store.put_device(&device).await.unwrap();
+        let stored = store.get_device().await.unwrap().unwrap();
+        assert_eq!(stored.id, device.id);
+        assert_eq!(stored.push_name, "Bot");
+        assert_eq!(stored.adv_secret_key, device.adv_secret_key);
+        assert_eq!(stored.noise_key.public_key, device.noise_key.public_key);
+        assert_eq!(
+            stored.signed_pre_key.signature,
+            device.signed_pre_key.signature
+        );
+
+        assert!(store.is_trusted_identity("1234.1", [1; 32]).await.unwrap());
+        store.put_identity("1234.1", [1; 32]).await.unwrap();
+        store.put_identity("5678.1", [3; 32]).await.unwrap();
+        assert!(!store.is_trusted_identity("1234.1", [2; 32]).await.unwrap());
+        store.delete_all_identities("12_4").await.unwrap();
+        assert!(store.get_identity("1234.1").await.unwrap().is_some());
+        store.delete_all_identities("1234").await.unwrap();
+        assert!(store.get_identity("1234.1").await.unwrap().is_none());
+        assert_eq!(store.get_identity("5678.1").await.unwrap(), Some([3; 32]));
+
+        store.put_session("1234.1", b"session").await.unwrap();
+        assert!(store.has_session("1234.1").await.unwrap());
+        assert_eq!(
+            store.get_session("1234.1").await.unwrap().as_deref(),
+            Some(&b"session"[..])
+        );
+        store.delete_all_sessions("1%").await.unwrap();
+        assert!(store.has_session("1234.1").await.unwrap());
+        store.delete_session("1234.1").await.unwrap();
+        assert!(!store.has_session("1234.1").await.unwrap());
+
+        let keys = store.get_or_gen_pre_keys(3).await.unwrap();
+        assert_eq!(
+            keys.iter().map(|key| key.key_id).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        store.mark_pre_keys_as_uploaded(2).await.unwrap();
+        assert_eq!(store.uploaded_pre_key_count().await.unwrap(), 2);
+        let keys = store.get_or_gen_pre_keys(2).await.unwrap();
+        assert_eq!(
+            keys.iter().map(|key| key.key_id).collect::<Vec<_>>(),
+            [3, 4]
+        );
+        let one = store.gen_one_pre_key().await.unwrap();
+        assert_eq!(one.key_id, 5);
+        assert_eq!(store.uploaded_pre_key_count().await.unwrap(), 3);
+        let stored_key = store.get_pre_key(1).await.unwrap().unwrap();
+        assert_eq!(stored_key.key_pair.public_key, {
+            let keys = store.get_or_gen_pre_keys(0).await.unwrap();
+            assert!(keys.is_empty());
+            stored_key.key_pair.public_key
+        });
+        store.remove_pre_key(1).await.unwrap();
+        assert!(store.get_pre_key(1).await.unwrap().is_none());
+        // IDs of removed keys aren't reused, as peers may still have bundles with them.
+        store.remove_pre_key(5).await.unwrap();
+        assert_eq!(store.gen_one_pre_key().await.unwrap().key_id, 6);
+
+        store
+            .put_sender_key("group@g.us", "1234.1", b"sender key")
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .get_sender_key("group@g.us", "1234.1")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(&b"sender key"[..])
+        );
+
+        for (id, timestamp) in [(b"old", 1), (b"new", 2)] {
+            store
+                .put_app_state_sync_key(
+                    id,
+                    AppStateSyncKey {
+                        data: id.to_vec(),
+                        fingerprint: Vec::new(),
+                        timestamp,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            store.get_latest_app_state_sync_key_id().await.unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(
+            store
+                .get_app_state_sync_key(b"old")
+                .await
+                .unwrap()
+                .unwrap()
+                .timestamp,
+            1
+        );
+
+        let user = JID::new("1234".to_string(), "s.whatsapp.net".to_string());
+        assert_eq!(
+            store.put_push_name(&user, "Alice").await.unwrap(),
+            (true, String::new())
+        );
+        assert_eq!(
+            store.put_push_name(&user, "Alice").await.unwrap(),
+            (false, "Alice".to_string())
+        );
+        store
+            .put_all_contact_names(&[ContactEntry {
+                jid: user.clone(),
+                first_name: "Al".to_string(),
+                full_name: "Alice Liddell".to_string(),
+            }])
+            .await
+            .unwrap();
+        let contact = store.get_contact(&user).await.unwrap().unwrap();
+        assert_eq!(contact.push_name, "Alice");
+        assert_eq!(contact.full_name, "Alice Liddell");
+        assert_eq!(store.get_all_contacts().await.unwrap().len(), 1);
+
+        let chat = JID::new("group".to_string(), "g.us".to_string());
+        assert!(store.get_chat_settings(&chat).await.unwrap().is_none());
+        store.put_pinned(&chat, true).await.unwrap();
+        store.put_archived(&chat, true).await.unwrap();
+        let settings = store.get_chat_settings(&chat).await.unwrap().unwrap();
+        assert!(settings.pinned && settings.archived);
+        assert_eq!(settings.muted_until, time::OffsetDateTime::UNIX_EPOCH);
+
+        store.delete_device().await.unwrap();
+        assert!(store.get_device().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use wa_types::{
+    jid::JID,
+    user::{ContactInfo, LocalChatSettings},
+};
+
+use crate::{
+    default_chat_settings, empty_contact, AppStateSyncKey, AppStateSyncKeyStore, ChatSettingsStore,
+    ContactEntry, ContactStore, Device, DeviceStore, IdentityStore, PreKey, PreKeyStore,
+    SenderKeyStore, SessionStore, StoreError,
+};
+
+#[derive(Default)]
+struct MemoryState {
+    device: Option<Device>,
+    identities: HashMap<String, [u8; 32]>,
+    sessions: HashMap<String, Vec<u8>>,
+    pre_keys: BTreeMap<u32, (PreKey, bool)>,
+    next_pre_key_id: u32,
+    sender_keys: HashMap<(String, String), Vec<u8>>,
+    app_state_sync_keys: HashMap<Vec<u8>, AppStateSyncKey>,
+    contacts: HashMap<JID, ContactInfo>,
+    chat_settings: HashMap<JID, LocalChatSettings>,
+}
+
+impl MemoryState {
+    fn new_pre_key(&mut self, uploaded: bool) -> PreKey {
+        self.next_pre_key_id += 1;
+        let pre_key = PreKey::generate(self.next_pre_key_id);
+        self.pre_keys
+            .insert(pre_key.key_id, (pre_key.clone(), uploaded));
+        pre_key
+    }
+
+    fn contact(&mut self, user: &JID) -> &mut ContactInfo {
+        self.contacts
+            .entry(user.clone())
+            .or_insert_with(empty_contact)
+    }
+
+    fn chat_settings(&mut self, chat: &JID) -> &mut LocalChatSettings {
+        self.chat_settings
+            .entry(chat.clone())
+            .or_insert_with(default_chat_settings)
+    }
+}
+
+/// Returns true if the signal address belongs to a device of the given phone number.
+fn is_address_of(address: &str, phone: &str) -> bool {
+    address
+        .strip_prefix(phone)
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// [`MemoryStore`] keeps everything in memory, so the device has to be paired again after a
+/// restart. It's mostly useful for tests and short-lived bots.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        // The state is never left half-updated, so a poisoned lock is still safe to use.
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[async_trait]
+impl DeviceStore for MemoryStore {
+    async fn get_device(&self) -> Result<Option<Device>, StoreError> {
+        Ok(self.state().device.clone())
+    }
+
+    async fn put_device(&self, device: &Device) -> Result<(), StoreError> {
+        self.state().device = Some(device.clone());
+        Ok(())
+    }
+
+    async fn delete_device(&self) -> Result<(), StoreError> {
+        self.state().device = None;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdentityStore for MemoryStore {
+    async fn put_identity(&self, address: &str, key: [u8; 32]) -> Result<(), StoreError> {
+        self.state().identities.insert(address.to_string(), key);
+        Ok(())
+    }
+
+    async fn get_identity(&self, address: &str) -> Result<Option<[u8; 32]>, StoreError> {
+        Ok(self.state().identities.get(address).copied())
+    }
+
+    async fn delete_all_identities(&self, phone: &str) -> Result<(), StoreError> {
+        self.state()
+            .identities
+            .retain(|address, _| !is_address_of(address, phone));
+        Ok(())
+    }
+
+    async fn delete_identity(&self, address: &str) -> Result<(), StoreError> {
+        self.state().identities.remove(address);
+        Ok(())
+    }
+
+    async fn is_trusted_identity(&self, address: &str, key: [u8; 32]) -> Result<bool, StoreError> {
+        Ok(self
+            .state()
+            .identities
+            .get(address)
+            .is_none_or(|stored| *stored == key))
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn get_session(&self, address: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.state().sessions.get(address).cloned())
+    }
+
+    async fn has_session(&self, address: &str) -> Result<bool, StoreError> {
+        Ok(self.state().sessions.contains_key(address))
+    }
+
+    async fn put_session(&self, address: &str, session: &[u8]) -> Result<(), StoreError> {
+        self.state()
+            .sessions
+            .insert(address.to_string(), session.to_vec());
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&self, phone: &str) -> Result<(), StoreError> {
+        self.state()
+            .sessions
+            .retain(|address, _| !is_address_of(address, phone));
+        Ok(())
+    }
+
+    async fn delete_session(&self, address: &str) -> Result<(), StoreError> {
+        self.state().sessions.remove(address);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PreKeyStore for MemoryStore {
+    async fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKey>, StoreError> {
+        let mut state = self.state();
+        let mut keys = state
+            .pre_keys
+            .values()
+            .filter(|(_, uploaded)| !uploaded)
+            .map(|(key, _)| key.clone())
+            .take(count as usize)
+            .collect::<Vec<_>>();
+        while keys.len() < count as usize {
+            keys.push(state.new_pre_key(false));
+        }
+        Ok(keys)
+    }
+
+    async fn gen_one_pre_key(&self) -> Result<PreKey, StoreError> {
+        Ok(self.state().new_pre_key(true))
+    }
+
+    async fn get_pre_key(&self, id: u32) -> Result<Option<PreKey>, StoreError> {
+        Ok(self.state().pre_keys.get(&id).map(|(key, _)| key.clone()))
+    }
+
+    async fn remove_pre_key(&self, id: u32) -> Result<(), StoreError> {
+        self.state().pre_keys.remove(&id);
+        Ok(())
+    }
+
+    async fn mark_pre_keys_as_uploaded(&self, up_to_id: u32) -> Result<(), StoreError> {
+        for (_, (_, uploaded)) in self.state().pre_keys.range_mut(..=up_to_id) {
+            *uploaded = true;
+        }
+        Ok(())
+    }
+
+    async fn uploaded_pre_key_count(&self) -> Result<u32, StoreError> {
+        Ok(self
+            .state()
+            .pre_keys
+            .values()
+            .filter(|(_, uploaded)| *uploaded)
+            .count() as u32)
+    }
+}
+
+#[async_trait]
+impl SenderKeyStore for MemoryStore {
+    async fn put_sender_key(
+        &self,
+        group: &str,
+        user: &str,
+        session: &[u8],
+    ) -> Result<(), StoreError> {
+        self.state()
+            .sender_keys
+            .insert((group.to_string(), user.to_string()), session.to_vec());
+        Ok(())
+    }
+
+    async fn get_sender_key(&self, group: &str, user: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .state()
+            .sender_keys
+            .get(&(group.to_string(), user.to_string()))
+            .cloned())
+    }
+}
+
+#[async_trait]
+impl AppStateSyncKeyStore for MemoryStore {
+    async fn put_app_state_sync_key(
+        &self,
+        id: &[u8],
+        key: AppStateSyncKey,
+    ) -> Result<(), StoreError> {
+        self.state().app_state_sync_keys.insert(id.to_vec(), key);
+        Ok(())
+    }
+
+    async fn get_app_state_sync_key(
+        &self,
+        id: &[u8],
+    ) -> Result<Option<AppStateSyncKey>, StoreError> {
+        Ok(self.state().app_state_sync_keys.get(id).cloned())
+    }
+
+    async fn get_latest_app_state_sync_key_id(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .state()
+            .app_state_sync_keys
+            .iter()
+            .max_by_key(|(_, key)| key.timestamp)
+            .map(|(id, _)| id.clone()))
+    }
+}
+
+#[async_trait]
+impl ContactStore for MemoryStore {
+    async fn put_push_name(
+        &self,
+        user: &JID,
+        push_name: &str,
+    ) -> Result<(bool, String), StoreError> {
+        let mut state = self.state();
+        let contact = state.contact(user);
+        if contact.push_name == push_name {
+            return Ok((false, contact.push_name.clone()));
+        }
+        Ok((
+            true,
+            std::mem::replace(&mut contact.push_name, push_name.to_string()),
+        ))
+    }
+
+    async fn put_business_name(
+        &self,
+        user: &JID,
+        business_name: &str,
+    ) -> Result<(bool, String), StoreError> {
+        let mut state = self.state();
+        let contact = state.contact(user);
+        if contact.business_name == business_name {
+            return Ok((false, contact.business_name.clone()));
+        }
+        Ok((
+            true,
+            std::mem::replace(&mut contact.business_name, business_name.to_string()),
+        ))
+    }
+
+    async fn put_contact_name(
+        &self,
+        user: &JID,
+        full_name: &str,
+        first_name: &str,
+    ) -> Result<(), StoreError> {
+        let mut state = self.state();
+        let contact = state.contact(user);
+        contact.full_name = full_name.to_string();
+        contact.first_name = first_name.to_string();
+        Ok(())
+    }
+
+    async fn put_all_contact_names(&self, contacts: &[ContactEntry]) -> Result<(), StoreError> {
+        let mut state = self.state();
+        for entry in contacts {
+            let contact = state.contact(&entry.jid);
+            contact.full_name.clone_from(&entry.full_name);
+            contact.first_name.clone_from(&entry.first_name);
+        }
+        Ok(())
+    }
+
+    async fn get_contact(&self, user: &JID) -> Result<Option<ContactInfo>, StoreError> {
+        Ok(self.state().contacts.get(user).cloned())
+    }
+
+    async fn get_all_contacts(&self) -> Result<HashMap<JID, ContactInfo>, StoreError> {
+        Ok(self.state().contacts.clone())
+    }
+}
+
+#[async_trait]
+impl ChatSettingsStore for MemoryStore {
+    async fn put_muted_until(
+        &self,
+        chat: &JID,
+        muted_until: time::OffsetDateTime,
+    ) -> Result<(), StoreError> {
+        self.state().chat_settings(chat).muted_until = muted_until;
+        Ok(())
+    }
+
+    async fn put_pinned(&self, chat: &JID, pinned: bool) -> Result<(), StoreError> {
+        self.state().chat_settings(chat).pinned = pinned;
+        Ok(())
+    }
+
+    async fn put_archived(&self, chat: &JID, archived: bool) -> Result<(), StoreError> {
+        self.state().chat_settings(chat).archived = archived;
+        Ok(())
+    }
+
+    async fn get_chat_settings(&self, chat: &JID) -> Result<Option<LocalChatSettings>, StoreError> {
+        Ok(self.state().chat_settings.get(chat).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store() {
+        crate::tests::exercise_store(&MemoryStore::new()).await;
+    }
+}
@@ -0,0 +1,354 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use libsignal_protocol::{
+    self as signal, Direction, IdentityKey, IdentityKeyPair, KeyPair, KyberPreKeyId,
+    KyberPreKeyRecord, PreKeyId, PreKeyRecord, ProtocolAddress, SenderKeyRecord, SessionRecord,
+    SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord,
+};
+use uuid::Uuid;
+use wa_types::jid::JID;
+
+use crate::{Device, PreKey, Store, StoreError};
+
+fn signal_error(method: &'static str) -> impl FnOnce(StoreError) -> SignalProtocolError {
+    move |err| match err {
+        StoreError::Signal(err) => err,
+        err => SignalProtocolError::InvalidState(method, err.to_string()),
+    }
+}
+
+fn identity_bytes(identity: &IdentityKey) -> Result<[u8; 32], SignalProtocolError> {
+    identity
+        .public_key()
+        .public_key_bytes()
+        .try_into()
+        .map_err(|_| SignalProtocolError::InvalidArgument("identity key isn't 32 bytes".into()))
+}
+
+/// [`SignalStore`] implements the libsignal store traits on top of a [`Store`], so that
+/// sessions and keys survive restarts.
+///
+/// Everything is keyed by the string form of the [`ProtocolAddress`] that
+/// `JID::signal_address` returns, e.g. `1234.5`, so all devices of a user can be found by
+/// their phone number. The identity key, registration ID and signed pre-key come from the
+/// [`Device`] and can't be changed through the libsignal traits.
+#[derive(Clone)]
+pub struct SignalStore {
+    store: Arc<dyn Store>,
+    identity_key: KeyPair,
+    registration_id: u32,
+    signed_pre_key: PreKey,
+}
+
+impl SignalStore {
+    pub fn new(store: Arc<dyn Store>, device: &Device) -> Self {
+        SignalStore {
+            store,
+            identity_key: device.identity_key,
+            registration_id: device.registration_id,
+            signed_pre_key: device.signed_pre_key.clone(),
+        }
+    }
+
+    /// Forgets the identity and session of the address, so that a new identity is trusted
+    /// again. This is used when a contact reinstalled WhatsApp and their identity changed.
+    pub async fn clear_identity(&self, address: &ProtocolAddress) -> Result<(), StoreError> {
+        let address = address.to_string();
+        self.store.delete_identity(&address).await?;
+        self.store.delete_session(&address).await
+    }
+
+    /// Returns the serialized sender key record that `sender` uses in `group`. WhatsApp
+    /// clients use their own sender key format rather than libsignal's, so these records are
+    /// kept by group JID instead of going through [`signal::SenderKeyStore`].
+    pub async fn get_sender_key(
+        &self,
+        group: &JID,
+        sender: &ProtocolAddress,
+    ) -> Result<Option<Vec<u8>>, StoreError> {
+        self.store
+            .get_sender_key(&group.to_string(), &sender.to_string())
+            .await
+    }
+
+    pub async fn put_sender_key(
+        &self,
+        group: &JID,
+        sender: &ProtocolAddress,
+        record: &[u8],
+    ) -> Result<(), StoreError> {
+        self.store
+            .put_sender_key(&group.to_string(), &sender.to_string(), record)
+            .await
+    }
+}
+
+#[async_trait(?Send)]
+impl signal::IdentityKeyStore for SignalStore {
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair, SignalProtocolError> {
+        Ok(IdentityKeyPair::from(self.identity_key))
+    }
+
+    async fn get_local_registration_id(&self) -> Result<u32, SignalProtocolError> {
+        Ok(self.registration_id)
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+    ) -> Result<bool, SignalProtocolError> {
+        let address = address.to_string();
+        let key = identity_bytes(identity)?;
+        let previous = self
+            .store
+            .get_identity(&address)
+            .await
+            .map_err(signal_error("save_identity"))?;
+        self.store
+            .put_identity(&address, key)
+            .await
+            .map_err(signal_error("save_identity"))?;
+        Ok(previous.is_some_and(|previous| previous != key))
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _direction: Direction,
+    ) -> Result<bool, SignalProtocolError> {
+        self.store
+            .is_trusted_identity(&address.to_string(), identity_bytes(identity)?)
+            .await
+            .map_err(signal_error("is_trusted_identity"))
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        self.store
+            .get_identity(&address.to_string())
+            .await
+            .map_err(signal_error("get_identity"))?
+            .map(|key| signal::PublicKey::from_djb_public_key_bytes(&key).map(IdentityKey::new))
+            .transpose()
+    }
+}
+
+#[async_trait(?Send)]
+impl signal::SessionStore for SignalStore {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        self.store
+            .get_session(&address.to_string())
+            .await
+            .map_err(signal_error("load_session"))?
+            .map(|session| SessionRecord::deserialize(&session))
+            .transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+    ) -> Result<(), SignalProtocolError> {
+        self.store
+            .put_session(&address.to_string(), &record.serialize()?)
+            .await
+            .map_err(signal_error("store_session"))
+    }
+}
+
+/// Pre-keys are generated by [`crate::PreKeyStore::get_or_gen_pre_keys`] rather than by
+/// libsignal, so saving them through this trait isn't supported.
+#[async_trait(?Send)]
+impl signal::PreKeyStore for SignalStore {
+    async fn get_pre_key(&self, prekey_id: PreKeyId) -> Result<PreKeyRecord, SignalProtocolError> {
+        let pre_key = self
+            .store
+            .get_pre_key(prekey_id.into())
+            .await
+            .map_err(signal_error("get_pre_key"))?
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        Ok(PreKeyRecord::new(prekey_id, &pre_key.key_pair))
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        _prekey_id: PreKeyId,
+        _record: &PreKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "save_pre_key",
+            "pre-keys are generated by the store".to_string(),
+        ))
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId) -> Result<(), SignalProtocolError> {
+        self.store
+            .remove_pre_key(prekey_id.into())
+            .await
+            .map_err(signal_error("remove_pre_key"))
+    }
+}
+
+/// The only signed pre-key is the one of the [`Device`], so saving other ones isn't
+/// supported.
+#[async_trait(?Send)]
+impl signal::SignedPreKeyStore for SignalStore {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+    ) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        if u32::from(signed_prekey_id) != self.signed_pre_key.key_id {
+            return Err(SignalProtocolError::InvalidSignedPreKeyId);
+        }
+        Ok(SignedPreKeyRecord::new(
+            signed_prekey_id,
+            0,
+            &self.signed_pre_key.key_pair,
+            self.signed_pre_key.signature.as_deref().unwrap_or_default(),
+        ))
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        _signed_prekey_id: SignedPreKeyId,
+        _record: &SignedPreKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "save_signed_pre_key",
+            "the signed pre-key is part of the device".to_string(),
+        ))
+    }
+}
+
+/// WhatsApp doesn't use Kyber pre-keys, so there never are any.
+#[async_trait(?Send)]
+impl signal::KyberPreKeyStore for SignalStore {
+    async fn get_kyber_pre_key(
+        &self,
+        _kyber_prekey_id: KyberPreKeyId,
+    ) -> Result<KyberPreKeyRecord, SignalProtocolError> {
+        Err(SignalProtocolError::InvalidKyberPreKeyId)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        _kyber_prekey_id: KyberPreKeyId,
+        _record: &KyberPreKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "save_kyber_pre_key",
+            "Kyber pre-keys aren't used".to_string(),
+        ))
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        _kyber_prekey_id: KyberPreKeyId,
+    ) -> Result<(), SignalProtocolError> {
+        Err(SignalProtocolError::InvalidKyberPreKeyId)
+    }
+}
+
+/// Sender keys are stored with the distribution ID in place of the group.
+#[async_trait(?Send)]
+impl signal::SenderKeyStore for SignalStore {
+    async fn store_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        record: &SenderKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        self.store
+            .put_sender_key(
+                &distribution_id.to_string(),
+                &sender.to_string(),
+                &record.serialize()?,
+            )
+            .await
+            .map_err(signal_error("store_sender_key"))
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
+        self.store
+            .get_sender_key(&distribution_id.to_string(), &sender.to_string())
+            .await
+            .map_err(signal_error("load_sender_key"))?
+            .map(|record| SenderKeyRecord::deserialize(&record))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsignal_protocol::{IdentityKeyStore, PreKeyStore, SessionStore, SignedPreKeyStore};
+    use rand::rngs::OsRng;
+    use wa_types::jid::JID;
+
+    use super::*;
+    use crate::{memory::MemoryStore, PreKeyStore as _, SessionStore as _};
+
+    #[tokio::test]
+    async fn adapters_use_signal_address() {
+        let store = Arc::new(MemoryStore::new());
+        let device = Device::generate().unwrap();
+        let mut signal_store = SignalStore::new(store.clone(), &device);
+        let address = JID::new_ad_jid("1234".to_string(), 0, 5).signal_address();
+
+        let identity = IdentityKey::new(KeyPair::generate(&mut OsRng).public_key);
+        assert!(!signal_store
+            .save_identity(&address, &identity)
+            .await
+            .unwrap());
+        assert_eq!(
+            signal_store.get_identity(&address).await.unwrap(),
+            Some(identity)
+        );
+        let other = IdentityKey::new(KeyPair::generate(&mut OsRng).public_key);
+        assert!(!signal_store
+            .is_trusted_identity(&address, &other, Direction::Receiving)
+            .await
+            .unwrap());
+        assert!(signal_store.save_identity(&address, &other).await.unwrap());
+
+        let record = SessionRecord::new_fresh();
+        signal_store.store_session(&address, &record).await.unwrap();
+        assert!(store.has_session("1234.5").await.unwrap());
+        assert!(signal_store.load_session(&address).await.unwrap().is_some());
+
+        let pre_key = &store.get_or_gen_pre_keys(1).await.unwrap()[0];
+        let record = signal_store
+            .get_pre_key(pre_key.key_id.into())
+            .await
+            .unwrap();
+        assert_eq!(record.public_key().unwrap(), pre_key.key_pair.public_key);
+        signal_store
+            .remove_pre_key(pre_key.key_id.into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            signal_store.get_pre_key(pre_key.key_id.into()).await,
+            Err(SignalProtocolError::InvalidPreKeyId)
+        ));
+
+        let signed = signal_store
+            .get_signed_pre_key(device.signed_pre_key.key_id.into())
+            .await
+            .unwrap();
+        assert_eq!(
+            signed.signature().unwrap(),
+            device.signed_pre_key.signature.unwrap()
+        );
+    }
+}
@@ -0,0 +1,699 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use libsignal_protocol::{KeyPair, PrivateKey};
+use prost::Message;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use wa_proto::items::wa_adv::AdvSignedDeviceIdentity;
+use wa_types::{
+    jid::JID,
+    user::{ContactInfo, LocalChatSettings},
+};
+
+use crate::{
+    AppStateSyncKey, AppStateSyncKeyStore, ChatSettingsStore, ContactEntry, ContactStore, Device,
+    DeviceStore, IdentityStore, PreKey, PreKeyStore, SenderKeyStore, SessionStore, StoreError,
+};
+
+/// [`MIGRATIONS`] upgrade the schema one version at a time. The index of a migration plus one
+/// is the version it upgrades to, which is stored in `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    // Version 1: initial schema.
+    "
+    CREATE TABLE device (
+        id                 INTEGER PRIMARY KEY CHECK (id = 0),
+        jid                TEXT,
+        lid                TEXT,
+        registration_id    INTEGER NOT NULL,
+        noise_key          BLOB    NOT NULL CHECK (length(noise_key) = 32),
+        identity_key       BLOB    NOT NULL CHECK (length(identity_key) = 32),
+        signed_pre_key     BLOB    NOT NULL CHECK (length(signed_pre_key) = 32),
+        signed_pre_key_id  INTEGER NOT NULL,
+        signed_pre_key_sig BLOB,
+        adv_secret_key     BLOB    NOT NULL CHECK (length(adv_secret_key) = 32),
+        account            BLOB,
+        platform           TEXT    NOT NULL DEFAULT '',
+        business_name      TEXT    NOT NULL DEFAULT '',
+        push_name          TEXT    NOT NULL DEFAULT ''
+    );
+
+    CREATE TABLE identity_keys (
+        their_id TEXT PRIMARY KEY,
+        identity BLOB NOT NULL CHECK (length(identity) = 32)
+    );
+
+    CREATE TABLE sessions (
+        their_id TEXT PRIMARY KEY,
+        session  BLOB NOT NULL
+    );
+
+    CREATE TABLE pre_keys (
+        key_id   INTEGER PRIMARY KEY,
+        key      BLOB    NOT NULL CHECK (length(key) = 32),
+        uploaded INTEGER NOT NULL
+    );
+
+    -- Pre-key IDs are never reused, even after the newest key was removed.
+    CREATE TABLE pre_key_counter (
+        id      INTEGER PRIMARY KEY CHECK (id = 0),
+        last_id INTEGER NOT NULL
+    );
+    INSERT INTO pre_key_counter (id, last_id) VALUES (0, 0);
+
+    CREATE TABLE sender_keys (
+        chat_id    TEXT NOT NULL,
+        sender_id  TEXT NOT NULL,
+        sender_key BLOB NOT NULL,
+        PRIMARY KEY (chat_id, sender_id)
+    );
+
+    CREATE TABLE app_state_sync_keys (
+        key_id      BLOB PRIMARY KEY,
+        key_data    BLOB    NOT NULL,
+        timestamp   INTEGER NOT NULL,
+        fingerprint BLOB    NOT NULL
+    );
+
+    CREATE TABLE contacts (
+        their_jid     TEXT PRIMARY KEY,
+        first_name    TEXT NOT NULL DEFAULT '',
+        full_name     TEXT NOT NULL DEFAULT '',
+        push_name     TEXT NOT NULL DEFAULT '',
+        business_name TEXT NOT NULL DEFAULT ''
+    );
+
+    CREATE TABLE chat_settings (
+        chat_jid    TEXT PRIMARY KEY,
+        muted_until INTEGER NOT NULL DEFAULT 0,
+        pinned      INTEGER NOT NULL DEFAULT 0,
+        archived    INTEGER NOT NULL DEFAULT 0
+    );
+    ",
+];
+
+fn key_pair_from_private(private_key: &[u8]) -> Result<KeyPair, StoreError> {
+    let private_key = PrivateKey::deserialize(private_key)?;
+    Ok(KeyPair::new(private_key.public_key()?, private_key))
+}
+
+fn parse_jid(jid: Option<String>) -> Result<Option<JID>, StoreError> {
+    jid.map(|jid| jid.parse().map_err(|_| StoreError::InvalidData("JID")))
+        .transpose()
+}
+
+fn to_array(bytes: Vec<u8>, what: &'static str) -> Result<[u8; 32], StoreError> {
+    bytes.try_into().map_err(|_| StoreError::InvalidData(what))
+}
+
+/// Escapes the `LIKE` wildcards in `value`, for use with `ESCAPE '\'`.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// [`SqliteStore`] persists everything in a SQLite database, which holds a single device.
+///
+/// Queries run synchronously on the calling task, which is fine as they only touch a local
+/// file and are quick.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database at the given path, creating it and upgrading the schema if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        SqliteStore::from_connection(Connection::open(path)?)
+    }
+
+    /// Opens a new in-memory database, which is lost when the store is dropped.
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        SqliteStore::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self, StoreError> {
+        upgrade(&mut conn)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // SQLite rolls back unfinished transactions, so a poisoned lock is still safe to use.
+        self.conn.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Runs all migrations newer than the current version of the database, each in its own
+/// transaction.
+fn upgrade(conn: &mut Connection) -> Result<(), StoreError> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let latest = MIGRATIONS.len() as u32;
+    if version > latest {
+        return Err(StoreError::UnsupportedVersion(version, latest));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn device_from_row(row: &Row<'_>) -> Result<Device, StoreError> {
+    let signed_pre_key_private: Vec<u8> = row.get("signed_pre_key")?;
+    let account: Option<Vec<u8>> = row.get("account")?;
+    Ok(Device {
+        noise_key: key_pair_from_private(&row.get::<_, Vec<u8>>("noise_key")?)?,
+        identity_key: key_pair_from_private(&row.get::<_, Vec<u8>>("identity_key")?)?,
+        signed_pre_key: PreKey {
+            key_id: row.get("signed_pre_key_id")?,
+            key_pair: key_pair_from_private(&signed_pre_key_private)?,
+            signature: row.get("signed_pre_key_sig")?,
+        },
+        registration_id: row.get("registration_id")?,
+        adv_secret_key: to_array(row.get("adv_secret_key")?, "adv secret key")?,
+        id: parse_jid(row.get("jid")?)?,
+        lid: parse_jid(row.get("lid")?)?,
+        account: account
+            .map(|account| AdvSignedDeviceIdentity::decode(account.as_slice()))
+            .transpose()?,
+        platform: row.get("platform")?,
+        business_name: row.get("business_name")?,
+        push_name: row.get("push_name")?,
+    })
+}
+
+#[async_trait]
+impl DeviceStore for SqliteStore {
+    async fn get_device(&self) -> Result<Option<Device>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT * FROM device WHERE id = 0")?;
+        let mut rows = stmt.query([])?;
+        rows.next()?.map(device_from_row).transpose()
+    }
+
+    async fn put_device(&self, device: &Device) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO device (
+                id, jid, lid, registration_id, noise_key, identity_key, signed_pre_key,
+                signed_pre_key_id, signed_pre_key_sig, adv_secret_key, account, platform,
+                business_name, push_name
+            ) VALUES (0, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                device.id.as_ref().map(JID::to_string),
+                device.lid.as_ref().map(JID::to_string),
+                device.registration_id,
+                device.noise_key.private_key.serialize(),
+                device.identity_key.private_key.serialize(),
+                device.signed_pre_key.key_pair.private_key.serialize(),
+                device.signed_pre_key.key_id,
+                device.signed_pre_key.signature,
+                device.adv_secret_key,
+                device.account.as_ref().map(Message::encode_to_vec),
+                device.platform,
+                device.business_name,
+                device.push_name,
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_device(&self) -> Result<(), StoreError> {
+        self.conn().execute("DELETE FROM device", [])?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdentityStore for SqliteStore {
+    async fn put_identity(&self, address: &str, key: [u8; 32]) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO identity_keys (their_id, identity) VALUES (?1, ?2)",
+            params![address, key],
+        )?;
+        Ok(())
+    }
+
+    async fn get_identity(&self, address: &str) -> Result<Option<[u8; 32]>, StoreError> {
+        self.conn()
+            .query_row(
+                "SELECT identity FROM identity_keys WHERE their_id = ?1",
+                [address],
+                |row| row.get(0),
+            )
+            .optional()?
+            .map(|key| to_array(key, "identity key"))
+            .transpose()
+    }
+
+    async fn delete_all_identities(&self, phone: &str) -> Result<(), StoreError> {
+        self.conn().execute(
+            "DELETE FROM identity_keys WHERE their_id LIKE ?1 || '.%' ESCAPE '\\'",
+            [escape_like(phone)],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_identity(&self, address: &str) -> Result<(), StoreError> {
+        self.conn()
+            .execute("DELETE FROM identity_keys WHERE their_id = ?1", [address])?;
+        Ok(())
+    }
+
+    async fn is_trusted_identity(&self, address: &str, key: [u8; 32]) -> Result<bool, StoreError> {
+        Ok(self
+            .get_identity(address)
+            .await?
+            .is_none_or(|stored| stored == key))
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn get_session(&self, address: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT session FROM sessions WHERE their_id = ?1",
+                [address],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    async fn has_session(&self, address: &str) -> Result<bool, StoreError> {
+        Ok(self.conn().query_row(
+            "SELECT EXISTS (SELECT 1 FROM sessions WHERE their_id = ?1)",
+            [address],
+            |row| row.get(0),
+        )?)
+    }
+
+    async fn put_session(&self, address: &str, session: &[u8]) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO sessions (their_id, session) VALUES (?1, ?2)",
+            params![address, session],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&self, phone: &str) -> Result<(), StoreError> {
+        self.conn().execute(
+            "DELETE FROM sessions WHERE their_id LIKE ?1 || '.%' ESCAPE '\\'",
+            [escape_like(phone)],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_session(&self, address: &str) -> Result<(), StoreError> {
+        self.conn()
+            .execute("DELETE FROM sessions WHERE their_id = ?1", [address])?;
+        Ok(())
+    }
+}
+
+fn pre_key_from_row(row: &Row<'_>) -> Result<PreKey, StoreError> {
+    Ok(PreKey {
+        key_id: row.get("key_id")?,
+        key_pair: key_pair_from_private(&row.get::<_, Vec<u8>>("key")?)?,
+        signature: None,
+    })
+}
+
+/// Generates a pre-key with the next ID from the counter and inserts it. The caller should
+/// run this in a transaction, so that the counter and keys stay consistent.
+fn insert_new_pre_key(conn: &Connection, uploaded: bool) -> Result<PreKey, StoreError> {
+    let id: u32 = conn.query_row(
+        "UPDATE pre_key_counter SET last_id = last_id + 1 WHERE id = 0 RETURNING last_id",
+        [],
+        |row| row.get(0),
+    )?;
+    let pre_key = PreKey::generate(id);
+    conn.execute(
+        "INSERT INTO pre_keys (key_id, key, uploaded) VALUES (?1, ?2, ?3)",
+        params![
+            pre_key.key_id,
+            pre_key.key_pair.private_key.serialize(),
+            uploaded
+        ],
+    )?;
+    Ok(pre_key)
+}
+
+#[async_trait]
+impl PreKeyStore for SqliteStore {
+    async fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKey>, StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let mut keys = {
+            let mut stmt = tx.prepare(
+                "SELECT key_id, key FROM pre_keys WHERE uploaded = 0 ORDER BY key_id LIMIT ?1",
+            )?;
+            let mut rows = stmt.query([count])?;
+            let mut keys = Vec::new();
+            while let Some(row) = rows.next()? {
+                keys.push(pre_key_from_row(row)?);
+            }
+            keys
+        };
+        while keys.len() < count as usize {
+            keys.push(insert_new_pre_key(&tx, false)?);
+        }
+        tx.commit()?;
+        Ok(keys)
+    }
+
+    async fn gen_one_pre_key(&self) -> Result<PreKey, StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let pre_key = insert_new_pre_key(&tx, true)?;
+        tx.commit()?;
+        Ok(pre_key)
+    }
+
+    async fn get_pre_key(&self, id: u32) -> Result<Option<PreKey>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT key_id, key FROM pre_keys WHERE key_id = ?1")?;
+        let mut rows = stmt.query([id])?;
+        rows.next()?.map(pre_key_from_row).transpose()
+    }
+
+    async fn remove_pre_key(&self, id: u32) -> Result<(), StoreError> {
+        self.conn()
+            .execute("DELETE FROM pre_keys WHERE key_id = ?1", [id])?;
+        Ok(())
+    }
+
+    async fn mark_pre_keys_as_uploaded(&self, up_to_id: u32) -> Result<(), StoreError> {
+        self.conn().execute(
+            "UPDATE pre_keys SET uploaded = 1 WHERE key_id <= ?1",
+            [up_to_id],
+        )?;
+        Ok(())
+    }
+
+    async fn uploaded_pre_key_count(&self) -> Result<u32, StoreError> {
+        Ok(self.conn().query_row(
+            "SELECT COUNT(*) FROM pre_keys WHERE uploaded = 1",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+}
+
+#[async_trait]
+impl SenderKeyStore for SqliteStore {
+    async fn put_sender_key(
+        &self,
+        group: &str,
+        user: &str,
+        session: &[u8],
+    ) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO sender_keys (chat_id, sender_id, sender_key)
+             VALUES (?1, ?2, ?3)",
+            params![group, user, session],
+        )?;
+        Ok(())
+    }
+
+    async fn get_sender_key(&self, group: &str, user: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT sender_key FROM sender_keys WHERE chat_id = ?1 AND sender_id = ?2",
+                [group, user],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+}
+
+#[async_trait]
+impl AppStateSyncKeyStore for SqliteStore {
+    async fn put_app_state_sync_key(
+        &self,
+        id: &[u8],
+        key: AppStateSyncKey,
+    ) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO app_state_sync_keys (key_id, key_data, timestamp, fingerprint)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![id, key.data, key.timestamp, key.fingerprint],
+        )?;
+        Ok(())
+    }
+
+    async fn get_app_state_sync_key(
+        &self,
+        id: &[u8],
+    ) -> Result<Option<AppStateSyncKey>, StoreError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT key_data, timestamp, fingerprint FROM app_state_sync_keys
+                 WHERE key_id = ?1",
+                [id],
+                |row| {
+                    Ok(AppStateSyncKey {
+                        data: row.get(0)?,
+                        timestamp: row.get(1)?,
+                        fingerprint: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    async fn get_latest_app_state_sync_key_id(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT key_id FROM app_state_sync_keys ORDER BY timestamp DESC LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+}
+
+/// Upserts a single name column of a contact, returning the previous value.
+fn put_contact_column(
+    conn: &mut Connection,
+    user: &JID,
+    column: &str,
+    name: &str,
+) -> Result<(bool, String), StoreError> {
+    let tx = conn.transaction()?;
+    let jid = user.to_string();
+    let previous: String = tx
+        .query_row(
+            &format!("SELECT {column} FROM contacts WHERE their_jid = ?1"),
+            [&jid],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or_default();
+    if previous == name {
+        return Ok((false, previous));
+    }
+    tx.execute(
+        &format!(
+            "INSERT INTO contacts (their_jid, {column}) VALUES (?1, ?2)
+             ON CONFLICT (their_jid) DO UPDATE SET {column} = excluded.{column}"
+        ),
+        [&jid, name],
+    )?;
+    tx.commit()?;
+    Ok((true, previous))
+}
+
+fn contact_from_row(row: &Row<'_>) -> rusqlite::Result<ContactInfo> {
+    Ok(ContactInfo {
+        first_name: row.get("first_name")?,
+        full_name: row.get("full_name")?,
+        push_name: row.get("push_name")?,
+        business_name: row.get("business_name")?,
+    })
+}
+
+const PUT_CONTACT_NAME_QUERY: &str = "
+    INSERT INTO contacts (their_jid, full_name, first_name) VALUES (?1, ?2, ?3)
+    ON CONFLICT (their_jid) DO UPDATE
+        SET full_name = excluded.full_name, first_name = excluded.first_name";
+
+#[async_trait]
+impl ContactStore for SqliteStore {
+    async fn put_push_name(
+        &self,
+        user: &JID,
+        push_name: &str,
+    ) -> Result<(bool, String), StoreError> {
+        put_contact_column(&mut self.conn(), user, "push_name", push_name)
+    }
+
+    async fn put_business_name(
+        &self,
+        user: &JID,
+        business_name: &str,
+    ) -> Result<(bool, String), StoreError> {
+        put_contact_column(&mut self.conn(), user, "business_name", business_name)
+    }
+
+    async fn put_contact_name(
+        &self,
+        user: &JID,
+        full_name: &str,
+        first_name: &str,
+    ) -> Result<(), StoreError> {
+        self.conn().execute(
+            PUT_CONTACT_NAME_QUERY,
+            params![user.to_string(), full_name, first_name],
+        )?;
+        Ok(())
+    }
+
+    async fn put_all_contact_names(&self, contacts: &[ContactEntry]) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(PUT_CONTACT_NAME_QUERY)?;
+            for contact in contacts {
+                stmt.execute(params![
+                    contact.jid.to_string(),
+                    contact.full_name,
+                    contact.first_name
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn get_contact(&self, user: &JID) -> Result<Option<ContactInfo>, StoreError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT * FROM contacts WHERE their_jid = ?1",
+                [user.to_string()],
+                contact_from_row,
+            )
+            .optional()?)
+    }
+
+    async fn get_all_contacts(&self) -> Result<HashMap<JID, ContactInfo>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT * FROM contacts")?;
+        let mut rows = stmt.query([])?;
+        let mut contacts = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let jid = parse_jid(Some(row.get("their_jid")?))?.unwrap();
+            contacts.insert(jid, contact_from_row(row)?);
+        }
+        Ok(contacts)
+    }
+}
+
+impl SqliteStore {
+    /// Upserts a single column of the settings of a chat.
+    fn put_chat_setting(
+        &self,
+        chat: &JID,
+        column: &str,
+        value: impl rusqlite::ToSql,
+    ) -> Result<(), StoreError> {
+        self.conn().execute(
+            &format!(
+                "INSERT INTO chat_settings (chat_jid, {column}) VALUES (?1, ?2)
+                 ON CONFLICT (chat_jid) DO UPDATE SET {column} = excluded.{column}"
+            ),
+            params![chat.to_string(), value],
+        )?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChatSettingsStore for SqliteStore {
+    async fn put_muted_until(
+        &self,
+        chat: &JID,
+        muted_until: time::OffsetDateTime,
+    ) -> Result<(), StoreError> {
+        self.put_chat_setting(chat, "muted_until", muted_until.unix_timestamp())
+    }
+
+    async fn put_pinned(&self, chat: &JID, pinned: bool) -> Result<(), StoreError> {
+        self.put_chat_setting(chat, "pinned", pinned)
+    }
+
+    async fn put_archived(&self, chat: &JID, archived: bool) -> Result<(), StoreError> {
+        self.put_chat_setting(chat, "archived", archived)
+    }
+
+    async fn get_chat_settings(&self, chat: &JID) -> Result<Option<LocalChatSettings>, StoreError> {
+        let settings = self
+            .conn()
+            .query_row(
+                "SELECT muted_until, pinned, archived FROM chat_settings WHERE chat_jid = ?1",
+                [chat.to_string()],
+                |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        settings
+            .map(|(muted_until, pinned, archived)| {
+                Ok(LocalChatSettings {
+                    muted_until: time::OffsetDateTime::from_unix_timestamp(muted_until)
+                        .map_err(|_| StoreError::InvalidData("mute timestamp"))?,
+                    pinned,
+                    archived,
+                })
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sqlite_store() {
+        crate::tests::exercise_store(&SqliteStore::open_in_memory().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn reopen_keeps_device() {
+        let path = std::env::temp_dir().join(format!("wa_store_{}.db", std::process::id()));
+        let device = Device::generate().unwrap();
+        {
+            let store = SqliteStore::open(&path).unwrap();
+            store.put_device(&device).await.unwrap();
+        }
+        let store = SqliteStore::open(&path).unwrap();
+        let stored = store.get_device().await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            stored.identity_key.public_key,
+            device.identity_key.public_key
+        );
+        assert_eq!(stored.registration_id, device.registration_id);
+    }
+
+    #[test]
+    fn newer_database_is_rejected() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", 99).unwrap();
+        assert!(matches!(
+            SqliteStore::from_connection(conn),
+            Err(StoreError::UnsupportedVersion(99, _))
+        ));
+    }
+}
@@ -0,0 +1,508 @@
+use strum::{Display, EnumString};
+use wa_proto::items::{
+    wa_sync_action::SyncActionValue, wa_web_protobufs_e2e,
+    wa_web_protobufs_history_sync::HistorySync as HistorySyncData,
+};
+
+use crate::{
+    call::{BasicCallMeta, CallRemoteMeta},
+    group::{
+        GroupAnnounce, GroupDelete, GroupEphemeral, GroupInfo, GroupLinkChange, GroupLocked,
+        GroupName, GroupTopic,
+    },
+    jid::JID,
+    message::{MessageID, MessageInfo, MessageSource},
+    newsletter::{NewsletterMessage, NewsletterMetadata, NewsletterMuteState, NewsletterRole},
+    presence::{ChatPresence, ChatPresenceMedia, Presence, ReceiptType},
+    user::{PrivacySettingType, PrivacySettings},
+};
+
+/// [`Event`] is everything the client reports to the application, from connection state
+/// changes to incoming messages.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// New QR codes are available for pairing. Only emitted when the device isn't logged in.
+    QR(QR),
+    /// The phone scanned the QR code or entered the pairing code, and the device is now linked.
+    PairSuccess(PairSuccess),
+    /// Pairing failed after the phone scanned the code.
+    PairError(PairError),
+    /// The client connected and logged in, and is ready to send and receive messages.
+    Connected,
+    /// The connection was closed, whether by the client or the server.
+    Disconnected,
+    /// The device was unlinked, either while connected or while connecting.
+    LoggedOut(LoggedOut),
+    /// Another client connected with the same keys, so the server closed this connection.
+    StreamReplaced,
+    /// The account is temporarily banned.
+    TemporaryBan(TemporaryBan),
+    /// The server rejected the connection because the client version is too old.
+    ClientOutdated,
+    /// The server rejected the connection for another reason.
+    ConnectFailure(ConnectFailure),
+    /// The server sent a stream error that isn't handled otherwise.
+    StreamError(StreamError),
+    /// The connection dropped and the client is about to reconnect automatically.
+    Reconnecting(Reconnecting),
+    /// The server didn't answer a keepalive ping in time.
+    KeepAliveTimeout(KeepAliveTimeout),
+    /// The server answered a keepalive ping after previous pings timed out.
+    KeepAliveRestored,
+
+    Message(Box<Message>),
+    UndecryptableMessage(Box<UndecryptableMessage>),
+    Receipt(Receipt),
+    Presence(PresenceUpdate),
+    ChatPresence(ChatPresenceUpdate),
+
+    GroupInfo(Box<GroupInfoChange>),
+    /// The current user was added to a group or created one.
+    JoinedGroup(Box<JoinedGroup>),
+
+    NewsletterJoin(Box<NewsletterMetadata>),
+    NewsletterLeave(NewsletterLeave),
+    NewsletterMuteChange(NewsletterMuteChange),
+    NewsletterLiveUpdate(NewsletterLiveUpdate),
+
+    CallOffer(CallOffer),
+    CallAccept(CallAccept),
+    CallTerminate(CallTerminate),
+    CallReject(BasicCallMeta),
+
+    HistorySync(Box<HistorySync>),
+    AppState(Box<AppState>),
+    AppStateSyncComplete(AppStateSyncComplete),
+    IdentityChange(IdentityChange),
+    Blocklist(Blocklist),
+    PrivacySettings(Box<PrivacySettingsChange>),
+}
+
+/// [`EventKind`] is the kind of an [`Event`] without its data, which is used to only handle
+/// some kinds of events.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "snake_case")]
+pub enum EventKind {
+    QR,
+    PairSuccess,
+    PairError,
+    Connected,
+    Disconnected,
+    LoggedOut,
+    StreamReplaced,
+    TemporaryBan,
+    ClientOutdated,
+    ConnectFailure,
+    StreamError,
+    Reconnecting,
+    KeepAliveTimeout,
+    KeepAliveRestored,
+    Message,
+    UndecryptableMessage,
+    Receipt,
+    Presence,
+    ChatPresence,
+    GroupInfo,
+    JoinedGroup,
+    NewsletterJoin,
+    NewsletterLeave,
+    NewsletterMuteChange,
+    NewsletterLiveUpdate,
+    CallOffer,
+    CallAccept,
+    CallTerminate,
+    CallReject,
+    HistorySync,
+    AppState,
+    AppStateSyncComplete,
+    IdentityChange,
+    Blocklist,
+    PrivacySettings,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::QR(_) => EventKind::QR,
+            Event::PairSuccess(_) => EventKind::PairSuccess,
+            Event::PairError(_) => EventKind::PairError,
+            Event::Connected => EventKind::Connected,
+            Event::Disconnected => EventKind::Disconnected,
+            Event::LoggedOut(_) => EventKind::LoggedOut,
+            Event::StreamReplaced => EventKind::StreamReplaced,
+            Event::TemporaryBan(_) => EventKind::TemporaryBan,
+            Event::ClientOutdated => EventKind::ClientOutdated,
+            Event::ConnectFailure(_) => EventKind::ConnectFailure,
+            Event::StreamError(_) => EventKind::StreamError,
+            Event::Reconnecting(_) => EventKind::Reconnecting,
+            Event::KeepAliveTimeout(_) => EventKind::KeepAliveTimeout,
+            Event::KeepAliveRestored => EventKind::KeepAliveRestored,
+            Event::Message(_) => EventKind::Message,
+            Event::UndecryptableMessage(_) => EventKind::UndecryptableMessage,
+            Event::Receipt(_) => EventKind::Receipt,
+            Event::Presence(_) => EventKind::Presence,
+            Event::ChatPresence(_) => EventKind::ChatPresence,
+            Event::GroupInfo(_) => EventKind::GroupInfo,
+            Event::JoinedGroup(_) => EventKind::JoinedGroup,
+            Event::NewsletterJoin(_) => EventKind::NewsletterJoin,
+            Event::NewsletterLeave(_) => EventKind::NewsletterLeave,
+            Event::NewsletterMuteChange(_) => EventKind::NewsletterMuteChange,
+            Event::NewsletterLiveUpdate(_) => EventKind::NewsletterLiveUpdate,
+            Event::CallOffer(_) => EventKind::CallOffer,
+            Event::CallAccept(_) => EventKind::CallAccept,
+            Event::CallTerminate(_) => EventKind::CallTerminate,
+            Event::CallReject(_) => EventKind::CallReject,
+            Event::HistorySync(_) => EventKind::HistorySync,
+            Event::AppState(_) => EventKind::AppState,
+            Event::AppStateSyncComplete(_) => EventKind::AppStateSyncComplete,
+            Event::IdentityChange(_) => EventKind::IdentityChange,
+            Event::Blocklist(_) => EventKind::Blocklist,
+            Event::PrivacySettings(_) => EventKind::PrivacySettings,
+        }
+    }
+}
+
+/// [`QR`] contains the QR codes to show to the user. Each code is valid for a while, after
+/// which the next one should be shown.
+#[derive(Clone, Debug)]
+pub struct QR {
+    pub codes: Vec<String>,
+}
+
+/// [`PairSuccess`] contains the details of the account the device was linked to.
+#[derive(Clone, Debug)]
+pub struct PairSuccess {
+    pub id: JID,
+    pub lid: Option<JID>,
+    pub business_name: String,
+    pub platform: String,
+}
+
+/// [`PairError`] contains the details of the account that tried to link the device, and why
+/// it failed.
+#[derive(Clone, Debug)]
+pub struct PairError {
+    pub id: Option<JID>,
+    pub error: String,
+}
+
+/// [`ConnectFailureReason`] is the code the server sends when it rejects a connection.
+#[derive(Clone, Debug, Display, EnumString, PartialEq)]
+pub enum ConnectFailureReason {
+    #[strum(to_string = "401")]
+    LoggedOut,
+    #[strum(to_string = "402")]
+    TempBanned,
+    #[strum(to_string = "403")]
+    MainDeviceGone,
+    #[strum(to_string = "405")]
+    ClientOutdated,
+    #[strum(to_string = "406")]
+    UnknownLogout,
+    #[strum(to_string = "409")]
+    BadUserAgent,
+    #[strum(to_string = "500")]
+    InternalServerError,
+    #[strum(to_string = "501")]
+    Experimental,
+    #[strum(to_string = "503")]
+    ServiceUnavailable,
+    #[strum(default)]
+    UnknownVariant(String),
+}
+
+impl ConnectFailureReason {
+    /// Returns true if the reason means the device was unlinked, so its keys are no longer
+    /// valid.
+    pub fn is_logged_out(&self) -> bool {
+        matches!(
+            self,
+            ConnectFailureReason::LoggedOut
+                | ConnectFailureReason::MainDeviceGone
+                | ConnectFailureReason::UnknownLogout
+        )
+    }
+
+    /// Returns true if the failure is temporary on the server's side, so connecting again
+    /// later may succeed.
+    pub fn should_reconnect(&self) -> bool {
+        matches!(
+            self,
+            ConnectFailureReason::InternalServerError | ConnectFailureReason::ServiceUnavailable
+        )
+    }
+}
+
+/// [`LoggedOut`] is emitted when the device was unlinked from the phone.
+#[derive(Clone, Debug)]
+pub struct LoggedOut {
+    /// True if the server rejected the connection because of the logout, false if it was
+    /// logged out while connected.
+    pub on_connect: bool,
+    pub reason: ConnectFailureReason,
+}
+
+/// [`TempBanReason`] is the reason for a [`TemporaryBan`].
+#[derive(Clone, Debug, Display, EnumString, PartialEq)]
+pub enum TempBanReason {
+    #[strum(to_string = "101")]
+    SentToTooManyPeople,
+    #[strum(to_string = "102")]
+    BlockedByUsers,
+    #[strum(to_string = "103")]
+    CreatedTooManyGroups,
+    #[strum(to_string = "104")]
+    SentTooManySameMessage,
+    #[strum(to_string = "106")]
+    BroadcastList,
+    #[strum(default)]
+    UnknownVariant(String),
+}
+
+/// [`TemporaryBan`] is emitted when the server rejects the connection because the account is
+/// temporarily banned.
+#[derive(Clone, Debug)]
+pub struct TemporaryBan {
+    pub code: TempBanReason,
+    pub expire: time::Duration,
+}
+
+/// [`ConnectFailure`] is emitted when the server rejects the connection for a reason that
+/// doesn't have its own event.
+#[derive(Clone, Debug)]
+pub struct ConnectFailure {
+    pub reason: ConnectFailureReason,
+    pub message: String,
+}
+
+/// [`StreamError`] is emitted for `stream:error` stanzas that aren't handled otherwise.
+#[derive(Clone, Debug)]
+pub struct StreamError {
+    pub code: String,
+}
+
+/// [`Reconnecting`] is emitted before each automatic reconnection attempt. The attempt
+/// count is only reset once the server accepts a login, so a connection that keeps dropping
+/// right after connecting is counted as the same outage.
+#[derive(Clone, Debug)]
+pub struct Reconnecting {
+    pub attempt: u32,
+    /// How long the client waits before connecting.
+    pub delay: std::time::Duration,
+}
+
+/// [`KeepAliveTimeout`] is emitted when a keepalive ping fails. The client reconnects after a
+/// number of consecutive failures.
+#[derive(Clone, Debug)]
+pub struct KeepAliveTimeout {
+    /// The number of consecutive pings that failed, including this one.
+    pub error_count: u32,
+    pub last_success: Option<time::OffsetDateTime>,
+}
+
+/// [`Message`] is an incoming message, or one sent by another device of the current user.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub info: MessageInfo,
+    pub message: wa_web_protobufs_e2e::Message,
+}
+
+/// [`UndecryptableMessage`] is emitted when a message couldn't be decrypted. A retry receipt
+/// is sent automatically, so the message might still arrive later.
+#[derive(Clone, Debug)]
+pub struct UndecryptableMessage {
+    pub info: MessageInfo,
+    /// True if the message was sent without any encrypted content, which means the sender's
+    /// phone will send it again later.
+    pub is_unavailable: bool,
+}
+
+/// [`Receipt`] is emitted when an outgoing message is delivered to or read by another user, or
+/// when another device of the current user reads an incoming message.
+#[derive(Clone, Debug)]
+pub struct Receipt {
+    pub source: MessageSource,
+    pub message_ids: Vec<MessageID>,
+    pub timestamp: time::OffsetDateTime,
+    pub r#type: ReceiptType,
+}
+
+/// [`PresenceUpdate`] is emitted when a user whose presence was subscribed to comes online or
+/// goes offline.
+#[derive(Clone, Debug)]
+pub struct PresenceUpdate {
+    pub from: JID,
+    pub presence: Presence,
+    /// When the user was last online, if they went offline and share their last seen time.
+    pub last_seen: Option<time::OffsetDateTime>,
+}
+
+/// [`ChatPresenceUpdate`] is emitted when a user starts or stops typing or recording in a
+/// chat.
+#[derive(Clone, Debug)]
+pub struct ChatPresenceUpdate {
+    pub source: MessageSource,
+    pub state: ChatPresence,
+    pub media: ChatPresenceMedia,
+}
+
+/// [`GroupInfoChange`] is emitted when the metadata or participants of a group change. Only
+/// the fields of the things that changed are set.
+#[derive(Clone, Debug)]
+pub struct GroupInfoChange {
+    pub jid: JID,
+    pub notify: String,
+    pub sender: Option<JID>,
+    pub timestamp: time::OffsetDateTime,
+
+    pub name: Option<GroupName>,
+    pub topic: Option<GroupTopic>,
+    pub locked: Option<GroupLocked>,
+    pub announce: Option<GroupAnnounce>,
+    pub ephemeral: Option<GroupEphemeral>,
+    pub delete: Option<GroupDelete>,
+    pub link: Option<GroupLinkChange>,
+    pub unlink: Option<GroupLinkChange>,
+
+    pub new_invite_link: Option<String>,
+    pub prev_participant_version_id: String,
+    pub participant_version_id: String,
+
+    pub join_reason: String,
+    pub join: Vec<JID>,
+    pub leave: Vec<JID>,
+    pub promote: Vec<JID>,
+    pub demote: Vec<JID>,
+}
+
+/// [`JoinedGroup`] is emitted when the current user joins a group, with the full info of the
+/// group.
+#[derive(Clone, Debug)]
+pub struct JoinedGroup {
+    /// Why the user joined, e.g. `invite` if they joined with a link.
+    pub reason: String,
+    /// `new` if the user created the group.
+    pub r#type: String,
+    pub create_key: String,
+    pub info: GroupInfo,
+}
+
+/// [`NewsletterLeave`] is emitted when the current user unfollows a newsletter.
+#[derive(Clone, Debug)]
+pub struct NewsletterLeave {
+    pub id: JID,
+    pub role: NewsletterRole,
+}
+
+/// [`NewsletterMuteChange`] is emitted when a newsletter is muted or unmuted.
+#[derive(Clone, Debug)]
+pub struct NewsletterMuteChange {
+    pub id: JID,
+    pub mute: NewsletterMuteState,
+}
+
+/// [`NewsletterLiveUpdate`] is emitted when view or reaction counts of newsletter messages
+/// change.
+#[derive(Clone, Debug)]
+pub struct NewsletterLiveUpdate {
+    pub jid: JID,
+    pub time: time::OffsetDateTime,
+    pub messages: Vec<NewsletterMessage>,
+}
+
+/// [`CallOffer`] is emitted when someone starts a call.
+#[derive(Clone, Debug)]
+pub struct CallOffer {
+    pub meta: BasicCallMeta,
+    pub remote: CallRemoteMeta,
+}
+
+/// [`CallAccept`] is emitted when a call is accepted on another device.
+#[derive(Clone, Debug)]
+pub struct CallAccept {
+    pub meta: BasicCallMeta,
+    pub remote: CallRemoteMeta,
+}
+
+/// [`CallTerminate`] is emitted when a call ends.
+#[derive(Clone, Debug)]
+pub struct CallTerminate {
+    pub meta: BasicCallMeta,
+    pub reason: String,
+}
+
+/// [`HistorySync`] contains past messages and chats sent by the phone after pairing.
+#[derive(Clone, Debug)]
+pub struct HistorySync {
+    pub data: HistorySyncData,
+}
+
+/// [`AppState`] is a change in app state, like a chat being muted or archived on another
+/// device.
+#[derive(Clone, Debug)]
+pub struct AppState {
+    /// The index of the mutation, which starts with the action name followed by its
+    /// arguments, e.g. `["mute", "1234@s.whatsapp.net"]`.
+    pub index: Vec<String>,
+    pub action: SyncActionValue,
+}
+
+/// [`AppStateSyncComplete`] is emitted when an app state collection has been fully synced.
+#[derive(Clone, Debug)]
+pub struct AppStateSyncComplete {
+    pub name: String,
+}
+
+/// [`IdentityChange`] is emitted when another user changes their primary device, which
+/// changes their identity key.
+#[derive(Clone, Debug)]
+pub struct IdentityChange {
+    pub jid: JID,
+    pub timestamp: time::OffsetDateTime,
+    /// True if the change was noticed from a message instead of a notification.
+    pub implicit: bool,
+}
+
+#[derive(Clone, Debug, Display, EnumString, PartialEq)]
+pub enum BlocklistAction {
+    #[strum(to_string = "")]
+    Modify,
+    #[strum(to_string = "default")]
+    Default,
+    #[strum(default)]
+    UnknownVariant(String),
+}
+
+#[derive(Clone, Debug, Display, EnumString, PartialEq)]
+pub enum BlocklistChangeAction {
+    #[strum(to_string = "block")]
+    Block,
+    #[strum(to_string = "unblock")]
+    Unblock,
+    #[strum(default)]
+    UnknownVariant(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct BlocklistChange {
+    pub jid: JID,
+    pub action: BlocklistChangeAction,
+}
+
+/// [`Blocklist`] is emitted when the blocklist changes. If the action is
+/// [`BlocklistAction::Default`], the changes are the whole list rather than a diff.
+#[derive(Clone, Debug)]
+pub struct Blocklist {
+    pub action: BlocklistAction,
+    pub dhash: String,
+    pub prev_dhash: String,
+    pub changes: Vec<BlocklistChange>,
+}
+
+/// [`PrivacySettingsChange`] is emitted when the user changes their privacy settings on
+/// another device.
+#[derive(Clone, Debug)]
+pub struct PrivacySettingsChange {
+    pub new_settings: PrivacySettings,
+    pub changed: Vec<PrivacySettingType>,
+}
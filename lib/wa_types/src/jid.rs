@@ -13,7 +13,7 @@ pub const INTEROP_SERVER: &str = "interop";
 pub const NEWSLETTER_SERVER: &str = "newsletter";
 pub const HOSTED_SERVER: &str = "hosted";
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct JID {
     pub user: String,
     pub raw_agent: u8,